@@ -0,0 +1,270 @@
+// Copyright 2018-2020, Wayfair GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Client side routing for the mring.
+//!
+//! A `RoutingTable` answers which nodes a key lives on without asking the
+//! cluster, `follow` keeps one current by loading the ring and applying the
+//! changes published on the `mring` channel.
+
+use async_tungstenite::async_std::connect_async;
+use futures::{SinkExt, StreamExt};
+use std::sync::{Arc, RwLock};
+use tungstenite::protocol::Message;
+use uring_common::{RequestId, Resizing, Ring, Route};
+use ws_proto::{MRRequest, PSMRing, Protocol, ProtocolSelect, Reply, SubscriberMsg};
+
+const GET_RING: RequestId = RequestId(2);
+
+/// The last known state of the ring
+#[derive(Default, Debug, Clone)]
+pub struct RoutingTable {
+    ring: Ring,
+}
+
+impl RoutingTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn version(&self) -> u64 {
+        self.ring.version
+    }
+
+    pub fn ring(&self) -> &Ring {
+        &self.ring
+    }
+
+    /// The nodes `key` lives on, the primary first. `None` until the ring
+    /// size is known.
+    pub fn route(&self, key: &str) -> Option<Route> {
+        self.ring.route(key.to_string())
+    }
+
+    /// Replaces the table with a snapshot unless it is older than what we
+    /// have, returns whether it was used.
+    pub fn load(&mut self, ring: Ring) -> bool {
+        if ring.version < self.ring.version {
+            return false;
+        }
+        self.ring = ring;
+        true
+    }
+
+    /// Applies a change published on the `mring` channel, changes we
+    /// already know of are ignored.
+    pub fn apply(&mut self, change: PSMRing) -> bool {
+        match change {
+            PSMRing::SetSize { size, version, .. } if version > self.ring.version => {
+                self.ring.size = size;
+                self.ring.version = version;
+                true
+            }
+            PSMRing::NodeAdded { next, version, .. }
+            | PSMRing::NodeRemoved { next, version, .. }
+                if version > self.ring.version =>
+            {
+                self.ring.nodes = next;
+                self.ring.version = version;
+                true
+            }
+            // single handoffs aren't published, so routes of all moving
+            // vnodes carry their previous nodes until the resize completed
+            PSMRing::Resized {
+                from,
+                size,
+                next,
+                relocations,
+                version,
+                ..
+            } if version > self.ring.version => {
+                let previous = std::mem::replace(&mut self.ring.nodes, next);
+                let resizing = Resizing::new(from, previous, &relocations);
+                self.ring.resizing = if resizing.is_done() {
+                    None
+                } else {
+                    Some(resizing)
+                };
+                self.ring.size = size;
+                self.ring.version = version;
+                true
+            }
+            PSMRing::ResizeCompleted { version, .. } if version > self.ring.version => {
+                self.ring.resizing = None;
+                self.ring.version = version;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Keeps `table` current from the uring node at `url` (e.g.
+/// `ws://127.0.0.1:9081`) until the connection closes.
+pub async fn follow(url: &str, table: Arc<RwLock<RoutingTable>>) -> tungstenite::Result<()> {
+    let (mut ws_stream, _) = connect_async(url).await?;
+
+    // subscribe before loading the ring so no change falls in between
+    let requests = vec![
+        serde_json::to_string(&ProtocolSelect::Subscribe {
+            channel: "mring".into(),
+            from: None,
+        }),
+        serde_json::to_string(&ProtocolSelect::Select {
+            rid: RequestId(1),
+            protocol: Protocol::MRing,
+        }),
+        serde_json::to_string(&MRRequest::GetRing { rid: GET_RING }),
+    ];
+    for request in requests {
+        ws_stream.send(Message::text(request.unwrap())).await?;
+    }
+
+    while let Some(msg) = ws_stream.next().await {
+        let msg = msg?;
+        if !msg.is_text() {
+            continue;
+        }
+        let msg = msg.into_data();
+        if let Ok(SubscriberMsg::Msg { msg, .. }) = serde_json::from_slice(&msg) {
+            if let Ok(change) = serde_json::from_value(msg) {
+                table.write().unwrap().apply(change);
+            }
+        } else if let Ok(Reply {
+            code: 200,
+            rid,
+            data,
+        }) = serde_json::from_slice(&msg)
+        {
+            if rid == GET_RING {
+                if let Ok(ring) = serde_json::from_value(data) {
+                    table.write().unwrap().load(ring);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uring_common::{MRingNode, Relocations};
+
+    fn nodes(ids: &[&str]) -> Vec<MRingNode> {
+        let count = ids.len() as u64;
+        ids.iter()
+            .enumerate()
+            .map(|(i, id)| MRingNode {
+                id: id.to_string(),
+                vnodes: (0..4).filter(|v| v % count == i as u64).collect(),
+                ..MRingNode::default()
+            })
+            .collect()
+    }
+
+    fn added(node: &str, next: &[&str], version: u64) -> PSMRing {
+        PSMRing::NodeAdded {
+            node: node.into(),
+            strategy: "continuous".into(),
+            next: nodes(next),
+            relocations: Relocations::new(),
+            version,
+        }
+    }
+
+    #[test]
+    fn follows_changes_in_order() {
+        let mut table = RoutingTable::new();
+        assert!(table.route("foobar").is_none());
+        assert!(table.apply(PSMRing::SetSize {
+            size: 4,
+            strategy: "continuous".into(),
+            replicas: 1,
+            version: 1,
+        }));
+        assert!(table.apply(added("n1", &["n1"], 2)));
+        assert_eq!(table.route("foobar").unwrap().nodes, vec!["n1"]);
+
+        // a snapshot taken before the last change doesn't roll it back
+        let stale = table.ring().clone();
+        assert!(table.apply(added("n2", &["n1", "n2"], 3)));
+        assert!(!table.load(stale));
+        assert!(!table.apply(added("n2", &["n1"], 3)));
+        assert_eq!(table.version(), 3);
+
+        let route = table.route("foobar").unwrap();
+        assert_eq!(route.vnode, 0);
+        assert_eq!(route.nodes, vec!["n1"]);
+        let route = table.route("b").unwrap();
+        assert_eq!(route.vnode, 1);
+        assert_eq!(route.nodes, vec!["n2"]);
+    }
+
+    #[test]
+    fn follows_resizes() {
+        let mut table = RoutingTable::new();
+        table.load(Ring {
+            version: 3,
+            size: 2,
+            nodes: vec![
+                MRingNode {
+                    id: "n1".into(),
+                    vnodes: vec![0],
+                    ..MRingNode::default()
+                },
+                MRingNode {
+                    id: "n2".into(),
+                    vnodes: vec![1],
+                    ..MRingNode::default()
+                },
+            ],
+            resizing: None,
+        });
+        let mut relocations = Relocations::new();
+        relocations
+            .entry("n1".into())
+            .or_default()
+            .destinations
+            .insert("n2".into(), vec![2]);
+        assert!(table.apply(PSMRing::Resized {
+            from: 2,
+            size: 4,
+            strategy: "continuous".into(),
+            next: vec![
+                MRingNode {
+                    id: "n1".into(),
+                    vnodes: vec![0, 3],
+                    ..MRingNode::default()
+                },
+                MRingNode {
+                    id: "n2".into(),
+                    vnodes: vec![1, 2],
+                    ..MRingNode::default()
+                },
+            ],
+            relocations,
+            version: 4,
+        }));
+        // "c" hashes to vnode 2, which is on its way from n1 to n2
+        let route = table.route("c").unwrap();
+        assert_eq!(route.nodes, vec!["n2"]);
+        assert_eq!(route.previous, vec!["n1"]);
+        assert!(table.apply(PSMRing::ResizeCompleted {
+            size: 4,
+            version: 5
+        }));
+        assert!(table.route("c").unwrap().previous.is_empty());
+    }
+}
@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::storage::Record;
 use crate::vnode;
 use async_std::net::SocketAddr;
 use async_std::net::ToSocketAddrs;
@@ -36,6 +37,8 @@ pub(crate) struct Handoff {
     pub partner: String,
     pub chunk: u64,
     pub direction: Direction,
+    /// Key of the last record sent, the next chunk starts after it
+    pub last: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -47,7 +50,7 @@ pub(crate) enum Message {
     HandoffData {
         vnode: u64,
         chunk: u64,
-        data: Vec<String>,
+        data: Vec<Record>,
     },
     HandoffFinish {
         vnode: u64,
@@ -79,11 +82,21 @@ async fn handle_connection(logger: Logger, mut connection: Connection) {
             Ok(Message::HandoffStart { src, vnode }) => {
                 assert!(connection.vnode.is_none());
                 connection.vnode = Some(vnode);
+                let (started, mut start) = channel(1);
                 connection
                     .tasks
-                    .send(vnode::Task::HandoffInStart { src, vnode })
+                    .send(vnode::Task::HandoffInStart {
+                        src,
+                        vnode,
+                        started,
+                    })
                     .await
                     .unwrap();
+                // the ack is held back until we can take the vnode
+                if start.next().await.is_none() {
+                    error!(logger, "handoff for node {} was dropped", vnode);
+                    break;
+                }
                 info!(logger, "handoff for node {} started", vnode);
                 connection
                     .tx
@@ -165,10 +178,14 @@ async fn accept_connection(logger: Logger, stream: TcpStream, tasks: Sender<vnod
             .send(message)
             .await
             .expect("Failed to forward request");
+        // the handler is gone once it dropped the handoff, closing the
+        // connection has the sender retry
         if let Some(resp) = response_rx.next().await {
             if ws_stream.send(resp).await.is_err() {
                 break;
             }
+        } else {
+            break;
         }
     }
     info!(logger, "Closing WebSocket connection: {}", addr);
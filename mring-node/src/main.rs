@@ -15,6 +15,7 @@
 #![recursion_limit = "2048"]
 
 mod handoff;
+mod storage;
 mod uring;
 mod vnode;
 
@@ -22,6 +23,7 @@ use async_std::task;
 use futures::channel::mpsc::channel;
 use slog::Drain;
 use std::env;
+use uring_common::NodeMeta;
 
 const CHANNEL_SIZE: usize = 64usize;
 
@@ -35,6 +37,7 @@ fn main() {
     let logger = slog::Logger::root(drain, o!());
 
     let (tasks_tx, tasks_rx) = channel(crate::CHANNEL_SIZE);
+    let (reports_tx, reports_rx) = channel(crate::CHANNEL_SIZE);
 
     let local = env::args()
         .nth(1)
@@ -45,11 +48,28 @@ fn main() {
         .nth(2)
         .unwrap_or_else(|| panic!("this program requires at least two argument"));
 
+    // placement hints for weighted rings
+    let meta = NodeMeta {
+        weight: env::var("MRING_WEIGHT")
+            .ok()
+            .and_then(|w| w.parse().ok())
+            .unwrap_or(1),
+        zone: env::var("MRING_ZONE").ok(),
+        rack: env::var("MRING_RACK").ok(),
+    };
+
+    // the vnodes we hold survive restarts
+    let path = env::var("MRING_DATA_DIR").unwrap_or_else(|_| format!("mring-{}", local));
+    let store = storage::Store::open(&path)
+        .unwrap_or_else(|e| panic!("failed to open vnode store at {}: {}", path, e));
+
     task::spawn(vnode::run(
         logger.clone(),
         local.clone(),
         tasks_rx,
         tasks_tx.clone(),
+        reports_tx,
+        store,
     ));
 
     task::spawn(handoff::listener(
@@ -58,5 +78,7 @@ fn main() {
         tasks_tx.clone(),
     ));
 
-    task::block_on(uring::run(logger, local, remote, tasks_tx))
+    task::block_on(uring::run(
+        logger, local, meta, remote, tasks_tx, reports_rx,
+    ))
 }
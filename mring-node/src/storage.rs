@@ -0,0 +1,303 @@
+// Copyright 2018-2020, Wayfair GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use rocksdb::{Direction, IteratorMode, WriteBatch, DB};
+use serde_derive::{Deserialize, Serialize};
+use uring_common::key_vnode;
+
+/// Marks a vnode as held by this node
+const VNODE: u8 = b'v';
+/// Marks a vnode still being handed to this node, it is only held once the
+/// handoff finished
+const INCOMING: u8 = b'i';
+/// Prefixes the records of a vnode
+const RECORD: u8 = b'r';
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub(crate) struct Record {
+    pub key: String,
+    pub value: String,
+}
+
+fn marker_key(marker: u8, vnode: u64) -> Vec<u8> {
+    let mut key = vec![marker];
+    key.extend_from_slice(&vnode.to_be_bytes());
+    key
+}
+
+fn vnode_key(vnode: u64) -> Vec<u8> {
+    marker_key(VNODE, vnode)
+}
+
+fn incoming_key(vnode: u64) -> Vec<u8> {
+    marker_key(INCOMING, vnode)
+}
+
+fn record_prefix(vnode: u64) -> Vec<u8> {
+    let mut key = vec![RECORD];
+    key.extend_from_slice(&vnode.to_be_bytes());
+    key
+}
+
+fn record_key(vnode: u64, key: &[u8]) -> Vec<u8> {
+    let mut k = record_prefix(vnode);
+    k.extend_from_slice(key);
+    k
+}
+
+/// The vnodes of this node on disk. Records are keyed by their vnode id
+/// first, so each vnode is a single range of keys.
+pub(crate) struct Store {
+    db: DB,
+}
+
+impl Store {
+    /// Opens the store, the records of handoffs to this node that didn't
+    /// finish before it stopped are discarded.
+    pub(crate) fn open(path: &str) -> Result<Self, rocksdb::Error> {
+        let store = Self {
+            db: DB::open_default(path)?,
+        };
+        for vnode in store.marked(INCOMING) {
+            store.remove(vnode);
+        }
+        Ok(store)
+    }
+
+    /// The vnodes held on disk
+    pub(crate) fn vnodes(&self) -> Vec<u64> {
+        self.marked(VNODE)
+    }
+
+    fn marked(&self, marker: u8) -> Vec<u64> {
+        let mut ids = Vec::new();
+        for (key, _) in self
+            .db
+            .iterator(IteratorMode::From(&[marker], Direction::Forward))
+        {
+            if key.len() != 9 || key[0] != marker {
+                break;
+            }
+            let mut id = [0u8; 8];
+            id.copy_from_slice(&key[1..]);
+            ids.push(u64::from_be_bytes(id));
+        }
+        ids
+    }
+
+    /// Marks `vnode` as held, ending a handoff to this node
+    pub(crate) fn create(&self, vnode: u64) {
+        let mut batch = WriteBatch::default();
+        batch.put(vnode_key(vnode), b"");
+        batch.delete(incoming_key(vnode));
+        self.db.write(batch).unwrap();
+    }
+
+    /// Marks `vnode` as being handed to this node, until it is `create`d
+    /// its records are dropped on the next `open`
+    pub(crate) fn receive(&self, vnode: u64) {
+        self.db.put(incoming_key(vnode), b"").unwrap();
+    }
+
+    /// Writes a chunk of records of `vnode` at once
+    pub(crate) fn put_all(&self, vnode: u64, records: &[Record]) {
+        let mut batch = WriteBatch::default();
+        for record in records {
+            batch.put(
+                record_key(vnode, record.key.as_bytes()),
+                record.value.as_bytes(),
+            );
+        }
+        self.db.write(batch).unwrap();
+    }
+
+    /// Up to `limit` records of `vnode` with keys after `after`, from the
+    /// first one without it
+    pub(crate) fn records_after(
+        &self,
+        vnode: u64,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Vec<Record> {
+        let prefix = record_prefix(vnode);
+        let start = if let Some(after) = after {
+            // the smallest key sorting after `after`
+            let mut start = record_key(vnode, after.as_bytes());
+            start.push(0);
+            start
+        } else {
+            prefix.clone()
+        };
+        self.db
+            .iterator(IteratorMode::From(&start, Direction::Forward))
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .take(limit)
+            .map(|(key, value)| Record {
+                key: String::from_utf8_lossy(&key[prefix.len()..]).into_owned(),
+                value: String::from_utf8_lossy(&value).into_owned(),
+            })
+            .collect()
+    }
+
+    /// Deletes `vnode` together with all its records
+    pub(crate) fn remove(&self, vnode: u64) {
+        let prefix = record_prefix(vnode);
+        let mut batch = WriteBatch::default();
+        batch.delete(vnode_key(vnode));
+        batch.delete(incoming_key(vnode));
+        for (key, _) in self
+            .db
+            .iterator(IteratorMode::From(&prefix, Direction::Forward))
+            .take_while(|(key, _)| key.starts_with(&prefix))
+        {
+            batch.delete(key);
+        }
+        self.db.write(batch).unwrap();
+    }
+
+    /// Splits the vnodes of a ring of `from` vnodes for a ring of `size`,
+    /// every record moves to the vnode its key hashes to. A vnode `v` is
+    /// split into `v + i * from`, all of which we hold until handed off.
+    pub(crate) fn split(&self, from: u64, size: u64) {
+        let mut batch = WriteBatch::default();
+        for vnode in self.vnodes().into_iter().filter(|v| *v < from) {
+            for i in 1..size / from {
+                batch.put(vnode_key(vnode + i * from), b"");
+            }
+            let prefix = record_prefix(vnode);
+            for (key, value) in self
+                .db
+                .iterator(IteratorMode::From(&prefix, Direction::Forward))
+                .take_while(|(key, _)| key.starts_with(&prefix))
+            {
+                let record = &key[prefix.len()..];
+                let child = key_vnode(record, size)
+                    .filter(|c| c % from == vnode)
+                    .unwrap_or(vnode);
+                if child != vnode {
+                    batch.put(record_key(child, record), value);
+                    batch.delete(key);
+                }
+            }
+        }
+        self.db.write(batch).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A fresh store in a directory of its own
+    fn open(name: &str) -> (Store, String) {
+        let path = std::env::temp_dir()
+            .join(format!("mring-node-{}-{}", name, std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let _ = std::fs::remove_dir_all(&path);
+        (Store::open(&path).unwrap(), path)
+    }
+
+    fn records(keys: &[&str]) -> Vec<Record> {
+        keys.iter()
+            .map(|key| Record {
+                key: key.to_string(),
+                value: format!("value of {}", key),
+            })
+            .collect()
+    }
+
+    fn all(store: &Store, vnode: u64) -> Vec<Record> {
+        store.records_after(vnode, None, std::usize::MAX)
+    }
+
+    #[test]
+    fn test_open_drops_incoming_vnodes() {
+        let (store, path) = open("incoming");
+        store.create(1);
+        store.put_all(1, &records(&["a", "b"]));
+        store.receive(2);
+        store.put_all(2, &records(&["c"]));
+        drop(store);
+
+        let store = Store::open(&path).unwrap();
+        assert_eq!(store.vnodes(), vec![1]);
+        assert_eq!(store.marked(INCOMING), Vec::<u64>::new());
+        assert_eq!(all(&store, 1), records(&["a", "b"]));
+        assert!(all(&store, 2).is_empty());
+        drop(store);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_split_moves_records_to_their_vnode() {
+        let (store, path) = open("split");
+        let keys: Vec<String> = (0..64).map(|i| format!("key-{}", i)).collect();
+        store.create(0);
+        store.create(1);
+        for key in &keys {
+            let vnode = key_vnode(key.as_bytes(), 2).unwrap();
+            store.put_all(vnode, &records(&[key.as_str()]));
+        }
+        store.split(2, 8);
+
+        assert_eq!(store.vnodes(), (0..8).collect::<Vec<u64>>());
+        let mut moved = 0;
+        for vnode in 0..8 {
+            for record in all(&store, vnode) {
+                assert_eq!(key_vnode(record.key.as_bytes(), 8), Some(vnode));
+                assert_eq!(record.value, format!("value of {}", record.key));
+                moved += 1;
+            }
+        }
+        assert_eq!(moved, keys.len());
+        drop(store);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_remove_only_deletes_its_vnode() {
+        let (store, path) = open("remove");
+        for vnode in 1..=3 {
+            store.create(vnode);
+            store.put_all(vnode, &records(&["a", "b"]));
+        }
+        store.receive(4);
+        store.remove(2);
+
+        assert_eq!(store.vnodes(), vec![1, 3]);
+        assert_eq!(store.marked(INCOMING), vec![4]);
+        assert_eq!(all(&store, 1), records(&["a", "b"]));
+        assert!(all(&store, 2).is_empty());
+        assert_eq!(all(&store, 3), records(&["a", "b"]));
+        drop(store);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_records_are_read_after_the_last_key() {
+        let (store, path) = open("records");
+        store.create(1);
+        store.put_all(1, &records(&["a", "a\u{0}", "ab", "b"]));
+        store.put_all(2, &records(&["c"]));
+
+        let first = store.records_after(1, None, 2);
+        assert_eq!(first, records(&["a", "a\u{0}"]));
+        let rest = store.records_after(1, Some(&first[1].key), 2);
+        assert_eq!(rest, records(&["ab", "b"]));
+        assert!(store.records_after(1, Some("b"), 2).is_empty());
+        drop(store);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -14,14 +14,32 @@
 
 use crate::vnode::Task;
 use async_tungstenite::async_std::connect_async;
-use futures::channel::mpsc::Sender;
+use futures::channel::mpsc::{Receiver, Sender};
 use futures::{select, FutureExt, SinkExt, StreamExt};
 use slog::Logger;
 use tungstenite::protocol::Message;
-use uring_common::{MRingNodes, Relocations, RequestId};
+use uring_common::{MRingNodes, NodeMeta, Relocations, RequestId, Ring};
 use ws_proto::{MRRequest, PSMRing, Protocol, ProtocolSelect, Reply, SubscriberMsg};
 
-pub(crate) async fn run(logger: Logger, id: String, connect_addr: String, mut tasks: Sender<Task>) {
+const ADD_NODE: RequestId = RequestId(2);
+const GET_RING: RequestId = RequestId(5);
+
+/// What the vnodes tell the cluster
+pub(crate) enum Report {
+    /// `vnode` was handed to us
+    HandoffCompleted { vnode: u64 },
+    /// Handing `vnode` to `target` failed, it is retried
+    HandoffFailed { vnode: u64, target: String },
+}
+
+pub(crate) async fn run(
+    logger: Logger,
+    id: String,
+    meta: NodeMeta,
+    connect_addr: String,
+    mut tasks: Sender<Task>,
+    mut reports: Receiver<Report>,
+) {
     let url = url::Url::parse(&connect_addr).unwrap();
 
     // After the TCP connection has been established, we set up our client to
@@ -39,11 +57,12 @@ pub(crate) async fn run(logger: Logger, id: String, connect_addr: String, mut ta
     // Finally we set the client to terminate once either half of this work
     // finishes. If we don't have any more data to read or we won't receive any
     // more work from the remote then we can exit.
-    let (mut ws_stream, _) = connect_async(url).await.expect("Failed to connect");
+    let (ws_stream, _) = connect_async(url).await.expect("Failed to connect");
     info!(
         logger,
         "WebSocket handshake has been successfully completed"
     );
+    let (mut ws_stream, mut ws_rx) = ws_stream.split();
 
     // subscribe to uring messages
     //
@@ -51,6 +70,7 @@ pub(crate) async fn run(logger: Logger, id: String, connect_addr: String, mut ta
         .send(Message::text(
             serde_json::to_string(&ProtocolSelect::Subscribe {
                 channel: "mring".into(),
+                from: None,
             })
             .unwrap(),
         ))
@@ -72,7 +92,8 @@ pub(crate) async fn run(logger: Logger, id: String, connect_addr: String, mut ta
         .send(Message::text(
             serde_json::to_string(&MRRequest::AddNode {
                 node: id.to_string(),
-                rid: RequestId(2),
+                rid: ADD_NODE,
+                meta,
             })
             .unwrap(),
         ))
@@ -81,21 +102,60 @@ pub(crate) async fn run(logger: Logger, id: String, connect_addr: String, mut ta
 
     loop {
         select! {
-            msg = ws_stream.next().fuse() => match msg {
+            msg = ws_rx.next().fuse() => match msg {
                 Some(Ok(msg)) =>{
-                    if msg.is_text() {
-                        handle_msg(&logger, &id, &mut tasks, msg.into_data()).await;
+                    if !msg.is_text() {
+                        continue;
+                    }
+                    let next = handle_msg(&logger, &id, &mut tasks, msg.into_data()).await;
+                    if let Some(request) = next {
+                        if ws_stream
+                            .send(Message::text(serde_json::to_string(&request).unwrap()))
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
                     }
                 },
                 Some(Err(_e)) => break,
                 None => break,
             },
+            report = reports.next() => {
+                let msg = match report {
+                    Some(Report::HandoffCompleted { vnode }) => MRRequest::HandoffCompleted {
+                        rid: RequestId(3),
+                        vnode,
+                        node: id.clone(),
+                    },
+                    Some(Report::HandoffFailed { vnode, target }) => MRRequest::HandoffFailed {
+                        rid: RequestId(4),
+                        vnode,
+                        node: target,
+                        reason: Some("handoff canceled".into()),
+                    },
+                    None => break,
+                };
+                if ws_stream
+                    .send(Message::text(serde_json::to_string(&msg).unwrap()))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            },
             complete => break
         }
     }
 }
 
-async fn handle_msg(logger: &Logger, id: &str, tasks: &mut Sender<Task>, msg: Vec<u8>) {
+/// Handles a message from uring, returns the request to send next
+async fn handle_msg(
+    logger: &Logger,
+    id: &str,
+    tasks: &mut Sender<Task>,
+    msg: Vec<u8>,
+) -> Option<MRRequest> {
     match serde_json::from_slice(&msg) {
         Ok(SubscriberMsg::Msg { msg, .. }) => match serde_json::from_value(msg) {
             Ok(PSMRing::SetSize { size, .. }) => info!(logger, "Size set to {}", size),
@@ -117,12 +177,38 @@ async fn handle_msg(logger: &Logger, id: &str, tasks: &mut Sender<Task>, msg: Ve
                 info!(logger, "Node '{}' removed", node,);
                 handle_change(logger, &id, tasks, relocations, next).await;
             }
+            Ok(PSMRing::Resized {
+                from,
+                size,
+                next,
+                relocations,
+                ..
+            }) => {
+                info!(logger, "Resizing from {} to {}", from, size);
+                tasks.send(Task::Split { from, size }).await.unwrap();
+                handle_relocations(&id, tasks, relocations, next).await;
+            }
+            Ok(PSMRing::ResizeCompleted { size, .. }) => info!(logger, "Resized to {}", size),
             Err(e) => error!(logger, "failed to decode: {}", e),
         },
+        Ok(SubscriberMsg::Compacted { .. }) => (),
         Err(e) => {
-            if serde_json::from_slice::<Reply>(&msg).is_err()
-                && serde_json::from_slice::<ProtocolSelect>(&msg).is_err()
-            {
+            if let Ok(Reply { rid, code, data }) = serde_json::from_slice(&msg) {
+                // once we are part of the ring, whether we were added just
+                // now or before a restart, we check what we hold against it
+                if rid == ADD_NODE {
+                    return Some(MRRequest::GetRing { rid: GET_RING });
+                }
+                if rid == GET_RING && code == 200 {
+                    match serde_json::from_value::<Ring>(data) {
+                        Ok(ring) => tasks
+                            .send(Task::Reconcile { next: ring.nodes })
+                            .await
+                            .unwrap(),
+                        Err(e) => error!(logger, "failed to decode ring: {}", e),
+                    }
+                }
+            } else if serde_json::from_slice::<ProtocolSelect>(&msg).is_err() {
                 error!(
                     logger,
                     "failed to decode: {} for '{:?}'",
@@ -132,13 +218,14 @@ async fn handle_msg(logger: &Logger, id: &str, tasks: &mut Sender<Task>, msg: Ve
             }
         }
     }
+    None
 }
 
 async fn handle_change(
     _logger: &Logger,
     id: &str,
     tasks: &mut Sender<Task>,
-    mut relocations: Relocations,
+    relocations: Relocations,
     next: MRingNodes,
 ) {
     // This is an initial assignment
@@ -155,9 +242,23 @@ async fn handle_change(
             .send(Task::Update { next: next.clone() })
             .await
             .unwrap();
-    } else if let Some(relocations) = relocations.remove(id) {
-        tasks.send(Task::Update { next }).await.unwrap();
-        for (target, ids) in relocations.destinations.into_iter() {
+    } else {
+        handle_relocations(id, tasks, relocations, next).await;
+    }
+}
+
+async fn handle_relocations(
+    id: &str,
+    tasks: &mut Sender<Task>,
+    mut relocations: Relocations,
+    next: MRingNodes,
+) {
+    tasks.send(Task::Update { next }).await.unwrap();
+    if let Some(relocations) = relocations.remove(id) {
+        // primary moves and replica copies are both handoffs, whether we
+        // keep the vnode afterwards depends on the updated ring
+        let copies = relocations.replicas.into_iter();
+        for (target, ids) in relocations.destinations.into_iter().chain(copies) {
             for vnode in ids {
                 let target = target.clone();
                 tasks
@@ -166,5 +267,8 @@ async fn handle_change(
                     .unwrap();
             }
         }
+        for vnode in relocations.dropped {
+            tasks.send(Task::DropReplica { vnode }).await.unwrap();
+        }
     }
 }
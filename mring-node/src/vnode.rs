@@ -13,20 +13,35 @@
 // limitations under the License.
 
 use crate::handoff;
+use crate::storage::{Record, Store};
+use crate::uring::Report;
 use async_std::task;
 use futures::channel::mpsc::{channel, Receiver, Sender};
 use futures::{select, FutureExt, SinkExt, StreamExt};
 use serde_derive::{Deserialize, Serialize};
 use slog::Logger;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::time::Duration;
 use uring_common::MRingNodes;
 
+/// Records sent per handoff chunk
+const CHUNK_SIZE: usize = 128;
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
 struct VNode {
     id: u64,
     handoff: Option<handoff::Handoff>,
-    data: Vec<String>,
+    /// targets waiting for the running handoff to finish
+    queued: VecDeque<String>,
+}
+
+impl VNode {
+    fn new(id: u64) -> Self {
+        Self {
+            id,
+            ..Self::default()
+        }
+    }
 }
 
 pub(crate) enum Task {
@@ -40,14 +55,29 @@ pub(crate) enum Task {
     Update {
         next: MRingNodes,
     },
+    /// Compares the vnodes we held before a restart with the ring
+    Reconcile {
+        next: MRingNodes,
+    },
+    DropReplica {
+        vnode: u64,
+    },
+    /// The ring grew from `from` to `size` vnodes
+    Split {
+        from: u64,
+        size: u64,
+    },
+    /// `started` is told once the handoff can start, vnodes we are still
+    /// handing off ourselves are only received after that finished
     HandoffInStart {
         src: String,
         vnode: u64,
+        started: Sender<()>,
     },
     HandoffIn {
         vnode: u64,
         chunk: u64,
-        data: Vec<String>,
+        data: Vec<Record>,
     },
     HandoffInEnd {
         vnode: u64,
@@ -58,7 +88,7 @@ pub(crate) enum Cmd {
     GetHandoffData {
         vnode: u64,
         chunk: u64,
-        reply: Sender<(u64, Vec<String>)>,
+        reply: Sender<(u64, Vec<Record>)>,
     },
     FinishHandoff {
         vnode: u64,
@@ -69,27 +99,136 @@ pub(crate) enum Cmd {
     },
 }
 
-#[derive(Default)]
 struct State {
     vnodes: HashMap<u64, VNode>,
     mappings: HashMap<u64, String>,
+    replicas: HashMap<u64, Vec<String>>,
+    /// handoffs to us waiting for our own handoff of the vnode to finish
+    waiting: HashMap<u64, (String, Sender<()>)>,
+    store: Store,
 }
 
 impl State {
+    /// Loads the vnodes held on disk, they are reconciled once we know the
+    /// ring
+    fn new(store: Store) -> Self {
+        let vnodes = store
+            .vnodes()
+            .into_iter()
+            .map(|id| (id, VNode::new(id)))
+            .collect();
+        Self {
+            vnodes,
+            mappings: HashMap::new(),
+            replicas: HashMap::new(),
+            waiting: HashMap::new(),
+            store,
+        }
+    }
+
+    fn remove(&mut self, vnode: u64) {
+        self.vnodes.remove(&vnode);
+        self.store.remove(vnode);
+    }
+
+    /// Starts receiving `vnode` from `src`, whatever we had of it is
+    /// replaced by what we receive, it is only held once all of it arrived
+    async fn receive(&mut self, vnode: u64, src: String, mut started: Sender<()>) {
+        self.remove(vnode);
+        self.store.receive(vnode);
+        let handoff = handoff::Handoff {
+            partner: src,
+            chunk: 0,
+            direction: handoff::Direction::Inbound,
+            last: None,
+        };
+        self.vnodes.insert(
+            vnode,
+            VNode {
+                id: vnode,
+                handoff: Some(handoff),
+                queued: VecDeque::new(),
+            },
+        );
+        // the connection is gone if the sender gave up waiting
+        let _ = started.send(()).await;
+    }
+
+    /// If we are still handing `vnode` off
+    fn sending(&self, vnode: u64) -> bool {
+        self.vnodes.get(&vnode).map_or(false, |v| {
+            !v.queued.is_empty()
+                || v.handoff
+                    .as_ref()
+                    .map_or(false, |h| h.direction == handoff::Direction::Outbound)
+        })
+    }
+
     pub fn update_ring(&mut self, mapping: MRingNodes) {
+        self.replicas.clear();
         for node in mapping.into_iter() {
             for vnode in &node.vnodes {
                 self.mappings.insert(*vnode, node.id.clone());
             }
+            for vnode in &node.replicas {
+                self.replicas
+                    .entry(*vnode)
+                    .or_default()
+                    .push(node.id.clone());
+            }
         }
     }
+
+    /// If `id` is the primary or one of the replicas of `vnode`
+    fn holds(&self, id: &str, vnode: u64) -> bool {
+        self.mappings.get(&vnode).map(String::as_str) == Some(id)
+            || self
+                .replicas
+                .get(&vnode)
+                .map_or(false, |nodes| nodes.iter().any(|n| n == id))
+    }
+}
+
+/// Starts handing `vnode` to `target`, or queues the target if a handoff
+/// is already running.
+async fn handoff_out(
+    logger: &Logger,
+    id: &str,
+    vnode: &mut VNode,
+    target: String,
+    cnc_tx: &Sender<Cmd>,
+) {
+    if vnode.handoff.is_some() {
+        vnode.queued.push_back(target);
+        return;
+    }
+    info!(logger, "relocating vnode {} to node {}", vnode.id, target);
+    vnode.handoff = Some(handoff::Handoff {
+        partner: target.clone(),
+        chunk: 0,
+        direction: handoff::Direction::Outbound,
+        last: None,
+    });
+    if let Ok(worker) = handoff::Worker::new(
+        logger.clone(),
+        id.to_string(),
+        target,
+        vnode.id,
+        cnc_tx.clone(),
+    )
+    .await
+    {
+        task::spawn(worker.handoff());
+    }
 }
 
 async fn handle_cmd(
     logger: &Logger,
+    id: &str,
     cmd: Option<Cmd>,
     state: &mut State,
     tasks_tx: &mut Sender<Task>,
+    reports: &mut Sender<Report>,
 ) {
     match cmd {
         Some(Cmd::GetHandoffData {
@@ -101,11 +240,12 @@ async fn handle_cmd(
                 if let Some(ref mut handoff) = vnode.handoff {
                     assert_eq!(chunk, handoff.chunk);
                     assert_eq!(handoff.direction, handoff::Direction::Outbound);
-                    if let Some(data) = vnode.data.get(chunk as usize) {
-                        reply.send((chunk, vec![data.clone()])).await.unwrap();
-                    } else {
-                        reply.send((chunk, vec![])).await.unwrap();
-                    };
+                    let after = handoff.last.as_deref();
+                    let data = state.store.records_after(vnode.id, after, CHUNK_SIZE);
+                    if let Some(record) = data.last() {
+                        handoff.last = Some(record.key.clone());
+                    }
+                    reply.send((chunk, data)).await.unwrap();
                     handoff.chunk += 1;
                 } else {
                     info!(logger, "Not in a migraiton");
@@ -122,6 +262,13 @@ async fn handle_cmd(
                     logger,
                     "Canceling handoff of vnode {} to {} - requeueing to restart", vnode, target
                 );
+                reports
+                    .send(Report::HandoffFailed {
+                        vnode,
+                        target: target.clone(),
+                    })
+                    .await
+                    .unwrap();
                 tasks_tx
                     .send(Task::HandoffOut { target, vnode })
                     .await
@@ -131,9 +278,35 @@ async fn handle_cmd(
             }
         }
         Some(Cmd::FinishHandoff { vnode }) => {
-            let v = state.vnodes.remove(&vnode).unwrap();
-            let m = v.handoff.unwrap();
-            assert_eq!(m.direction, handoff::Direction::Outbound);
+            let held = state.holds(id, vnode);
+            let next = if let Some(v) = state.vnodes.get_mut(&vnode) {
+                let outbound = v
+                    .handoff
+                    .as_ref()
+                    .map_or(false, |m| m.direction == handoff::Direction::Outbound);
+                if !outbound {
+                    info!(logger, "vnode {} is not handed off", vnode);
+                    return;
+                }
+                v.handoff = None;
+                v.queued.pop_front()
+            } else {
+                info!(logger, "Unknown vnode");
+                return;
+            };
+            // we only let go of a vnode once every copy is out and we
+            // are neither its primary nor one of its replicas
+            if let Some(target) = next {
+                tasks_tx
+                    .send(Task::HandoffOut { target, vnode })
+                    .await
+                    .unwrap();
+            } else if let Some((src, started)) = state.waiting.remove(&vnode) {
+                info!(logger, "receiving held back vnode {} from {}", vnode, src);
+                state.receive(vnode, src, started).await;
+            } else if !held {
+                state.remove(vnode);
+            }
         }
         None => (),
     }
@@ -145,6 +318,7 @@ async fn handle_tick(
     state: &mut State,
     tasks: &mut Receiver<Task>,
     cnc_tx: &Sender<Cmd>,
+    reports: &mut Sender<Report>,
 ) -> bool {
     select! {
         task = tasks.next() =>
@@ -154,36 +328,72 @@ async fn handle_tick(
             }
             Some(Task::HandoffOut { target, vnode }) => {
                 if let Some(vnode) = state.vnodes.get_mut(&vnode){
-                    info!(
-                        logger,
-                        "relocating vnode {} to node {}", vnode.id, target
-                    );
-                    assert!(vnode.handoff.is_none());
-                        vnode.handoff = Some(handoff::Handoff {
-                            partner: target.clone(),
-                            chunk: 0,
-                            direction: handoff::Direction::Outbound
-
-                        });
-                        if let Ok(worker) = handoff::Worker::new(logger.clone(), id.to_string(), target, vnode.id, cnc_tx.clone()).await {
-                            task::spawn(worker.handoff());
+                    handoff_out(logger, id, vnode, target, cnc_tx).await;
+                }
+            },
+            Some(Task::DropReplica { vnode }) => {
+                let held = state.holds(id, vnode);
+                if let Some(node) = state.vnodes.get(&vnode) {
+                    // a running handoff drops the vnode once it finishes
+                    if node.handoff.is_none() && node.queued.is_empty() && !held {
+                        info!(logger, "dropping replica of vnode {}", vnode);
+                        state.remove(vnode);
                     }
                 }
             },
-            Some(Task::HandoffInStart { vnode, src }) => {
-                state.vnodes.remove(&vnode);
-                state.vnodes.insert(vnode, VNode{id: vnode, data: vec![], handoff: Some(handoff::Handoff{partner: src, chunk: 0, direction: handoff::Direction::Inbound})});
+            Some(Task::Reconcile { next }) => {
+                state.update_ring(next);
+                // vnodes that moved on while we were away go to their
+                // current primary, the finished handoff drops them
+                let stale: Vec<(u64, String)> = state
+                    .vnodes
+                    .keys()
+                    .filter(|vnode| !state.holds(id, **vnode))
+                    .filter_map(|vnode| {
+                        state.mappings.get(vnode).map(|primary| (*vnode, primary.clone()))
+                    })
+                    .collect();
+                info!(logger, "reconciled {} vnodes, handing off {:?}", state.vnodes.len(), stale);
+                for (vnode, target) in stale {
+                    if let Some(vnode) = state.vnodes.get_mut(&vnode) {
+                        handoff_out(logger, id, vnode, target, cnc_tx).await;
+                    }
+                }
             }
-            Some(Task::HandoffIn { mut data, vnode, chunk }) => {
+            Some(Task::Split { from, size }) => {
+                // the keys of a vnode are spread over `vnode + i * from`,
+                // we hold all of them until they are handed off
+                state.store.split(from, size);
+                let split: Vec<u64> = state
+                    .vnodes
+                    .keys()
+                    .filter(|v| **v < from)
+                    .flat_map(|v| (1..size / from).map(move |i| v + i * from))
+                    .collect();
+                for vnode in split {
+                    state.vnodes.insert(vnode, VNode::new(vnode));
+                }
+            }
+            Some(Task::HandoffInStart { vnode, src, started }) => {
+                // the records we still send must not be replaced before
+                // they are out
+                if state.sending(vnode) {
+                    info!(logger, "holding back handoff of vnode {} from {} until ours finished", vnode, src);
+                    state.waiting.insert(vnode, (src, started));
+                } else {
+                    state.receive(vnode, src, started).await;
+                }
+            }
+            Some(Task::HandoffIn { data, vnode, chunk }) => {
                 info!(
                     logger,
-                    "accepting vnode {} with: {:?}", vnode, data
+                    "accepting {} records of vnode {}", data.len(), vnode
                 );
                 if let Some(node) = state.vnodes.get_mut(&vnode) {
                     if let Some(ref mut handoff) = &mut node.handoff {
                         assert_eq!(handoff.chunk, chunk);
                         assert_eq!(handoff.direction, handoff::Direction::Inbound);
-                        node.data.append(&mut data);
+                        state.store.put_all(vnode, &data);
                         handoff.chunk = chunk + 1;
                     } else {
                         error!(logger, "no handoff in progress for data vnode {}", vnode)
@@ -201,23 +411,27 @@ async fn handle_tick(
                         error!(logger, "no handoff in progress for data vnode {}", vnode)
                     }
                     node.handoff = None;
-                    node.data.push(id.to_string());
+                    state.store.create(vnode);
+                    reports.send(Report::HandoffCompleted { vnode }).await.unwrap();
+                    // as the new primary we bring the replicas up to date
+                    if state.mappings.get(&vnode).map(String::as_str) == Some(id) {
+                        let replicas = state.replicas.get(&vnode).cloned().unwrap_or_default();
+                        for target in replicas {
+                            handoff_out(logger, id, node, target, cnc_tx).await;
+                        }
+                    }
                 } else {
                     error!(logger, "no handoff in progress for data vnode {}", vnode)
                 }
             }
             Some(Task::Assign{vnodes: ids}) => {
                 info!(logger, "Initializing with {:?}", ids);
-                let my_id = &id;
+                // vnodes we reloaded from disk keep their records
                 for id in ids {
-                    state.vnodes.insert(
-                        id,
-                        VNode {
-                            handoff: None,
-                            id,
-                            data: vec![my_id.to_string()],
-                        },
-                    );
+                    if !state.vnodes.contains_key(&id) {
+                        state.store.create(id);
+                        state.vnodes.insert(id, VNode::new(id));
+                    }
                 }
             }
             None => return false,
@@ -233,16 +447,18 @@ pub(crate) async fn run(
     id: String,
     mut tasks: Receiver<Task>,
     mut tasks_tx: Sender<Task>,
+    mut reports: Sender<Report>,
+    store: Store,
 ) {
-    let mut state = State::default();
+    let mut state = State::new(store);
 
     let (cnc_tx, mut cnc_rx) = channel(crate::CHANNEL_SIZE);
 
     let mut ticks = async_std::stream::interval(Duration::from_secs(1));
     loop {
         select! {
-            cmd = cnc_rx.next() => handle_cmd(&logger, cmd, &mut state, &mut tasks_tx).await,
-            tick = ticks.next().fuse() =>  if ! handle_tick(&logger, &id, &mut state, &mut tasks, &cnc_tx).await {
+            cmd = cnc_rx.next() => handle_cmd(&logger, &id, cmd, &mut state, &mut tasks_tx, &mut reports).await,
+            tick = ticks.next().fuse() =>  if ! handle_tick(&logger, &id, &mut state, &mut tasks, &cnc_tx, &mut reports).await {
                 break
             },
         }
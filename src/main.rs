@@ -27,9 +27,9 @@ pub mod version;
 
 use crate::network::{ws, Network, RaftNetworkMsg};
 use crate::raft_node::*;
-use crate::service::mring::{self, placement::continuous};
+use crate::service::mring;
 use crate::service::{kv, Service};
-use crate::storage::URRocksStorage;
+use crate::storage::{MemStorage, URRocksStorage};
 use async_std::task;
 use clap::{App as ClApp, Arg};
 use futures::{select, FutureExt, StreamExt};
@@ -66,27 +66,47 @@ pub struct KVs {
     value: Vec<u8>,
 }
 
-async fn raft_loop<N: Network>(
+async fn raft_loop<S, N>(
     id: NodeId,
+    storage_config: storage::Config,
     bootstrap: bool,
     ring_size: Option<u64>,
+    ring_strategy: Option<String>,
+    ring_replicas: Option<u64>,
+    auto_promote: Option<u64>,
     pubsub: pubsub::Channel,
     network: N,
     logger: Logger,
-) where
-    N: 'static,
+) -> Result<(), storage::Error>
+where
+    S: storage::Storage + Send + Sync + 'static,
+    N: Network + 'static,
 {
     // Tick the raft node per 100ms. So use an `Instant` to trace it.
-    let mut node: RaftNode<URRocksStorage, _> = if bootstrap {
-        RaftNode::create_raft_leader(&logger, id, pubsub, network).await
+    let node: Result<RaftNode<S, _>, _> = if bootstrap {
+        RaftNode::create_raft_leader(&logger, id, storage_config, pubsub, network).await
     } else {
-        RaftNode::create_raft_follower(&logger, id, pubsub, network).await
+        RaftNode::create_raft_follower(&logger, id, storage_config, pubsub, network).await
+    };
+    let mut node = match node {
+        Ok(node) => node,
+        Err(e) => {
+            error!(logger, "Failed to open storage: {}", e);
+            return Err(e);
+        }
     };
     node.set_raft_tick_duration(Duration::from_millis(100));
+    node.set_auto_promote(auto_promote);
     node.log().await;
-    let kv = kv::Service::new(&logger, 0);
+    let mut kv = kv::Service::new(&logger, 0);
+    // a follower that hasn't joined yet has no group and nothing to replay
+    if let Some(group) = node.raft_group.as_ref() {
+        kv.replay(group, &mut node.pubsub).await;
+    }
     node.add_service(kv::ID, Box::new(kv));
-    let mut vnode: mring::Service<continuous::Strategy> = mring::Service::new();
+    let lock = service::lock::Service::new(&logger);
+    node.add_service(service::lock::ID, Box::new(lock));
+    let mut vnode = mring::Service::new();
 
     if let Some(size) = ring_size {
         if bootstrap {
@@ -94,7 +114,7 @@ async fn raft_loop<N: Network>(
                 .execute(
                     node.raft_group.as_ref().unwrap(),
                     &mut node.pubsub,
-                    service::mring::Event::set_size(size),
+                    service::mring::Event::set_size(size, ring_strategy, ring_replicas),
                 )
                 .await
                 .unwrap();
@@ -109,7 +129,46 @@ async fn raft_loop<N: Network>(
     let status = Box::new(status);
     node.add_service(service::status::ID, status);
 
-    node.node_loop().await.unwrap()
+    node.node_loop().await.unwrap();
+    Ok(())
+}
+
+fn parse_arg<T: std::str::FromStr>(
+    matches: &clap::ArgMatches,
+    name: &str,
+) -> Result<Option<T>, storage::Error> {
+    matches
+        .value_of(name)
+        .map(|v| {
+            v.parse()
+                .map_err(|_| storage::Error::Config(format!("invalid {} `{}`", name, v)))
+        })
+        .transpose()
+}
+
+fn parse_storage_config(matches: &clap::ArgMatches) -> Result<storage::Config, storage::Error> {
+    let mut config = storage::Config::default();
+    if let Some(dir) = matches.value_of("data-dir") {
+        config.data_dir = dir.into();
+    }
+    config.wal_dir = matches.value_of("wal-dir").map(Into::into);
+    if let Some(sync) = matches.value_of("sync") {
+        config.sync = sync.parse()?;
+    }
+    if let Some(compression) = matches.value_of("compression") {
+        config.compression = compression.parse()?;
+    }
+    if let Some(cache_size) = parse_arg(matches, "cache-size")? {
+        config.cache_size = cache_size;
+    }
+    if let Some(retain_entries) = parse_arg(matches, "retain-entries")? {
+        config.compaction.retain_entries = retain_entries;
+    }
+    config.compaction.retain_bytes = parse_arg(matches, "retain-bytes")?;
+    if let Some(snapshot_interval) = parse_arg(matches, "snapshot-interval")? {
+        config.compaction.snapshot_interval = snapshot_interval;
+    }
+    Ok(config)
 }
 
 fn main() -> std::io::Result<()> {
@@ -142,6 +201,98 @@ fn main() -> std::io::Result<()> {
                 .help("Initialized mring size, only has an effect when used together with --bootstrap")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("ring-strategy")
+                .long("ring-strategy")
+                .value_name("STRATEGY")
+                .possible_values(&["continuous", "rendezvous", "weighted"])
+                .help("Placement strategy of the initialized mring, defaults to continuous")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ring-replicas")
+                .long("ring-replicas")
+                .value_name("REPLICAS")
+                .help("Number of nodes holding each vnode of the initialized mring, defaults to 1")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("auto-promote")
+                .long("auto-promote")
+                .value_name("MAX_LAG")
+                .help("Promote learners to voters once they are within MAX_LAG entries of the leader")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("storage")
+                .long("storage")
+                .value_name("STORAGE")
+                .possible_values(&["rocksdb", "memory"])
+                .default_value("rocksdb")
+                .help("Storage backend, memory keeps nothing across restarts")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("data-dir")
+                .long("data-dir")
+                .value_name("DIR")
+                .default_value(".")
+                .help("Directory the raft-rocks-<id> database is kept in")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("wal-dir")
+                .long("wal-dir")
+                .value_name("DIR")
+                .help("Directory for the write ahead log, defaults to the data dir")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sync")
+                .long("sync")
+                .value_name("SYNC")
+                .possible_values(&["always", "batched"])
+                .default_value("always")
+                .help("Sync every write or only raft log appends and the hard state")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compression")
+                .long("compression")
+                .value_name("COMPRESSION")
+                .possible_values(&["none", "snappy", "lz4", "zstd"])
+                .default_value("snappy")
+                .help("Compression used for the database")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cache-size")
+                .long("cache-size")
+                .value_name("BYTES")
+                .help("Size of the block cache")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retain-entries")
+                .long("retain-entries")
+                .value_name("ENTRIES")
+                .help("Raft log entries kept behind the applied index")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retain-bytes")
+                .long("retain-bytes")
+                .value_name("BYTES")
+                .help("Upper bound for the size of the raft log kept behind the applied index")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("snapshot-interval")
+                .long("snapshot-interval")
+                .value_name("ENTRIES")
+                .help("Take a snapshot every ENTRIES applied entries")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("http-endpoint")
                 .long("http")
@@ -189,23 +340,54 @@ fn main() -> std::io::Result<()> {
     };
     let peers = matches.values_of_lossy("peers").unwrap_or(vec![]);
     let ring_size: Option<u64> = matches.value_of("ring-size").map(|s| s.parse().unwrap());
+    let ring_strategy: Option<String> = matches.value_of("ring-strategy").map(String::from);
+    let ring_replicas: Option<u64> = matches
+        .value_of("ring-replicas")
+        .map(|s| s.parse().unwrap());
+    let auto_promote: Option<u64> = matches.value_of("auto-promote").map(|s| s.parse().unwrap());
     let bootstrap = matches.is_present("bootstrap");
     let endpoint = matches.value_of("endpoint").unwrap_or("127.0.0.1:8080");
     let id = NodeId(matches.value_of("id").unwrap_or("1").parse().unwrap());
     let loop_logger = logger.clone();
     let rest_endpoint = matches.value_of("http-endpoint");
 
+    let storage_config = match parse_storage_config(&matches) {
+        Ok(config) => config,
+        Err(e) => {
+            error!(logger, "{}", e);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e));
+        }
+    };
+
     let ps_tx = pubsub::start(&logger);
 
     let network = ws::Network::new(&logger, id, endpoint, rest_endpoint, peers, ps_tx.clone());
 
-    task::block_on(raft_loop(
-        id,
-        bootstrap,
-        ring_size,
-        ps_tx,
-        network,
-        loop_logger,
-    ));
-    Ok(())
+    let res = match matches.value_of("storage") {
+        Some("memory") => task::block_on(raft_loop::<MemStorage, _>(
+            id,
+            storage_config,
+            bootstrap,
+            ring_size,
+            ring_strategy,
+            ring_replicas,
+            auto_promote,
+            ps_tx,
+            network,
+            loop_logger,
+        )),
+        _ => task::block_on(raft_loop::<URRocksStorage, _>(
+            id,
+            storage_config,
+            bootstrap,
+            ring_size,
+            ring_strategy,
+            ring_replicas,
+            auto_promote,
+            ps_tx,
+            network,
+            loop_logger,
+        )),
+    };
+    res.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
 }
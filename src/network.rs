@@ -15,10 +15,12 @@
 pub mod ws;
 
 use crate::network::ws::WsMessage;
+use crate::storage::SnapshotChunk;
 use crate::*;
 use async_trait::async_trait;
 use futures::channel::mpsc::{Sender, TryRecvError};
 use raft::eraftpb::Message as RaftMessage;
+use raft::SnapshotStatus;
 use std::{fmt, io};
 
 #[derive(Debug)]
@@ -43,9 +45,18 @@ pub enum RaftNetworkMsg {
     ForwardProposal(NodeId, ProposalId, ServiceId, EventId, Vec<u8>),
     GetNode(NodeId, Sender<bool>),
     AddNode(NodeId, Sender<bool>),
+    AddLearner(NodeId, Sender<bool>),
+    PromoteNode(NodeId, Sender<bool>),
+    RemoveNode(NodeId, Sender<bool>),
+    TransferLeader(NodeId, RequestId, Sender<WsMessage>),
+    ChangeMembership(Vec<NodeId>, Vec<NodeId>, RequestId, Sender<WsMessage>),
 
     Event(EventId, ServiceId, Vec<u8>),
     RaftMsg(RaftMessage),
+    SnapshotChunk(NodeId, SnapshotChunk),
+    /// The outcome of a snapshot transfer to a peer, for raft to go on
+    /// replicating to it.
+    ReportSnapshot(NodeId, SnapshotStatus),
 }
 
 pub enum TryNextError {
@@ -64,7 +75,17 @@ pub trait Network: Send + Sync {
     ) -> Result<(), Error>;
     async fn event_reply(&mut self, id: EventId, code: u16, reply: Vec<u8>) -> Result<(), Error>;
     async fn send_msg(&mut self, msg: RaftMessage) -> Result<(), Error>;
+    async fn ack_snapshot_chunk(
+        &mut self,
+        to: NodeId,
+        index: u64,
+        offset: u64,
+    ) -> Result<(), Error>;
+    /// Resends the oldest unacknowledged chunk of stalled snapshot transfers
+    /// and returns the peers whose transfer was given up.
+    async fn resend_snapshot_chunks(&mut self) -> Vec<NodeId>;
     fn connections(&self) -> Vec<NodeId>;
+    fn remove_peer(&mut self, id: NodeId);
     async fn forward_proposal(
         &mut self,
         from: NodeId,
@@ -103,9 +124,23 @@ impl Network for NullNetwork {
     async fn send_msg(&mut self, _msg: RaftMessage) -> Result<(), network::Error> {
         unimplemented!()
     }
+    async fn ack_snapshot_chunk(
+        &mut self,
+        _to: NodeId,
+        _index: u64,
+        _offset: u64,
+    ) -> Result<(), network::Error> {
+        unimplemented!()
+    }
+    async fn resend_snapshot_chunks(&mut self) -> Vec<NodeId> {
+        unimplemented!()
+    }
     fn connections(&self) -> Vec<NodeId> {
         unimplemented!()
     }
+    fn remove_peer(&mut self, _id: NodeId) {
+        unimplemented!()
+    }
     async fn forward_proposal(
         &mut self,
         _from: NodeId,
@@ -20,23 +20,36 @@ use crate::network::{
     Error, EventId, Network as NetworkTrait, ProposalId, RaftNetworkMsg, ServiceId,
 };
 use crate::pubsub;
-use crate::service::{kv, mring};
-use crate::{NodeId, RequestId};
+use crate::service::{kv, lock, mring};
+use crate::storage::{snapshot_checksum, SnapshotChunk};
+use crate::{Consistency, Encoding, NodeId, NodeMeta, RequestId};
 use async_std::task;
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures::channel::mpsc::{unbounded, Sender, UnboundedReceiver, UnboundedSender};
 use futures::{SinkExt, StreamExt};
-use raft::eraftpb::Message as RaftMessage;
+use raft::eraftpb::{Message as RaftMessage, MessageType};
+use raft::SnapshotStatus;
 use serde_derive::{Deserialize, Serialize};
 use slog::Logger;
+use std::cmp::min;
 use std::collections::HashMap;
 use std::io;
-use ws_proto::Reply as ProtoReply;
+use std::time::{Duration, Instant};
+use ws_proto::{Quota, Reply as ProtoReply};
 
+/// A kv namespace, `None` is the default one.
+type Namespace = Option<String>;
 type LocalMailboxes = HashMap<NodeId, Sender<WsMessage>>;
 type RemoteMailboxes = HashMap<NodeId, Sender<WsMessage>>;
 
+/// Size of the chunks snapshot data is streamed in.
+const SNAPSHOT_CHUNK_SIZE: usize = 512 * 1024;
+/// How long a chunk may go unacknowledged before it is sent again.
+const SNAPSHOT_CHUNK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Resends of a chunk after which the transfer is given up.
+const MAX_SNAPSHOT_RESENDS: u32 = 10;
+
 #[derive(Clone)]
 pub(crate) struct Node {
     id: NodeId,
@@ -57,16 +70,32 @@ pub struct Network {
     next_eid: u64,
     pending: HashMap<EventId, Reply>,
     prot_pending: HashMap<EventId, (RequestId, protocol_driver::HandlerOutboundChannelSender)>,
+    snapshots: HashMap<NodeId, OutgoingSnapshot>,
 }
 
 pub(crate) struct Reply(RequestId, Sender<WsMessage>);
 
+/// A snapshot being streamed to a peer, the raft message is held back
+/// until the peer acknowledged all of the data.
+struct OutgoingSnapshot {
+    msg: RaftMessage,
+    data: Vec<u8>,
+    checksum: String,
+    acked: u64,
+    /// When the last chunk went out
+    sent: Instant,
+    /// Resends since the peer last acknowledged a chunk
+    resends: u32,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum CtrlMsg {
     Hello(NodeId, String),
     HelloAck(NodeId, String, Vec<(NodeId, String)>),
     AckProposal(ProposalId, bool),
     ForwardProposal(NodeId, ProposalId, ServiceId, EventId, Vec<u8>),
+    SnapshotChunk(NodeId, SnapshotChunk),
+    AckSnapshotChunk(NodeId, u64, u64),
 }
 
 pub(crate) enum UrMsg {
@@ -83,21 +112,61 @@ pub(crate) enum UrMsg {
     AckProposal(ProposalId, bool),
     ForwardProposal(NodeId, ProposalId, ServiceId, EventId, Vec<u8>),
     RaftMsg(RaftMessage),
+    SnapshotChunk(NodeId, SnapshotChunk),
+    AckSnapshotChunk(NodeId, u64, u64),
     GetNode(NodeId, Sender<bool>),
     AddNode(NodeId, Sender<bool>),
+    AddLearner(NodeId, Sender<bool>),
+    PromoteNode(NodeId, Sender<bool>),
+    RemoveNode(NodeId, Sender<bool>),
+    TransferLeader(NodeId, RequestId, Sender<WsMessage>),
+    ChangeMembership(Vec<NodeId>, Vec<NodeId>, RequestId, Sender<WsMessage>),
 
     // KV related
-    Get(Vec<u8>, Reply),
-    Put(Vec<u8>, Vec<u8>, Reply),
-    Cas(Vec<u8>, Option<Vec<u8>>, Vec<u8>, Reply),
-    Delete(Vec<u8>, Reply),
+    Get(Namespace, Vec<u8>, Consistency, Encoding, Reply),
+    Put(Namespace, Vec<u8>, Vec<u8>, Option<u64>, Encoding, Reply),
+    Cas(
+        Namespace,
+        Vec<u8>,
+        Option<Vec<u8>>,
+        Vec<u8>,
+        Encoding,
+        Reply,
+    ),
+    Delete(Namespace, Vec<u8>, Encoding, Reply),
+    Txn(Namespace, Vec<kv::TxnOp>, Encoding, Reply),
+    Scan(ws_proto::Scan, Reply),
+    Grant(Namespace, u64, Reply),
+    KeepAlive(Namespace, u64, Reply),
+    Revoke(Namespace, u64, Reply),
+    History(Namespace, Vec<u8>, Consistency, Encoding, Reply),
+    Add(Namespace, Vec<u8>, i64, Option<i64>, Option<i64>, Reply),
+    CreateNamespace(String, Quota, Reply),
+    Namespaces(Reply),
 
     // VNode
-    MRingSetSize(u64, Reply),
+    MRingSetSize(u64, Option<String>, Option<u64>, Reply),
     MRingGetSize(Reply),
     MRingGetNodes(Reply),
-    MRingAddNode(String, Reply),
+    MRingGetNode(String, Reply),
+    MRingAddNode(String, NodeMeta, Reply),
     MRingRemoveNode(String, Reply),
+    MRingGetRing(Reply),
+    MRingLookup(String, Reply),
+    MRingLookupMany(Vec<String>, Reply),
+    MRingResize(u64, Reply),
+    MRingHandoffCompleted(u64, String, Reply),
+    MRingHandoffFailed(u64, String, Option<String>, Reply),
+    MRingGetRelocations(Reply),
+    MRingAbandonRelocations(Reply),
+
+    // Lock related
+    LockAcquire(String, String, u64, Reply),
+    LockRelease(u64, Reply),
+    LockKeepAlive(u64, Reply),
+    LockGet(String, Reply),
+    LockCampaign(String, String, u64, Reply),
+    LockLeader(String, Reply),
 
     Protocol(ProtocolMessage),
 }
@@ -151,12 +220,12 @@ impl NetworkTrait for Network {
                     Some(RaftNetworkMsg::Event(eid, service_id, event))
                 }
             },
-            UrMsg::MRingSetSize(size, reply) => {
+            UrMsg::MRingSetSize(size, strategy, replicas, reply) => {
                 let eid = self.register_reply(reply);
                 Some(RaftNetworkMsg::Event(
                     eid,
                     mring::ID,
-                    mring::Event::set_size(size),
+                    mring::Event::set_size(size, strategy, replicas),
                 ))
             }
             UrMsg::MRingGetSize(reply) => {
@@ -175,12 +244,20 @@ impl NetworkTrait for Network {
                     mring::Event::get_nodes(),
                 ))
             }
-            UrMsg::MRingAddNode(node, reply) => {
+            UrMsg::MRingGetNode(node, reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    mring::ID,
+                    mring::Event::get_node(node),
+                ))
+            }
+            UrMsg::MRingAddNode(node, meta, reply) => {
                 let eid = self.register_reply(reply);
                 Some(RaftNetworkMsg::Event(
                     eid,
                     mring::ID,
-                    mring::Event::add_node(node),
+                    mring::Event::add_node(node, meta),
                 ))
             }
             UrMsg::MRingRemoveNode(node, reply) => {
@@ -191,39 +268,244 @@ impl NetworkTrait for Network {
                     mring::Event::remove_node(node),
                 ))
             }
+            UrMsg::MRingGetRing(reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    mring::ID,
+                    mring::Event::get_ring(),
+                ))
+            }
+            UrMsg::MRingLookup(key, reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    mring::ID,
+                    mring::Event::lookup(key),
+                ))
+            }
+            UrMsg::MRingLookupMany(keys, reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    mring::ID,
+                    mring::Event::lookup_many(keys),
+                ))
+            }
+            UrMsg::MRingResize(size, reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    mring::ID,
+                    mring::Event::resize(size),
+                ))
+            }
+            UrMsg::MRingHandoffCompleted(vnode, node, reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    mring::ID,
+                    mring::Event::handoff_completed(vnode, node),
+                ))
+            }
+            UrMsg::MRingHandoffFailed(vnode, node, reason, reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    mring::ID,
+                    mring::Event::handoff_failed(vnode, node, reason),
+                ))
+            }
+            UrMsg::MRingGetRelocations(reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    mring::ID,
+                    mring::Event::get_relocations(),
+                ))
+            }
+            UrMsg::MRingAbandonRelocations(reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    mring::ID,
+                    mring::Event::abandon_relocations(),
+                ))
+            }
+            UrMsg::LockAcquire(name, owner, ttl, reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    lock::ID,
+                    lock::Event::acquire(name, owner, ttl),
+                ))
+            }
+            UrMsg::LockRelease(lease, reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    lock::ID,
+                    lock::Event::release(lease),
+                ))
+            }
+            UrMsg::LockKeepAlive(lease, reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    lock::ID,
+                    lock::Event::keep_alive(lease),
+                ))
+            }
+            UrMsg::LockGet(name, reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(eid, lock::ID, lock::Event::get(name)))
+            }
+            UrMsg::LockCampaign(election, value, ttl, reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    lock::ID,
+                    lock::Event::campaign(election, value, ttl),
+                ))
+            }
+            UrMsg::LockLeader(name, reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    lock::ID,
+                    lock::Event::leader(name),
+                ))
+            }
             UrMsg::Status(rid, reply) => Some(Status(rid, reply)),
             UrMsg::Version(rid, reply) => Some(Version(rid, reply)),
             UrMsg::GetNode(id, reply) => Some(GetNode(id, reply)),
             UrMsg::AddNode(id, reply) => Some(AddNode(id, reply)),
-            UrMsg::Get(key, reply) => {
+            UrMsg::AddLearner(id, reply) => Some(AddLearner(id, reply)),
+            UrMsg::PromoteNode(id, reply) => Some(PromoteNode(id, reply)),
+            UrMsg::RemoveNode(id, reply) => Some(RemoveNode(id, reply)),
+            UrMsg::TransferLeader(id, rid, reply) => Some(TransferLeader(id, rid, reply)),
+            UrMsg::ChangeMembership(add, remove, rid, reply) => {
+                Some(ChangeMembership(add, remove, rid, reply))
+            }
+            UrMsg::Get(ns, key, consistency, encoding, reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    kv::ID,
+                    kv::Event::get(ns, key, consistency, encoding),
+                ))
+            }
+            UrMsg::Put(ns, key, value, lease, encoding, reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    kv::ID,
+                    kv::Event::put(ns, key, value, lease, encoding),
+                ))
+            }
+            UrMsg::Cas(ns, key, check_value, store_value, encoding, reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    kv::ID,
+                    kv::Event::cas(ns, key, check_value, store_value, encoding),
+                ))
+            }
+            UrMsg::Delete(ns, key, encoding, reply) => {
                 let eid = self.register_reply(reply);
-                Some(RaftNetworkMsg::Event(eid, kv::ID, kv::Event::get(key)))
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    kv::ID,
+                    kv::Event::delete(ns, key, encoding),
+                ))
             }
-            UrMsg::Put(key, value, reply) => {
+            UrMsg::Txn(ns, ops, encoding, reply) => {
                 let eid = self.register_reply(reply);
                 Some(RaftNetworkMsg::Event(
                     eid,
                     kv::ID,
-                    kv::Event::put(key, value),
+                    kv::Event::txn(ns, ops, encoding),
                 ))
             }
-            UrMsg::Cas(key, check_value, store_value, reply) => {
+            UrMsg::Scan(scan, reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(eid, kv::ID, kv::Event::scan(scan)))
+            }
+            UrMsg::Grant(ns, ttl, reply) => {
                 let eid = self.register_reply(reply);
                 Some(RaftNetworkMsg::Event(
                     eid,
                     kv::ID,
-                    kv::Event::cas(key, check_value, store_value),
+                    kv::Event::grant(ns, ttl),
                 ))
             }
-            UrMsg::Delete(key, reply) => {
+            UrMsg::KeepAlive(ns, lease, reply) => {
                 let eid = self.register_reply(reply);
-                Some(RaftNetworkMsg::Event(eid, kv::ID, kv::Event::delete(key)))
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    kv::ID,
+                    kv::Event::keep_alive(ns, lease),
+                ))
+            }
+            UrMsg::Revoke(ns, lease, reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    kv::ID,
+                    kv::Event::revoke(ns, lease),
+                ))
+            }
+            UrMsg::History(ns, key, consistency, encoding, reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    kv::ID,
+                    kv::Event::history(ns, key, consistency, encoding),
+                ))
+            }
+            UrMsg::Add(ns, key, delta, min, max, reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    kv::ID,
+                    kv::Event::add(ns, key, delta, min, max),
+                ))
+            }
+            UrMsg::CreateNamespace(ns, quota, reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(
+                    eid,
+                    kv::ID,
+                    kv::Event::create_namespace(ns, quota),
+                ))
+            }
+            UrMsg::Namespaces(reply) => {
+                let eid = self.register_reply(reply);
+                Some(RaftNetworkMsg::Event(eid, kv::ID, kv::Event::namespaces()))
             }
             UrMsg::AckProposal(pid, success) => Some(AckProposal(pid, success)),
             UrMsg::ForwardProposal(from, pid, sid, eid, data) => {
                 Some(ForwardProposal(from, pid, sid, eid, data))
             }
             UrMsg::RaftMsg(msg) => Some(RaftMsg(msg)),
+            UrMsg::SnapshotChunk(from, chunk) => Some(SnapshotChunk(from, chunk)),
+            UrMsg::AckSnapshotChunk(from, index, offset) => {
+                let current = self
+                    .snapshots
+                    .get(&from)
+                    .map(|s| s.msg.get_snapshot().get_metadata().index == index)
+                    .unwrap_or_default();
+                let status = if current {
+                    self.snapshot_acked(from, offset).await
+                } else {
+                    None
+                };
+                if let Some(status) = status {
+                    Some(ReportSnapshot(from, status))
+                } else {
+                    self.next().await
+                }
+            }
             // Connection handling of websocket connections
             // partially based on the problem that actix ws client
             // doens't reconnect
@@ -242,6 +524,7 @@ impl NetworkTrait for Network {
                 if id != self.id {
                     info!(self.logger, "register(local)"; "remote-id" => id, "remote-peer" => peer, "discovered-peers" => format!("{:?}", peers));
                     self.local_mailboxes.insert(id, endpoint.clone());
+                    self.resume_snapshot(id).await;
                     for (peer_id, peer) in peers {
                         if !self.known_peers.contains_key(&peer_id) {
                             self.known_peers.insert(peer_id, peer.clone());
@@ -277,6 +560,7 @@ impl NetworkTrait for Network {
                         .await
                         .unwrap();
                     self.remote_mailboxes.insert(id, endpoint.clone());
+                    self.resume_snapshot(id).await;
                 }
                 self.next().await
             }
@@ -323,8 +607,29 @@ impl NetworkTrait for Network {
         }
     }
 
-    async fn send_msg(&mut self, msg: RaftMessage) -> Result<(), Error> {
+    async fn send_msg(&mut self, mut msg: RaftMessage) -> Result<(), Error> {
         let to = NodeId(msg.to);
+        if msg.get_msg_type() == MessageType::MsgSnapshot {
+            // Stream the data ahead and only send the metadata through raft.
+            let data = msg.mut_snapshot().take_data();
+            let checksum = snapshot_checksum(&data);
+            self.snapshots.insert(
+                to,
+                OutgoingSnapshot {
+                    msg,
+                    data,
+                    checksum,
+                    acked: 0,
+                    sent: Instant::now(),
+                    resends: 0,
+                },
+            );
+            if let Err(e) = self.send_snapshot_chunk(to, 0).await {
+                // The chunk is sent again once it timed out.
+                warn!(self.logger, "snapshot transfer interrupted"; "to" => to, "error" => format!("{}", e));
+            }
+            return Ok(());
+        }
         if let Some(remote) = self.local_mailboxes.get_mut(&to) {
             remote
                 .send(WsMessage::Raft(msg))
@@ -341,6 +646,45 @@ impl NetworkTrait for Network {
         }
     }
 
+    async fn ack_snapshot_chunk(
+        &mut self,
+        to: NodeId,
+        index: u64,
+        offset: u64,
+    ) -> Result<(), Error> {
+        let msg = WsMessage::Ctrl(CtrlMsg::AckSnapshotChunk(self.id, index, offset));
+        self.send_to(to, msg).await
+    }
+
+    async fn resend_snapshot_chunks(&mut self) -> Vec<NodeId> {
+        let stalled: Vec<NodeId> = self
+            .snapshots
+            .iter()
+            .filter(|(_, s)| s.sent.elapsed() >= SNAPSHOT_CHUNK_TIMEOUT)
+            .map(|(to, _)| *to)
+            .collect();
+        let mut failed = Vec::new();
+        for to in stalled {
+            let (offset, resends) = if let Some(snapshot) = self.snapshots.get_mut(&to) {
+                snapshot.resends += 1;
+                (snapshot.acked, snapshot.resends)
+            } else {
+                continue;
+            };
+            if resends > MAX_SNAPSHOT_RESENDS {
+                warn!(self.logger, "snapshot transfer given up"; "to" => to, "offset" => offset);
+                self.snapshots.remove(&to);
+                failed.push(to);
+            } else {
+                info!(self.logger, "resending snapshot chunk"; "to" => to, "offset" => offset);
+                if let Err(e) = self.send_snapshot_chunk(to, offset).await {
+                    warn!(self.logger, "snapshot transfer interrupted"; "to" => to, "error" => format!("{}", e));
+                }
+            }
+        }
+        failed
+    }
+
     fn connections(&self) -> Vec<NodeId> {
         let mut k1: Vec<NodeId> = self.local_mailboxes.keys().copied().collect();
         let mut k2: Vec<NodeId> = self.remote_mailboxes.keys().copied().collect();
@@ -350,6 +694,14 @@ impl NetworkTrait for Network {
         k1
     }
 
+    fn remove_peer(&mut self, id: NodeId) {
+        info!(self.logger, "removing peer"; "id" => id);
+        self.local_mailboxes.remove(&id);
+        self.remote_mailboxes.remove(&id);
+        self.known_peers.remove(&id);
+        self.snapshots.remove(&id);
+    }
+
     async fn forward_proposal(
         &mut self,
         from: NodeId,
@@ -509,6 +861,85 @@ impl Network {
             next_eid: 1,
             pending: HashMap::new(),
             prot_pending: HashMap::new(),
+            snapshots: HashMap::new(),
+        }
+    }
+
+    async fn send_to(&mut self, to: NodeId, msg: WsMessage) -> Result<(), Error> {
+        if let Some(remote) = self.local_mailboxes.get_mut(&to) {
+            remote
+                .send(msg)
+                .await
+                .map_err(|e| Error::Io(io::Error::new(io::ErrorKind::ConnectionAborted, e)))
+        } else if let Some(remote) = self.remote_mailboxes.get_mut(&to) {
+            remote
+                .send(msg)
+                .await
+                .map_err(|e| Error::Io(io::Error::new(io::ErrorKind::ConnectionAborted, e)))
+        } else {
+            Err(Error::NotConnected(to))
+        }
+    }
+
+    /// Sends the chunk of the snapshot for `to` that starts at `offset`.
+    async fn send_snapshot_chunk(&mut self, to: NodeId, offset: u64) -> Result<(), Error> {
+        let chunk = if let Some(snapshot) = self.snapshots.get_mut(&to) {
+            snapshot.acked = offset;
+            snapshot.sent = Instant::now();
+            let len = snapshot.data.len();
+            let start = min(offset as usize, len);
+            let end = min(start + SNAPSHOT_CHUNK_SIZE, len);
+            let checksum = if end == len {
+                Some(snapshot.checksum.clone())
+            } else {
+                None
+            };
+            SnapshotChunk::new(
+                snapshot.msg.get_snapshot().get_metadata(),
+                start as u64,
+                &snapshot.data[start..end],
+                checksum,
+            )
+        } else {
+            return Ok(());
+        };
+        self.send_to(to, WsMessage::Ctrl(CtrlMsg::SnapshotChunk(self.id, chunk)))
+            .await
+    }
+
+    /// Continues a snapshot transfer from the offset the peer asked for, once
+    /// all data is acknowledged the raft message itself is sent and the
+    /// outcome of the transfer returned.
+    async fn snapshot_acked(&mut self, to: NodeId, offset: u64) -> Option<SnapshotStatus> {
+        let done = if let Some(snapshot) = self.snapshots.get_mut(&to) {
+            snapshot.resends = 0;
+            offset >= snapshot.data.len() as u64
+        } else {
+            return None;
+        };
+        if done {
+            let snapshot = self.snapshots.remove(&to)?;
+            info!(self.logger, "snapshot transferred"; "to" => to, "bytes" => snapshot.data.len());
+            return match self.send_to(to, WsMessage::Raft(snapshot.msg)).await {
+                Ok(()) => Some(SnapshotStatus::Finish),
+                Err(e) => {
+                    warn!(self.logger, "snapshot message lost"; "to" => to, "error" => format!("{}", e));
+                    Some(SnapshotStatus::Failure)
+                }
+            };
+        }
+        if let Err(e) = self.send_snapshot_chunk(to, offset).await {
+            warn!(self.logger, "snapshot transfer interrupted"; "to" => to, "error" => format!("{}", e));
+        }
+        None
+    }
+
+    async fn resume_snapshot(&mut self, to: NodeId) {
+        if let Some(offset) = self.snapshots.get(&to).map(|s| s.acked) {
+            info!(self.logger, "resuming snapshot transfer"; "to" => to, "offset" => offset);
+            if let Err(e) = self.send_snapshot_chunk(to, offset).await {
+                warn!(self.logger, "snapshot transfer interrupted"; "to" => to, "error" => format!("{}", e));
+            }
         }
     }
     fn register_reply(&mut self, reply: Reply) -> EventId {
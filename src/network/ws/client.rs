@@ -74,6 +74,20 @@ impl Connection {
                                 .unbounded_send(UrMsg::AckProposal(pid, success))
                         );
                     }
+                    CtrlMsg::SnapshotChunk(from, chunk) => {
+                        eat_error_and_blow!(
+                            self.logger,
+                            self.handler
+                                .unbounded_send(UrMsg::SnapshotChunk(from, chunk))
+                        );
+                    }
+                    CtrlMsg::AckSnapshotChunk(from, index, offset) => {
+                        eat_error_and_blow!(
+                            self.logger,
+                            self.handler
+                                .unbounded_send(UrMsg::AckSnapshotChunk(from, index, offset))
+                        );
+                    }
                     _ => (),
                 }
             } else {
@@ -129,6 +143,20 @@ impl Connection {
                         error!(self.logger, "status response not selected response for");
                         return false;
                     }
+                    ProtocolSelect::ChangeMembership { .. } => {
+                        error!(
+                            self.logger,
+                            "change membership response not selected response for"
+                        );
+                        return false;
+                    }
+                    ProtocolSelect::TransferLeader { .. } => {
+                        error!(
+                            self.logger,
+                            "transfer leader response not selected response for"
+                        );
+                        return false;
+                    }
                 }
             } else {
                 dbg!(("unknown message type", msg));
@@ -14,6 +14,7 @@
 // use crate::{NodeId, KV};
 
 mod kv;
+mod lock;
 mod mring;
 use super::Node;
 use super::*;
@@ -139,6 +140,72 @@ async fn uring_post(cx: Request<Node>) -> Result<Response> {
         .and_then(response_json_200)
 }
 
+async fn uring_learner_post(cx: Request<Node>) -> Result<Response> {
+    let (tx, mut rx) = channel(crate::CHANNEL_SIZE);
+    let id: u64 = cx.param("id").map_err(param_err)?;
+    cx.state()
+        .tx
+        .unbounded_send(UrMsg::AddLearner(NodeId(id), tx))?;
+    rx.next()
+        .await
+        .ok_or(StatusCode::NOT_FOUND.into())
+        .and_then(response_json_200)
+}
+
+async fn uring_promote(cx: Request<Node>) -> Result<Response> {
+    let (tx, mut rx) = channel(crate::CHANNEL_SIZE);
+    let id: u64 = cx.param("id").map_err(param_err)?;
+    cx.state()
+        .tx
+        .unbounded_send(UrMsg::PromoteNode(NodeId(id), tx))?;
+    rx.next()
+        .await
+        .ok_or(StatusCode::NOT_FOUND.into())
+        .and_then(response_json_200)
+}
+
+async fn uring_delete(cx: Request<Node>) -> Result<Response> {
+    let (tx, mut rx) = channel(crate::CHANNEL_SIZE);
+    let id: u64 = cx.param("id").map_err(param_err)?;
+    cx.state()
+        .tx
+        .unbounded_send(UrMsg::RemoveNode(NodeId(id), tx))?;
+    rx.next()
+        .await
+        .ok_or(StatusCode::NOT_FOUND.into())
+        .and_then(response_json_200)
+}
+
+#[derive(Deserialize)]
+struct MembershipBody {
+    #[serde(default)]
+    add: Vec<NodeId>,
+    #[serde(default)]
+    remove: Vec<NodeId>,
+}
+
+async fn uring_membership(mut cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let body: MembershipBody = cx.body_json().await?;
+    request(
+        cx,
+        UrMsg::ChangeMembership(body.add, body.remove, RequestId(666), tx),
+        rx,
+    )
+    .await
+}
+
+async fn uring_transfer_leader(cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let id: u64 = cx.param("id").map_err(param_err)?;
+    request(
+        cx,
+        UrMsg::TransferLeader(NodeId(id), RequestId(666), tx),
+        rx,
+    )
+    .await
+}
+
 pub(crate) async fn run(logger: Logger, node: Node, addr: String) -> std::io::Result<()> {
     use async_std::net::{SocketAddr, ToSocketAddrs};
     let addr: SocketAddr = addr
@@ -154,21 +221,96 @@ pub(crate) async fn run(logger: Logger, node: Node, addr: String) -> std::io::Re
         .get(|c| async { unerror(version(c).await) });
     app.at("/status")
         .get(|c| async { unerror(status(c).await) });
+    app.at("/kv")
+        .get(|c| async { unerror(kv::scan(c).await) });
+    app.at("/kv/lease")
+        .post(|c| async { unerror(kv::grant(c).await) });
+    app.at("/kv/lease/:lease")
+        .post(|c| async { unerror(kv::keep_alive(c).await) })
+        .delete(|c| async { unerror(kv::revoke(c).await) });
+    app.at("/kv/txn")
+        .post(|c| async { unerror(kv::txn(c).await) });
+    app.at("/kv/:id/history")
+        .get(|c| async { unerror(kv::history(c).await) });
+    app.at("/kv/:id/add")
+        .post(|c| async { unerror(kv::add(c).await) });
     app.at("/kv/:id")
         .get(|c| async { unerror(kv::get(c).await) })
         .post(|c| async { unerror(kv::post(c).await) })
         .delete(|c| async { unerror(kv::delete(c).await) })
         .at("/cas")
         .post(|c| async { unerror(kv::cas(c).await) });
+    app.at("/namespaces")
+        .get(|c| async { unerror(kv::namespaces(c).await) });
+    app.at("/namespaces/:ns")
+        .post(|c| async { unerror(kv::create_namespace(c).await) });
+    // namespaces can't be named `lease` or `txn`, keys named like the key
+    // actions are reachable below `/namespaces/:ns/kv`
+    app.at("/kv/:ns/:id/history")
+        .get(|c| async { unerror(kv::history(c).await) });
+    app.at("/kv/:ns/:id/add")
+        .post(|c| async { unerror(kv::add(c).await) });
+    app.at("/kv/:ns/:id")
+        .get(|c| async { unerror(kv::get(c).await) })
+        .post(|c| async { unerror(kv::post(c).await) })
+        .delete(|c| async { unerror(kv::delete(c).await) })
+        .at("/cas")
+        .post(|c| async { unerror(kv::cas(c).await) });
+    app.at("/namespaces/:ns/kv/:id/history")
+        .get(|c| async { unerror(kv::history(c).await) });
+    app.at("/namespaces/:ns/kv/:id/add")
+        .post(|c| async { unerror(kv::add(c).await) });
+    app.at("/namespaces/:ns/kv/:id")
+        .get(|c| async { unerror(kv::get(c).await) })
+        .post(|c| async { unerror(kv::post(c).await) })
+        .delete(|c| async { unerror(kv::delete(c).await) })
+        .at("/cas")
+        .post(|c| async { unerror(kv::cas(c).await) });
     app.at("/uring/:id")
         .get(|c| async { unerror(uring_get(c).await) })
-        .post(|c| async { unerror(uring_post(c).await) });
+        .post(|c| async { unerror(uring_post(c).await) })
+        .delete(|c| async { unerror(uring_delete(c).await) });
+    app.at("/uring/membership")
+        .post(|c| async { unerror(uring_membership(c).await) });
+    app.at("/uring/learner/:id")
+        .post(|c| async { unerror(uring_learner_post(c).await) });
+    app.at("/uring/promote/:id")
+        .post(|c| async { unerror(uring_promote(c).await) });
+    app.at("/uring/leader/:id")
+        .post(|c| async { unerror(uring_transfer_leader(c).await) });
+    app.at("/lock/lease/:lease")
+        .post(|c| async { unerror(lock::keep_alive(c).await) })
+        .delete(|c| async { unerror(lock::release(c).await) });
+    app.at("/lock/:name")
+        .get(|c| async { unerror(lock::get(c).await) })
+        .post(|c| async { unerror(lock::acquire(c).await) });
+    app.at("/election/:name")
+        .get(|c| async { unerror(lock::observe(c).await) })
+        .post(|c| async { unerror(lock::campaign(c).await) });
     app.at("/mring")
         .get(|c| async { unerror(mring::get_size(c).await) })
-        .post(|c| async { unerror(mring::set_size(c).await) })
-        .at("/node")
-        .post(|c| async { unerror(mring::get_nodes(c).await) })
+        .post(|c| async { unerror(mring::set_size(c).await) });
+    app.at("/mring/nodes")
+        .get(|c| async { unerror(mring::get_nodes(c).await) })
+        .post(|c| async { unerror(mring::add_node(c).await) });
+    app.at("/mring/nodes/:id")
+        .get(|c| async { unerror(mring::get_node(c).await) })
+        .delete(|c| async { unerror(mring::remove_node(c).await) });
+    // kept for existing clients, `/mring/nodes` replaces it
+    app.at("/mring/node")
+        .get(|c| async { unerror(mring::get_nodes(c).await) })
         .post(|c| async { unerror(mring::add_node(c).await) });
+    app.at("/mring/ring")
+        .get(|c| async { unerror(mring::get_ring(c).await) });
+    app.at("/mring/relocations")
+        .get(|c| async { unerror(mring::get_relocations(c).await) })
+        .delete(|c| async { unerror(mring::abandon_relocations(c).await) });
+    app.at("/mring/resize")
+        .post(|c| async { unerror(mring::resize(c).await) });
+    app.at("/mring/lookup")
+        .post(|c| async { unerror(mring::lookup_many(c).await) });
+    app.at("/mring/lookup/:key")
+        .get(|c| async { unerror(mring::lookup(c).await) });
     info!(logger, "Starting server on {}", addr);
     app.listen(addr).await?;
     Ok(())
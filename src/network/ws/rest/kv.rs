@@ -14,35 +14,99 @@
 // use crate::{NodeId, KV};
 
 use super::*;
+use crate::service::kv::{self as service, TxnOp};
+use crate::{Consistency, Encoding};
 use futures::channel::mpsc::channel;
 use tide::{Request, Response};
+use ws_proto::Quota;
 
 fn param_err<T: std::fmt::Debug>(e: ParamError<T>) -> Error {
     Error::Param(format!("{:?}", e))
 }
 
+/// The namespace of a request, routes without a `:ns` segment address the
+/// default one.
+fn namespace(cx: &Request<Node>) -> Option<String> {
+    cx.param("ns").ok()
+}
+
+/// A namespace for routes that have no room for it in the path.
+#[derive(Deserialize, Debug)]
+struct NsQuery {
+    #[serde(default)]
+    ns: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GetQuery {
+    #[serde(default)]
+    consistency: Consistency,
+    #[serde(default)]
+    encoding: Encoding,
+}
+
 pub(crate) async fn get(cx: Request<Node>) -> Result<Response> {
     let (tx, rx) = channel(crate::CHANNEL_SIZE);
     let key: String = cx.param("id").map_err(param_err)?;
+    let ns = namespace(&cx);
+    let query: GetQuery = cx.query()?;
     let id = key.clone().into_bytes();
-    info!(cx.state().logger, "GET /kv/{}", key);
-    request(cx, UrMsg::Get(id.clone(), reply(tx)), rx).await
+    info!(cx.state().logger, "GET /kv/{} ({:?})", key, query.consistency);
+    request(
+        cx,
+        UrMsg::Get(ns, id.clone(), query.consistency, query.encoding, reply(tx)),
+        rx,
+    )
+    .await
+}
+
+pub(crate) async fn history(cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let key: String = cx.param("id").map_err(param_err)?;
+    let ns = namespace(&cx);
+    let query: GetQuery = cx.query()?;
+    info!(cx.state().logger, "GET /kv/{}/history", key);
+    request(
+        cx,
+        UrMsg::History(
+            ns,
+            key.into_bytes(),
+            query.consistency,
+            query.encoding,
+            reply(tx),
+        ),
+        rx,
+    )
+    .await
+}
+
+pub(crate) async fn scan(cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let query: ws_proto::Scan = cx.query()?;
+    info!(cx.state().logger, "GET /kv {:?}", query);
+    request(cx, UrMsg::Scan(query, reply(tx)), rx).await
 }
 
 #[derive(Deserialize, Debug)]
 struct PostBody {
-    value: String,
+    value: serde_json::Value,
+    #[serde(default)]
+    lease: Option<u64>,
+    #[serde(default)]
+    encoding: Encoding,
 }
 
 pub(crate) async fn post(mut cx: Request<Node>) -> Result<Response> {
     let (tx, rx) = channel(crate::CHANNEL_SIZE);
     let key: String = cx.param("id").map_err(param_err)?;
+    let ns = namespace(&cx);
     let id = key.clone().into_bytes();
     let body: PostBody = cx.body_json().await?;
     info!(cx.state().logger, "POST /kv/{} -> {}", key, body.value);
+    let value = service::decode(body.encoding, body.value).map_err(Error::Param)?;
     request(
         cx,
-        UrMsg::Put(id, body.value.clone().into_bytes(), reply(tx)),
+        UrMsg::Put(ns, id, value, body.lease, body.encoding, reply(tx)),
         rx,
     )
     .await
@@ -50,22 +114,57 @@ pub(crate) async fn post(mut cx: Request<Node>) -> Result<Response> {
 
 #[derive(Deserialize)]
 struct CasBody {
-    check: Option<String>,
-    store: String,
+    check: Option<serde_json::Value>,
+    store: serde_json::Value,
+    #[serde(default)]
+    encoding: Encoding,
 }
 
 pub(crate) async fn cas(mut cx: Request<Node>) -> Result<Response> {
     let (tx, rx) = channel(crate::CHANNEL_SIZE);
     let id: String = cx.param("id").map_err(param_err)?;
     let id = id.into_bytes();
+    let ns = namespace(&cx);
     let body: CasBody = cx.body_json().await?;
+    let encoding = body.encoding;
+    let check = body
+        .check
+        .map(|v| service::decode(encoding, v))
+        .transpose()
+        .map_err(Error::Param)?;
+    let store = service::decode(encoding, body.store).map_err(Error::Param)?;
+
+    request(
+        cx,
+        UrMsg::Cas(ns, id, check, store, encoding, reply(tx)),
+        rx,
+    )
+    .await
+}
 
+#[derive(Deserialize)]
+struct AddBody {
+    delta: i64,
+    #[serde(default)]
+    min: Option<i64>,
+    #[serde(default)]
+    max: Option<i64>,
+}
+
+pub(crate) async fn add(mut cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let key: String = cx.param("id").map_err(param_err)?;
+    let ns = namespace(&cx);
+    let body: AddBody = cx.body_json().await?;
+    info!(cx.state().logger, "POST /kv/{}/add {}", key, body.delta);
     request(
         cx,
-        UrMsg::Cas(
-            id,
-            body.check.clone().map(String::into_bytes),
-            body.store.clone().into_bytes(),
+        UrMsg::Add(
+            ns,
+            key.into_bytes(),
+            body.delta,
+            body.min,
+            body.max,
             reply(tx),
         ),
         rx,
@@ -73,9 +172,81 @@ pub(crate) async fn cas(mut cx: Request<Node>) -> Result<Response> {
     .await
 }
 
+#[derive(Deserialize)]
+struct TxnBody {
+    #[serde(default)]
+    ns: Option<String>,
+    ops: Vec<ws_proto::TxnOp>,
+    #[serde(default)]
+    encoding: Encoding,
+}
+
+pub(crate) async fn txn(mut cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let body: TxnBody = cx.body_json().await?;
+    info!(cx.state().logger, "POST /kv/txn ({} ops)", body.ops.len());
+    let encoding = body.encoding;
+    let ops = body
+        .ops
+        .into_iter()
+        .map(|op| TxnOp::decode(op, encoding))
+        .collect::<std::result::Result<_, _>>()
+        .map_err(Error::Param)?;
+    request(cx, UrMsg::Txn(body.ns, ops, encoding, reply(tx)), rx).await
+}
+
 pub(crate) async fn delete(cx: Request<Node>) -> Result<Response> {
     let (tx, rx) = channel(crate::CHANNEL_SIZE);
     let key: String = cx.param("id").map_err(param_err)?;
+    let ns = namespace(&cx);
+    let query: GetQuery = cx.query()?;
     let id = key.clone().into_bytes();
-    request(cx, UrMsg::Delete(id.clone(), reply(tx)), rx).await
+    request(
+        cx,
+        UrMsg::Delete(ns, id.clone(), query.encoding, reply(tx)),
+        rx,
+    )
+    .await
+}
+
+#[derive(Deserialize)]
+struct GrantBody {
+    #[serde(default)]
+    ns: Option<String>,
+    ttl: u64,
+}
+
+pub(crate) async fn grant(mut cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let body: GrantBody = cx.body_json().await?;
+    info!(cx.state().logger, "POST /kv/lease ttl {}", body.ttl);
+    request(cx, UrMsg::Grant(body.ns, body.ttl, reply(tx)), rx).await
+}
+
+pub(crate) async fn keep_alive(cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let lease: u64 = cx.param("lease").map_err(param_err)?;
+    let query: NsQuery = cx.query()?;
+    request(cx, UrMsg::KeepAlive(query.ns, lease, reply(tx)), rx).await
+}
+
+pub(crate) async fn revoke(cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let lease: u64 = cx.param("lease").map_err(param_err)?;
+    let query: NsQuery = cx.query()?;
+    info!(cx.state().logger, "DELETE /kv/lease/{}", lease);
+    request(cx, UrMsg::Revoke(query.ns, lease, reply(tx)), rx).await
+}
+
+pub(crate) async fn create_namespace(mut cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let ns: String = cx.param("ns").map_err(param_err)?;
+    let quota: Quota = cx.body_json().await?;
+    info!(cx.state().logger, "POST /namespaces/{} {:?}", ns, quota);
+    request(cx, UrMsg::CreateNamespace(ns, quota, reply(tx)), rx).await
+}
+
+pub(crate) async fn namespaces(cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    request(cx, UrMsg::Namespaces(reply(tx)), rx).await
 }
@@ -0,0 +1,83 @@
+// Copyright 2018-2020, Wayfair GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::*;
+use futures::channel::mpsc::channel;
+use tide::{Request, Response};
+
+#[derive(Deserialize)]
+struct AcquireBody {
+    owner: String,
+    ttl: u64,
+}
+
+pub(crate) async fn acquire(mut cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let name: String = cx.param("name").map_err(param_err)?;
+    let body: AcquireBody = cx.body_json().await?;
+    info!(cx.state().logger, "POST /lock/{} for {}", name, body.owner);
+    request(
+        cx,
+        UrMsg::LockAcquire(name, body.owner, body.ttl, reply(tx)),
+        rx,
+    )
+    .await
+}
+
+pub(crate) async fn get(cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let name: String = cx.param("name").map_err(param_err)?;
+    request(cx, UrMsg::LockGet(name, reply(tx)), rx).await
+}
+
+pub(crate) async fn keep_alive(cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let lease: u64 = cx.param("lease").map_err(param_err)?;
+    request(cx, UrMsg::LockKeepAlive(lease, reply(tx)), rx).await
+}
+
+pub(crate) async fn release(cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let lease: u64 = cx.param("lease").map_err(param_err)?;
+    info!(cx.state().logger, "DELETE /lock/lease/{}", lease);
+    request(cx, UrMsg::LockRelease(lease, reply(tx)), rx).await
+}
+
+#[derive(Deserialize)]
+struct CampaignBody {
+    value: String,
+    ttl: u64,
+}
+
+pub(crate) async fn campaign(mut cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let name: String = cx.param("name").map_err(param_err)?;
+    let body: CampaignBody = cx.body_json().await?;
+    info!(
+        cx.state().logger,
+        "POST /election/{} for {}", name, body.value
+    );
+    request(
+        cx,
+        UrMsg::LockCampaign(name, body.value, body.ttl, reply(tx)),
+        rx,
+    )
+    .await
+}
+
+pub(crate) async fn observe(cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let name: String = cx.param("name").map_err(param_err)?;
+    request(cx, UrMsg::LockLeader(name, reply(tx)), rx).await
+}
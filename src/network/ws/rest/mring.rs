@@ -20,6 +20,10 @@ use tide::{Request, Response};
 #[derive(Deserialize, Serialize)]
 pub struct MRingSize {
     size: u64,
+    #[serde(default)]
+    strategy: Option<String>,
+    #[serde(default)]
+    replicas: Option<u64>,
 }
 
 pub(crate) async fn get_size(cx: Request<Node>) -> Result<Response> {
@@ -30,7 +34,12 @@ pub(crate) async fn get_size(cx: Request<Node>) -> Result<Response> {
 pub(crate) async fn set_size(mut cx: Request<Node>) -> Result<Response> {
     let (tx, rx) = channel(crate::CHANNEL_SIZE);
     let body: MRingSize = cx.body_json().await?;
-    request(cx, UrMsg::MRingSetSize(body.size, reply(tx)), rx).await
+    request(
+        cx,
+        UrMsg::MRingSetSize(body.size, body.strategy, body.replicas, reply(tx)),
+        rx,
+    )
+    .await
 }
 
 pub(crate) async fn get_nodes(cx: Request<Node>) -> Result<Response> {
@@ -41,10 +50,67 @@ pub(crate) async fn get_nodes(cx: Request<Node>) -> Result<Response> {
 #[derive(Deserialize, Serialize)]
 pub struct MRingNode {
     node: String,
+    #[serde(flatten)]
+    meta: NodeMeta,
 }
 
 pub(crate) async fn add_node(mut cx: Request<Node>) -> Result<Response> {
     let (tx, rx) = channel(crate::CHANNEL_SIZE);
     let body: MRingNode = cx.body_json().await?;
-    request(cx, UrMsg::MRingAddNode(body.node.clone(), reply(tx)), rx).await
+    request(cx, UrMsg::MRingAddNode(body.node, body.meta, reply(tx)), rx).await
+}
+
+pub(crate) async fn get_node(cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let node: String = cx.param("id").map_err(param_err)?;
+    request(cx, UrMsg::MRingGetNode(node, reply(tx)), rx).await
+}
+
+pub(crate) async fn remove_node(cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let node: String = cx.param("id").map_err(param_err)?;
+    request(cx, UrMsg::MRingRemoveNode(node, reply(tx)), rx).await
+}
+
+pub(crate) async fn get_ring(cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    request(cx, UrMsg::MRingGetRing(reply(tx)), rx).await
+}
+
+pub(crate) async fn lookup(cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let key: String = cx.param("key").map_err(param_err)?;
+    request(cx, UrMsg::MRingLookup(key, reply(tx)), rx).await
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct MRingKeys {
+    keys: Vec<String>,
+}
+
+pub(crate) async fn lookup_many(mut cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let body: MRingKeys = cx.body_json().await?;
+    request(cx, UrMsg::MRingLookupMany(body.keys, reply(tx)), rx).await
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct MRingResize {
+    size: u64,
+}
+
+pub(crate) async fn resize(mut cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    let body: MRingResize = cx.body_json().await?;
+    request(cx, UrMsg::MRingResize(body.size, reply(tx)), rx).await
+}
+
+pub(crate) async fn get_relocations(cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    request(cx, UrMsg::MRingGetRelocations(reply(tx)), rx).await
+}
+
+pub(crate) async fn abandon_relocations(cx: Request<Node>) -> Result<Response> {
+    let (tx, rx) = channel(crate::CHANNEL_SIZE);
+    request(cx, UrMsg::MRingAbandonRelocations(reply(tx)), rx).await
 }
@@ -92,12 +92,30 @@ impl Connection {
                     Protocol::KV => self.handle_kv_msg(serde_json::from_value(cmd).unwrap()),
                     _ => false,
                 },
-                Ok(ProtocolSelect::Subscribe { channel }) => self
+                Ok(ProtocolSelect::TransferLeader { rid, node }) => self
+                    .node
+                    .tx
+                    .send(UrMsg::TransferLeader(node, rid, self.ws_tx.clone()))
+                    .await
+                    .is_ok(),
+                Ok(ProtocolSelect::ChangeMembership { rid, add, remove }) => self
+                    .node
+                    .tx
+                    .send(UrMsg::ChangeMembership(
+                        add,
+                        remove,
+                        rid,
+                        self.ws_tx.clone(),
+                    ))
+                    .await
+                    .is_ok(),
+                Ok(ProtocolSelect::Subscribe { channel, from }) => self
                     .node
                     .pubsub
                     .send(pubsub::Msg::Subscribe {
                         channel,
                         tx: self.ps_tx.clone(),
+                        from,
                     })
                     .await
                     .is_ok(),
@@ -142,6 +160,16 @@ impl Connection {
                     .tx
                     .unbounded_send(UrMsg::ForwardProposal(from, pid, sid, eid, value))
                     .is_ok(),
+                Ok(CtrlMsg::SnapshotChunk(from, chunk)) => self
+                    .node
+                    .tx
+                    .unbounded_send(UrMsg::SnapshotChunk(from, chunk))
+                    .is_ok(),
+                Ok(CtrlMsg::AckSnapshotChunk(from, index, offset)) => self
+                    .node
+                    .tx
+                    .unbounded_send(UrMsg::AckSnapshotChunk(from, index, offset))
+                    .is_ok(),
                 Ok(_) => true,
                 Err(e) => {
                     error!(
@@ -182,48 +210,220 @@ impl Connection {
         }
     }
 
+    /// Answers a request that can't be passed on to the node.
+    fn reject(&mut self, rid: RequestId, error: String) -> bool {
+        let reply = Reply {
+            code: 400,
+            rid,
+            data: error.into(),
+        };
+        self.ws_tx.try_send(reply.into()).is_ok()
+    }
+
+    fn add(
+        &mut self,
+        rid: RequestId,
+        ns: Option<String>,
+        key: String,
+        delta: i64,
+        min: Option<i64>,
+        max: Option<i64>,
+    ) -> bool {
+        self.node
+            .tx
+            .unbounded_send(UrMsg::Add(
+                ns,
+                key.into_bytes(),
+                delta,
+                min,
+                max,
+                WsReply(rid, self.ws_tx.clone()),
+            ))
+            .is_ok()
+    }
+
     fn handle_kv_msg(&mut self, msg: KVRequest) -> bool {
         match msg {
-            KVRequest::Get { rid, key } => self
+            KVRequest::Get {
+                rid,
+                ns,
+                key,
+                consistency,
+                encoding,
+            } => self
                 .node
                 .tx
                 .unbounded_send(UrMsg::Get(
+                    ns,
                     key.into_bytes(),
+                    consistency,
+                    encoding,
                     WsReply(rid, self.ws_tx.clone()),
                 ))
                 .is_ok(),
-            KVRequest::Put { rid, key, store } => self
+            KVRequest::Put {
+                rid,
+                ns,
+                key,
+                store,
+                lease,
+                encoding,
+            } => match kv::decode(encoding, store) {
+                Ok(value) => self
+                    .node
+                    .tx
+                    .unbounded_send(UrMsg::Put(
+                        ns,
+                        key.into_bytes(),
+                        value,
+                        lease,
+                        encoding,
+                        WsReply(rid, self.ws_tx.clone()),
+                    ))
+                    .is_ok(),
+                Err(e) => self.reject(rid, e),
+            },
+            KVRequest::Delete {
+                rid,
+                ns,
+                key,
+                encoding,
+            } => self
                 .node
                 .tx
-                .unbounded_send(UrMsg::Put(
+                .unbounded_send(UrMsg::Delete(
+                    ns,
                     key.into_bytes(),
-                    store.into_bytes(),
+                    encoding,
                     WsReply(rid, self.ws_tx.clone()),
                 ))
                 .is_ok(),
-            KVRequest::Delete { rid, key } => self
+            KVRequest::Cas {
+                rid,
+                ns,
+                key,
+                check,
+                store,
+                encoding,
+            } => {
+                let check = check.map(|v| kv::decode(encoding, v)).transpose();
+                match (check, kv::decode(encoding, store)) {
+                    (Ok(check), Ok(store)) => self
+                        .node
+                        .tx
+                        .unbounded_send(UrMsg::Cas(
+                            ns,
+                            key.into_bytes(),
+                            check,
+                            store,
+                            encoding,
+                            WsReply(rid, self.ws_tx.clone()),
+                        ))
+                        .is_ok(),
+                    (Err(e), _) | (_, Err(e)) => self.reject(rid, e),
+                }
+            }
+            KVRequest::Txn {
+                rid,
+                ns,
+                ops,
+                encoding,
+            } => {
+                let ops: Result<Vec<_>, _> = ops
+                    .into_iter()
+                    .map(|op| kv::TxnOp::decode(op, encoding))
+                    .collect();
+                match ops {
+                    Ok(ops) => self
+                        .node
+                        .tx
+                        .unbounded_send(UrMsg::Txn(
+                            ns,
+                            ops,
+                            encoding,
+                            WsReply(rid, self.ws_tx.clone()),
+                        ))
+                        .is_ok(),
+                    Err(e) => self.reject(rid, e),
+                }
+            }
+            KVRequest::Scan { rid, scan } => self
                 .node
                 .tx
-                .unbounded_send(UrMsg::Delete(
-                    key.into_bytes(),
+                .unbounded_send(UrMsg::Scan(scan, WsReply(rid, self.ws_tx.clone())))
+                .is_ok(),
+            KVRequest::Grant { rid, ns, ttl } => self
+                .node
+                .tx
+                .unbounded_send(UrMsg::Grant(ns, ttl, WsReply(rid, self.ws_tx.clone())))
+                .is_ok(),
+            KVRequest::KeepAlive { rid, ns, lease } => self
+                .node
+                .tx
+                .unbounded_send(UrMsg::KeepAlive(
+                    ns,
+                    lease,
                     WsReply(rid, self.ws_tx.clone()),
                 ))
                 .is_ok(),
-            KVRequest::Cas {
+            KVRequest::Revoke { rid, ns, lease } => self
+                .node
+                .tx
+                .unbounded_send(UrMsg::Revoke(ns, lease, WsReply(rid, self.ws_tx.clone())))
+                .is_ok(),
+            KVRequest::History {
                 rid,
+                ns,
                 key,
-                check,
-                store,
+                consistency,
+                encoding,
             } => self
                 .node
                 .tx
-                .unbounded_send(UrMsg::Cas(
+                .unbounded_send(UrMsg::History(
+                    ns,
                     key.into_bytes(),
-                    check.map(String::into_bytes),
-                    store.into_bytes(),
+                    consistency,
+                    encoding,
                     WsReply(rid, self.ws_tx.clone()),
                 ))
                 .is_ok(),
+            KVRequest::Incr {
+                rid,
+                ns,
+                key,
+                min,
+                max,
+            } => self.add(rid, ns, key, 1, min, max),
+            KVRequest::Decr {
+                rid,
+                ns,
+                key,
+                min,
+                max,
+            } => self.add(rid, ns, key, -1, min, max),
+            KVRequest::Add {
+                rid,
+                ns,
+                key,
+                delta,
+                min,
+                max,
+            } => self.add(rid, ns, key, delta, min, max),
+            KVRequest::CreateNamespace { rid, ns, quota } => self
+                .node
+                .tx
+                .unbounded_send(UrMsg::CreateNamespace(
+                    ns,
+                    quota,
+                    WsReply(rid, self.ws_tx.clone()),
+                ))
+                .is_ok(),
+            KVRequest::Namespaces { rid } => self
+                .node
+                .tx
+                .unbounded_send(UrMsg::Namespaces(WsReply(rid, self.ws_tx.clone())))
+                .is_ok(),
         }
     }
 
@@ -255,20 +455,39 @@ impl Connection {
                 .unbounded_send(UrMsg::MRingGetSize(WsReply(rid, self.ws_tx.clone())))
                 .is_ok(),
 
-            MRRequest::SetSize { rid, size } => self
+            MRRequest::SetSize {
+                rid,
+                size,
+                strategy,
+                replicas,
+            } => self
                 .node
                 .tx
-                .unbounded_send(UrMsg::MRingSetSize(size, WsReply(rid, self.ws_tx.clone())))
+                .unbounded_send(UrMsg::MRingSetSize(
+                    size,
+                    strategy,
+                    replicas,
+                    WsReply(rid, self.ws_tx.clone()),
+                ))
                 .is_ok(),
             MRRequest::GetNodes { rid } => self
                 .node
                 .tx
                 .unbounded_send(UrMsg::MRingGetNodes(WsReply(rid, self.ws_tx.clone())))
                 .is_ok(),
-            MRRequest::AddNode { rid, node } => self
+            MRRequest::GetNode { rid, node } => self
+                .node
+                .tx
+                .unbounded_send(UrMsg::MRingGetNode(node, WsReply(rid, self.ws_tx.clone())))
+                .is_ok(),
+            MRRequest::AddNode { rid, node, meta } => self
                 .node
                 .tx
-                .unbounded_send(UrMsg::MRingAddNode(node, WsReply(rid, self.ws_tx.clone())))
+                .unbounded_send(UrMsg::MRingAddNode(
+                    node,
+                    meta,
+                    WsReply(rid, self.ws_tx.clone()),
+                ))
                 .is_ok(),
             MRRequest::RemoveNode { rid, node } => self
                 .node
@@ -278,9 +497,119 @@ impl Connection {
                     WsReply(rid, self.ws_tx.clone()),
                 ))
                 .is_ok(),
+            MRRequest::GetRing { rid } => self
+                .node
+                .tx
+                .unbounded_send(UrMsg::MRingGetRing(WsReply(rid, self.ws_tx.clone())))
+                .is_ok(),
+            MRRequest::Lookup { rid, key } => self
+                .node
+                .tx
+                .unbounded_send(UrMsg::MRingLookup(key, WsReply(rid, self.ws_tx.clone())))
+                .is_ok(),
+            MRRequest::LookupMany { rid, keys } => self
+                .node
+                .tx
+                .unbounded_send(UrMsg::MRingLookupMany(
+                    keys,
+                    WsReply(rid, self.ws_tx.clone()),
+                ))
+                .is_ok(),
+            MRRequest::Resize { rid, size } => self
+                .node
+                .tx
+                .unbounded_send(UrMsg::MRingResize(size, WsReply(rid, self.ws_tx.clone())))
+                .is_ok(),
+            MRRequest::HandoffCompleted { rid, vnode, node } => self
+                .node
+                .tx
+                .unbounded_send(UrMsg::MRingHandoffCompleted(
+                    vnode,
+                    node,
+                    WsReply(rid, self.ws_tx.clone()),
+                ))
+                .is_ok(),
+            MRRequest::HandoffFailed {
+                rid,
+                vnode,
+                node,
+                reason,
+            } => self
+                .node
+                .tx
+                .unbounded_send(UrMsg::MRingHandoffFailed(
+                    vnode,
+                    node,
+                    reason,
+                    WsReply(rid, self.ws_tx.clone()),
+                ))
+                .is_ok(),
+            MRRequest::GetRelocations { rid } => self
+                .node
+                .tx
+                .unbounded_send(UrMsg::MRingGetRelocations(WsReply(rid, self.ws_tx.clone())))
+                .is_ok(),
+            MRRequest::AbandonRelocations { rid } => self
+                .node
+                .tx
+                .unbounded_send(UrMsg::MRingAbandonRelocations(WsReply(
+                    rid,
+                    self.ws_tx.clone(),
+                )))
+                .is_ok(),
         }
     }
 
+    async fn handle_lock(&mut self, msg: Message) -> bool {
+        if msg.is_text() {
+            let text = msg.into_data();
+            match serde_json::from_slice(&text) {
+                Ok(msg) => self.handle_lock_msg(msg),
+                Err(e) => {
+                    error!(
+                        self.node.logger,
+                        "Failed to decode LockRequest message: {} => {}",
+                        e,
+                        String::from_utf8(text).unwrap_or_default()
+                    );
+                    false
+                }
+            }
+        } else {
+            true
+        }
+    }
+
+    fn handle_lock_msg(&mut self, msg: LockRequest) -> bool {
+        let msg = match msg {
+            LockRequest::Acquire {
+                rid,
+                name,
+                owner,
+                ttl,
+            } => UrMsg::LockAcquire(name, owner, ttl, WsReply(rid, self.ws_tx.clone())),
+            LockRequest::Campaign {
+                rid,
+                election,
+                value,
+                ttl,
+            } => UrMsg::LockCampaign(election, value, ttl, WsReply(rid, self.ws_tx.clone())),
+            LockRequest::Release { rid, lease } | LockRequest::Resign { rid, lease } => {
+                UrMsg::LockRelease(lease, WsReply(rid, self.ws_tx.clone()))
+            }
+            LockRequest::KeepAlive { rid, lease } => {
+                UrMsg::LockKeepAlive(lease, WsReply(rid, self.ws_tx.clone()))
+            }
+            LockRequest::Get { rid, name } => {
+                UrMsg::LockGet(name, WsReply(rid, self.ws_tx.clone()))
+            }
+            LockRequest::Observe { rid, election } => {
+                UrMsg::LockLeader(election, WsReply(rid, self.ws_tx.clone()))
+            }
+        };
+        self.node.tx.unbounded_send(msg).is_ok()
+    }
+
     async fn handle_version(&mut self, msg: Message) -> bool {
         if msg.is_text() {
             let text = msg.into_data();
@@ -358,6 +687,7 @@ impl Connection {
                                 Some(Protocol::MRing) => self.handle_mring(msg).await,
                                 Some(Protocol::Version) => self.handle_version(msg).await,
                                 Some(Protocol::Status) => self.handle_status(msg).await,
+                                Some(Protocol::Lock) => self.handle_lock(msg).await,
                             };
 
                             handled_ok || self.handle_control(msg2, true).await
@@ -368,7 +698,7 @@ impl Connection {
                 }
                 msg = self.ws_rx.next() => {
                     match self.protocol {
-                        None | Some(Protocol::Status) | Some(Protocol::Version) | Some(Protocol::KV) | Some(Protocol::MRing) => match msg {
+                        None | Some(Protocol::Status) | Some(Protocol::Version) | Some(Protocol::KV) | Some(Protocol::MRing) | Some(Protocol::Lock) => match msg {
                             Some(WsMessage::Ctrl(msg)) =>self.tx.send(Message::Text(serde_json::to_string(&msg).unwrap())).await.is_ok(),
                             Some(WsMessage::Reply(_, msg)) =>self.tx.send(Message::Text(serde_json::to_string(&msg).unwrap())).await.is_ok(),
                             None | Some(WsMessage::Raft(_)) => false,
@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use crate::service::kv;
+use crate::{Consistency, Encoding};
 use async_trait::async_trait;
 use protocol_driver::{interceptor, DriverErrorType, HandlerInboundMessage, RequestId};
 use serde_derive::{Deserialize, Serialize};
@@ -21,22 +22,123 @@ use std::collections::HashMap;
 #[derive(Deserialize, Serialize, Debug)]
 enum Request {
     Get {
+        #[serde(default)]
+        ns: Option<String>,
         key: String,
+        #[serde(default)]
+        consistency: Consistency,
+        #[serde(default)]
+        encoding: Encoding,
         rid: RequestId,
     },
     Put {
+        #[serde(default)]
+        ns: Option<String>,
         key: String,
-        store: String,
+        store: serde_json::Value,
+        #[serde(default)]
+        lease: Option<u64>,
+        #[serde(default)]
+        encoding: Encoding,
         rid: RequestId,
     },
     Delete {
+        #[serde(default)]
+        ns: Option<String>,
         key: String,
+        #[serde(default)]
+        encoding: Encoding,
         rid: RequestId,
     },
     Cas {
+        #[serde(default)]
+        ns: Option<String>,
         key: String,
-        check: Option<String>,
-        store: String,
+        check: Option<serde_json::Value>,
+        store: serde_json::Value,
+        #[serde(default)]
+        encoding: Encoding,
+        rid: RequestId,
+    },
+    Txn {
+        #[serde(default)]
+        ns: Option<String>,
+        ops: Vec<ws_proto::TxnOp>,
+        #[serde(default)]
+        encoding: Encoding,
+        rid: RequestId,
+    },
+    Scan {
+        #[serde(flatten)]
+        scan: ws_proto::Scan,
+        rid: RequestId,
+    },
+    Grant {
+        #[serde(default)]
+        ns: Option<String>,
+        ttl: u64,
+        rid: RequestId,
+    },
+    KeepAlive {
+        #[serde(default)]
+        ns: Option<String>,
+        lease: u64,
+        rid: RequestId,
+    },
+    Revoke {
+        #[serde(default)]
+        ns: Option<String>,
+        lease: u64,
+        rid: RequestId,
+    },
+    History {
+        #[serde(default)]
+        ns: Option<String>,
+        key: String,
+        #[serde(default)]
+        consistency: Consistency,
+        #[serde(default)]
+        encoding: Encoding,
+        rid: RequestId,
+    },
+    Incr {
+        #[serde(default)]
+        ns: Option<String>,
+        key: String,
+        #[serde(default)]
+        min: Option<i64>,
+        #[serde(default)]
+        max: Option<i64>,
+        rid: RequestId,
+    },
+    Decr {
+        #[serde(default)]
+        ns: Option<String>,
+        key: String,
+        #[serde(default)]
+        min: Option<i64>,
+        #[serde(default)]
+        max: Option<i64>,
+        rid: RequestId,
+    },
+    Add {
+        #[serde(default)]
+        ns: Option<String>,
+        key: String,
+        delta: i64,
+        #[serde(default)]
+        min: Option<i64>,
+        #[serde(default)]
+        max: Option<i64>,
+        rid: RequestId,
+    },
+    CreateNamespace {
+        ns: String,
+        #[serde(default)]
+        quota: ws_proto::Quota,
+        rid: RequestId,
+    },
+    Namespaces {
         rid: RequestId,
     },
 }
@@ -52,30 +154,137 @@ impl interceptor::Intercept for Handler {
         use kv::Event;
         msg.service_id = Some(kv::ID);
         msg.data = match dbg!(serde_json::from_slice(&msg.data)) {
-            Ok(Request::Get { key, rid }) => {
+            Ok(Request::Get {
+                ns,
+                key,
+                consistency,
+                encoding,
+                rid,
+            }) => {
                 self.ids.insert(msg.id, rid);
-                Event::get(key.into_bytes())
+                Event::get(ns, key.into_bytes(), consistency, encoding)
             }
-            Ok(Request::Put { key, store, rid }) => {
+            Ok(Request::Put {
+                ns,
+                key,
+                store,
+                lease,
+                encoding,
+                rid,
+            }) => {
+                let value = match kv::decode(encoding, store) {
+                    Ok(value) => value,
+                    Err(_) => return interceptor::Reply::Err(DriverErrorType::BadInput),
+                };
                 self.ids.insert(msg.id, rid);
-                Event::put(key.into_bytes(), store.into_bytes())
+                Event::put(ns, key.into_bytes(), value, lease, encoding)
             }
-            Ok(Request::Delete { key, rid }) => {
+            Ok(Request::Delete {
+                ns,
+                key,
+                encoding,
+                rid,
+            }) => {
                 self.ids.insert(msg.id, rid);
-                Event::delete(key.into_bytes())
+                Event::delete(ns, key.into_bytes(), encoding)
             }
             Ok(Request::Cas {
+                ns,
                 key,
                 check,
                 store,
+                encoding,
                 rid,
             }) => {
+                let check = check.map(|v| kv::decode(encoding, v)).transpose();
+                let (check, store) = match (check, kv::decode(encoding, store)) {
+                    (Ok(check), Ok(store)) => (check, store),
+                    _ => return interceptor::Reply::Err(DriverErrorType::BadInput),
+                };
+                self.ids.insert(msg.id, rid);
+                kv::Event::cas(ns, key.into_bytes(), check, store, encoding)
+            }
+            Ok(Request::Txn {
+                ns,
+                ops,
+                encoding,
+                rid,
+            }) => {
+                let ops: Result<Vec<_>, _> = ops
+                    .into_iter()
+                    .map(|op| kv::TxnOp::decode(op, encoding))
+                    .collect();
+                let ops = match ops {
+                    Ok(ops) => ops,
+                    Err(_) => return interceptor::Reply::Err(DriverErrorType::BadInput),
+                };
+                self.ids.insert(msg.id, rid);
+                Event::txn(ns, ops, encoding)
+            }
+            Ok(Request::Scan { scan, rid }) => {
+                self.ids.insert(msg.id, rid);
+                Event::scan(scan)
+            }
+            Ok(Request::Grant { ns, ttl, rid }) => {
+                self.ids.insert(msg.id, rid);
+                Event::grant(ns, ttl)
+            }
+            Ok(Request::KeepAlive { ns, lease, rid }) => {
+                self.ids.insert(msg.id, rid);
+                Event::keep_alive(ns, lease)
+            }
+            Ok(Request::Revoke { ns, lease, rid }) => {
+                self.ids.insert(msg.id, rid);
+                Event::revoke(ns, lease)
+            }
+            Ok(Request::History {
+                ns,
+                key,
+                consistency,
+                encoding,
+                rid,
+            }) => {
+                self.ids.insert(msg.id, rid);
+                Event::history(ns, key.into_bytes(), consistency, encoding)
+            }
+            Ok(Request::Incr {
+                ns,
+                key,
+                min,
+                max,
+                rid,
+            }) => {
+                self.ids.insert(msg.id, rid);
+                Event::add(ns, key.into_bytes(), 1, min, max)
+            }
+            Ok(Request::Decr {
+                ns,
+                key,
+                min,
+                max,
+                rid,
+            }) => {
+                self.ids.insert(msg.id, rid);
+                Event::add(ns, key.into_bytes(), -1, min, max)
+            }
+            Ok(Request::Add {
+                ns,
+                key,
+                delta,
+                min,
+                max,
+                rid,
+            }) => {
+                self.ids.insert(msg.id, rid);
+                Event::add(ns, key.into_bytes(), delta, min, max)
+            }
+            Ok(Request::CreateNamespace { ns, quota, rid }) => {
+                self.ids.insert(msg.id, rid);
+                Event::create_namespace(ns, quota)
+            }
+            Ok(Request::Namespaces { rid }) => {
                 self.ids.insert(msg.id, rid);
-                kv::Event::cas(
-                    key.into_bytes(),
-                    check.map(String::into_bytes),
-                    store.into_bytes(),
-                )
+                Event::namespaces()
             }
             Err(_) => return interceptor::Reply::Err(DriverErrorType::BadInput),
         };
@@ -24,7 +24,11 @@ use serde_derive::{Deserialize, Serialize};
 
 #[derive(Deserialize, Serialize, Debug)]
 enum Request {
-    Subscribe { channel: String },
+    Subscribe {
+        channel: String,
+        #[serde(default)]
+        from: Option<u64>,
+    },
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -48,7 +52,7 @@ impl Handler {
 impl interceptor::Intercept for Handler {
     async fn inbound(&mut self, msg: HandlerInboundMessage) -> interceptor::Reply {
         match dbg!(serde_json::from_slice(&msg.data)) {
-            Ok(Request::Subscribe { channel: c }) => {
+            Ok(Request::Subscribe { channel: c, from }) => {
                 let (tx, mut rx) = channel(64);
                 let mut outbound = msg.outbound_channel;
                 let id = msg.id;
@@ -68,7 +72,11 @@ impl interceptor::Intercept for Handler {
                     }
                 });
                 self.pubsub
-                    .send(pubsub::Msg::Subscribe { channel: c, tx })
+                    .send(pubsub::Msg::Subscribe {
+                        channel: c,
+                        tx,
+                        from,
+                    })
                     .await
                     .unwrap();
 
@@ -17,20 +17,33 @@ use futures::channel::mpsc::{channel, Receiver, Sender};
 use futures::{SinkExt, StreamExt};
 use serde::Serialize;
 use slog::Logger;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use ws_proto::SubscriberMsg;
 
 pub type Channel = Sender<Msg>;
 
+/// Versioned messages kept per channel for subscribers that start from an
+/// earlier revision.
+const BACKLOG_SIZE: usize = 4096;
+
 pub enum Msg {
     Subscribe {
         channel: String,
         tx: Sender<SubscriberMsg>,
+        /// Replays the versioned messages from this revision on first
+        from: Option<u64>,
     },
     Msg {
         channel: String,
         msg: serde_json::Value,
     },
+    /// A message carrying the revision of the change it announces,
+    /// revisions of a channel have to increase by one.
+    Versioned {
+        channel: String,
+        revision: u64,
+        msg: serde_json::Value,
+    },
 }
 
 impl Msg {
@@ -42,29 +55,144 @@ impl Msg {
         let channel = channel.to_string();
         Self::Msg { channel, msg }
     }
+    pub fn versioned<T>(channel: &str, revision: u64, msg: T) -> Self
+    where
+        T: Serialize,
+    {
+        let msg = serde_json::to_value(&msg).unwrap();
+        let channel = channel.to_string();
+        Self::Versioned {
+            channel,
+            revision,
+            msg,
+        }
+    }
+}
+
+struct Subscriber {
+    tx: Sender<SubscriberMsg>,
+    /// Versioned messages before this revision are skipped
+    from: u64,
+}
+
+/// The most recent versioned messages of a channel.
+#[derive(Default)]
+struct Backlog {
+    msgs: VecDeque<(u64, serde_json::Value)>,
+    last: u64,
+}
+
+impl Backlog {
+    /// Returns false for revisions that were already seen, e.g. when the
+    /// log is replayed on startup.
+    fn push(&mut self, revision: u64, msg: &serde_json::Value) -> bool {
+        if revision <= self.last {
+            return false;
+        }
+        if revision != self.last + 1 {
+            // we missed revisions (e.g. a snapshot was installed), what we
+            // have can't be replayed without a gap
+            self.msgs.clear();
+        }
+        if self.msgs.len() == BACKLOG_SIZE {
+            self.msgs.pop_front();
+        }
+        self.msgs.push_back((revision, msg.clone()));
+        self.last = revision;
+        true
+    }
+
+    /// The oldest revision that can still be replayed.
+    fn first(&self) -> u64 {
+        self.msgs
+            .front()
+            .map(|(revision, _)| *revision)
+            .unwrap_or(self.last + 1)
+    }
+}
+
+async fn send(
+    subscriptions: &mut Vec<Subscriber>,
+    channel: &str,
+    revision: u64,
+    msg: &serde_json::Value,
+) {
+    let mut s1 = Vec::with_capacity(subscriptions.len());
+    for mut sub in subscriptions.drain(..) {
+        let channel = channel.to_string();
+        let msg = msg.clone();
+        if revision < sub.from
+            || sub
+                .tx
+                .send(SubscriberMsg::Msg { channel, msg })
+                .await
+                .is_ok()
+        {
+            s1.push(sub)
+        }
+    }
+    std::mem::swap(subscriptions, &mut s1);
 }
 
 async fn pubsub_loop(logger: Logger, mut rx: Receiver<Msg>) {
-    let mut subscriptions: HashMap<String, Vec<Sender<SubscriberMsg>>> = HashMap::new();
+    let mut subscriptions: HashMap<String, Vec<Subscriber>> = HashMap::new();
+    let mut backlogs: HashMap<String, Backlog> = HashMap::new();
     while let Some(msg) = rx.next().await {
         match msg {
-            Msg::Subscribe { channel, tx } => {
+            Msg::Subscribe {
+                channel,
+                mut tx,
+                from,
+            } => {
                 info!(logger, "Sub {}", channel);
+                let backlog = backlogs.entry(channel.clone()).or_default();
+                if let Some(from) = from {
+                    // Replaying and subscribing happen in one go, so the
+                    // subscriber neither misses nor repeats a message.
+                    if from < backlog.first() {
+                        let revision = backlog.first();
+                        let compacted = SubscriberMsg::Compacted {
+                            channel: channel.clone(),
+                            revision,
+                        };
+                        if tx.send(compacted).await.is_err() {
+                            continue;
+                        }
+                    }
+                    let mut ok = true;
+                    for (_, msg) in backlog.msgs.iter().filter(|(r, _)| *r >= from) {
+                        let channel = channel.clone();
+                        let msg = msg.clone();
+                        if tx.send(SubscriberMsg::Msg { channel, msg }).await.is_err() {
+                            ok = false;
+                            break;
+                        }
+                    }
+                    if !ok {
+                        continue;
+                    }
+                }
+                let from = from.unwrap_or_default().max(backlog.last + 1);
                 let subscriptions = subscriptions.entry(channel).or_default();
-                subscriptions.push(tx);
+                subscriptions.push(Subscriber { tx, from });
             }
             Msg::Msg { channel, msg } => {
                 info!(logger, "Msg: {} >> {}", channel, msg);
                 let subscriptions = subscriptions.entry(channel.clone()).or_default();
-                let mut s1 = Vec::with_capacity(subscriptions.len());
-                for mut tx in subscriptions.drain(..) {
-                    let channel = channel.clone();
-                    let msg = msg.clone();
-                    if tx.send(SubscriberMsg::Msg { channel, msg }).await.is_ok() {
-                        s1.push(tx)
-                    }
+                send(subscriptions, &channel, std::u64::MAX, &msg).await;
+            }
+            Msg::Versioned {
+                channel,
+                revision,
+                msg,
+            } => {
+                info!(logger, "Msg: {}@{} >> {}", channel, revision, msg);
+                let backlog = backlogs.entry(channel.clone()).or_default();
+                if !backlog.push(revision, &msg) {
+                    continue;
                 }
-                std::mem::swap(subscriptions, &mut s1);
+                let subscriptions = subscriptions.entry(channel.clone()).or_default();
+                send(subscriptions, &channel, revision, &msg).await;
             }
         }
     }
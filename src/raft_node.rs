@@ -17,22 +17,30 @@ use crate::network::ws::WsMessage;
 use crate::storage::*;
 use crate::version::VERSION;
 use async_std::sync::Mutex;
+use futures::channel::mpsc::Sender;
 use futures::SinkExt;
 use protobuf::Message as PBMessage;
-use raft::eraftpb::ConfState;
+use raft::eraftpb::{ConfChangeSingle, ConfChangeTransition, ConfChangeV2, ConfState};
 use raft::eraftpb::Message;
-use raft::{prelude::*, Error, Result, StateRole};
+use raft::{prelude::*, Error, Result, SnapshotStatus, StateRole, StorageError};
 use serde_derive::{Deserialize, Serialize};
 use slog::Logger;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
 use std::io::{Error as IoError, ErrorKind as IoErrorKind};
 
+// Reads waiting for a ReadIndex confirmation are answered with 503 after this
+const READ_INDEX_TIMEOUT: Duration = Duration::from_secs(5);
+// Leader transfers not observed within this are reported as failed
+const LEADER_TRANSFER_TIMEOUT: Duration = Duration::from_secs(5);
+
 fn example_config() -> Config {
     Config {
         election_tick: 10,
         heartbeat_tick: 3,
         pre_vote: true,
+        // lease reads rely on the leader stepping down once it lost its quorum
+        check_quorum: true,
         ..Default::default()
     }
 }
@@ -55,11 +63,35 @@ pub struct RaftNodeStatus {
     randomized_election_timeout: usize,
     term: u64,
     last_index: u64,
+    learner: bool,
+    voters: Vec<u64>,
+    learners: Vec<u64>,
+    // Non empty while in a joint configuration
+    voters_outgoing: Vec<u64>,
+    pending_conf: bool,
 }
 
 // unsafe impl Send for RaftNodeStatus {}
 // unsafe impl Sync for RaftNodeStatus {}
 
+/// A local read waiting for raft to confirm the commit index (ReadIndex)
+struct PendingRead {
+    eid: EventId,
+    sid: ServiceId,
+    data: Vec<u8>,
+    // The commit index raft confirmed for this read, once known
+    index: Option<u64>,
+    since: Instant,
+}
+
+/// A leader transfer waiting for the new leader to be observed
+struct PendingTransfer {
+    transferee: NodeId,
+    rid: RequestId,
+    reply: Sender<WsMessage>,
+    since: Instant,
+}
+
 pub struct RaftNode<Storage, Network>
 where
     Storage: storage::Storage,
@@ -69,12 +101,17 @@ where
     // None if the raft is not initialized.
     id: NodeId,
     pub raft_group: Option<Mutex<RawNode<Storage>>>,
+    storage_config: storage::Config,
     network: Network,
     proposals: VecDeque<Proposal>,
     pending_proposals: HashMap<ProposalId, Proposal>,
     pending_acks: HashMap<ProposalId, EventId>,
+    pending_reads: HashMap<Vec<u8>, PendingRead>,
+    pending_transfer: Option<PendingTransfer>,
     proposal_id: u64,
     tick_duration: Duration,
+    // Learners within this many entries of the leader's log get promoted
+    auto_promote: Option<u64>,
     services: HashMap<ServiceId, Box<dyn Service<Storage>>>,
     pub pubsub: pubsub::Channel,
     last_state: StateRole,
@@ -159,11 +196,18 @@ where
                             reply.send(WsMessage::Reply(200, ws_proto::Reply { code: 200, rid, data: serde_json::to_value(self.version()).unwrap() })).await.unwrap();
                         }
                         RaftNetworkMsg::Event(eid, sid, data) => {
-                            if let Some(mut service) = self.services.get_mut(&sid) {
+                            let local = self.services.get(&sid).map(|service| {
                                 if service.is_local(&data).unwrap() {
-                                    let (code, value) = service.execute(raft, &mut self.pubsub, data).await.unwrap();
-                                    self.network.event_reply(eid, code, value).await.unwrap();
+                                    Some(service.consistency(&data).unwrap())
                                 } else {
+                                    None
+                                }
+                            });
+                            match local {
+                                Some(Some(Consistency::Stale)) => self.execute_local(eid, sid, data).await,
+                                Some(Some(Consistency::Lease)) if self.is_leader() => self.execute_local(eid, sid, data).await,
+                                Some(Some(_)) => self.read_index(eid, sid, data),
+                                Some(None) => {
                                     let pid = self.next_pid();
                                     let from = self.id;
                                     if let Err(e) = self.propose_event(from, pid, sid, eid, data).await {
@@ -173,9 +217,10 @@ where
                                         self.pending_acks.insert(pid, eid);
                                     }
                                 }
-                            } else {
-                                error!(self.logger, "Unknown Service: {}", sid);
-                                self.network.event_reply(eid, 500, serde_json::to_vec(&format!("Service {} not known", sid)).unwrap()).await.unwrap();
+                                None => {
+                                    error!(self.logger, "Unknown Service: {}", sid);
+                                    self.network.event_reply(eid, 500, serde_json::to_vec(&format!("Service {} not known", sid)).unwrap()).await.unwrap();
+                                }
                             }
                         }
                         RaftNetworkMsg::GetNode(id, mut reply) => {
@@ -186,6 +231,27 @@ where
                             info!(self.logger, "Adding node"; "id" => id);
                             reply.send(self.add_node(id).await).await.unwrap();
                         }
+                        RaftNetworkMsg::AddLearner(id, mut reply) => {
+                            info!(self.logger, "Adding learner"; "id" => id);
+                            reply.send(self.add_learner(id).await).await.unwrap();
+                        }
+                        RaftNetworkMsg::PromoteNode(id, mut reply) => {
+                            info!(self.logger, "Promoting learner"; "id" => id);
+                            reply.send(self.promote_node(id).await).await.unwrap();
+                        }
+                        RaftNetworkMsg::RemoveNode(id, mut reply) => {
+                            info!(self.logger, "Removing node"; "id" => id);
+                            reply.send(self.remove_node(id).await).await.unwrap();
+                        }
+                        RaftNetworkMsg::TransferLeader(id, rid, reply) => {
+                            info!(self.logger, "Transferring leadership"; "id" => id);
+                            self.transfer_leader(id, rid, reply).await;
+                        }
+                        RaftNetworkMsg::ChangeMembership(add, remove, rid, mut reply) => {
+                            info!(self.logger, "Changing membership"; "add" => format!("{:?}", add), "remove" => format!("{:?}", remove));
+                            let (code, data) = self.change_membership(add, remove).await;
+                            reply.send(ws_proto::Reply { code, rid, data }.into()).await.unwrap();
+                        }
                         RaftNetworkMsg::AckProposal(pid, success) => {
                             info!(self.logger, "proposal acknowledged"; "pid" => pid);
                             if let Some(proposal) = self.pending_proposals.remove(&pid) {
@@ -209,6 +275,21 @@ where
                                 error!(self.logger, "step error"; "error" => format!("{}", e));
                             }
                         }
+                        RaftNetworkMsg::SnapshotChunk(from, chunk) => {
+                            let index = chunk.index;
+                            match self.write_snapshot_chunk(&chunk).await {
+                                Ok(offset) => {
+                                    if let Err(e) = self.network.ack_snapshot_chunk(from, index, offset).await {
+                                        warn!(self.logger, "snapshot chunk ack error"; "error" => format!("{}", e));
+                                    }
+                                }
+                                Err(e) => error!(self.logger, "snapshot chunk error"; "error" => format!("{}", e)),
+                            }
+                        }
+                        RaftNetworkMsg::ReportSnapshot(to, status) => {
+                            info!(self.logger, "snapshot reported"; "to" => to, "status" => format!("{:?}", status));
+                            raft.try_lock().unwrap().report_snapshot(to.0, status);
+                        }
                     }
                 },
                 tick = ticks.next().fuse() => {
@@ -238,11 +319,19 @@ where
                             .unwrap();
                         self.last_state = this_state;
                     }
+                    self.check_transfer().await;
+                    for to in self.network.resend_snapshot_chunks().await {
+                        let mut raft = self.raft_group.as_ref().unwrap().try_lock().unwrap();
+                        raft.report_snapshot(to.0, SnapshotStatus::Failure);
+                    }
                     self.raft_group.as_mut().unwrap().try_lock().unwrap().tick();
                     self.on_ready().await.unwrap();
+                    self.serve_reads().await;
                     if self.is_leader() {
+                        self.propose_service_events().await;
                         // Handle new proposals.
                         self.propose_all().await?;
+                        self.promote_learners().await;
                     }
                 }
             }
@@ -250,6 +339,81 @@ where
         Ok(())
     }
 
+    async fn execute_local(&mut self, eid: EventId, sid: ServiceId, data: Vec<u8>) {
+        if let Some(service) = self.services.get_mut(&sid) {
+            let (code, value) = service
+                .execute(self.raft_group.as_ref().unwrap(), &mut self.pubsub, data)
+                .await
+                .unwrap();
+            self.network.event_reply(eid, code, value).await.unwrap();
+        }
+    }
+
+    /// Requests a ReadIndex from raft, the read is answered by `serve_reads`
+    /// once the node has applied up to the confirmed commit index.
+    fn read_index(&mut self, eid: EventId, sid: ServiceId, data: Vec<u8>) {
+        // event ids are unique per node, and read states only ever get
+        // reported back to the node that requested them
+        let ctx = serde_json::to_vec(&eid).unwrap();
+        self.raft_group
+            .as_ref()
+            .unwrap()
+            .try_lock()
+            .unwrap()
+            .read_index(ctx.clone());
+        self.pending_reads.insert(
+            ctx,
+            PendingRead {
+                eid,
+                sid,
+                data,
+                index: None,
+                since: Instant::now(),
+            },
+        );
+    }
+
+    async fn serve_reads(&mut self) {
+        if self.pending_reads.is_empty() {
+            return;
+        }
+        let applied = self
+            .raft_group
+            .as_ref()
+            .unwrap()
+            .try_lock()
+            .unwrap()
+            .raft
+            .raft_log
+            .applied;
+        let done: Vec<Vec<u8>> = self
+            .pending_reads
+            .iter()
+            .filter(|(_, read)| match read.index {
+                Some(index) => index <= applied,
+                None => read.since.elapsed() >= READ_INDEX_TIMEOUT,
+            })
+            .map(|(ctx, _)| ctx.clone())
+            .collect();
+        for ctx in done {
+            if let Some(read) = self.pending_reads.remove(&ctx) {
+                if read.index.is_some() {
+                    self.execute_local(read.eid, read.sid, read.data).await;
+                } else {
+                    warn!(self.logger, "read index timed out"; "eid" => read.eid);
+                    self.network
+                        .event_reply(
+                            read.eid,
+                            503,
+                            serde_json::to_vec(&"read index timed out").unwrap(),
+                        )
+                        .await
+                        .unwrap();
+                }
+            }
+        }
+    }
+
     pub async fn propose_event(
         &mut self,
         from: NodeId,
@@ -288,6 +452,25 @@ where
         }
     }
 
+    /// Queues the events services propose on their own behalf.
+    async fn propose_service_events(&mut self) {
+        let raft = self.raft_group.as_ref().unwrap();
+        let mut events = Vec::new();
+        for (sid, service) in self.services.iter_mut() {
+            match service.leader_tick(raft).await {
+                Ok(data) => events.extend(data.into_iter().map(|data| (*sid, data))),
+                Err(e) => {
+                    error!(self.logger, "service tick error"; "sid" => *sid, "error" => format!("{}", e))
+                }
+            }
+        }
+        for (sid, data) in events {
+            let pid = self.next_pid();
+            self.proposals
+                .push_back(Proposal::internal(pid, self.id, sid, data));
+        }
+    }
+
     pub async fn add_node(&mut self, id: NodeId) -> bool {
         if self.is_leader() && !self.node_known(id).await {
             self.pubsub
@@ -313,6 +496,267 @@ where
         }
     }
 
+    pub async fn add_learner(&mut self, id: NodeId) -> bool {
+        if self.is_leader() && !self.node_known(id).await && !self.is_learner(id) {
+            self.pubsub
+                .send(pubsub::Msg::new(
+                    "uring",
+                    PSURing::AddLearner {
+                        new_node: id,
+                        node: self.id,
+                    },
+                ))
+                .await
+                .unwrap();
+            let mut conf_change = ConfChange::default();
+            conf_change.node_id = id.0;
+            conf_change.set_change_type(ConfChangeType::AddLearnerNode);
+            let pid = self.next_pid();
+            let proposal = Proposal::conf_change(pid, self.id, &conf_change);
+
+            self.proposals.push_back(proposal);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Promotes a learner to a voter.
+    pub async fn promote_node(&mut self, id: NodeId) -> bool {
+        if self.is_leader() && self.is_learner(id) {
+            self.pubsub
+                .send(pubsub::Msg::new(
+                    "uring",
+                    PSURing::PromoteNode {
+                        learner: id,
+                        node: self.id,
+                    },
+                ))
+                .await
+                .unwrap();
+            let mut conf_change = ConfChange::default();
+            conf_change.node_id = id.0;
+            conf_change.set_change_type(ConfChangeType::AddNode);
+            let pid = self.next_pid();
+            let proposal = Proposal::conf_change(pid, self.id, &conf_change);
+
+            self.proposals.push_back(proposal);
+            true
+        } else {
+            false
+        }
+    }
+
+    // Promotes learners that caught up to within `auto_promote` entries of
+    // the leaders log, one at a time since raft only allows a single pending
+    // conf change.
+    async fn promote_learners(&mut self) {
+        let max_lag = if let Some(max_lag) = self.auto_promote {
+            max_lag
+        } else {
+            return;
+        };
+        let candidate = {
+            let g = self.raft_group.as_ref().unwrap().try_lock().unwrap();
+            if g.raft.has_pending_conf() {
+                return;
+            }
+            let last_index = g.raft.raft_log.last_index();
+            let prs = g.raft.prs();
+            prs.conf()
+                .learners()
+                .iter()
+                .copied()
+                .find(|id| {
+                    prs.get(*id)
+                        .map(|pr| pr.matched + max_lag >= last_index)
+                        .unwrap_or_default()
+                })
+                .map(NodeId)
+        };
+        if let Some(id) = candidate {
+            info!(self.logger, "Learner caught up, promoting"; "id" => id);
+            self.promote_node(id).await;
+        }
+    }
+
+    pub async fn remove_node(&mut self, id: NodeId) -> bool {
+        if !self.is_leader() || !self.node_known(id).await {
+            return false;
+        }
+        let voters = self
+            .raft_group
+            .as_ref()
+            .unwrap()
+            .try_lock()
+            .unwrap()
+            .raft
+            .prs()
+            .conf()
+            .voters()
+            .ids()
+            .len();
+        if voters <= 1 {
+            warn!(self.logger, "Refusing to remove the last voter"; "id" => id);
+            return false;
+        }
+        self.pubsub
+            .send(pubsub::Msg::new(
+                "uring",
+                PSURing::RemoveNode {
+                    old_node: id,
+                    node: self.id,
+                },
+            ))
+            .await
+            .unwrap();
+        let mut conf_change = ConfChange::default();
+        conf_change.node_id = id.0;
+        conf_change.set_change_type(ConfChangeType::RemoveNode);
+        let pid = self.next_pid();
+        let proposal = Proposal::conf_change(pid, self.id, &conf_change);
+
+        self.proposals.push_back(proposal);
+        true
+    }
+
+    /// Adds and removes several voters at once through joint consensus,
+    /// raft leaves the joint configuration automatically once it applied.
+    pub async fn change_membership(
+        &mut self,
+        add: Vec<NodeId>,
+        remove: Vec<NodeId>,
+    ) -> (u16, serde_json::Value) {
+        if !self.is_leader() {
+            return (412, "not the leader".into());
+        }
+        if add.is_empty() && remove.is_empty() {
+            return (400, "no membership changes requested".into());
+        }
+        {
+            let g = self.raft_group.as_ref().unwrap().try_lock().unwrap();
+            let cs = g.raft.prs().conf().to_conf_state();
+            if g.raft.has_pending_conf() || !cs.voters_outgoing.is_empty() {
+                return (409, "membership change already in progress".into());
+            }
+            if let Some(id) = add.iter().find(|id| cs.voters.contains(&id.0)) {
+                return (400, format!("node {} is already a voter", id.0).into());
+            }
+            if let Some(id) = remove.iter().find(|id| !cs.voters.contains(&id.0)) {
+                return (400, format!("node {} is not a voter", id.0).into());
+            }
+            let remaining = cs
+                .voters
+                .iter()
+                .filter(|id| !remove.contains(&NodeId(**id)))
+                .count();
+            if remaining + add.len() == 0 {
+                return (400, "can't remove all voters".into());
+            }
+        }
+        self.pubsub
+            .send(pubsub::Msg::new(
+                "uring",
+                PSURing::MembershipChange {
+                    node: self.id,
+                    add: add.clone(),
+                    remove: remove.clone(),
+                },
+            ))
+            .await
+            .unwrap();
+        let changes = add
+            .iter()
+            .map(|id| (id, ConfChangeType::AddNode))
+            .chain(remove.iter().map(|id| (id, ConfChangeType::RemoveNode)))
+            .map(|(id, change_type)| {
+                let mut change = ConfChangeSingle::default();
+                change.node_id = id.0;
+                change.set_change_type(change_type);
+                change
+            })
+            .collect();
+        let mut conf_change = ConfChangeV2::default();
+        conf_change.set_transition(ConfChangeTransition::Auto);
+        conf_change.set_changes(protobuf::RepeatedField::from_vec(changes));
+        let pid = self.next_pid();
+        let proposal = Proposal::conf_change_v2(pid, self.id, &conf_change);
+        self.proposals.push_back(proposal);
+        (202, serde_json::json!({ "add": add, "remove": remove }))
+    }
+
+    pub async fn transfer_leader(
+        &mut self,
+        transferee: NodeId,
+        rid: RequestId,
+        mut reply: Sender<WsMessage>,
+    ) {
+        let (code, data) = if self.pending_transfer.is_some() {
+            (409, "leader transfer already in progress".into())
+        } else if !self.node_known(transferee).await {
+            (404, "node not known".into())
+        } else if self.leader() == transferee {
+            (200, serde_json::to_value(transferee).unwrap())
+        } else {
+            let pid = self.next_pid();
+            let mut proposal = Proposal::transfer_leader(pid, self.id, transferee);
+            let mut raft_group = self.raft_group.as_ref().unwrap().try_lock().unwrap();
+            if let Err(e) = propose_and_check_failed_proposal(&mut *raft_group, &mut proposal) {
+                (500, format!("{}", e).into())
+            } else {
+                // The result is reported by `check_transfer` once the new
+                // leader is observed or the transfer timed out.
+                self.pending_transfer = Some(PendingTransfer {
+                    transferee,
+                    rid,
+                    reply,
+                    since: Instant::now(),
+                });
+                return;
+            }
+        };
+        reply
+            .send(ws_proto::Reply { code, rid, data }.into())
+            .await
+            .unwrap();
+    }
+
+    async fn check_transfer(&mut self) {
+        let success = match self.pending_transfer {
+            Some(ref t) if self.leader() == t.transferee => true,
+            Some(ref t) if t.since.elapsed() >= LEADER_TRANSFER_TIMEOUT => false,
+            _ => return,
+        };
+        if let Some(PendingTransfer {
+            transferee,
+            rid,
+            mut reply,
+            ..
+        }) = self.pending_transfer.take()
+        {
+            self.pubsub
+                .send(pubsub::Msg::new(
+                    "uring",
+                    PSURing::LeaderTransfer {
+                        node: self.id,
+                        transferee,
+                        success,
+                    },
+                ))
+                .await
+                .unwrap();
+            let (code, data) = if success {
+                (200, serde_json::to_value(transferee).unwrap())
+            } else {
+                (504, "leader transfer timed out".into())
+            };
+            reply
+                .send(ws_proto::Reply { code, rid, data }.into())
+                .await
+                .unwrap();
+        }
+    }
+
     pub fn next_pid(&mut self) -> ProposalId {
         let pid = self.proposal_id;
         self.proposal_id += 1;
@@ -336,6 +780,19 @@ where
             false
         }
     }
+    pub fn is_learner(&self, id: NodeId) -> bool {
+        if let Some(ref g) = self.raft_group {
+            g.try_lock()
+                .unwrap()
+                .raft
+                .prs()
+                .conf()
+                .learners()
+                .contains(&id.0)
+        } else {
+            false
+        }
+    }
     pub async fn log(&self) {
         if let Some(g) = self.raft_group.as_ref() {
             let role = self.role();
@@ -356,6 +813,7 @@ where
                 "votes" => format!("{:?}", raft.prs().votes()),
 
                 "voters" => format!("{:?}", raft.prs().conf().voters()),
+                "learners" => format!("{:?}", raft.prs().conf().learners()),
 
                 "promotable" => raft.promotable(),
                 "pass-election-timeout" => raft.pass_election_timeout(),
@@ -398,43 +856,59 @@ where
     pub async fn create_raft_leader(
         logger: &Logger,
         id: NodeId,
+        storage_config: storage::Config,
         pubsub: pubsub::Channel,
         network: Network,
-    ) -> Self {
+    ) -> std::result::Result<Self, storage::Error> {
         let mut cfg = example_config();
         cfg.id = id.0;
 
-        let storage = Storage::new_with_conf_state(id, ConfState::from((vec![id.0], vec![]))).await;
+        let storage = Storage::new_with_conf_state(
+            id,
+            &storage_config,
+            ConfState::from((vec![id.0], vec![])),
+        )
+        .await?;
+        cfg.applied = storage.applied().await;
         let raft_group = Some(Mutex::new(RawNode::new(&cfg, storage, logger).unwrap()));
-        Self {
+        Ok(Self {
             logger: logger.clone(),
             id,
             raft_group,
+            storage_config,
             proposals: VecDeque::new(),
             network,
             pending_proposals: HashMap::new(),
             pending_acks: HashMap::new(),
+            pending_reads: HashMap::new(),
+            pending_transfer: None,
             proposal_id: 0,
             tick_duration: Duration::from_millis(100),
+            auto_promote: None,
             services: HashMap::new(),
             pubsub,
             last_state: StateRole::PreCandidate,
-        }
+        })
     }
 
     pub fn set_raft_tick_duration(&mut self, d: Duration) {
         self.tick_duration = d;
     }
 
+    pub fn set_auto_promote(&mut self, max_lag: Option<u64>) {
+        self.auto_promote = max_lag;
+    }
+
     // Create a raft follower.
     pub async fn create_raft_follower(
         logger: &Logger,
         id: NodeId,
+        storage_config: storage::Config,
         pubsub: pubsub::Channel,
         network: Network,
-    ) -> Self {
-        let storage = Storage::new(id).await;
-        Self {
+    ) -> std::result::Result<Self, storage::Error> {
+        let storage = Storage::new(id, &storage_config).await?;
+        Ok(Self {
             logger: logger.clone(),
             id,
             raft_group: if storage.last_index().unwrap() == 1 {
@@ -442,18 +916,23 @@ where
             } else {
                 let mut cfg = example_config();
                 cfg.id = id.0;
+                cfg.applied = storage.applied().await;
                 Some(Mutex::new(RawNode::new(&cfg, storage, logger).unwrap()))
             },
+            storage_config,
             proposals: VecDeque::new(),
             network,
             pending_proposals: HashMap::new(),
             pending_acks: HashMap::new(),
+            pending_reads: HashMap::new(),
+            pending_transfer: None,
             proposal_id: 0,
             tick_duration: Duration::from_millis(100),
+            auto_promote: None,
             services: HashMap::new(),
             pubsub,
             last_state: StateRole::PreCandidate,
-        }
+        })
     }
 
     // Initialize raft for followers.
@@ -463,7 +942,14 @@ where
         }
         let mut cfg = example_config();
         cfg.id = msg.to;
-        let storage = Storage::new(self.id).await;
+        let storage = match Storage::new(self.id, &self.storage_config).await {
+            Ok(storage) => storage,
+            Err(e) => {
+                error!(self.logger, "failed to open storage"; "error" => format!("{}", e));
+                return;
+            }
+        };
+        cfg.applied = storage.applied().await;
         self.raft_group = Some(Mutex::new(
             RawNode::new(&cfg, storage, &self.logger).unwrap(),
         ));
@@ -479,8 +965,32 @@ where
             }
         }
         let mut raft_group = self.raft_group.as_mut().unwrap().try_lock().unwrap();
+        if msg.get_msg_type() == MessageType::MsgSnapshot {
+            // The data is streamed in chunks ahead of the message, raft only
+            // gets to see the snapshot once all of it arrived.
+            let meta = msg.get_snapshot().get_metadata();
+            if !raft_group
+                .store()
+                .snapshot_received(meta.index, meta.term)
+                .await
+            {
+                warn!(self.logger, "dropping incomplete snapshot"; "index" => meta.index, "term" => meta.term);
+                return Ok(());
+            }
+        }
         raft_group.step(msg)
     }
+
+    async fn write_snapshot_chunk(&mut self, chunk: &SnapshotChunk) -> Result<u64> {
+        // Chunks can arrive before the first message initialized the group,
+        // they aren't acked so the leader resends them once they timed out.
+        let group = self
+            .raft_group
+            .as_ref()
+            .ok_or(Error::Store(StorageError::SnapshotTemporarilyUnavailable))?;
+        let mut raft_node = group.try_lock().unwrap();
+        raft_node.mut_store().write_snapshot_chunk(chunk).await
+    }
     async fn append(&self, entries: &[Entry]) -> Result<()> {
         let raft_node = self.raft_group.as_ref().unwrap().try_lock().unwrap();
         raft_node.store().append(entries).await
@@ -497,9 +1007,37 @@ where
     }
 
     // interface for raft-rs
-    async fn set_hard_state(&mut self, commit: u64, term: u64) -> Result<()> {
+    async fn set_hard_state(&mut self, hs: HardState) -> Result<()> {
         let mut raft_node = self.raft_group.as_ref().unwrap().try_lock().unwrap();
-        raft_node.mut_store().set_hard_state(commit, term).await
+        raft_node.mut_store().set_hard_state(hs).await
+    }
+
+    // Records the applied index and compacts the log behind it.
+    async fn set_applied(&mut self, applied: u64) -> Result<()> {
+        let mut raft_node = self.raft_group.as_ref().unwrap().try_lock().unwrap();
+        let store = raft_node.mut_store();
+        store.set_applied(applied).await?;
+        store.compact(applied).await
+    }
+
+    async fn apply_conf_change_v2(&mut self, data: &[u8]) -> Result<()> {
+        let mut cc = ConfChangeV2::default();
+        cc.merge_from_bytes(data).unwrap();
+        let (before, cs) = {
+            let mut raft_node = self.raft_group.as_ref().unwrap().try_lock().unwrap();
+            let before = raft_node.raft.prs().conf().to_conf_state();
+            (before, raft_node.apply_conf_change(&cc)?)
+        };
+        // Removed voters stay part of the outgoing configuration until the
+        // joint state is left, so peers are only dropped once they are gone
+        // from the configuration entirely.
+        let after = members(&cs);
+        for id in members(&before).difference(&after) {
+            if *id != self.id.0 {
+                self.network.remove_peer(NodeId(*id));
+            }
+        }
+        self.set_conf_state(cs).await
     }
 
     pub(crate) async fn on_ready(&mut self) -> Result<()> {
@@ -527,6 +1065,15 @@ where
             .unwrap()
             .ready();
 
+        // Apply the snapshot. It's necessary because in `RawNode::advance` we stabilize the snapshot.
+        if *ready.snapshot() != Snapshot::default() {
+            let s = ready.snapshot().clone();
+            if let Err(e) = self.apply_snapshot(s).await {
+                println!("apply snapshot fail: {:?}, need to retry or panic", e);
+                return Err(e);
+            }
+        }
+
         // Persistent raft logs. It's necessary because in `RawNode::advance` we stabilize
         // raft logs to the latest position.
         if let Err(e) = self.append(ready.entries()).await {
@@ -534,12 +1081,17 @@ where
             return Err(e);
         }
 
-        // Apply the snapshot. It's necessary because in `RawNode::advance` we stabilize the snapshot.
-        if *ready.snapshot() != Snapshot::default() {
-            let s = ready.snapshot().clone();
-            if let Err(e) = self.apply_snapshot(s).await {
-                println!("apply snapshot fail: {:?}, need to retry or panic", e);
-                return Err(e);
+        // Persist term, vote and commit so they survive a restart.
+        if let Some(hs) = ready.hs() {
+            let hs = hs.clone();
+            self.set_hard_state(hs).await?;
+        }
+
+        // Remember the commit index confirmed for pending reads, they can be
+        // served once it has been applied.
+        for rs in ready.read_states() {
+            if let Some(read) = self.pending_reads.get_mut(&rs.request_ctx) {
+                read.index = Some(rs.index);
             }
         }
 
@@ -552,6 +1104,10 @@ where
         if let Some(committed_entries) = ready.committed_entries.take() {
             for entry in &committed_entries {
                 if entry.data.is_empty() {
+                    if entry.get_entry_type() == EntryType::EntryConfChangeV2 {
+                        // Leaving a joint configuration, proposed by raft itself.
+                        self.apply_conf_change_v2(&entry.data).await?;
+                    }
                     // From new elected leaders.
                     continue;
                 }
@@ -559,7 +1115,7 @@ where
                     // For conf change messages, make them effective.
                     let mut cc = ConfChange::default();
                     cc.merge_from_bytes(&entry.data).unwrap();
-                    let _node_id = cc.node_id;
+                    let node_id = NodeId(cc.node_id);
 
                     let cs: ConfState = self
                         .raft_group
@@ -570,6 +1126,11 @@ where
                         .apply_conf_change(&cc)
                         .unwrap();
                     self.set_conf_state(cs).await?;
+                    if cc.get_change_type() == ConfChangeType::RemoveNode && node_id != self.id {
+                        self.network.remove_peer(node_id);
+                    }
+                } else if let EntryType::EntryConfChangeV2 = entry.get_entry_type() {
+                    self.apply_conf_change_v2(&entry.data).await?;
                 } else {
                     // For normal proposals, extract the key-value pair and then
                     // insert them into the kv engine.
@@ -584,6 +1145,7 @@ where
                             //     .raft
                             //     .raft_log
                             //     .store;
+                            service.applying(entry.index);
                             let (code, value) = service
                                 .execute(
                                     self.raft_group.as_ref().unwrap(),
@@ -633,8 +1195,7 @@ where
                 }
             }
             if let Some(last_committed) = committed_entries.last() {
-                self.set_hard_state(last_committed.index, last_committed.term)
-                    .await?;
+                self.set_applied(last_committed.index).await?;
             }
         }
         // Call `RawNode::advance` interface to update position flags in the raft.
@@ -682,6 +1243,13 @@ where
     Storage: storage::Storage,
 {
     let node = node.try_lock().unwrap();
+    let conf = node.raft.prs().conf();
+    let mut voters: Vec<u64> = conf.voters().ids().iter().collect();
+    voters.sort();
+    let mut learners: Vec<u64> = conf.learners().iter().copied().collect();
+    learners.sort();
+    let mut voters_outgoing = conf.to_conf_state().voters_outgoing;
+    voters_outgoing.sort();
     Ok(RaftNodeStatus {
         id: node.raft.id,
         role: format!("{:?}", node.raft.state),
@@ -691,9 +1259,24 @@ where
         randomized_election_timeout: node.raft.randomized_election_timeout(),
         term: node.raft.term,
         last_index: node.raft.raft_log.store.last_index().unwrap_or(0),
+        learner: learners.contains(&node.raft.id),
+        voters,
+        learners,
+        voters_outgoing,
+        pending_conf: node.raft.has_pending_conf(),
     })
 }
 
+fn members(cs: &ConfState) -> HashSet<u64> {
+    cs.voters
+        .iter()
+        .chain(cs.voters_outgoing.iter())
+        .chain(cs.learners.iter())
+        .chain(cs.learners_next.iter())
+        .copied()
+        .collect()
+}
+
 pub(crate) fn propose_and_check_failed_proposal<Storage>(
     raft_group: &mut RawNode<Storage>,
     proposal: &mut Proposal,
@@ -707,10 +1290,14 @@ where
         raft_group.propose(vec![], data)?;
     } else if let Some(ref cc) = proposal.conf_change {
         raft_group.propose_conf_change(vec![], cc.clone())?;
-    } else if let Some(_transferee) = proposal.transfer_leader {
-        // TODO 0.8
-        // TODO: implement transfer leader.
-        unimplemented!();
+    } else if let Some(ref cc) = proposal.conf_change_v2 {
+        raft_group.propose_conf_change(vec![], cc.clone())?;
+    } else if let Some(transferee) = proposal.transfer_leader {
+        // A leader transfer doesn't append to the log, its outcome is
+        // observed through the following state change.
+        raft_group.transfer_leader(transferee);
+        proposal.proposed = last_index1;
+        return Ok(false);
     } else {
     }
 
@@ -729,6 +1316,7 @@ pub struct Proposal {
     proposer: NodeId, // node id of the proposer
     normal: Option<Event>,
     conf_change: Option<ConfChange>, // conf change.
+    conf_change_v2: Option<ConfChangeV2>, // joint consensus conf change.
     transfer_leader: Option<u64>,
     // If it's proposed, it will be set to the index of the entry.
     pub(crate) proposed: u64,
@@ -741,6 +1329,46 @@ impl Proposal {
             proposer,
             normal: None,
             conf_change: Some(cc.clone()),
+            conf_change_v2: None,
+            transfer_leader: None,
+            proposed: 0,
+        }
+    }
+    pub fn conf_change_v2(id: ProposalId, proposer: NodeId, cc: &ConfChangeV2) -> Self {
+        Self {
+            id,
+            proposer,
+            normal: None,
+            conf_change: None,
+            conf_change_v2: Some(cc.clone()),
+            transfer_leader: None,
+            proposed: 0,
+        }
+    }
+    pub fn transfer_leader(id: ProposalId, proposer: NodeId, transferee: NodeId) -> Self {
+        Self {
+            id,
+            proposer,
+            normal: None,
+            conf_change: None,
+            conf_change_v2: None,
+            transfer_leader: Some(transferee.0),
+            proposed: 0,
+        }
+    }
+    /// A proposal a service makes on its own, nobody waits for its result.
+    pub fn internal(id: ProposalId, proposer: NodeId, sid: ServiceId, data: Vec<u8>) -> Self {
+        Self {
+            id,
+            proposer,
+            normal: Some(Event {
+                nid: None,
+                eid: EventId(0),
+                sid,
+                data,
+            }),
+            conf_change: None,
+            conf_change_v2: None,
             transfer_leader: None,
             proposed: 0,
         }
@@ -763,6 +1391,7 @@ impl Proposal {
                 data,
             }),
             conf_change: None,
+            conf_change_v2: None,
             transfer_leader: None,
             proposed: 0,
         }
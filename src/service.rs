@@ -13,10 +13,11 @@
 // limitations under the License.
 
 pub mod kv;
+pub mod lock;
 pub mod mring;
 pub mod status;
 pub mod version;
-use crate::{pubsub, storage};
+use crate::{pubsub, storage, Consistency};
 
 use async_std::sync::Mutex;
 use async_trait::async_trait;
@@ -49,4 +50,20 @@ where
         event: Vec<u8>,
     ) -> Result<(u16, Vec<u8>), Error>;
     fn is_local(&self, event: &[u8]) -> Result<bool, Error>;
+    /// Consistency a local event has to be answered with, only consulted
+    /// when `is_local` returns true.
+    fn consistency(&self, _event: &[u8]) -> Result<Consistency, Error> {
+        Ok(Consistency::Stale)
+    }
+    /// Called with the raft index of a committed event right before it is
+    /// executed, the index is the same on every node.
+    fn applying(&mut self, _index: u64) {}
+    /// Called on the leader with every raft tick, returns events the service
+    /// wants to propose on its own, e.g. for timeouts.
+    async fn leader_tick(
+        &mut self,
+        _node: &Mutex<RawNode<Storage>>,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        Ok(Vec::new())
+    }
 }
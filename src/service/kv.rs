@@ -12,17 +12,33 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod encoding;
+pub(crate) mod lease;
+pub(crate) mod namespace;
+mod revision;
 use super::*;
-use crate::{pubsub, storage, ServiceId};
+use crate::storage::{BatchOp, BatchResult};
+use crate::{pubsub, storage, Consistency, Encoding, ServiceId};
 use async_std::sync::Mutex;
 use async_trait::async_trait;
+pub use encoding::decode;
+use encoding::{encode, encode_key, encode_opt, event_encoding, event_value};
 use futures::SinkExt;
+use lease::Leases;
+use namespace::{Namespace, Usage};
 use raft::RawNode;
+use revision::{Change, Revisions};
 use serde_derive::{Deserialize, Serialize};
 use slog::Logger;
+use std::collections::HashMap;
+use ws_proto::Quota;
 
 pub const ID: ServiceId = ServiceId(0);
 
+/// Entries returned by a scan unless the client asks for a different limit.
+const DEFAULT_SCAN_LIMIT: usize = 100;
+const MAX_SCAN_LIMIT: usize = 1000;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) enum PSEvent {
     Put {
@@ -30,35 +46,151 @@ pub(crate) enum PSEvent {
         key: String,
         new: String,
         old: Option<String>,
+        encoding: Encoding,
+        revision: u64,
     },
     Cas {
         scope: u16,
         key: String,
         new: String,
         old: Option<String>,
+        encoding: Encoding,
+        revision: u64,
     },
     CasConflict {
         scope: u16,
         key: String,
         new: String,
         conflict: Option<String>,
+        encoding: Encoding,
     },
     Delete {
         scope: u16,
         key: String,
         old: Option<String>,
+        encoding: Encoding,
+        revision: u64,
+    },
+    TxnConflict {
+        scope: u16,
+        conflicts: Vec<TxnConflict>,
+        encoding: Encoding,
+    },
+    Add {
+        scope: u16,
+        key: String,
+        delta: i64,
+        new: i64,
+        old: Option<i64>,
+        revision: u64,
     },
 }
+
+impl PSEvent {
+    fn set_revision(&mut self, to: u64) {
+        match self {
+            PSEvent::Put { revision, .. }
+            | PSEvent::Cas { revision, .. }
+            | PSEvent::Delete { revision, .. }
+            | PSEvent::Add { revision, .. } => *revision = to,
+            PSEvent::CasConflict { .. } | PSEvent::TxnConflict { .. } => (),
+        }
+    }
+}
+
+/// A compare of a transaction that did not hold.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct TxnConflict {
+    /// Position of the compare in the transaction
+    op: usize,
+    key: String,
+    value: Option<serde_json::Value>,
+}
+
+/// The kv service, it serves the default namespace along with the ones
+/// created at runtime, each in a scope of its own.
 pub struct Service {
+    logger: Logger,
+    /// Namespaces by name, `None` is the default namespace
+    spaces: HashMap<Option<String>, Space>,
+    /// Whether all namespaces were loaded from storage, otherwise they are
+    /// loaded on first use.
+    loaded: bool,
+}
+
+/// The state of a single namespace.
+struct Space {
+    name: Option<String>,
     scope: u16,
+    channel: String,
+    /// Only created namespaces have a quota and track their usage
+    quota: Option<Quota>,
     logger: Logger,
+    leases: Leases,
+    revisions: Revisions,
+}
+
+impl Space {
+    fn new(logger: &Logger, name: Option<String>, scope: u16, quota: Option<Quota>) -> Self {
+        let channel = match &name {
+            Some(name) => format!("kv/{}", name),
+            None => "kv".to_string(),
+        };
+        Self {
+            name,
+            scope,
+            channel,
+            quota,
+            logger: logger.clone(),
+            leases: Leases::new(scope),
+            revisions: Revisions::new(scope),
+        }
+    }
 }
 
 impl Service {
     pub fn new(logger: &Logger, scope: u16) -> Self {
+        let mut spaces = HashMap::new();
+        spaces.insert(None, Space::new(logger, None, scope, None));
         Self {
-            scope,
             logger: logger.clone(),
+            spaces,
+            loaded: false,
+        }
+    }
+
+    fn add(&mut self, namespace: Namespace) -> &mut Space {
+        let Namespace { name, scope, quota } = namespace;
+        let space = Space::new(&self.logger, Some(name.clone()), scope, Some(quota));
+        self.spaces.entry(Some(name)).or_insert(space)
+    }
+
+    /// The namespace called `name`, loaded from storage if it isn't known
+    /// yet.
+    async fn space<Storage>(
+        &mut self,
+        storage: &Storage,
+        name: &Option<String>,
+    ) -> Option<&mut Space>
+    where
+        Storage: storage::Storage + Send + Sync,
+    {
+        if !self.spaces.contains_key(name) {
+            let namespace = namespace::get(storage, name.as_deref()?).await?;
+            self.add(namespace);
+        }
+        self.spaces.get_mut(name)
+    }
+
+    async fn load_all<Storage>(&mut self, storage: &Storage)
+    where
+        Storage: storage::Storage + Send + Sync,
+    {
+        if !self.loaded {
+            for namespace in namespace::list(storage).await {
+                self.add(namespace);
+            }
+            self.loaded = true;
         }
     }
 }
@@ -67,180 +199,1256 @@ impl Service {
 pub enum Event {
     Get {
         key: Vec<u8>,
+        #[serde(default)]
+        consistency: Consistency,
+        /// Encoding of the values in the reply
+        #[serde(default)]
+        encoding: Encoding,
     },
     Put {
         key: Vec<u8>,
         value: Vec<u8>,
+        /// Lease the key is deleted with
+        #[serde(default)]
+        lease: Option<u64>,
+        #[serde(default)]
+        encoding: Encoding,
     },
     Cas {
         key: Vec<u8>,
         check_value: Option<Vec<u8>>,
         store_value: Vec<u8>,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    Delete {
+        key: Vec<u8>,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    Txn {
+        ops: Vec<TxnOp>,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    Scan {
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+        reverse: bool,
+        token: Option<String>,
+        #[serde(default)]
+        consistency: Consistency,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    Prefix {
+        prefix: Vec<u8>,
+        limit: usize,
+        token: Option<String>,
+        #[serde(default)]
+        consistency: Consistency,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    Grant {
+        ttl: u64,
+    },
+    KeepAlive {
+        lease: u64,
+    },
+    Revoke {
+        lease: u64,
+    },
+    /// Proposed by the leader once a lease ran out without a keepalive.
+    Expire {
+        lease: u64,
+        epoch: u64,
+    },
+    History {
+        key: Vec<u8>,
+        #[serde(default)]
+        consistency: Consistency,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    /// Adds `delta` to the integer stored at `key`, absent keys count as 0.
+    /// Nothing is written if the result would leave the bounds.
+    Add {
+        key: Vec<u8>,
+        delta: i64,
+        #[serde(default)]
+        min: Option<i64>,
+        #[serde(default)]
+        max: Option<i64>,
+    },
+    CreateNamespace {
+        quota: Quota,
+    },
+    /// A struct variant as unit variants can't be flattened into a `Request`
+    Namespaces {},
+}
+
+/// An event along with the namespace it applies to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Request {
+    /// `None` for the default namespace
+    #[serde(default)]
+    ns: Option<String>,
+    #[serde(flatten)]
+    event: Event,
+}
+
+/// A namespace as it is listed, along with its usage.
+#[derive(Serialize, Debug)]
+struct NamespaceInfo {
+    name: String,
+    scope: u16,
+    quota: Quota,
+    usage: Usage,
+}
+
+/// A previous version of a key, `value` is `None` if it was deleted.
+#[derive(Serialize, Debug)]
+struct Version {
+    revision: u64,
+    value: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Debug)]
+struct ScanEntry {
+    key: String,
+    value: serde_json::Value,
+}
+
+/// Which page of a scan is returned and how.
+struct Page {
+    limit: usize,
+    token: Option<String>,
+    encoding: Encoding,
+}
+
+/// Counters are stored as decimal strings so they read like any other value.
+fn parse_counter(value: &[u8]) -> Option<i64> {
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+fn quota_exceeded() -> (u16, Vec<u8>) {
+    (507, serde_json::to_vec(&"quota exceeded").unwrap())
+}
+
+/// The reply for stored values the client's encoding can't represent.
+fn not_acceptable(encoding: Encoding) -> (u16, Vec<u8>) {
+    let msg = format!("value can't be represented with {:?} encoding", encoding);
+    (406, serde_json::to_vec(&msg).unwrap())
+}
+
+/// A page of scan results, `next` is the token for the following page if
+/// there are more entries.
+#[derive(Serialize, Debug)]
+struct ScanPage {
+    entries: Vec<ScanEntry>,
+    next: Option<String>,
+}
+
+/// The smallest key sorting after every key starting with `prefix`, `None`
+/// if no such key exists.
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < std::u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// An operation of a transaction, the transaction is only committed when all
+/// of its compares hold.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TxnOp {
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
     },
     Delete {
         key: Vec<u8>,
     },
+    Compare {
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+    },
+}
+
+impl TxnOp {
+    fn key(&self) -> &[u8] {
+        match self {
+            TxnOp::Put { key, .. } | TxnOp::Delete { key } | TxnOp::Compare { key, .. } => key,
+        }
+    }
+    fn into_batch_op(self, scope: u16) -> storage::BatchOp {
+        match self {
+            TxnOp::Put { key, value } => storage::BatchOp::Put { scope, key, value },
+            TxnOp::Delete { key } => storage::BatchOp::Delete { scope, key },
+            TxnOp::Compare { key, value } => storage::BatchOp::Compare { scope, key, value },
+        }
+    }
+}
+
+impl TxnOp {
+    /// Converts an op sent by a client, decoding its value with `encoding`.
+    pub fn decode(op: ws_proto::TxnOp, encoding: Encoding) -> Result<Self, String> {
+        Ok(match op {
+            ws_proto::TxnOp::Put { key, store } => TxnOp::Put {
+                key: key.into_bytes(),
+                value: decode(encoding, store)?,
+            },
+            ws_proto::TxnOp::Delete { key } => TxnOp::Delete {
+                key: key.into_bytes(),
+            },
+            ws_proto::TxnOp::Compare { key, check } => TxnOp::Compare {
+                key: key.into_bytes(),
+                value: check.map(|v| decode(encoding, v)).transpose()?,
+            },
+        })
+    }
 }
 
 impl Event {
-    pub fn get(key: Vec<u8>) -> Vec<u8> {
-        serde_json::to_vec(&Event::Get { key }).unwrap()
+    fn into_request(self, ns: Option<String>) -> Vec<u8> {
+        serde_json::to_vec(&Request { ns, event: self }).unwrap()
     }
-    pub fn put(key: Vec<u8>, value: Vec<u8>) -> Vec<u8> {
-        serde_json::to_vec(&Event::Put { key, value }).unwrap()
+    pub fn get(
+        ns: Option<String>,
+        key: Vec<u8>,
+        consistency: Consistency,
+        encoding: Encoding,
+    ) -> Vec<u8> {
+        Event::Get {
+            key,
+            consistency,
+            encoding,
+        }
+        .into_request(ns)
     }
-    pub fn cas(key: Vec<u8>, check_value: Option<Vec<u8>>, store_value: Vec<u8>) -> Vec<u8> {
-        serde_json::to_vec(&Event::Cas {
+    pub fn put(
+        ns: Option<String>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        lease: Option<u64>,
+        encoding: Encoding,
+    ) -> Vec<u8> {
+        Event::Put {
+            key,
+            value,
+            lease,
+            encoding,
+        }
+        .into_request(ns)
+    }
+    pub fn cas(
+        ns: Option<String>,
+        key: Vec<u8>,
+        check_value: Option<Vec<u8>>,
+        store_value: Vec<u8>,
+        encoding: Encoding,
+    ) -> Vec<u8> {
+        Event::Cas {
             key,
             check_value,
             store_value,
-        })
-        .unwrap()
+            encoding,
+        }
+        .into_request(ns)
+    }
+    pub fn delete(ns: Option<String>, key: Vec<u8>, encoding: Encoding) -> Vec<u8> {
+        Event::Delete { key, encoding }.into_request(ns)
+    }
+    pub fn txn(ns: Option<String>, ops: Vec<TxnOp>, encoding: Encoding) -> Vec<u8> {
+        Event::Txn { ops, encoding }.into_request(ns)
+    }
+    pub fn scan(scan: ws_proto::Scan) -> Vec<u8> {
+        let limit = scan
+            .limit
+            .unwrap_or(DEFAULT_SCAN_LIMIT)
+            .max(1)
+            .min(MAX_SCAN_LIMIT);
+        let event = if let Some(prefix) = scan.prefix {
+            Event::Prefix {
+                prefix: prefix.into_bytes(),
+                limit,
+                token: scan.token,
+                consistency: scan.consistency,
+                encoding: scan.encoding,
+            }
+        } else {
+            Event::Scan {
+                start: scan.start.map(String::into_bytes).unwrap_or_default(),
+                end: scan.end.map(String::into_bytes),
+                limit,
+                reverse: scan.reverse,
+                token: scan.token,
+                consistency: scan.consistency,
+                encoding: scan.encoding,
+            }
+        };
+        event.into_request(scan.ns)
+    }
+    pub fn grant(ns: Option<String>, ttl: u64) -> Vec<u8> {
+        Event::Grant { ttl }.into_request(ns)
+    }
+    pub fn keep_alive(ns: Option<String>, lease: u64) -> Vec<u8> {
+        Event::KeepAlive { lease }.into_request(ns)
+    }
+    pub fn revoke(ns: Option<String>, lease: u64) -> Vec<u8> {
+        Event::Revoke { lease }.into_request(ns)
+    }
+    pub fn history(
+        ns: Option<String>,
+        key: Vec<u8>,
+        consistency: Consistency,
+        encoding: Encoding,
+    ) -> Vec<u8> {
+        Event::History {
+            key,
+            consistency,
+            encoding,
+        }
+        .into_request(ns)
+    }
+    pub fn add(
+        ns: Option<String>,
+        key: Vec<u8>,
+        delta: i64,
+        min: Option<i64>,
+        max: Option<i64>,
+    ) -> Vec<u8> {
+        Event::Add {
+            key,
+            delta,
+            min,
+            max,
+        }
+        .into_request(ns)
     }
-    pub fn delete(key: Vec<u8>) -> Vec<u8> {
-        serde_json::to_vec(&Event::Delete { key }).unwrap()
+    pub fn create_namespace(ns: String, quota: Quota) -> Vec<u8> {
+        Event::CreateNamespace { quota }.into_request(Some(ns))
+    }
+    pub fn namespaces() -> Vec<u8> {
+        Event::Namespaces {}.into_request(None)
+    }
+    fn expire(ns: Option<String>, lease: u64, epoch: u64) -> Vec<u8> {
+        Event::Expire { lease, epoch }.into_request(ns)
     }
 }
 
-#[async_trait]
-impl<Storage> super::Service<Storage> for Service
-where
-    Storage: storage::Storage + Send + Sync + 'static,
-{
-    async fn execute(
+impl Space {
+    async fn scan<Storage>(
+        &self,
+        storage: &Storage,
+        mut start: Vec<u8>,
+        mut end: Option<Vec<u8>>,
+        reverse: bool,
+        page: Page,
+    ) -> Result<(u16, Vec<u8>), Error>
+    where
+        Storage: storage::Storage + Send + Sync,
+    {
+        let Page {
+            limit,
+            token,
+            encoding,
+        } = page;
+        // The token is the bound the next page starts from, it can only
+        // narrow the requested range.
+        if let Some(token) = token {
+            match base64::decode(&token) {
+                Ok(bound) if reverse => {
+                    end = Some(match end {
+                        Some(end) if end < bound => end,
+                        _ => bound,
+                    })
+                }
+                Ok(bound) => start = start.max(bound),
+                Err(_) => return Ok((400, serde_json::to_vec(&"invalid token").unwrap())),
+            }
+        }
+        let mut entries = storage
+            .scan(self.scope, &start, end.as_deref(), limit + 1, reverse)
+            .await;
+        let next = if entries.len() > limit {
+            entries.truncate(limit);
+            entries.last().map(|(key, _)| {
+                if reverse {
+                    base64::encode(key)
+                } else {
+                    let mut bound = key.clone();
+                    bound.push(0);
+                    base64::encode(bound)
+                }
+            })
+        } else {
+            None
+        };
+        let entries: Option<Vec<ScanEntry>> = entries
+            .into_iter()
+            .map(|(key, value)| {
+                Some(ScanEntry {
+                    key: encode_key(encoding, &key)?,
+                    value: encode(encoding, &value)?,
+                })
+            })
+            .collect();
+        let page = if let Some(entries) = entries {
+            ScanPage { entries, next }
+        } else {
+            return Ok(not_acceptable(encoding));
+        };
+        Ok((200, serde_json::to_vec(&page).unwrap()))
+    }
+
+    /// Revokes a lease and publishes the deletion of its keys.
+    async fn revoke<Storage>(
         &mut self,
-        node: &Mutex<RawNode<Storage>>,
+        storage: &Storage,
         pubsub: &mut pubsub::Channel,
-        event: Vec<u8>,
-    ) -> Result<(u16, Vec<u8>), Error> {
-        let raft_node = node.try_lock().unwrap();
-        let storage = raft_node.store();
-        match serde_json::from_slice(&event) {
-            Ok(Event::Get { key }) => {
+        lease: u64,
+        epoch: Option<u64>,
+    ) -> Result<(u16, Vec<u8>), Error>
+    where
+        Storage: storage::Storage + Send + Sync,
+    {
+        let (mut ops, deleted) =
+            if let Some(revoked) = self.leases.revoke(storage, lease, epoch).await {
+                revoked
+            } else {
+                return Ok((404, serde_json::to_vec(&"lease not found").unwrap()));
+            };
+        let sizes: Vec<(&[u8], Option<usize>, Option<usize>)> = deleted
+            .iter()
+            .map(|(key, old)| (&key[..], old.as_ref().map(Vec::len), None))
+            .collect();
+        // the deletions, the usage and the revisions are written together
+        ops.extend(self.usage_ops(storage, &sizes).await.unwrap_or_default());
+        let mut keys = Vec::with_capacity(deleted.len());
+        let mut changes = Vec::with_capacity(deleted.len());
+        for (key, old) in deleted {
+            keys.push(String::from_utf8(key.clone()).unwrap_or_default());
+            changes.push(Change {
+                event: self.delete_event(&key, old.as_deref()),
+                key,
+                value: None,
+            });
+        }
+        let events = self.record(storage, &mut ops, changes).await;
+        storage.write_batch(&ops).await;
+        self.publish(pubsub, events).await;
+        Ok((200, serde_json::to_vec(&keys).unwrap()))
+    }
+
+    /// Ops storing the usage after `changes`, each a key going from a value
+    /// of the old to one of the new size. `None` if that breaks the quota.
+    async fn usage_ops<Storage>(
+        &self,
+        storage: &Storage,
+        changes: &[(&[u8], Option<usize>, Option<usize>)],
+    ) -> Option<Vec<BatchOp>>
+    where
+        Storage: storage::Storage + Send + Sync,
+    {
+        let (name, quota) = match (&self.name, &self.quota) {
+            (Some(name), Some(quota)) => (name, quota),
+            _ => return Some(Vec::new()),
+        };
+        let usage = namespace::usage(storage, name).await;
+        let mut next = usage;
+        for (key, old, new) in changes {
+            next.change(key, *old, *new);
+        }
+        if usage.exceeded_by(&next, quota) {
+            None
+        } else {
+            Some(vec![namespace::usage_op(name, &next)])
+        }
+    }
+
+    fn put_event(&self, key: &[u8], new: &[u8], old: Option<&[u8]>) -> PSEvent {
+        let encoding = event_encoding(vec![Some(new), old]);
+        PSEvent::Put {
+            scope: self.scope,
+            key: String::from_utf8_lossy(key).into_owned(),
+            new: event_value(encoding, new),
+            old: old.map(|old| event_value(encoding, old)),
+            encoding,
+            revision: 0,
+        }
+    }
+
+    fn cas_event(&self, key: &[u8], new: &[u8], old: Option<&[u8]>) -> PSEvent {
+        let encoding = event_encoding(vec![Some(new), old]);
+        PSEvent::Cas {
+            scope: self.scope,
+            key: String::from_utf8_lossy(key).into_owned(),
+            new: event_value(encoding, new),
+            old: old.map(|old| event_value(encoding, old)),
+            encoding,
+            revision: 0,
+        }
+    }
+
+    fn add_event(&self, key: &[u8], delta: i64, new: i64, old: Option<i64>) -> PSEvent {
+        PSEvent::Add {
+            scope: self.scope,
+            key: String::from_utf8_lossy(key).into_owned(),
+            delta,
+            new,
+            old,
+            revision: 0,
+        }
+    }
+
+    fn delete_event(&self, key: &[u8], old: Option<&[u8]>) -> PSEvent {
+        let encoding = event_encoding(vec![old]);
+        PSEvent::Delete {
+            scope: self.scope,
+            key: String::from_utf8_lossy(key).into_owned(),
+            old: old.map(|old| event_value(encoding, old)),
+            encoding,
+            revision: 0,
+        }
+    }
+
+    /// Assigns revisions to changes about to be applied, the ops recording
+    /// them are added to `ops` so both are written in one batch.
+    async fn record<Storage>(
+        &self,
+        storage: &Storage,
+        ops: &mut Vec<BatchOp>,
+        changes: Vec<Change>,
+    ) -> Vec<(u64, PSEvent)>
+    where
+        Storage: storage::Storage + Send + Sync,
+    {
+        let (revision_ops, events) = self.revisions.record(storage, changes).await;
+        ops.extend(revision_ops);
+        events
+    }
+
+    /// Publishes recorded changes on the channel of the namespace.
+    async fn publish(&self, pubsub: &mut pubsub::Channel, events: Vec<(u64, PSEvent)>) {
+        for (revision, event) in events {
+            pubsub
+                .send(pubsub::Msg::versioned(&self.channel, revision, event))
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn replay<Storage>(&self, storage: &Storage, pubsub: &mut pubsub::Channel)
+    where
+        Storage: storage::Storage + Send + Sync,
+    {
+        for (revision, event) in self.revisions.log(storage).await {
+            pubsub
+                .send(pubsub::Msg::versioned(&self.channel, revision, event))
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn execute<Storage>(
+        &mut self,
+        storage: &Storage,
+        pubsub: &mut pubsub::Channel,
+        event: Event,
+    ) -> Result<(u16, Vec<u8>), Error>
+    where
+        Storage: storage::Storage + Send + Sync,
+    {
+        match event {
+            Event::Get { key, encoding, .. } => {
                 debug!(
                     self.logger,
                     "READ {:?}",
                     String::from_utf8(key.clone()).ok()
                 );
-                if let Some(s) = storage
-                    .get(self.scope, &key)
-                    .await
-                    .and_then(|v| String::from_utf8(v).ok())
-                {
-                    Ok((200u16, serde_json::to_vec(&s).unwrap()))
+                if let Some(value) = storage.get(self.scope, &key).await {
+                    if let Some(value) = encode(encoding, &value) {
+                        Ok((200u16, serde_json::to_vec(&value).unwrap()))
+                    } else {
+                        Ok(not_acceptable(encoding))
+                    }
                 } else {
                     Ok((404u16, serde_json::to_vec(&"not found").ok().unwrap()))
                 }
             }
-            Ok(Event::Put { key, value }) => {
+            Event::Put {
+                key,
+                value,
+                lease,
+                encoding,
+            } => {
                 debug!(
                     self.logger,
-                    "WROTE {:?}: {:?}",
+                    "WROTE {:?}: {} bytes",
                     String::from_utf8(key.clone()).ok(),
-                    String::from_utf8(value.clone()).ok()
+                    value.len()
                 );
-                let old = storage
-                    .get(self.scope, &key)
-                    .await
-                    .and_then(|value| String::from_utf8(value).ok());
-                storage.put(self.scope, &key, &value).await;
-                let msg = serde_json::to_value(&PSEvent::Put {
-                    scope: self.scope,
-                    key: String::from_utf8(key).unwrap_or_default(),
-                    new: String::from_utf8(value).unwrap_or_default(),
-                    old: old.clone(),
-                })
-                .unwrap();
-                pubsub
-                    .send(pubsub::Msg::Msg {
-                        channel: "kv".into(),
-                        msg: msg,
-                    })
-                    .await
-                    .unwrap();
-
-                if let Some(old) = old {
-                    Ok((201, serde_json::to_vec(&old).unwrap()))
-                } else {
-                    Ok((201, serde_json::to_vec(&serde_json::Value::Null).unwrap()))
+                if let Some(lease) = lease {
+                    if self.leases.get(storage, lease).await.is_none() {
+                        return Ok((404, serde_json::to_vec(&"lease not found").unwrap()));
+                    }
                 }
+                let old = storage.get(self.scope, &key).await;
+                // Checked before writing so a 406 never hides a write.
+                let reply = if let Some(reply) = encode_opt(encoding, old.as_deref()) {
+                    reply
+                } else {
+                    return Ok(not_acceptable(encoding));
+                };
+                let change = [(&key[..], old.as_ref().map(Vec::len), Some(value.len()))];
+                let usage = if let Some(usage) = self.usage_ops(storage, &change).await {
+                    usage
+                } else {
+                    return Ok(quota_exceeded());
+                };
+                let mut ops = vec![BatchOp::Put {
+                    scope: self.scope,
+                    key: key.clone(),
+                    value: value.clone(),
+                }];
+                ops.extend(self.leases.attach_ops(storage, &key, lease).await);
+                ops.extend(usage);
+                let change = Change {
+                    event: self.put_event(&key, &value, old.as_deref()),
+                    key,
+                    value: Some(value),
+                };
+                let events = self.record(storage, &mut ops, vec![change]).await;
+                storage.write_batch(&ops).await;
+                self.publish(pubsub, events).await;
+                Ok((201, serde_json::to_vec(&reply).unwrap()))
             }
-            Ok(Event::Cas {
+            Event::Cas {
                 key,
                 check_value,
                 store_value,
-            }) => {
-                if let Some(conflict) = storage
-                    .cas(
-                        self.scope,
-                        &key,
-                        check_value.as_ref().map(|v| v.as_slice()),
-                        &store_value,
-                    )
-                    .await
-                {
-                    let conflict = conflict.and_then(|o| String::from_utf8(o).ok());
+                encoding,
+            } => {
+                // Usage only changes if the compare holds, on a conflict the
+                // whole batch is dropped anyway.
+                let current = storage.get(self.scope, &key).await;
+                let usage = if current == check_value {
+                    let change = [(
+                        &key[..],
+                        current.as_ref().map(Vec::len),
+                        Some(store_value.len()),
+                    )];
+                    if let Some(usage) = self.usage_ops(storage, &change).await {
+                        usage
+                    } else {
+                        return Ok(quota_exceeded());
+                    }
+                } else {
+                    Vec::new()
+                };
+                let mut ops = vec![
+                    BatchOp::Compare {
+                        scope: self.scope,
+                        key: key.clone(),
+                        value: check_value.clone(),
+                    },
+                    BatchOp::Put {
+                        scope: self.scope,
+                        key: key.clone(),
+                        value: store_value.clone(),
+                    },
+                ];
+                ops.extend(self.leases.attach_ops(storage, &key, None).await);
+                ops.extend(usage);
+                // the revision is dropped along with the batch on a conflict
+                let change = Change {
+                    event: self.cas_event(&key, &store_value, check_value.as_deref()),
+                    key: key.clone(),
+                    value: Some(store_value.clone()),
+                };
+                let events = self.record(storage, &mut ops, vec![change]).await;
+                if let BatchResult::Conflict(mut conflicts) = storage.write_batch(&ops).await {
+                    let conflict = conflicts.pop().and_then(|(_, o)| o);
+                    let ps_encoding =
+                        event_encoding(vec![Some(&store_value[..]), conflict.as_deref()]);
                     let msg = serde_json::to_value(&PSEvent::CasConflict {
                         scope: self.scope,
-                        key: String::from_utf8(key).unwrap_or_default(),
-                        new: String::from_utf8(store_value).unwrap_or_default(),
-                        conflict: conflict.clone(),
+                        key: String::from_utf8_lossy(&key).into_owned(),
+                        new: event_value(ps_encoding, &store_value),
+                        conflict: conflict.as_ref().map(|c| event_value(ps_encoding, c)),
+                        encoding: ps_encoding,
                     })
                     .unwrap();
                     pubsub
                         .send(pubsub::Msg::Msg {
-                            channel: "kv".into(),
+                            channel: self.channel.clone(),
                             msg: msg,
                         })
                         .await
                         .unwrap();
-                    if let Some(conflict) = conflict {
-                        Ok((
-                            409,
-                            serde_json::to_vec(&serde_json::Value::String(conflict)).unwrap(),
-                        ))
+                    if let Some(conflict) = encode_opt(encoding, conflict.as_deref()) {
+                        Ok((409, serde_json::to_vec(&conflict).unwrap()))
                     } else {
-                        Ok((409, serde_json::to_vec(&serde_json::Value::Null).unwrap()))
+                        Ok(not_acceptable(encoding))
                     }
                 } else {
-                    let old = check_value.and_then(|c| String::from_utf8(c).ok());
-                    let new = String::from_utf8(store_value).ok();
-                    let msg = serde_json::to_value(&PSEvent::Cas {
-                        scope: self.scope,
-                        key: String::from_utf8(key).unwrap_or_default(),
-                        new: new.clone().unwrap_or_default(),
-                        old,
-                    })
-                    .unwrap();
-                    pubsub
-                        .send(pubsub::Msg::Msg {
-                            channel: "kv".into(),
-                            msg: msg,
+                    self.publish(pubsub, events).await;
+                    Ok((201, serde_json::to_vec(&"set").unwrap()))
+                }
+            }
+            Event::Delete { key, encoding } => {
+                let old = if let Some(old) = storage.get(self.scope, &key).await {
+                    old
+                } else {
+                    return Ok((404u16, serde_json::to_vec(&"not found").unwrap()));
+                };
+                let reply = if let Some(reply) = encode(encoding, &old) {
+                    reply
+                } else {
+                    return Ok(not_acceptable(encoding));
+                };
+                let change = [(&key[..], Some(old.len()), None)];
+                let usage = self.usage_ops(storage, &change).await.unwrap_or_default();
+                let mut ops = vec![BatchOp::Delete {
+                    scope: self.scope,
+                    key: key.clone(),
+                }];
+                ops.extend(self.leases.attach_ops(storage, &key, None).await);
+                ops.extend(usage);
+                let change = Change {
+                    event: self.delete_event(&key, Some(&old)),
+                    key,
+                    value: None,
+                };
+                let events = self.record(storage, &mut ops, vec![change]).await;
+                storage.write_batch(&ops).await;
+                self.publish(pubsub, events).await;
+                Ok((200u16, serde_json::to_vec(&reply).unwrap()))
+            }
+            Event::Txn { ops, encoding } => {
+                // Values written by the transaction itself came in with
+                // `encoding`, so only the stored ones have to be checked.
+                let mut values: HashMap<&[u8], Option<Vec<u8>>> = HashMap::new();
+                for op in &ops {
+                    let current = storage.get(self.scope, op.key()).await;
+                    if encode_opt(encoding, current.as_deref()).is_none() {
+                        return Ok(not_acceptable(encoding));
+                    }
+                    values.insert(op.key(), current);
+                }
+                // Later ops see the values written by earlier ones.
+                let mut sizes = Vec::new();
+                let mut changes = Vec::new();
+                for op in &ops {
+                    let new = match op {
+                        TxnOp::Put { value, .. } => Some(value.clone()),
+                        TxnOp::Delete { .. } => None,
+                        TxnOp::Compare { .. } => continue,
+                    };
+                    let old = values.insert(op.key(), new.clone()).unwrap_or_default();
+                    sizes.push((
+                        op.key(),
+                        old.as_ref().map(Vec::len),
+                        new.as_ref().map(Vec::len),
+                    ));
+                    let key = op.key().to_vec();
+                    changes.push(Change {
+                        event: match &new {
+                            Some(value) => self.put_event(&key, value, old.as_deref()),
+                            None => self.delete_event(&key, old.as_deref()),
+                        },
+                        key,
+                        value: new,
+                    });
+                }
+                let usage = if let Some(usage) = self.usage_ops(storage, &sizes).await {
+                    usage
+                } else {
+                    return Ok(quota_exceeded());
+                };
+                let mut batch: Vec<BatchOp> = ops
+                    .iter()
+                    .cloned()
+                    .map(|op| op.into_batch_op(self.scope))
+                    .collect();
+                // Written keys lose their lease, the detaching ops go last so
+                // results and conflicts still line up with the transaction.
+                for op in &ops {
+                    if let TxnOp::Put { key, .. } | TxnOp::Delete { key } = op {
+                        batch.extend(self.leases.attach_ops(storage, key, None).await);
+                    }
+                }
+                batch.extend(usage);
+                let events = self.record(storage, &mut batch, changes).await;
+                match storage.write_batch(&batch).await {
+                    BatchResult::Applied(mut previous) => {
+                        previous.truncate(ops.len());
+                        let reply: Vec<Option<serde_json::Value>> = previous
+                            .iter()
+                            .map(|v| encode_opt(encoding, v.as_deref()).unwrap_or_default())
+                            .collect();
+                        self.publish(pubsub, events).await;
+                        Ok((201, serde_json::to_vec(&reply).unwrap()))
+                    }
+                    BatchResult::Conflict(conflicts) => {
+                        let ps_encoding =
+                            event_encoding(conflicts.iter().map(|(_, v)| v.as_deref()));
+                        let conflicts_in = |encoding: Encoding| -> Vec<TxnConflict> {
+                            conflicts
+                                .iter()
+                                .map(|(op, value)| TxnConflict {
+                                    op: *op,
+                                    key: String::from_utf8_lossy(ops[*op].key()).into_owned(),
+                                    value: encode_opt(encoding, value.as_deref())
+                                        .unwrap_or_default(),
+                                })
+                                .collect()
+                        };
+                        let msg = serde_json::to_value(&PSEvent::TxnConflict {
+                            scope: self.scope,
+                            conflicts: conflicts_in(ps_encoding),
+                            encoding: ps_encoding,
                         })
-                        .await
                         .unwrap();
-                    Ok((201, serde_json::to_vec(&"set").unwrap()))
+                        pubsub
+                            .send(pubsub::Msg::Msg {
+                                channel: self.channel.clone(),
+                                msg: msg,
+                            })
+                            .await
+                            .unwrap();
+                        Ok((409, serde_json::to_vec(&conflicts_in(encoding)).unwrap()))
+                    }
                 }
             }
-            Ok(Event::Delete { key }) => Ok((
-                200u16,
-                storage
-                    .delete(self.scope, &key)
+            Event::Scan {
+                start,
+                end,
+                limit,
+                reverse,
+                token,
+                encoding,
+                ..
+            } => {
+                let page = Page {
+                    limit,
+                    token,
+                    encoding,
+                };
+                self.scan(storage, start, end, reverse, page).await
+            }
+            Event::Prefix {
+                prefix,
+                limit,
+                token,
+                encoding,
+                ..
+            } => {
+                let end = prefix_end(&prefix);
+                let page = Page {
+                    limit,
+                    token,
+                    encoding,
+                };
+                self.scan(storage, prefix, end, false, page).await
+            }
+            Event::Grant { ttl } => {
+                if ttl == 0 {
+                    return Ok((400, serde_json::to_vec(&"ttl must be positive").unwrap()));
+                }
+                let lease = self.leases.grant(storage, ttl).await;
+                Ok((201, serde_json::to_vec(&lease).unwrap()))
+            }
+            Event::KeepAlive { lease } => {
+                if let Some(lease) = self.leases.keep_alive(storage, lease).await {
+                    Ok((200, serde_json::to_vec(&lease).unwrap()))
+                } else {
+                    Ok((404, serde_json::to_vec(&"lease not found").unwrap()))
+                }
+            }
+            Event::Revoke { lease } => self.revoke(storage, pubsub, lease, None).await,
+            Event::Expire { lease, epoch } => {
+                debug!(self.logger, "EXPIRE lease {} in epoch {}", lease, epoch);
+                self.revoke(storage, pubsub, lease, Some(epoch)).await
+            }
+            Event::History { key, encoding, .. } => {
+                let versions: Option<Vec<Version>> = self
+                    .revisions
+                    .history(storage, &key)
                     .await
-                    .and_then(|v| String::from_utf8(v).ok())
-                    .and_then(|s| serde_json::to_vec(&serde_json::Value::String(s)).ok())
-                    .unwrap(),
-            )),
-            _ => Err(Error::UnknownEvent),
+                    .into_iter()
+                    .map(|(revision, value)| {
+                        Some(Version {
+                            revision,
+                            value: encode_opt(encoding, value.as_deref())?,
+                        })
+                    })
+                    .collect();
+                if let Some(versions) = versions {
+                    Ok((200, serde_json::to_vec(&versions).unwrap()))
+                } else {
+                    Ok(not_acceptable(encoding))
+                }
+            }
+            Event::Add {
+                key,
+                delta,
+                min,
+                max,
+            } => {
+                let old = match storage.get(self.scope, &key).await {
+                    Some(value) => match parse_counter(&value) {
+                        Some(n) => Some((n, value.len())),
+                        None => {
+                            return Ok((400, serde_json::to_vec(&"not an integer").unwrap()));
+                        }
+                    },
+                    None => None,
+                };
+                let current = old.map(|(n, _)| n).unwrap_or_default();
+                let new = match current.checked_add(delta) {
+                    Some(new)
+                        if min.map(|min| new >= min).unwrap_or(true)
+                            && max.map(|max| new <= max).unwrap_or(true) =>
+                    {
+                        new
+                    }
+                    _ => return Ok((409, serde_json::to_vec(&current).unwrap())),
+                };
+                let value = new.to_string().into_bytes();
+                let change = [(&key[..], old.map(|(_, len)| len), Some(value.len()))];
+                let usage = if let Some(usage) = self.usage_ops(storage, &change).await {
+                    usage
+                } else {
+                    return Ok(quota_exceeded());
+                };
+                let mut ops = vec![BatchOp::Put {
+                    scope: self.scope,
+                    key: key.clone(),
+                    value: value.clone(),
+                }];
+                ops.extend(self.leases.attach_ops(storage, &key, None).await);
+                ops.extend(usage);
+                let change = Change {
+                    event: self.add_event(&key, delta, new, old.map(|(n, _)| n)),
+                    key,
+                    value: Some(value),
+                };
+                let events = self.record(storage, &mut ops, vec![change]).await;
+                storage.write_batch(&ops).await;
+                self.publish(pubsub, events).await;
+                Ok((200, serde_json::to_vec(&new).unwrap()))
+            }
+            Event::CreateNamespace { .. } | Event::Namespaces { .. } => Err(Error::UnknownEvent),
+        }
+    }
+}
+
+impl Service {
+    /// Publishes the events still kept in the logs of the namespaces, so
+    /// watchers can start from a revision committed before the node
+    /// (re)started.
+    pub async fn replay<Storage>(
+        &mut self,
+        node: &Mutex<RawNode<Storage>>,
+        pubsub: &mut pubsub::Channel,
+    ) where
+        Storage: storage::Storage + Send + Sync,
+    {
+        let raft_node = node.try_lock().unwrap();
+        let storage = raft_node.store();
+        self.load_all(storage).await;
+        for space in self.spaces.values() {
+            space.replay(storage, pubsub).await;
+        }
+    }
+
+    async fn create_namespace<Storage>(
+        &mut self,
+        storage: &Storage,
+        name: Option<String>,
+        quota: Quota,
+    ) -> Result<(u16, Vec<u8>), Error>
+    where
+        Storage: storage::Storage + Send + Sync,
+    {
+        let name = match name {
+            Some(name) if namespace::reserved(&name) => {
+                return Ok((400, serde_json::to_vec(&"reserved namespace name").unwrap()))
+            }
+            Some(name) if namespace::valid_name(&name) => name,
+            _ => return Ok((400, serde_json::to_vec(&"invalid namespace name").unwrap())),
+        };
+        if namespace::get(storage, &name).await.is_some() {
+            return Ok((409, serde_json::to_vec(&"namespace exists").unwrap()));
+        }
+        if let Some(namespace) = namespace::create(storage, &name, quota).await {
+            info!(self.logger, "Created namespace {}", name; "scope" => namespace.scope);
+            let reply = serde_json::to_vec(&namespace).unwrap();
+            self.add(namespace);
+            Ok((201, reply))
+        } else {
+            Ok((507, serde_json::to_vec(&"no scope left").unwrap()))
+        }
+    }
+
+    async fn namespaces<Storage>(&self, storage: &Storage) -> Result<(u16, Vec<u8>), Error>
+    where
+        Storage: storage::Storage + Send + Sync,
+    {
+        let mut infos = Vec::new();
+        for Namespace { name, scope, quota } in namespace::list(storage).await {
+            let usage = namespace::usage(storage, &name).await;
+            infos.push(NamespaceInfo {
+                name,
+                scope,
+                quota,
+                usage,
+            });
+        }
+        Ok((200, serde_json::to_vec(&infos).unwrap()))
+    }
+}
+
+#[async_trait]
+impl<Storage> super::Service<Storage> for Service
+where
+    Storage: storage::Storage + Send + Sync + 'static,
+{
+    async fn execute(
+        &mut self,
+        node: &Mutex<RawNode<Storage>>,
+        pubsub: &mut pubsub::Channel,
+        event: Vec<u8>,
+    ) -> Result<(u16, Vec<u8>), Error> {
+        let raft_node = node.try_lock().unwrap();
+        let storage = raft_node.store();
+        let Request { ns, event } =
+            serde_json::from_slice(&event).map_err(|_| Error::UnknownEvent)?;
+        match event {
+            Event::CreateNamespace { quota } => self.create_namespace(storage, ns, quota).await,
+            Event::Namespaces { .. } => self.namespaces(storage).await,
+            event => {
+                if let Some(space) = self.space(storage, &ns).await {
+                    space.execute(storage, pubsub, event).await
+                } else {
+                    Ok((404, serde_json::to_vec(&"namespace not found").unwrap()))
+                }
+            }
         }
     }
     fn is_local(&self, event: &[u8]) -> Result<bool, Error> {
         match serde_json::from_slice(&event) {
-            Ok(Event::Get { .. }) => Ok(true),
-            Ok(Event::Put { .. }) => Ok(false),
-            Ok(Event::Cas { .. }) => Ok(false),
-            Ok(Event::Delete { .. }) => Ok(false),
+            Ok(Request { event, .. }) => Ok(match event {
+                Event::Get { .. } => true,
+                Event::Put { .. } => false,
+                Event::Cas { .. } => false,
+                Event::Delete { .. } => false,
+                Event::Txn { .. } => false,
+                Event::Scan { .. } => true,
+                Event::Prefix { .. } => true,
+                Event::Grant { .. } => false,
+                Event::KeepAlive { .. } => false,
+                Event::Revoke { .. } => false,
+                Event::Expire { .. } => false,
+                Event::History { .. } => true,
+                Event::Add { .. } => false,
+                Event::CreateNamespace { .. } => false,
+                Event::Namespaces { .. } => true,
+            }),
             _ => Err(Error::UnknownEvent),
         }
     }
+    fn consistency(&self, event: &[u8]) -> Result<Consistency, Error> {
+        match serde_json::from_slice(&event) {
+            Ok(Request { event, .. }) => Ok(match event {
+                Event::Get { consistency, .. }
+                | Event::Scan { consistency, .. }
+                | Event::Prefix { consistency, .. }
+                | Event::History { consistency, .. } => consistency,
+                _ => Consistency::Stale,
+            }),
+            _ => Err(Error::UnknownEvent),
+        }
+    }
+    async fn leader_tick(&mut self, node: &Mutex<RawNode<Storage>>) -> Result<Vec<Vec<u8>>, Error> {
+        let raft_node = node.try_lock().unwrap();
+        let storage = raft_node.store();
+        // Every namespace with leases has to be tracked on the leader.
+        self.load_all(storage).await;
+        let mut events = Vec::new();
+        for space in self.spaces.values_mut() {
+            for (lease, epoch) in space.leases.expired(storage).await {
+                events.push(Event::expire(space.name.clone(), lease, epoch));
+            }
+        }
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::{Config, MemStorage, Storage as _, WriteStorage as _};
+    use crate::NodeId;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_namespaces_are_isolated_and_enforce_quotas() {
+        let storage = block_on(MemStorage::new(NodeId(1), &Config::default())).unwrap();
+        let (mut pubsub, _rx) = futures::channel::mpsc::channel(crate::CHANNEL_SIZE);
+        let mut kv = Service::new(&slog::Logger::root(slog::Discard, o!()), 0);
+        let put = |value: &str| Event::Put {
+            key: b"snot".to_vec(),
+            value: value.as_bytes().to_vec(),
+            lease: None,
+            encoding: Encoding::Raw,
+        };
+        block_on(async {
+            let quota = Quota {
+                max_keys: None,
+                max_bytes: Some(8),
+            };
+            let ns = Some("badger".to_string());
+            let (code, _) = kv
+                .create_namespace(&storage, ns.clone(), quota)
+                .await
+                .unwrap();
+            assert_eq!(code, 201);
+            let (code, _) = kv
+                .create_namespace(&storage, ns.clone(), Quota::default())
+                .await
+                .unwrap();
+            assert_eq!(code, 409);
+            // `/kv/lease/<lease>` couldn't address its keys
+            let (code, _) = kv
+                .create_namespace(&storage, Some("lease".to_string()), Quota::default())
+                .await
+                .unwrap();
+            assert_eq!(code, 400);
+
+            let space = kv.space(&storage, &ns).await.unwrap();
+            assert_eq!(space.channel, "kv/badger");
+            let (code, _) = space
+                .execute(&storage, &mut pubsub, put("1234"))
+                .await
+                .unwrap();
+            assert_eq!(code, 201);
+            // 4 bytes of key and 5 of value don't fit into 8
+            let (code, _) = space
+                .execute(&storage, &mut pubsub, put("12345"))
+                .await
+                .unwrap();
+            assert_eq!(code, 507);
+            assert_eq!(
+                storage.get(space.scope, b"snot").await,
+                Some(b"1234".to_vec())
+            );
+
+            // the default namespace is a different scope without a quota
+            let default = kv.space(&storage, &None).await.unwrap();
+            assert_eq!(
+                default
+                    .execute(&storage, &mut pubsub, put("12345"))
+                    .await
+                    .unwrap()
+                    .0,
+                201
+            );
+            assert!(kv.space(&storage, &Some("snot".into())).await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_counters_stay_within_bounds() {
+        let storage = block_on(MemStorage::new(NodeId(1), &Config::default())).unwrap();
+        let (mut pubsub, _rx) = futures::channel::mpsc::channel(crate::CHANNEL_SIZE);
+        let mut kv = Service::new(&slog::Logger::root(slog::Discard, o!()), 0);
+        let add = |delta: i64, max: Option<i64>| Event::Add {
+            key: b"hits".to_vec(),
+            delta,
+            min: Some(0),
+            max,
+        };
+        block_on(async {
+            let space = kv.space(&storage, &None).await.unwrap();
+            let steps = vec![
+                (add(2, None), 200, "2"),
+                (add(1, Some(3)), 200, "3"),
+                // out of bounds answers with the current value, nothing is written
+                (add(1, Some(3)), 409, "3"),
+                (add(-4, None), 409, "3"),
+                (add(-3, None), 200, "0"),
+            ];
+            for (event, code, value) in steps {
+                let reply = space.execute(&storage, &mut pubsub, event).await.unwrap();
+                assert_eq!(reply, (code, value.as_bytes().to_vec()));
+            }
+            assert_eq!(storage.get(0, b"hits").await, Some(b"0".to_vec()));
+
+            storage.put(0, b"hits", b"badger").await;
+            let reply = space.execute(&storage, &mut pubsub, add(1, None)).await;
+            assert_eq!(reply.unwrap().0, 400);
+        });
+    }
+
+    #[test]
+    fn test_scan_tokens_stay_within_the_range() {
+        let storage = block_on(MemStorage::new(NodeId(1), &Config::default())).unwrap();
+        let (mut pubsub, _rx) = futures::channel::mpsc::channel(crate::CHANNEL_SIZE);
+        let mut kv = Service::new(&slog::Logger::root(slog::Discard, o!()), 0);
+        block_on(async {
+            for key in &["a", "b/1", "b/2", "c"] {
+                storage.put(0, key.as_bytes(), b"x").await;
+            }
+            let space = kv.space(&storage, &None).await.unwrap();
+            let keys = |reply: Vec<u8>| -> Vec<String> {
+                let page: serde_json::Value = serde_json::from_slice(&reply).unwrap();
+                page["entries"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|e| e["key"].as_str().unwrap().to_string())
+                    .collect()
+            };
+            // forged tokens pointing before the prefix or past the end
+            let prefix = Event::Prefix {
+                prefix: b"b/".to_vec(),
+                limit: 10,
+                token: Some(base64::encode(b"a")),
+                consistency: Consistency::Stale,
+                encoding: Encoding::Raw,
+            };
+            let (code, reply) = space.execute(&storage, &mut pubsub, prefix).await.unwrap();
+            assert_eq!(code, 200);
+            assert_eq!(keys(reply), vec!["b/1", "b/2"]);
+            let scan = Event::Scan {
+                start: b"b/".to_vec(),
+                end: Some(b"b/2".to_vec()),
+                limit: 10,
+                reverse: true,
+                token: Some(base64::encode(b"d")),
+                consistency: Consistency::Stale,
+                encoding: Encoding::Raw,
+            };
+            let (code, reply) = space.execute(&storage, &mut pubsub, scan).await.unwrap();
+            assert_eq!(code, 200);
+            assert_eq!(keys(reply), vec!["b/1"]);
+        });
+    }
+
+    #[test]
+    fn test_prefix_end() {
+        assert_eq!(prefix_end(b"config/"), Some(b"config0".to_vec()));
+        assert_eq!(prefix_end(b"a\xff\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_end(b"\xff"), None);
+        assert_eq!(prefix_end(b""), None);
+    }
 }
@@ -0,0 +1,132 @@
+// Copyright 2018-2020, Wayfair GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::Encoding;
+use serde_json::Value;
+
+/// Turns a value sent by a client into the bytes that are stored.
+pub fn decode(encoding: Encoding, value: Value) -> Result<Vec<u8>, String> {
+    match (encoding, value) {
+        (Encoding::Raw, Value::String(s)) => Ok(s.into_bytes()),
+        (Encoding::Base64, Value::String(s)) => {
+            base64::decode(&s).map_err(|e| format!("invalid base64: {}", e))
+        }
+        (Encoding::Json, value) => Ok(serde_json::to_vec(&value).unwrap()),
+        (_, _) => Err(format!("expected a string for {:?} encoding", encoding)),
+    }
+}
+
+/// Represents stored bytes in the given encoding, `None` if they can't be,
+/// e.g. raw values that aren't UTF-8.
+pub fn encode(encoding: Encoding, data: &[u8]) -> Option<Value> {
+    match encoding {
+        Encoding::Raw => std::str::from_utf8(data)
+            .ok()
+            .map(|s| Value::String(s.to_string())),
+        Encoding::Base64 => Some(Value::String(base64::encode(data))),
+        Encoding::Json => serde_json::from_slice(data).ok(),
+    }
+}
+
+/// Represents a stored key in the given encoding. Keys are strings, so
+/// `json` reads them like `raw`; `None` if the key isn't UTF-8 then.
+pub fn encode_key(encoding: Encoding, key: &[u8]) -> Option<String> {
+    match encoding {
+        Encoding::Base64 => Some(base64::encode(key)),
+        Encoding::Raw | Encoding::Json => std::str::from_utf8(key).ok().map(String::from),
+    }
+}
+
+/// Like `encode` for values that might be absent, the outer `None` means the
+/// value couldn't be represented.
+pub fn encode_opt(encoding: Encoding, data: Option<&[u8]>) -> Option<Option<Value>> {
+    match data {
+        Some(data) => encode(encoding, data).map(Some),
+        None => Some(None),
+    }
+}
+
+/// Events have no reader to pick an encoding, so their values are raw unless
+/// one of them isn't UTF-8.
+pub fn event_encoding<'v, I>(values: I) -> Encoding
+where
+    I: IntoIterator<Item = Option<&'v [u8]>>,
+{
+    if values
+        .into_iter()
+        .flatten()
+        .all(|v| std::str::from_utf8(v).is_ok())
+    {
+        Encoding::Raw
+    } else {
+        Encoding::Base64
+    }
+}
+
+/// A value of an event in the encoding picked by `event_encoding`.
+pub fn event_value(encoding: Encoding, data: &[u8]) -> String {
+    match encoding {
+        Encoding::Base64 => base64::encode(data),
+        _ => String::from_utf8_lossy(data).into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_values_survive_a_round_trip() {
+        let blob = vec![0u8, 159, 146, 150, 255];
+        let encoded = encode(Encoding::Base64, &blob).unwrap();
+        assert_eq!(decode(Encoding::Base64, encoded), Ok(blob.clone()));
+        assert_eq!(encode(Encoding::Raw, &blob), None);
+        assert_eq!(encode(Encoding::Json, &blob), None);
+
+        let doc = json!({"snot": ["badger", 1]});
+        let stored = decode(Encoding::Json, doc.clone()).unwrap();
+        assert_eq!(encode(Encoding::Json, &stored), Some(doc));
+        assert_eq!(
+            encode(Encoding::Raw, &stored),
+            Some(json!(r#"{"snot":["badger",1]}"#))
+        );
+
+        assert!(decode(Encoding::Raw, json!(1)).is_err());
+        assert!(decode(Encoding::Base64, json!("not base64!")).is_err());
+    }
+
+    #[test]
+    fn test_keys_are_strings() {
+        let blob = vec![0u8, 159, 146, 150, 255];
+        assert_eq!(encode_key(Encoding::Base64, &blob), Some("AJ+Slv8=".into()));
+        assert_eq!(encode_key(Encoding::Raw, &blob), None);
+        assert_eq!(
+            encode_key(Encoding::Json, b"config/a"),
+            Some("config/a".into())
+        );
+    }
+
+    #[test]
+    fn test_events_fall_back_to_base64() {
+        let text: &[u8] = b"badger";
+        let blob: &[u8] = &[0xff, 0x00];
+        assert_eq!(event_encoding(vec![Some(text), None]), Encoding::Raw);
+        assert_eq!(
+            event_encoding(vec![Some(text), Some(blob)]),
+            Encoding::Base64
+        );
+        assert_eq!(event_value(Encoding::Base64, blob), "/wA=");
+    }
+}
@@ -0,0 +1,353 @@
+// Copyright 2018-2020, Wayfair GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::prefix_end;
+use crate::storage::{self, BatchOp};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Leases are kept in a shadow scope of the kv scope so they don't show up
+/// in scans, kv scopes have to stay below this bit.
+const SCOPE_BIT: u16 = 0x8000;
+const NEXT_ID: &[u8] = b"next";
+/// lease id -> lease
+const LEASE_PREFIX: u8 = b'l';
+/// key -> lease id
+const KEY_PREFIX: u8 = b'k';
+/// lease id + key -> ()
+const ATTACHED_PREFIX: u8 = b'a';
+/// Time after which an expiry that wasn't applied is proposed again.
+const RETRY: Duration = Duration::from_secs(1);
+/// A gap between leader ticks longer than this means the node just became
+/// leader and has to pick up the leases it doesn't track yet.
+const RELOAD_AFTER: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Lease {
+    pub id: u64,
+    /// Time to live in seconds
+    pub ttl: u64,
+    /// Bumped by every keepalive, an expiry only revokes the epoch it was
+    /// proposed for.
+    pub epoch: u64,
+}
+
+/// Keys attached to a lease are deleted once the lease is revoked or
+/// expires. Expiry is tracked by every replica but only the leader proposes
+/// it, so all replicas delete the same keys at the same log index.
+pub(crate) struct Leases {
+    scope: u16,
+    deadlines: HashMap<u64, (Instant, u64)>,
+    last_tick: Option<Instant>,
+}
+
+fn lease_key(id: u64) -> Vec<u8> {
+    let mut k = vec![LEASE_PREFIX];
+    k.extend_from_slice(&id.to_be_bytes());
+    k
+}
+
+fn key_key(key: &[u8]) -> Vec<u8> {
+    let mut k = vec![KEY_PREFIX];
+    k.extend_from_slice(key);
+    k
+}
+
+fn attached_key(id: u64, key: &[u8]) -> Vec<u8> {
+    let mut k = vec![ATTACHED_PREFIX];
+    k.extend_from_slice(&id.to_be_bytes());
+    k.extend_from_slice(key);
+    k
+}
+
+fn read_id(data: &[u8]) -> Option<u64> {
+    let mut id = [0u8; 8];
+    if data.len() != 8 {
+        return None;
+    }
+    id.copy_from_slice(data);
+    Some(u64::from_be_bytes(id))
+}
+
+impl Leases {
+    pub fn new(scope: u16) -> Self {
+        Self {
+            scope,
+            deadlines: HashMap::new(),
+            last_tick: None,
+        }
+    }
+
+    fn lease_scope(&self) -> u16 {
+        self.scope | SCOPE_BIT
+    }
+
+    fn track(&mut self, lease: &Lease) {
+        let deadline = Instant::now() + Duration::from_secs(lease.ttl);
+        self.deadlines.insert(lease.id, (deadline, lease.epoch));
+    }
+
+    fn put_lease(&self, lease: &Lease) -> BatchOp {
+        BatchOp::Put {
+            scope: self.lease_scope(),
+            key: lease_key(lease.id),
+            value: serde_json::to_vec(lease).unwrap(),
+        }
+    }
+
+    pub async fn get<Storage>(&self, storage: &Storage, id: u64) -> Option<Lease>
+    where
+        Storage: storage::Storage,
+    {
+        storage
+            .get(self.lease_scope(), &lease_key(id))
+            .await
+            .and_then(|v| serde_json::from_slice(&v).ok())
+    }
+
+    /// Ops that attach `key` to the lease `id`, or only detach it from its
+    /// current lease if `id` is `None`.
+    pub async fn attach_ops<Storage>(
+        &self,
+        storage: &Storage,
+        key: &[u8],
+        id: Option<u64>,
+    ) -> Vec<BatchOp>
+    where
+        Storage: storage::Storage,
+    {
+        let scope = self.lease_scope();
+        let mut ops = Vec::new();
+        let current = storage
+            .get(scope, &key_key(key))
+            .await
+            .and_then(|v| read_id(&v));
+        if let Some(current) = current {
+            if Some(current) == id {
+                return ops;
+            }
+            ops.push(BatchOp::Delete {
+                scope,
+                key: attached_key(current, key),
+            });
+            if id.is_none() {
+                ops.push(BatchOp::Delete {
+                    scope,
+                    key: key_key(key),
+                });
+            }
+        }
+        if let Some(id) = id {
+            ops.push(BatchOp::Put {
+                scope,
+                key: key_key(key),
+                value: id.to_be_bytes().to_vec(),
+            });
+            ops.push(BatchOp::Put {
+                scope,
+                key: attached_key(id, key),
+                value: Vec::new(),
+            });
+        }
+        ops
+    }
+
+    pub async fn grant<Storage>(&mut self, storage: &Storage, ttl: u64) -> Lease
+    where
+        Storage: storage::Storage,
+    {
+        let scope = self.lease_scope();
+        let id = storage
+            .get(scope, NEXT_ID)
+            .await
+            .and_then(|v| read_id(&v))
+            .unwrap_or(1);
+        let lease = Lease { id, ttl, epoch: 0 };
+        storage
+            .write_batch(&[
+                self.put_lease(&lease),
+                BatchOp::Put {
+                    scope,
+                    key: NEXT_ID.to_vec(),
+                    value: (id + 1).to_be_bytes().to_vec(),
+                },
+            ])
+            .await;
+        self.track(&lease);
+        lease
+    }
+
+    pub async fn keep_alive<Storage>(&mut self, storage: &Storage, id: u64) -> Option<Lease>
+    where
+        Storage: storage::Storage,
+    {
+        let mut lease = self.get(storage, id).await?;
+        lease.epoch += 1;
+        storage.write_batch(&[self.put_lease(&lease)]).await;
+        self.track(&lease);
+        Some(lease)
+    }
+
+    /// Removes the lease along with the keys attached to it. Returns the ops
+    /// deleting them, to be written by the caller, and the deleted keys with
+    /// their values.
+    pub async fn revoke<Storage>(
+        &mut self,
+        storage: &Storage,
+        id: u64,
+        epoch: Option<u64>,
+    ) -> Option<(Vec<BatchOp>, Vec<(Vec<u8>, Option<Vec<u8>>)>)>
+    where
+        Storage: storage::Storage,
+    {
+        let lease = self.get(storage, id).await?;
+        if epoch.map(|epoch| epoch != lease.epoch).unwrap_or_default() {
+            return None;
+        }
+        let scope = self.lease_scope();
+        let prefix = attached_key(id, &[]);
+        let attached = storage
+            .scan(
+                scope,
+                &prefix,
+                prefix_end(&prefix).as_deref(),
+                std::usize::MAX,
+                false,
+            )
+            .await;
+        let mut ops = vec![BatchOp::Delete {
+            scope,
+            key: lease_key(id),
+        }];
+        let mut deleted = Vec::with_capacity(attached.len());
+        for (k, _) in &attached {
+            let key = k[prefix.len()..].to_vec();
+            deleted.push((key.clone(), storage.get(self.scope, &key).await));
+            ops.push(BatchOp::Delete {
+                scope,
+                key: k.clone(),
+            });
+            ops.push(BatchOp::Delete {
+                scope,
+                key: key_key(&key),
+            });
+            ops.push(BatchOp::Delete {
+                scope: self.scope,
+                key,
+            });
+        }
+        self.deadlines.remove(&id);
+        Some((ops, deleted))
+    }
+
+    /// Leases whose ttl passed without a keepalive, along with the epoch they
+    /// expired in. Only called on the leader.
+    pub async fn expired<Storage>(&mut self, storage: &Storage) -> Vec<(u64, u64)>
+    where
+        Storage: storage::Storage,
+    {
+        let now = Instant::now();
+        if self
+            .last_tick
+            .map(|last| now.duration_since(last) > RELOAD_AFTER)
+            .unwrap_or(true)
+        {
+            self.reload(storage, now).await;
+        }
+        self.last_tick = Some(now);
+        let mut expired = Vec::new();
+        for (id, (deadline, epoch)) in self.deadlines.iter_mut() {
+            if *deadline <= now {
+                expired.push((*id, *epoch));
+                *deadline = now + RETRY;
+            }
+        }
+        expired
+    }
+
+    /// Syncs the tracked deadlines with the stored leases, leases that were
+    /// not tracked yet get their full ttl from now on.
+    async fn reload<Storage>(&mut self, storage: &Storage, now: Instant)
+    where
+        Storage: storage::Storage,
+    {
+        let prefix = [LEASE_PREFIX];
+        let leases: Vec<Lease> = storage
+            .scan(
+                self.lease_scope(),
+                &prefix,
+                prefix_end(&prefix).as_deref(),
+                std::usize::MAX,
+                false,
+            )
+            .await
+            .into_iter()
+            .filter_map(|(_, v)| serde_json::from_slice(&v).ok())
+            .collect();
+        let mut deadlines = HashMap::with_capacity(leases.len());
+        for lease in leases {
+            let deadline = match self.deadlines.get(&lease.id) {
+                Some((deadline, epoch)) if *epoch == lease.epoch => *deadline,
+                _ => now + Duration::from_secs(lease.ttl),
+            };
+            deadlines.insert(lease.id, (deadline, lease.epoch));
+        }
+        self.deadlines = deadlines;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::{Config, MemStorage, Storage as _, WriteStorage as _};
+    use crate::NodeId;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_revoke_only_deletes_attached_keys() {
+        let storage = block_on(MemStorage::new(NodeId(1), &Config::default())).unwrap();
+        let mut leases = Leases::new(0);
+        block_on(async {
+            let lease = leases.grant(&storage, 10).await;
+            for key in &[&b"session/a"[..], b"session/b"] {
+                let mut ops = vec![BatchOp::Put {
+                    scope: 0,
+                    key: key.to_vec(),
+                    value: b"alive".to_vec(),
+                }];
+                ops.extend(leases.attach_ops(&storage, key, Some(lease.id)).await);
+                storage.write_batch(&ops).await;
+            }
+            // overwritten without a lease, so it has to survive the lease
+            let ops = leases.attach_ops(&storage, b"session/b", None).await;
+            storage.write_batch(&ops).await;
+
+            let renewed = leases.keep_alive(&storage, lease.id).await.unwrap();
+            assert_eq!(renewed.epoch, 1);
+            assert!(leases.revoke(&storage, lease.id, Some(0)).await.is_none());
+
+            let (ops, deleted) = leases.revoke(&storage, lease.id, Some(1)).await.unwrap();
+            storage.write_batch(&ops).await;
+            assert_eq!(
+                deleted,
+                vec![(b"session/a".to_vec(), Some(b"alive".to_vec()))]
+            );
+            assert_eq!(storage.get(0, b"session/a").await, None);
+            assert_eq!(storage.get(0, b"session/b").await, Some(b"alive".to_vec()));
+            assert_eq!(leases.get(&storage, lease.id).await, None);
+            assert!(leases.expired(&storage).await.is_empty());
+        });
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright 2018-2020, Wayfair GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::prefix_end;
+use crate::storage::{self, BatchOp};
+use serde_derive::{Deserialize, Serialize};
+use ws_proto::Quota;
+
+/// Namespaces and their usage are kept in a scope of their own. The scopes
+/// of namespaces are handed out between the ones of the other services and
+/// this one.
+const REGISTRY_SCOPE: u16 = 0x3fff;
+const FIRST_SCOPE: u16 = 0x100;
+const NEXT_SCOPE: &[u8] = b"next";
+/// name -> namespace
+const NAMESPACE_PREFIX: u8 = b'n';
+/// name -> usage
+const USAGE_PREFIX: u8 = b'u';
+const MAX_NAME_LEN: usize = 64;
+/// The routes below `/kv` that take the place of a namespace in
+/// `/kv/<ns>/<key>`
+const RESERVED: &[&str] = &["lease", "txn"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Namespace {
+    pub name: String,
+    pub scope: u16,
+    pub quota: Quota,
+}
+
+/// Live keys of a namespace and their size, keys included.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Usage {
+    pub keys: u64,
+    pub bytes: u64,
+}
+
+impl Usage {
+    /// Accounts for `key` changing from a value of `old` bytes to one of
+    /// `new` bytes, `None` meaning there is no value.
+    pub fn change(&mut self, key: &[u8], old: Option<usize>, new: Option<usize>) {
+        if let Some(old) = old {
+            self.keys = self.keys.saturating_sub(1);
+            self.bytes = self.bytes.saturating_sub((key.len() + old) as u64);
+        }
+        if let Some(new) = new {
+            self.keys += 1;
+            self.bytes += (key.len() + new) as u64;
+        }
+    }
+
+    /// Whether going from `self` to `next` breaks the quota. Changes that
+    /// don't grow the usage are fine, so an over quota namespace can still
+    /// be cleaned up.
+    pub fn exceeded_by(&self, next: &Usage, quota: &Quota) -> bool {
+        let keys = quota
+            .max_keys
+            .map(|max| next.keys > max)
+            .unwrap_or_default();
+        let bytes = quota
+            .max_bytes
+            .map(|max| next.bytes > max)
+            .unwrap_or_default();
+        (keys || bytes) && (next.keys > self.keys || next.bytes > self.bytes)
+    }
+}
+
+/// Names end up in urls and pubsub channels, so they are kept simple.
+pub(crate) fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'-')
+}
+
+/// Names no namespace can be created with, its keys couldn't be addressed.
+pub(crate) fn reserved(name: &str) -> bool {
+    RESERVED.contains(&name)
+}
+
+fn namespace_key(name: &str) -> Vec<u8> {
+    let mut k = vec![NAMESPACE_PREFIX];
+    k.extend_from_slice(name.as_bytes());
+    k
+}
+
+fn usage_key(name: &str) -> Vec<u8> {
+    let mut k = vec![USAGE_PREFIX];
+    k.extend_from_slice(name.as_bytes());
+    k
+}
+
+pub(crate) async fn get<Storage>(storage: &Storage, name: &str) -> Option<Namespace>
+where
+    Storage: storage::Storage,
+{
+    storage
+        .get(REGISTRY_SCOPE, &namespace_key(name))
+        .await
+        .and_then(|v| serde_json::from_slice(&v).ok())
+}
+
+pub(crate) async fn list<Storage>(storage: &Storage) -> Vec<Namespace>
+where
+    Storage: storage::Storage,
+{
+    let prefix = [NAMESPACE_PREFIX];
+    storage
+        .scan(
+            REGISTRY_SCOPE,
+            &prefix,
+            prefix_end(&prefix).as_deref(),
+            std::usize::MAX,
+            false,
+        )
+        .await
+        .into_iter()
+        .filter_map(|(_, v)| serde_json::from_slice(&v).ok())
+        .collect()
+}
+
+/// Registers a namespace with the next free scope, `None` if all scopes are
+/// taken.
+pub(crate) async fn create<Storage>(
+    storage: &Storage,
+    name: &str,
+    quota: Quota,
+) -> Option<Namespace>
+where
+    Storage: storage::Storage,
+{
+    let mut next = [0u8; 2];
+    let scope = match storage.get(REGISTRY_SCOPE, NEXT_SCOPE).await {
+        Some(v) if v.len() == 2 => {
+            next.copy_from_slice(&v);
+            u16::from_be_bytes(next)
+        }
+        _ => FIRST_SCOPE,
+    };
+    if scope >= REGISTRY_SCOPE {
+        return None;
+    }
+    let namespace = Namespace {
+        name: name.to_string(),
+        scope,
+        quota,
+    };
+    storage
+        .write_batch(&[
+            BatchOp::Put {
+                scope: REGISTRY_SCOPE,
+                key: namespace_key(name),
+                value: serde_json::to_vec(&namespace).unwrap(),
+            },
+            BatchOp::Put {
+                scope: REGISTRY_SCOPE,
+                key: NEXT_SCOPE.to_vec(),
+                value: (scope + 1).to_be_bytes().to_vec(),
+            },
+            usage_op(name, &Usage::default()),
+        ])
+        .await;
+    Some(namespace)
+}
+
+pub(crate) async fn usage<Storage>(storage: &Storage, name: &str) -> Usage
+where
+    Storage: storage::Storage,
+{
+    storage
+        .get(REGISTRY_SCOPE, &usage_key(name))
+        .await
+        .and_then(|v| serde_json::from_slice(&v).ok())
+        .unwrap_or_default()
+}
+
+/// Stores the usage of a namespace, meant to go into the same batch as the
+/// writes it accounts for.
+pub(crate) fn usage_op(name: &str, usage: &Usage) -> BatchOp {
+    BatchOp::Put {
+        scope: REGISTRY_SCOPE,
+        key: usage_key(name),
+        value: serde_json::to_vec(usage).unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::{Config, MemStorage, Storage as _};
+    use crate::NodeId;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_namespaces_get_their_own_scope() {
+        let storage = block_on(MemStorage::new(NodeId(1), &Config::default())).unwrap();
+        block_on(async {
+            let quota = Quota {
+                max_keys: Some(2),
+                max_bytes: None,
+            };
+            let snot = create(&storage, "snot", quota.clone()).await.unwrap();
+            let badger = create(&storage, "badger", Quota::default()).await.unwrap();
+            assert_eq!(snot.scope, FIRST_SCOPE);
+            assert_eq!(badger.scope, FIRST_SCOPE + 1);
+            assert_eq!(get(&storage, "snot").await, Some(snot));
+            assert_eq!(list(&storage).await.len(), 2);
+            assert_eq!(usage(&storage, "snot").await, Usage::default());
+
+            let mut before = Usage::default();
+            before.change(b"a", None, Some(3));
+            before.change(b"b", None, Some(3));
+            assert_eq!(before, Usage { keys: 2, bytes: 8 });
+            let mut grown = before;
+            grown.change(b"c", None, Some(1));
+            assert!(before.exceeded_by(&grown, &quota));
+            // over quota already, but shrinking is fine
+            let mut shrunk = grown;
+            shrunk.change(b"a", Some(3), Some(1));
+            assert!(!grown.exceeded_by(&shrunk, &quota));
+        });
+    }
+
+    #[test]
+    fn test_valid_name() {
+        assert!(valid_name("snot-badger_2"));
+        assert!(!valid_name(""));
+        assert!(!valid_name("snot/badger"));
+        assert!(!valid_name(&"a".repeat(MAX_NAME_LEN + 1)));
+        assert!(reserved("lease"));
+        assert!(reserved("txn"));
+        assert!(!reserved("leases"));
+    }
+}
@@ -0,0 +1,286 @@
+// Copyright 2018-2020, Wayfair GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{prefix_end, PSEvent};
+use crate::storage::{self, BatchOp};
+use std::collections::{HashMap, VecDeque};
+
+/// Revisions are kept in a shadow scope of the kv scope so they don't show
+/// up in scans.
+const SCOPE_BIT: u16 = 0x4000;
+const REVISION: &[u8] = b"revision";
+/// key length + key + revision -> value
+const HISTORY_PREFIX: u8 = b'h';
+/// revision -> event
+const LOG_PREFIX: u8 = b'e';
+/// Previous versions kept per key.
+pub(crate) const HISTORY_SIZE: usize = 16;
+/// Events kept for watchers that reconnect.
+pub(crate) const LOG_SIZE: u64 = 4096;
+
+/// A mutation of a key, `value` is `None` for deletes.
+pub(crate) struct Change {
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,
+    pub event: PSEvent,
+}
+
+/// Every committed mutation gets the next revision. The previous versions of
+/// each key and the most recent events are kept so watchers can catch up.
+pub(crate) struct Revisions {
+    scope: u16,
+}
+
+fn history_prefix(key: &[u8]) -> Vec<u8> {
+    let mut k = vec![HISTORY_PREFIX];
+    k.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    k.extend_from_slice(key);
+    k
+}
+
+fn history_key(key: &[u8], revision: u64) -> Vec<u8> {
+    let mut k = history_prefix(key);
+    k.extend_from_slice(&revision.to_be_bytes());
+    k
+}
+
+fn log_key(revision: u64) -> Vec<u8> {
+    let mut k = vec![LOG_PREFIX];
+    k.extend_from_slice(&revision.to_be_bytes());
+    k
+}
+
+fn read_revision(data: &[u8]) -> u64 {
+    let mut revision = [0u8; 8];
+    if data.len() >= 8 {
+        revision.copy_from_slice(&data[data.len() - 8..]);
+    }
+    u64::from_be_bytes(revision)
+}
+
+// Deletes are stored as an empty value, everything else gets a marker byte.
+fn encode_version(value: &Option<Vec<u8>>) -> Vec<u8> {
+    match value {
+        Some(value) => {
+            let mut v = Vec::with_capacity(value.len() + 1);
+            v.push(1);
+            v.extend_from_slice(value);
+            v
+        }
+        None => Vec::new(),
+    }
+}
+
+fn decode_version(data: &[u8]) -> Option<Vec<u8>> {
+    if data.is_empty() {
+        None
+    } else {
+        Some(data[1..].to_vec())
+    }
+}
+
+impl Revisions {
+    pub fn new(scope: u16) -> Self {
+        Self { scope }
+    }
+
+    fn revision_scope(&self) -> u16 {
+        self.scope | SCOPE_BIT
+    }
+
+    pub async fn current<Storage>(&self, storage: &Storage) -> u64
+    where
+        Storage: storage::Storage,
+    {
+        storage
+            .get(self.revision_scope(), REVISION)
+            .await
+            .map(|v| read_revision(&v))
+            .unwrap_or_default()
+    }
+
+    /// Assigns the next revisions to changes about to be applied. Returns
+    /// the ops recording them in the key history and the event log, which
+    /// go into the same batch as the changes, and the events to publish
+    /// once it is written.
+    pub async fn record<Storage>(
+        &self,
+        storage: &Storage,
+        changes: Vec<Change>,
+    ) -> (Vec<BatchOp>, Vec<(u64, PSEvent)>)
+    where
+        Storage: storage::Storage,
+    {
+        let scope = self.revision_scope();
+        let mut revision = self.current(storage).await;
+        let mut ops = Vec::with_capacity(changes.len() * 4);
+        let mut events = Vec::with_capacity(changes.len());
+        // stored versions dropped and revisions added per key by the
+        // changes recorded so far
+        let mut pending: HashMap<Vec<u8>, (usize, VecDeque<u64>)> = HashMap::new();
+        for Change {
+            key,
+            value,
+            mut event,
+        } in changes
+        {
+            revision += 1;
+            event.set_revision(revision);
+            ops.extend(vec![
+                BatchOp::Put {
+                    scope,
+                    key: REVISION.to_vec(),
+                    value: revision.to_be_bytes().to_vec(),
+                },
+                BatchOp::Put {
+                    scope,
+                    key: history_key(&key, revision),
+                    value: encode_version(&value),
+                },
+                BatchOp::Put {
+                    scope,
+                    key: log_key(revision),
+                    value: serde_json::to_vec(&event).unwrap(),
+                },
+            ]);
+            if revision > LOG_SIZE {
+                ops.push(BatchOp::Delete {
+                    scope,
+                    key: log_key(revision - LOG_SIZE),
+                });
+            }
+            let prefix = history_prefix(&key);
+            let versions = storage
+                .scan(
+                    scope,
+                    &prefix,
+                    prefix_end(&prefix).as_deref(),
+                    HISTORY_SIZE,
+                    false,
+                )
+                .await;
+            let (dropped, added) = pending.entry(key.clone()).or_default();
+            added.push_back(revision);
+            // the oldest versions make room for the new one
+            if versions.len() - *dropped + added.len() > HISTORY_SIZE {
+                let oldest = if *dropped < versions.len() {
+                    *dropped += 1;
+                    versions[*dropped - 1].0.clone()
+                } else {
+                    history_key(&key, added.pop_front().unwrap_or_default())
+                };
+                ops.push(BatchOp::Delete { scope, key: oldest });
+            }
+            events.push((revision, event));
+        }
+        (ops, events)
+    }
+
+    /// Previous versions of a key, newest first.
+    pub async fn history<Storage>(
+        &self,
+        storage: &Storage,
+        key: &[u8],
+    ) -> Vec<(u64, Option<Vec<u8>>)>
+    where
+        Storage: storage::Storage,
+    {
+        let prefix = history_prefix(key);
+        storage
+            .scan(
+                self.revision_scope(),
+                &prefix,
+                prefix_end(&prefix).as_deref(),
+                HISTORY_SIZE,
+                true,
+            )
+            .await
+            .into_iter()
+            .map(|(k, v)| (read_revision(&k), decode_version(&v)))
+            .collect()
+    }
+
+    /// The events still kept in the log, oldest first.
+    pub async fn log<Storage>(&self, storage: &Storage) -> Vec<(u64, serde_json::Value)>
+    where
+        Storage: storage::Storage,
+    {
+        let prefix = [LOG_PREFIX];
+        storage
+            .scan(
+                self.revision_scope(),
+                &prefix,
+                prefix_end(&prefix).as_deref(),
+                LOG_SIZE as usize,
+                false,
+            )
+            .await
+            .into_iter()
+            .filter_map(|(k, v)| Some((read_revision(&k), serde_json::from_slice(&v).ok()?)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::{Config, MemStorage, Storage as _, WriteStorage as _};
+    use crate::{Encoding, NodeId};
+    use futures::executor::block_on;
+
+    fn put(key: &[u8], value: &[u8]) -> Change {
+        Change {
+            key: key.to_vec(),
+            value: Some(value.to_vec()),
+            event: PSEvent::Put {
+                scope: 0,
+                key: String::from_utf8(key.to_vec()).unwrap(),
+                new: String::from_utf8(value.to_vec()).unwrap(),
+                old: None,
+                encoding: Encoding::Raw,
+                revision: 0,
+            },
+        }
+    }
+
+    #[test]
+    fn test_history_is_bounded_per_key() {
+        let storage = block_on(MemStorage::new(NodeId(1), &Config::default())).unwrap();
+        let revisions = Revisions::new(0);
+        block_on(async {
+            let changes: Vec<Change> = (0..HISTORY_SIZE + 2)
+                .map(|i| put(b"snot", format!("badger{}", i).as_bytes()))
+                .chain(vec![put(b"snotty", b"badger")])
+                .collect();
+            let (ops, recorded) = revisions.record(&storage, changes).await;
+            storage.write_batch(&ops).await;
+            assert_eq!(recorded.len(), HISTORY_SIZE + 3);
+            assert_eq!(revisions.current(&storage).await, HISTORY_SIZE as u64 + 3);
+
+            let history = revisions.history(&storage, b"snot").await;
+            assert_eq!(history.len(), HISTORY_SIZE);
+            assert_eq!(history[0].0, HISTORY_SIZE as u64 + 2);
+            assert_eq!(history[HISTORY_SIZE - 1].0, 3);
+            assert_eq!(
+                history[0].1,
+                Some(format!("badger{}", HISTORY_SIZE + 1).into_bytes())
+            );
+            assert_eq!(revisions.history(&storage, b"snotty").await.len(), 1);
+
+            let log = revisions.log(&storage).await;
+            assert_eq!(log.len(), HISTORY_SIZE + 3);
+            assert_eq!(log[0].0, 1);
+        });
+    }
+}
@@ -0,0 +1,654 @@
+// Copyright 2018-2020, Wayfair GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::kv::lease::Leases;
+use super::kv::namespace::valid_name;
+use super::*;
+use crate::storage::BatchOp;
+use crate::{pubsub, storage, ServiceId};
+use async_std::sync::Mutex;
+use async_trait::async_trait;
+use futures::SinkExt;
+use raft::RawNode;
+use serde_derive::{Deserialize, Serialize};
+use slog::Logger;
+use std::collections::VecDeque;
+
+pub const ID: ServiceId = ServiceId(4);
+
+/// Locks live in the scope of the service like the ring does, their leases
+/// in its shadow scope.
+const SCOPE: u16 = ID.0 as u16;
+/// name -> lock
+const LOCK_PREFIX: u8 = b'l';
+/// name -> election, elections are locks of their own
+const ELECTION_PREFIX: u8 = b'e';
+/// lease id -> lock or election key, attached to the lease so it goes away
+/// with it
+const CLAIM_PREFIX: u8 = b'c';
+
+/// Locks and elections work the same way but never share a name, a lock
+/// `x` and an election `x` are two objects on two channels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Lock,
+    Election,
+}
+
+impl Kind {
+    fn prefix(self) -> u8 {
+        match self {
+            Kind::Lock => LOCK_PREFIX,
+            Kind::Election => ELECTION_PREFIX,
+        }
+    }
+
+    fn from_prefix(prefix: u8) -> Option<Self> {
+        match prefix {
+            LOCK_PREFIX => Some(Kind::Lock),
+            ELECTION_PREFIX => Some(Kind::Election),
+            _ => None,
+        }
+    }
+
+    fn key(self, name: &str) -> Vec<u8> {
+        let mut k = vec![self.prefix()];
+        k.extend_from_slice(name.as_bytes());
+        k
+    }
+
+    fn channel(self, name: &str) -> String {
+        match self {
+            Kind::Lock => format!("lock/{}", name),
+            Kind::Election => format!("election/{}", name),
+        }
+    }
+}
+
+/// A holder of or a waiter for a lock, identified by its lease.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Claim {
+    pub owner: String,
+    pub lease: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub(crate) struct Lock {
+    pub holder: Option<Claim>,
+    /// Fencing token of the holder, the raft index it became holder at
+    pub token: u64,
+    /// Waiters in the order they asked for the lock
+    pub waiters: VecDeque<Claim>,
+}
+
+/// The reply to acquiring a lock, `token` is only set for the holder.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Acquired {
+    name: String,
+    owner: String,
+    lease: u64,
+    token: Option<u64>,
+    /// 0 for the holder, otherwise the place in line
+    position: usize,
+}
+
+/// Published on `lock/<name>` or `election/<name>`, waiters learn from
+/// `Acquired` that they hold the lock now and observers of an election who
+/// leads it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum PSLock {
+    Acquired {
+        name: String,
+        owner: String,
+        lease: u64,
+        token: u64,
+    },
+    Queued {
+        name: String,
+        owner: String,
+        lease: u64,
+        position: usize,
+    },
+    Released {
+        name: String,
+        owner: String,
+        lease: u64,
+    },
+}
+
+/// Mutual exclusion on top of raft: a lock is held by one claim at a time,
+/// others wait in line. Claims are bound to a lease so the lock moves on
+/// when a holder dies, every holder gets a token that grows with each
+/// change of holders so stale holders can be fenced off.
+pub struct Service {
+    logger: Logger,
+    leases: Leases,
+    /// Raft index of the event being executed
+    index: u64,
+}
+
+impl Service {
+    pub fn new(logger: &Logger) -> Self {
+        Self {
+            logger: logger.clone(),
+            leases: Leases::new(SCOPE),
+            index: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Event {
+    Acquire {
+        name: String,
+        owner: String,
+        ttl: u64,
+    },
+    Release {
+        lease: u64,
+    },
+    KeepAlive {
+        lease: u64,
+    },
+    Expire {
+        lease: u64,
+        epoch: u64,
+    },
+    Get {
+        name: String,
+    },
+    Campaign {
+        election: String,
+        value: String,
+        ttl: u64,
+    },
+    Leader {
+        election: String,
+    },
+}
+
+impl Event {
+    pub fn acquire(name: String, owner: String, ttl: u64) -> Vec<u8> {
+        serde_json::to_vec(&Event::Acquire { name, owner, ttl }).unwrap()
+    }
+    pub fn release(lease: u64) -> Vec<u8> {
+        serde_json::to_vec(&Event::Release { lease }).unwrap()
+    }
+    pub fn keep_alive(lease: u64) -> Vec<u8> {
+        serde_json::to_vec(&Event::KeepAlive { lease }).unwrap()
+    }
+    pub fn get(name: String) -> Vec<u8> {
+        serde_json::to_vec(&Event::Get { name }).unwrap()
+    }
+    pub fn campaign(election: String, value: String, ttl: u64) -> Vec<u8> {
+        serde_json::to_vec(&Event::Campaign {
+            election,
+            value,
+            ttl,
+        })
+        .unwrap()
+    }
+    pub fn leader(election: String) -> Vec<u8> {
+        serde_json::to_vec(&Event::Leader { election }).unwrap()
+    }
+    fn expire(lease: u64, epoch: u64) -> Vec<u8> {
+        serde_json::to_vec(&Event::Expire { lease, epoch }).unwrap()
+    }
+}
+
+fn claim_key(lease: u64) -> Vec<u8> {
+    let mut k = vec![CLAIM_PREFIX];
+    k.extend_from_slice(&lease.to_be_bytes());
+    k
+}
+
+impl Lock {
+    /// Removes the claim of `lease`, returns it and whether it was the
+    /// holder.
+    fn remove(&mut self, lease: u64) -> Option<(Claim, bool)> {
+        if self.holder.as_ref().map(|h| h.lease == lease) == Some(true) {
+            return self.holder.take().map(|h| (h, true));
+        }
+        let pos = self.waiters.iter().position(|w| w.lease == lease)?;
+        self.waiters.remove(pos).map(|w| (w, false))
+    }
+
+    /// Hands the lock to the first waiter if nobody holds it.
+    fn promote(&mut self, token: u64) -> Option<&Claim> {
+        if self.holder.is_none() {
+            self.holder = self.waiters.pop_front();
+            self.token = token;
+        }
+        self.holder.as_ref()
+    }
+}
+
+impl Service {
+    async fn lock<Storage>(&self, storage: &Storage, kind: Kind, name: &str) -> Lock
+    where
+        Storage: storage::Storage,
+    {
+        storage
+            .get(SCOPE, &kind.key(name))
+            .await
+            .and_then(|v| serde_json::from_slice(&v).ok())
+            .unwrap_or_default()
+    }
+
+    /// Stores a lock, locks nobody holds or waits for are removed.
+    fn lock_op(kind: Kind, name: &str, lock: &Lock) -> BatchOp {
+        if lock.holder.is_none() && lock.waiters.is_empty() {
+            BatchOp::Delete {
+                scope: SCOPE,
+                key: kind.key(name),
+            }
+        } else {
+            BatchOp::Put {
+                scope: SCOPE,
+                key: kind.key(name),
+                value: serde_json::to_vec(lock).unwrap(),
+            }
+        }
+    }
+
+    async fn acquire<Storage>(
+        &mut self,
+        storage: &Storage,
+        pubsub: &mut pubsub::Channel,
+        kind: Kind,
+        name: String,
+        owner: String,
+        ttl: u64,
+    ) -> Result<(u16, Vec<u8>), Error>
+    where
+        Storage: storage::Storage + Send + Sync,
+    {
+        if !valid_name(&name) {
+            return Ok((400, serde_json::to_vec(&"invalid name").unwrap()));
+        }
+        if ttl == 0 {
+            return Ok((400, serde_json::to_vec(&"ttl must be positive").unwrap()));
+        }
+        let mut lock = self.lock(storage, kind, &name).await;
+        if lock
+            .holder
+            .iter()
+            .chain(&lock.waiters)
+            .any(|c| c.owner == owner)
+        {
+            return Ok((409, serde_json::to_vec(&lock).unwrap()));
+        }
+        let lease = self.leases.grant(storage, ttl).await.id;
+        lock.waiters.push_back(Claim {
+            owner: owner.clone(),
+            lease,
+        });
+        let holder = lock.promote(self.index).map(|h| h.lease) == Some(lease);
+        let position = if holder { 0 } else { lock.waiters.len() };
+        let mut ops = vec![
+            Self::lock_op(kind, &name, &lock),
+            BatchOp::Put {
+                scope: SCOPE,
+                key: claim_key(lease),
+                value: kind.key(&name),
+            },
+        ];
+        ops.extend(
+            self.leases
+                .attach_ops(storage, &claim_key(lease), Some(lease))
+                .await,
+        );
+        storage.write_batch(&ops).await;
+        let (code, acquired, msg) = if holder {
+            let msg = PSLock::Acquired {
+                name: name.clone(),
+                owner: owner.clone(),
+                lease,
+                token: lock.token,
+            };
+            (201, Some(lock.token), msg)
+        } else {
+            let msg = PSLock::Queued {
+                name: name.clone(),
+                owner: owner.clone(),
+                lease,
+                position,
+            };
+            (202, None, msg)
+        };
+        pubsub
+            .send(pubsub::Msg::new(&kind.channel(&name), msg))
+            .await
+            .unwrap();
+        let reply = Acquired {
+            name,
+            owner,
+            lease,
+            token: acquired,
+            position,
+        };
+        Ok((code, serde_json::to_vec(&reply).unwrap()))
+    }
+
+    /// Drops the claim of a lease, the next waiter becomes holder if it was
+    /// holding the lock.
+    async fn release<Storage>(
+        &mut self,
+        storage: &Storage,
+        pubsub: &mut pubsub::Channel,
+        lease: u64,
+        epoch: Option<u64>,
+    ) -> Result<(u16, Vec<u8>), Error>
+    where
+        Storage: storage::Storage + Send + Sync,
+    {
+        let (mut ops, deleted) =
+            if let Some(revoked) = self.leases.revoke(storage, lease, epoch).await {
+                revoked
+            } else {
+                return Ok((404, serde_json::to_vec(&"lease not found").unwrap()));
+            };
+        let mut released = Vec::new();
+        let mut msgs = Vec::new();
+        for (_, key) in deleted {
+            let key = if let Some(key) = key {
+                key
+            } else {
+                continue;
+            };
+            let kind = if let Some(kind) = key.first().and_then(|p| Kind::from_prefix(*p)) {
+                kind
+            } else {
+                continue;
+            };
+            let name = String::from_utf8_lossy(&key[1..]).into_owned();
+            let mut lock = self.lock(storage, kind, &name).await;
+            let (claim, was_holder) = if let Some(removed) = lock.remove(lease) {
+                removed
+            } else {
+                continue;
+            };
+            msgs.push((
+                kind.channel(&name),
+                PSLock::Released {
+                    name: name.clone(),
+                    owner: claim.owner,
+                    lease,
+                },
+            ));
+            if was_holder {
+                if let Some(next) = lock.promote(self.index) {
+                    msgs.push((
+                        kind.channel(&name),
+                        PSLock::Acquired {
+                            name: name.clone(),
+                            owner: next.owner.clone(),
+                            lease: next.lease,
+                            token: lock.token,
+                        },
+                    ));
+                }
+            }
+            ops.push(Self::lock_op(kind, &name, &lock));
+            released.push(name);
+        }
+        storage.write_batch(&ops).await;
+        for (channel, msg) in msgs {
+            pubsub.send(pubsub::Msg::new(&channel, msg)).await.unwrap();
+        }
+        Ok((200, serde_json::to_vec(&released).unwrap()))
+    }
+}
+
+#[async_trait]
+impl<Storage> super::Service<Storage> for Service
+where
+    Storage: storage::Storage + Send + Sync + 'static,
+{
+    async fn execute(
+        &mut self,
+        node: &Mutex<RawNode<Storage>>,
+        pubsub: &mut pubsub::Channel,
+        event: Vec<u8>,
+    ) -> Result<(u16, Vec<u8>), Error> {
+        let raft_node = node.try_lock().unwrap();
+        let storage = raft_node.store();
+        match serde_json::from_slice(&event) {
+            Ok(Event::Acquire { name, owner, ttl }) => {
+                debug!(self.logger, "ACQUIRE {} for {}", name, owner);
+                self.acquire(storage, pubsub, Kind::Lock, name, owner, ttl)
+                    .await
+            }
+            Ok(Event::Campaign {
+                election,
+                value,
+                ttl,
+            }) => {
+                debug!(self.logger, "CAMPAIGN {} for {}", election, value);
+                self.acquire(storage, pubsub, Kind::Election, election, value, ttl)
+                    .await
+            }
+            Ok(Event::Release { lease }) => self.release(storage, pubsub, lease, None).await,
+            Ok(Event::KeepAlive { lease }) => {
+                if let Some(lease) = self.leases.keep_alive(storage, lease).await {
+                    Ok((200, serde_json::to_vec(&lease).unwrap()))
+                } else {
+                    Ok((404, serde_json::to_vec(&"lease not found").unwrap()))
+                }
+            }
+            Ok(Event::Expire { lease, epoch }) => {
+                debug!(self.logger, "EXPIRE lease {} in epoch {}", lease, epoch);
+                self.release(storage, pubsub, lease, Some(epoch)).await
+            }
+            Ok(Event::Get { name }) | Ok(Event::Leader { election: name })
+                if !valid_name(&name) =>
+            {
+                Ok((400, serde_json::to_vec(&"invalid name").unwrap()))
+            }
+            Ok(Event::Get { name }) => {
+                let lock = self.lock(storage, Kind::Lock, &name).await;
+                Ok((200, serde_json::to_vec(&lock).unwrap()))
+            }
+            Ok(Event::Leader { election }) => {
+                let lock = self.lock(storage, Kind::Election, &election).await;
+                if let Some(holder) = lock.holder {
+                    let leader = Acquired {
+                        name: election,
+                        owner: holder.owner,
+                        lease: holder.lease,
+                        token: Some(lock.token),
+                        position: 0,
+                    };
+                    Ok((200, serde_json::to_vec(&leader).unwrap()))
+                } else {
+                    Ok((404, serde_json::to_vec(&"no leader").unwrap()))
+                }
+            }
+            _ => Err(Error::UnknownEvent),
+        }
+    }
+
+    fn is_local(&self, event: &[u8]) -> Result<bool, Error> {
+        match serde_json::from_slice(&event) {
+            Ok(Event::Get { .. }) | Ok(Event::Leader { .. }) => Ok(true),
+            Ok(_) => Ok(false),
+            _ => Err(Error::UnknownEvent),
+        }
+    }
+
+    fn applying(&mut self, index: u64) {
+        self.index = index;
+    }
+
+    async fn leader_tick(&mut self, node: &Mutex<RawNode<Storage>>) -> Result<Vec<Vec<u8>>, Error> {
+        let raft_node = node.try_lock().unwrap();
+        let storage = raft_node.store();
+        Ok(self
+            .leases
+            .expired(storage)
+            .await
+            .into_iter()
+            .map(|(lease, epoch)| Event::expire(lease, epoch))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::{Config, MemStorage, Storage as _};
+    use crate::NodeId;
+    use futures::executor::block_on;
+
+    async fn acquire(
+        s: &mut Service,
+        storage: &MemStorage,
+        pubsub: &mut pubsub::Channel,
+        index: u64,
+        owner: &str,
+    ) -> (u16, Option<Acquired>) {
+        s.index = index;
+        let (code, reply) = s
+            .acquire(storage, pubsub, Kind::Lock, "snot".into(), owner.into(), 10)
+            .await
+            .unwrap();
+        (code, serde_json::from_slice(&reply).ok())
+    }
+
+    #[test]
+    fn test_waiters_get_the_lock_in_order() {
+        let storage = block_on(MemStorage::new(NodeId(1), &Config::default())).unwrap();
+        let (mut pubsub, _rx) = futures::channel::mpsc::channel(crate::CHANNEL_SIZE);
+        let mut s = Service::new(&slog::Logger::root(slog::Discard, o!()));
+        block_on(async {
+            let (code, a) = acquire(&mut s, &storage, &mut pubsub, 3, "a").await;
+            let a = a.unwrap();
+            assert_eq!((code, a.token, a.position), (201, Some(3), 0));
+            let (code, b) = acquire(&mut s, &storage, &mut pubsub, 4, "b").await;
+            let b = b.unwrap();
+            assert_eq!((code, b.token, b.position), (202, None, 1));
+            let (code, c) = acquire(&mut s, &storage, &mut pubsub, 5, "c").await;
+            let c = c.unwrap();
+            assert_eq!((code, c.position), (202, 2));
+            let (code, _) = acquire(&mut s, &storage, &mut pubsub, 6, "b").await;
+            assert_eq!(code, 409);
+
+            // a waiter leaving doesn't change the holder
+            s.index = 7;
+            s.release(&storage, &mut pubsub, b.lease, None)
+                .await
+                .unwrap();
+            let lock = s.lock(&storage, Kind::Lock, "snot").await;
+            assert_eq!(lock.holder.unwrap().owner, "a");
+            assert_eq!(lock.token, 3);
+
+            // the holder leaving hands the lock on with a new token
+            s.index = 8;
+            s.release(&storage, &mut pubsub, a.lease, None)
+                .await
+                .unwrap();
+            let lock = s.lock(&storage, Kind::Lock, "snot").await;
+            assert_eq!(lock.holder.unwrap().owner, "c");
+            assert_eq!(lock.token, 8);
+            assert!(lock.waiters.is_empty());
+
+            s.index = 9;
+            s.release(&storage, &mut pubsub, c.lease, None)
+                .await
+                .unwrap();
+            assert_eq!(s.lock(&storage, Kind::Lock, "snot").await, Lock::default());
+            let (code, _) = s
+                .release(&storage, &mut pubsub, c.lease, None)
+                .await
+                .unwrap();
+            assert_eq!(code, 404);
+        });
+    }
+    #[test]
+    fn test_elections_are_apart_from_locks() {
+        let storage = block_on(MemStorage::new(NodeId(1), &Config::default())).unwrap();
+        let (mut pubsub, mut rx) = futures::channel::mpsc::channel(crate::CHANNEL_SIZE);
+        let mut s = Service::new(&slog::Logger::root(slog::Discard, o!()));
+        block_on(async {
+            let (code, _) = s
+                .acquire(
+                    &storage,
+                    &mut pubsub,
+                    Kind::Lock,
+                    "snot".into(),
+                    "a".into(),
+                    10,
+                )
+                .await
+                .unwrap();
+            assert_eq!(code, 201);
+            // the same owner can't queue twice for a lock but can campaign
+            // in the election of the same name
+            let (code, reply) = s
+                .acquire(
+                    &storage,
+                    &mut pubsub,
+                    Kind::Election,
+                    "snot".into(),
+                    "a".into(),
+                    10,
+                )
+                .await
+                .unwrap();
+            assert_eq!(code, 201);
+            let campaign: Acquired = serde_json::from_slice(&reply).unwrap();
+            let channels: Vec<String> = (0..2)
+                .filter_map(|_| match rx.try_next() {
+                    Ok(Some(pubsub::Msg::Msg { channel, .. })) => Some(channel),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(channels, vec!["lock/snot", "election/snot"]);
+
+            // resigning leaves the lock alone
+            s.release(&storage, &mut pubsub, campaign.lease, None)
+                .await
+                .unwrap();
+            assert_eq!(
+                s.lock(&storage, Kind::Election, "snot").await,
+                Lock::default()
+            );
+            let lock = s.lock(&storage, Kind::Lock, "snot").await;
+            assert_eq!(lock.holder.unwrap().owner, "a");
+        });
+    }
+
+    #[test]
+    fn test_names_are_validated() {
+        let storage = block_on(MemStorage::new(NodeId(1), &Config::default())).unwrap();
+        let (mut pubsub, _rx) = futures::channel::mpsc::channel(crate::CHANNEL_SIZE);
+        let mut s = Service::new(&slog::Logger::root(slog::Discard, o!()));
+        block_on(async {
+            for kind in &[Kind::Lock, Kind::Election] {
+                let (code, _) = s
+                    .acquire(
+                        &storage,
+                        &mut pubsub,
+                        *kind,
+                        "snot/badger".into(),
+                        "a".into(),
+                        10,
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(code, 400);
+            }
+        });
+    }
+}
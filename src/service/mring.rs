@@ -18,68 +18,148 @@ use crate::{pubsub, storage, ServiceId};
 use async_std::sync::Mutex;
 use async_trait::async_trait;
 use byteorder::{BigEndian, ReadBytesExt};
-use bytes::BufMut;
 use futures::SinkExt;
+use placement::{Strategy, MAX_RING_SIZE};
 use serde_derive::{Deserialize, Serialize};
 use std::io::Cursor;
-use std::marker::PhantomData;
-use uring_common::{MRingNodes, Relocations};
+use uring_common::{
+    Lookup, Lookups, MRingNodes, NodeMeta, PendingRelocation, Relocations, Resizing, Ring,
+};
 use ws_proto::PSMRing;
 pub const ID: ServiceId = ServiceId(1);
 use raft::RawNode;
 
-pub struct Service<Placement>
-where
-    Placement: placement::Placement,
-{
-    marker: PhantomData<Placement>,
-}
+/// The placement strategy is picked when the ring size is set and stored
+/// with the ring.
+#[derive(Default)]
+pub struct Service {}
 
-impl<Placement> Service<Placement>
-where
-    Placement: placement::Placement,
-{
+impl Service {
     pub fn new() -> Self {
-        Self {
-            marker: PhantomData::default(),
-        }
+        Self {}
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Event {
     GetSize,
-    SetSize { size: u64 },
+    SetSize {
+        size: u64,
+        #[serde(default)]
+        strategy: Option<String>,
+        /// Number of nodes holding each vnode, defaults to 1
+        #[serde(default)]
+        replicas: Option<u64>,
+    },
     GetNodes,
-    AddNode { node: String },
-    RemoveNode { node: String },
+    GetNode {
+        node: String,
+    },
+    AddNode {
+        node: String,
+        #[serde(flatten)]
+        meta: NodeMeta,
+    },
+    RemoveNode {
+        node: String,
+    },
+    GetRing,
+    Lookup {
+        key: String,
+    },
+    LookupMany {
+        keys: Vec<String>,
+    },
+    Resize {
+        size: u64,
+    },
+    HandoffCompleted {
+        vnode: u64,
+        node: String,
+    },
+    HandoffFailed {
+        vnode: u64,
+        node: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    GetRelocations,
+    AbandonRelocations,
 }
 
 impl Event {
     pub fn get_size() -> Vec<u8> {
         serde_json::to_vec(&Event::GetSize).unwrap()
     }
-    pub fn set_size(size: u64) -> Vec<u8> {
-        serde_json::to_vec(&Event::SetSize { size }).unwrap()
+    pub fn set_size(size: u64, strategy: Option<String>, replicas: Option<u64>) -> Vec<u8> {
+        serde_json::to_vec(&Event::SetSize {
+            size,
+            strategy,
+            replicas,
+        })
+        .unwrap()
     }
     pub fn get_nodes() -> Vec<u8> {
         serde_json::to_vec(&Event::GetNodes).unwrap()
     }
-    pub fn add_node(node: String) -> Vec<u8> {
-        serde_json::to_vec(&Event::AddNode { node }).unwrap()
+    pub fn get_node(node: String) -> Vec<u8> {
+        serde_json::to_vec(&Event::GetNode { node }).unwrap()
+    }
+    pub fn add_node(node: String, meta: NodeMeta) -> Vec<u8> {
+        serde_json::to_vec(&Event::AddNode { node, meta }).unwrap()
     }
     pub fn remove_node(node: String) -> Vec<u8> {
         serde_json::to_vec(&Event::RemoveNode { node }).unwrap()
     }
+    pub fn get_ring() -> Vec<u8> {
+        serde_json::to_vec(&Event::GetRing).unwrap()
+    }
+    pub fn lookup(key: String) -> Vec<u8> {
+        serde_json::to_vec(&Event::Lookup { key }).unwrap()
+    }
+    pub fn lookup_many(keys: Vec<String>) -> Vec<u8> {
+        serde_json::to_vec(&Event::LookupMany { keys }).unwrap()
+    }
+    pub fn resize(size: u64) -> Vec<u8> {
+        serde_json::to_vec(&Event::Resize { size }).unwrap()
+    }
+    pub fn handoff_completed(vnode: u64, node: String) -> Vec<u8> {
+        serde_json::to_vec(&Event::HandoffCompleted { vnode, node }).unwrap()
+    }
+    pub fn handoff_failed(vnode: u64, node: String, reason: Option<String>) -> Vec<u8> {
+        serde_json::to_vec(&Event::HandoffFailed {
+            vnode,
+            node,
+            reason,
+        })
+        .unwrap()
+    }
+    pub fn get_relocations() -> Vec<u8> {
+        serde_json::to_vec(&Event::GetRelocations).unwrap()
+    }
+    pub fn abandon_relocations() -> Vec<u8> {
+        serde_json::to_vec(&Event::AbandonRelocations).unwrap()
+    }
 }
 
 pub const RING_SIZE: &[u8; 9] = b"ring-size";
 pub const NODES: &[u8; 10] = b"ring-nodes";
+pub const STRATEGY: &[u8; 13] = b"ring-strategy";
+pub const REPLICAS: &[u8; 13] = b"ring-replicas";
+pub const VERSION: &[u8; 12] = b"ring-version";
+pub const RESIZING: &[u8; 13] = b"ring-resizing";
+pub const RELOCATIONS: &[u8; 16] = b"ring-relocations";
 
-impl<Placement> Service<Placement>
-where
-    Placement: placement::Placement,
-{
+/// Handoffs that failed this often are given up so they can't block ring
+/// changes forever, `AbandonRelocations` gives up on all of them at once.
+pub const MAX_HANDOFF_FAILURES: u64 = 10;
+
+/// The reply to sizes above `MAX_RING_SIZE`
+fn too_big() -> String {
+    format!("size must be at most {} vnodes", MAX_RING_SIZE)
+}
+
+impl Service {
     async fn size<Storage>(&self, storage: &Storage) -> Option<u64>
     where
         Storage: storage::Storage,
@@ -88,7 +168,8 @@ where
             .get(mring::ID.0 as u16, RING_SIZE)
             .await
             .and_then(|v| {
-                let mut rdr = Cursor::new(v);
+                // the size used to be written after 8 zero bytes
+                let mut rdr = Cursor::new(&v[v.len().saturating_sub(8)..]);
                 rdr.read_u64::<BigEndian>().ok()
             })
     }
@@ -102,24 +183,193 @@ where
             .await
             .and_then(|v| serde_json::from_slice(&v).ok())
     }
-}
 
-#[async_trait]
-impl<Storage, Placement> super::Service<Storage> for Service<Placement>
-where
-    Storage: storage::Storage + Send + Sync + 'static,
-    Placement: placement::Placement + Send + Sync,
-{
-    async fn execute(
+    /// Rings created before strategies were selectable are continuous.
+    async fn strategy<Storage>(&self, storage: &Storage) -> Strategy
+    where
+        Storage: storage::Storage,
+    {
+        storage
+            .get(mring::ID.0 as u16, STRATEGY)
+            .await
+            .and_then(|v| String::from_utf8(v).ok())
+            .and_then(|s| s.parse().ok())
+            .unwrap_or_default()
+    }
+
+    /// Rings created before replication was configurable have one copy.
+    async fn replicas<Storage>(&self, storage: &Storage) -> usize
+    where
+        Storage: storage::Storage,
+    {
+        storage
+            .get(mring::ID.0 as u16, REPLICAS)
+            .await
+            .and_then(|v| {
+                let mut rdr = Cursor::new(v);
+                rdr.read_u64::<BigEndian>().ok()
+            })
+            .unwrap_or(1) as usize
+    }
+
+    async fn version<Storage>(&self, storage: &Storage) -> u64
+    where
+        Storage: storage::Storage,
+    {
+        storage
+            .get(mring::ID.0 as u16, VERSION)
+            .await
+            .and_then(|v| {
+                let mut rdr = Cursor::new(v);
+                rdr.read_u64::<BigEndian>().ok()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Every change of the ring gets the next version, it is published with
+    /// the change so subscribers can replay from it.
+    async fn next_version<Storage>(&self, storage: &Storage) -> u64
+    where
+        Storage: storage::Storage,
+    {
+        let version = self.version(storage).await + 1;
+        storage
+            .put(mring::ID.0 as u16, VERSION, &version.to_be_bytes())
+            .await;
+        version
+    }
+
+    async fn resizing<Storage>(&self, storage: &Storage) -> Option<Resizing>
+    where
+        Storage: storage::Storage,
+    {
+        storage
+            .get(mring::ID.0 as u16, RESIZING)
+            .await
+            .and_then(|v| serde_json::from_slice(&v).ok())
+    }
+
+    /// Handoffs of the last ring change that didn't complete yet
+    async fn relocations<Storage>(&self, storage: &Storage) -> Vec<PendingRelocation>
+    where
+        Storage: storage::Storage,
+    {
+        storage
+            .get(mring::ID.0 as u16, RELOCATIONS)
+            .await
+            .and_then(|v| serde_json::from_slice(&v).ok())
+            .unwrap_or_default()
+    }
+
+    async fn store_relocations<Storage>(&self, storage: &Storage, pending: &[PendingRelocation])
+    where
+        Storage: storage::Storage,
+    {
+        if pending.is_empty() {
+            storage.delete(mring::ID.0 as u16, RELOCATIONS).await;
+        } else {
+            storage
+                .put(
+                    mring::ID.0 as u16,
+                    RELOCATIONS,
+                    &serde_json::to_vec(pending).unwrap(),
+                )
+                .await;
+        }
+    }
+
+    /// Records the handoffs of a ring change
+    async fn track<Storage>(&self, storage: &Storage, relocations: &Relocations)
+    where
+        Storage: storage::Storage,
+    {
+        let pending = PendingRelocation::from_relocations(relocations);
+        self.store_relocations(storage, &pending).await;
+    }
+
+    /// Tells a resize in progress that the handoffs in `done` completed or
+    /// were given up, the resize ends once it waits for none.
+    async fn settle<Storage>(
+        &self,
+        storage: &Storage,
+        pubsub: &mut pubsub::Channel,
+        done: &[PendingRelocation],
+    ) where
+        Storage: storage::Storage + Send + Sync,
+    {
+        let mut resizing = if let Some(resizing) = self.resizing(storage).await {
+            resizing
+        } else {
+            return;
+        };
+        for r in done {
+            resizing.complete(r.vnode, &r.dst);
+        }
+        if resizing.is_done() {
+            storage.delete(mring::ID.0 as u16, RESIZING).await;
+            let size = self.size(storage).await.unwrap_or_default();
+            let version = self.next_version(storage).await;
+            pubsub
+                .send(pubsub::Msg::versioned(
+                    "mring",
+                    version,
+                    PSMRing::ResizeCompleted { size, version },
+                ))
+                .await
+                .unwrap();
+        } else {
+            storage
+                .put(
+                    mring::ID.0 as u16,
+                    RESIZING,
+                    &serde_json::to_vec(&resizing).unwrap(),
+                )
+                .await;
+        }
+    }
+
+    /// The reply refusing a ring change while the last one is still
+    /// handing off vnodes. Handoffs stay pending until they complete or are
+    /// given up, also when their source left the ring since.
+    async fn rebalancing<Storage>(&self, storage: &Storage) -> Option<(u16, Vec<u8>)>
+    where
+        Storage: storage::Storage,
+    {
+        let pending = self.relocations(storage).await;
+        if pending.is_empty() {
+            None
+        } else {
+            let msg = format!(
+                "{} handoffs of the last ring change are in flight",
+                pending.len()
+            );
+            Some((409, serde_json::to_vec(&msg).unwrap()))
+        }
+    }
+
+    async fn ring<Storage>(&self, storage: &Storage) -> Ring
+    where
+        Storage: storage::Storage,
+    {
+        Ring {
+            version: self.version(storage).await,
+            size: self.size(storage).await.unwrap_or_default(),
+            nodes: self.nodes(storage).await.unwrap_or_default(),
+            resizing: self.resizing(storage).await,
+        }
+    }
+
+    async fn handle<Storage>(
         &mut self,
-        node: &Mutex<RawNode<Storage>>,
+        storage: &Storage,
         pubsub: &mut pubsub::Channel,
-        event: Vec<u8>,
-    ) -> Result<(u16, Vec<u8>), Error> {
-        let raft_node = node.try_lock().unwrap();
-        let storage = raft_node.store();
-        match serde_json::from_slice(&event) {
-            Ok(Event::GetSize) => {
+        event: Event,
+    ) -> Result<(u16, Vec<u8>), Error>
+    where
+        Storage: storage::Storage + Send + Sync,
+    {
+        match event {
+            Event::GetSize => {
                 if let Some(size) = self.size(storage).await {
                     Ok((
                         200u16,
@@ -132,7 +382,25 @@ where
                     ))
                 }
             }
-            Ok(Event::SetSize { size }) => {
+            Event::SetSize {
+                size,
+                strategy,
+                replicas,
+            } => {
+                let strategy: Strategy = match strategy.as_deref().map(str::parse).transpose() {
+                    Ok(strategy) => strategy.unwrap_or_default(),
+                    Err(e) => return Ok((400, serde_json::to_vec(&e).unwrap())),
+                };
+                if size > MAX_RING_SIZE {
+                    return Ok((400, serde_json::to_vec(&too_big()).unwrap()));
+                }
+                let replicas = replicas.unwrap_or(1);
+                if replicas == 0 {
+                    return Ok((
+                        400,
+                        serde_json::to_vec(&"replicas must be at least 1").unwrap(),
+                    ));
+                }
                 if let Some(size) = self.size(storage).await {
                     return Ok((
                         409,
@@ -140,16 +408,26 @@ where
                     ));
                 }
 
-                let mut data: Vec<u8> = vec![0; 8];
-                data.put_u64(size);
-                storage.put(mring::ID.0 as u16, RING_SIZE, &data).await;
+                storage
+                    .put(mring::ID.0 as u16, RING_SIZE, &size.to_be_bytes())
+                    .await;
+                storage
+                    .put(mring::ID.0 as u16, STRATEGY, strategy.name().as_bytes())
+                    .await;
+                storage
+                    .put(mring::ID.0 as u16, REPLICAS, &replicas.to_be_bytes())
+                    .await;
 
+                let version = self.next_version(storage).await;
                 pubsub
-                    .send(pubsub::Msg::new(
+                    .send(pubsub::Msg::versioned(
                         "mring",
+                        version,
                         PSMRing::SetSize {
                             size,
-                            strategy: Placement::name(),
+                            strategy: strategy.name(),
+                            replicas,
+                            version,
                         },
                     ))
                     .await
@@ -160,39 +438,68 @@ where
                     serde_json::to_vec(&serde_json::Value::from(size)).unwrap(),
                 ))
             }
-            Ok(Event::GetNodes) => Ok((200, storage.get(mring::ID.0 as u16, NODES).await.unwrap())),
-            Ok(Event::AddNode { node }) => {
+            Event::GetNodes => {
+                let nodes = self.nodes(storage).await.unwrap_or_default();
+                Ok((200, serde_json::to_vec(&nodes).unwrap()))
+            }
+            Event::GetNode { node } => {
+                let nodes = self.nodes(storage).await.unwrap_or_default();
+                if let Some(node) = nodes.into_iter().find(|n| n.id == node) {
+                    Ok((200, serde_json::to_vec(&node).unwrap()))
+                } else {
+                    Ok((404, serde_json::to_vec(&"unknown node").unwrap()))
+                }
+            }
+            Event::AddNode { node, meta } => {
+                if meta.weight == 0 {
+                    return Ok((
+                        400,
+                        serde_json::to_vec(&"weight must be at least 1").unwrap(),
+                    ));
+                }
                 let size = if let Some(size) = self.size(&*storage).await {
                     size
                 } else {
                     return Ok((412, serde_json::to_vec(&"mring size not set").unwrap()));
                 };
+                if let Some(busy) = self.rebalancing(&*storage).await {
+                    return Ok(busy);
+                }
+                let strategy = self.strategy(&*storage).await;
+                let replicas = self.replicas(&*storage).await;
+                let version = self.next_version(&*storage).await;
                 let next = if let Some(current) = self.nodes(&*storage).await {
-                    let (next, relocations) = Placement::add_node(size, current, node.clone());
+                    let (next, relocations) =
+                        strategy.add_node(size, replicas, current, node.clone(), meta);
+                    self.track(&*storage, &relocations).await;
                     pubsub
-                        .send(pubsub::Msg::new(
+                        .send(pubsub::Msg::versioned(
                             "mring",
+                            version,
                             PSMRing::NodeAdded {
                                 node,
-                                strategy: Placement::name(),
+                                strategy: strategy.name(),
                                 next: next.clone(),
                                 relocations,
+                                version,
                             },
                         ))
                         .await
                         .unwrap();
                     next
                 } else {
-                    let next = Placement::new(size, node.clone());
+                    let next = strategy.initial(size, node.clone(), meta);
 
                     pubsub
-                        .send(pubsub::Msg::new(
+                        .send(pubsub::Msg::versioned(
                             "mring",
+                            version,
                             PSMRing::NodeAdded {
                                 node,
-                                strategy: Placement::name(),
+                                strategy: strategy.name(),
                                 next: next.clone(),
                                 relocations: Relocations::new(),
+                                version,
                             },
                         ))
                         .await
@@ -203,23 +510,33 @@ where
                 storage.put(mring::ID.0 as u16, NODES, &next).await;
                 Ok((200, next))
             }
-            Ok(Event::RemoveNode { node }) => {
+            Event::RemoveNode { node } => {
                 let size = if let Some(size) = self.size(&*storage).await {
                     size
                 } else {
                     return Ok((412, serde_json::to_vec(&"mring size not set").unwrap()));
                 };
+                if let Some(busy) = self.rebalancing(&*storage).await {
+                    return Ok(busy);
+                }
 
+                let strategy = self.strategy(&*storage).await;
+                let replicas = self.replicas(&*storage).await;
                 if let Some(current) = self.nodes(&*storage).await {
-                    let (next, relocations) = Placement::remove_node(size, current, node.clone());
+                    let (next, relocations) =
+                        strategy.remove_node(size, replicas, current, node.clone());
+                    self.track(&*storage, &relocations).await;
+                    let version = self.next_version(&*storage).await;
                     pubsub
-                        .send(pubsub::Msg::new(
+                        .send(pubsub::Msg::versioned(
                             "mring",
+                            version,
                             PSMRing::NodeRemoved {
                                 node,
-                                strategy: Placement::name(),
+                                strategy: strategy.name(),
                                 next: next.clone(),
                                 relocations,
+                                version,
                             },
                         ))
                         .await
@@ -234,17 +551,464 @@ where
                     ));
                 }
             }
-            Err(_) => Err(Error::UnknownEvent),
+            Event::GetRing => Ok((200, serde_json::to_vec(&self.ring(storage).await).unwrap())),
+            Event::Lookup { key } => {
+                let ring = self.ring(storage).await;
+                if let Some(route) = ring.route(key) {
+                    let version = ring.version;
+                    Ok((200, serde_json::to_vec(&Lookup { version, route }).unwrap()))
+                } else {
+                    Ok((412, serde_json::to_vec(&"mring size not set").unwrap()))
+                }
+            }
+            Event::LookupMany { keys } => {
+                let ring = self.ring(storage).await;
+                if let Some(routes) = keys.into_iter().map(|key| ring.route(key)).collect() {
+                    let version = ring.version;
+                    Ok((
+                        200,
+                        serde_json::to_vec(&Lookups { version, routes }).unwrap(),
+                    ))
+                } else {
+                    Ok((412, serde_json::to_vec(&"mring size not set").unwrap()))
+                }
+            }
+            Event::Resize { size } => {
+                if size > MAX_RING_SIZE {
+                    return Ok((400, serde_json::to_vec(&too_big()).unwrap()));
+                }
+                let current = match self.size(storage).await {
+                    Some(current) if current > 0 => current,
+                    _ => return Ok((412, serde_json::to_vec(&"mring size not set").unwrap())),
+                };
+                if let Some(busy) = self.rebalancing(storage).await {
+                    return Ok(busy);
+                }
+                if size <= current || size % current != 0 {
+                    return Ok((
+                        400,
+                        serde_json::to_vec(&"size must be a multiple of the current size").unwrap(),
+                    ));
+                }
+                let strategy = self.strategy(storage).await;
+                let replicas = self.replicas(storage).await;
+                let nodes = self.nodes(storage).await.unwrap_or_default();
+                let (next, relocations) = strategy.resize(current, size, replicas, &nodes);
+                // the old ring keeps serving the moving vnodes until they
+                // arrived
+                let resizing = Resizing::new(current, nodes, &relocations);
+                self.track(storage, &relocations).await;
+                if !resizing.is_done() {
+                    storage
+                        .put(
+                            mring::ID.0 as u16,
+                            RESIZING,
+                            &serde_json::to_vec(&resizing).unwrap(),
+                        )
+                        .await;
+                }
+                storage
+                    .put(mring::ID.0 as u16, RING_SIZE, &size.to_be_bytes())
+                    .await;
+                storage
+                    .put(
+                        mring::ID.0 as u16,
+                        NODES,
+                        &serde_json::to_vec(&next).unwrap(),
+                    )
+                    .await;
+                let version = self.next_version(storage).await;
+                pubsub
+                    .send(pubsub::Msg::versioned(
+                        "mring",
+                        version,
+                        PSMRing::Resized {
+                            from: current,
+                            size,
+                            strategy: strategy.name(),
+                            next,
+                            relocations,
+                            version,
+                        },
+                    ))
+                    .await
+                    .unwrap();
+                Ok((200, serde_json::to_vec(&self.ring(storage).await).unwrap()))
+            }
+            Event::HandoffCompleted { vnode, node } => {
+                let (done, pending): (Vec<_>, Vec<_>) = self
+                    .relocations(storage)
+                    .await
+                    .into_iter()
+                    .partition(|r| r.vnode == vnode && r.dst == node);
+                if done.is_empty() {
+                    return Ok((
+                        404,
+                        serde_json::to_vec(&"no handoff pending for this vnode").unwrap(),
+                    ));
+                }
+                self.store_relocations(storage, &pending).await;
+                self.settle(storage, pubsub, &done).await;
+                Ok((200, serde_json::to_vec(&pending).unwrap()))
+            }
+            Event::HandoffFailed {
+                vnode,
+                node,
+                reason,
+            } => {
+                let mut pending = self.relocations(storage).await;
+                let mut found = false;
+                for r in pending
+                    .iter_mut()
+                    .filter(|r| r.vnode == vnode && r.dst == node)
+                {
+                    r.failures += 1;
+                    r.error = reason.clone();
+                    found = true;
+                }
+                if !found {
+                    return Ok((
+                        404,
+                        serde_json::to_vec(&"no handoff pending for this vnode").unwrap(),
+                    ));
+                }
+                let (pending, abandoned): (Vec<_>, Vec<_>) = pending
+                    .into_iter()
+                    .partition(|r| r.failures < MAX_HANDOFF_FAILURES);
+                self.store_relocations(storage, &pending).await;
+                self.settle(storage, pubsub, &abandoned).await;
+                Ok((200, serde_json::to_vec(&pending).unwrap()))
+            }
+            Event::GetRelocations => Ok((
+                200,
+                serde_json::to_vec(&self.relocations(storage).await).unwrap(),
+            )),
+            Event::AbandonRelocations => {
+                let abandoned = self.relocations(storage).await;
+                self.store_relocations(storage, &[]).await;
+                self.settle(storage, pubsub, &abandoned).await;
+                Ok((200, serde_json::to_vec(&abandoned).unwrap()))
+            }
         }
     }
+}
+
+#[async_trait]
+impl<Storage> super::Service<Storage> for Service
+where
+    Storage: storage::Storage + Send + Sync + 'static,
+{
+    async fn execute(
+        &mut self,
+        node: &Mutex<RawNode<Storage>>,
+        pubsub: &mut pubsub::Channel,
+        event: Vec<u8>,
+    ) -> Result<(u16, Vec<u8>), Error> {
+        let raft_node = node.try_lock().unwrap();
+        let storage = raft_node.store();
+        let event = serde_json::from_slice(&event).map_err(|_| Error::UnknownEvent)?;
+        self.handle(storage, pubsub, event).await
+    }
     fn is_local(&self, event: &[u8]) -> Result<bool, Error> {
         match serde_json::from_slice(&event) {
             Ok(Event::GetSize) => Ok(true),
             Ok(Event::GetNodes) => Ok(true),
+            Ok(Event::GetNode { .. }) => Ok(true),
             Ok(Event::SetSize { .. }) => Ok(false),
             Ok(Event::AddNode { .. }) => Ok(false),
             Ok(Event::RemoveNode { .. }) => Ok(false),
+            Ok(Event::GetRing) => Ok(true),
+            Ok(Event::Lookup { .. }) => Ok(true),
+            Ok(Event::LookupMany { .. }) => Ok(true),
+            Ok(Event::Resize { .. }) => Ok(false),
+            Ok(Event::HandoffCompleted { .. }) => Ok(false),
+            Ok(Event::HandoffFailed { .. }) => Ok(false),
+            Ok(Event::GetRelocations) => Ok(true),
+            Ok(Event::AbandonRelocations) => Ok(false),
             Err(_) => Err(Error::UnknownEvent),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::{Config, MemStorage, Storage as _, WriteStorage as _};
+    use crate::NodeId;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_size_reads_both_layouts() {
+        let storage = block_on(MemStorage::new(NodeId(1), &Config::default())).unwrap();
+        let mring = Service::new();
+        block_on(async {
+            assert_eq!(mring.size(&storage).await, None);
+            let mut legacy = vec![0; 8];
+            legacy.extend_from_slice(&64u64.to_be_bytes());
+            storage.put(mring::ID.0 as u16, RING_SIZE, &legacy).await;
+            assert_eq!(mring.size(&storage).await, Some(64));
+            storage
+                .put(mring::ID.0 as u16, RING_SIZE, &32u64.to_be_bytes())
+                .await;
+            assert_eq!(mring.size(&storage).await, Some(32));
+        });
+    }
+
+    #[test]
+    fn test_lookup_after_set_size_and_add_node() {
+        let storage = block_on(MemStorage::new(NodeId(1), &Config::default())).unwrap();
+        let (mut pubsub, _rx) = futures::channel::mpsc::channel(crate::CHANNEL_SIZE);
+        let mut mring = Service::new();
+        block_on(async {
+            let lookup = || Event::Lookup {
+                key: "foobar".into(),
+            };
+            let (code, _) = mring.handle(&storage, &mut pubsub, lookup()).await.unwrap();
+            assert_eq!(code, 412);
+
+            let set_size = Event::SetSize {
+                size: 8,
+                strategy: None,
+                replicas: None,
+            };
+            let (code, _) = mring.handle(&storage, &mut pubsub, set_size).await.unwrap();
+            assert_eq!(code, 200);
+            let add_node = Event::AddNode {
+                node: "n1".into(),
+                meta: NodeMeta::default(),
+            };
+            let (code, _) = mring.handle(&storage, &mut pubsub, add_node).await.unwrap();
+            assert_eq!(code, 200);
+
+            let (code, reply) = mring.handle(&storage, &mut pubsub, lookup()).await.unwrap();
+            assert_eq!(code, 200);
+            let lookup: Lookup = serde_json::from_slice(&reply).unwrap();
+            assert_eq!(lookup.version, 2);
+            assert_eq!(lookup.route.vnode, 0);
+            assert_eq!(lookup.route.nodes, vec!["n1"]);
+        });
+    }
+
+    #[test]
+    fn test_sizes_are_capped() {
+        let storage = block_on(MemStorage::new(NodeId(1), &Config::default())).unwrap();
+        let (mut pubsub, _rx) = futures::channel::mpsc::channel(crate::CHANNEL_SIZE);
+        let mut mring = Service::new();
+        block_on(async {
+            let set_size = |size| Event::SetSize {
+                size,
+                strategy: None,
+                replicas: None,
+            };
+            let event = set_size(MAX_RING_SIZE + 1);
+            let (code, _) = mring.handle(&storage, &mut pubsub, event).await.unwrap();
+            assert_eq!(code, 400);
+            assert_eq!(mring.size(&storage).await, None);
+
+            let event = set_size(MAX_RING_SIZE / 2);
+            let (code, _) = mring.handle(&storage, &mut pubsub, event).await.unwrap();
+            assert_eq!(code, 200);
+            let event = Event::Resize {
+                size: MAX_RING_SIZE * 2,
+            };
+            let (code, _) = mring.handle(&storage, &mut pubsub, event).await.unwrap();
+            assert_eq!(code, 400);
+            assert_eq!(mring.size(&storage).await, Some(MAX_RING_SIZE / 2));
+        });
+    }
+    /// A ring of 8 vnodes with n1 and n2, n1 still handing 4 to 7 to n2
+    async fn two_nodes(mring: &mut Service, storage: &MemStorage, pubsub: &mut pubsub::Channel) {
+        let set_size = Event::SetSize {
+            size: 8,
+            strategy: None,
+            replicas: None,
+        };
+        assert_eq!(
+            mring.handle(storage, pubsub, set_size).await.unwrap().0,
+            200
+        );
+        for node in &["n1", "n2"] {
+            let (code, _) = mring.handle(storage, pubsub, add(node)).await.unwrap();
+            assert_eq!(code, 200);
+        }
+    }
+
+    fn add(node: &str) -> Event {
+        Event::AddNode {
+            node: node.into(),
+            meta: NodeMeta::default(),
+        }
+    }
+
+    fn completed(vnode: u64, node: &str) -> Event {
+        Event::HandoffCompleted {
+            vnode,
+            node: node.into(),
+        }
+    }
+
+    fn failed(vnode: u64, node: &str) -> Event {
+        Event::HandoffFailed {
+            vnode,
+            node: node.into(),
+            reason: Some("badger".into()),
+        }
+    }
+
+    #[test]
+    fn test_handoffs_hold_up_ring_changes() {
+        let storage = block_on(MemStorage::new(NodeId(1), &Config::default())).unwrap();
+        let (mut pubsub, _rx) = futures::channel::mpsc::channel(crate::CHANNEL_SIZE);
+        let mut mring = Service::new();
+        block_on(async {
+            two_nodes(&mut mring, &storage, &mut pubsub).await;
+            let pending: Vec<_> = mring
+                .relocations(&storage)
+                .await
+                .into_iter()
+                .map(|r| (r.vnode, r.src, r.dst))
+                .collect();
+            let moved = |v| (v, "n1".to_string(), "n2".to_string());
+            assert_eq!(pending, vec![moved(4), moved(5), moved(6), moved(7)]);
+
+            let (code, _) = mring
+                .handle(&storage, &mut pubsub, add("n3"))
+                .await
+                .unwrap();
+            assert_eq!(code, 409);
+            let (code, _) = mring
+                .handle(&storage, &mut pubsub, completed(4, "n3"))
+                .await
+                .unwrap();
+            assert_eq!(code, 404);
+            for vnode in 4..7 {
+                let event = completed(vnode, "n2");
+                let (code, _) = mring.handle(&storage, &mut pubsub, event).await.unwrap();
+                assert_eq!(code, 200);
+            }
+            let (code, _) = mring
+                .handle(&storage, &mut pubsub, add("n3"))
+                .await
+                .unwrap();
+            assert_eq!(code, 409);
+            let event = completed(7, "n2");
+            let (code, _) = mring.handle(&storage, &mut pubsub, event).await.unwrap();
+            assert_eq!(code, 200);
+            assert!(mring.relocations(&storage).await.is_empty());
+            let (code, _) = mring
+                .handle(&storage, &mut pubsub, add("n3"))
+                .await
+                .unwrap();
+            assert_eq!(code, 200);
+        });
+    }
+
+    #[test]
+    fn test_failed_handoffs_are_given_up() {
+        let storage = block_on(MemStorage::new(NodeId(1), &Config::default())).unwrap();
+        let (mut pubsub, _rx) = futures::channel::mpsc::channel(crate::CHANNEL_SIZE);
+        let mut mring = Service::new();
+        block_on(async {
+            two_nodes(&mut mring, &storage, &mut pubsub).await;
+            let (code, _) = mring
+                .handle(&storage, &mut pubsub, failed(4, "n1"))
+                .await
+                .unwrap();
+            assert_eq!(code, 404);
+            let (code, _) = mring
+                .handle(&storage, &mut pubsub, failed(4, "n2"))
+                .await
+                .unwrap();
+            assert_eq!(code, 200);
+            let pending = mring.relocations(&storage).await;
+            assert_eq!(pending[0].vnode, 4);
+            assert_eq!(pending[0].failures, 1);
+            assert_eq!(pending[0].error.as_deref(), Some("badger"));
+
+            for _ in 1..MAX_HANDOFF_FAILURES {
+                let event = failed(4, "n2");
+                let (code, _) = mring.handle(&storage, &mut pubsub, event).await.unwrap();
+                assert_eq!(code, 200);
+            }
+            let pending = mring.relocations(&storage).await;
+            assert_eq!(
+                pending.iter().map(|r| r.vnode).collect::<Vec<_>>(),
+                vec![5, 6, 7]
+            );
+
+            let event = Event::AbandonRelocations;
+            let (code, reply) = mring.handle(&storage, &mut pubsub, event).await.unwrap();
+            assert_eq!(code, 200);
+            let abandoned: Vec<PendingRelocation> = serde_json::from_slice(&reply).unwrap();
+            assert_eq!(abandoned, pending);
+            assert!(mring.relocations(&storage).await.is_empty());
+            let (code, _) = mring
+                .handle(&storage, &mut pubsub, add("n3"))
+                .await
+                .unwrap();
+            assert_eq!(code, 200);
+        });
+    }
+
+    #[test]
+    fn test_handoffs_from_removed_nodes_stay_pending() {
+        let storage = block_on(MemStorage::new(NodeId(1), &Config::default())).unwrap();
+        let (mut pubsub, _rx) = futures::channel::mpsc::channel(crate::CHANNEL_SIZE);
+        let mut mring = Service::new();
+        block_on(async {
+            two_nodes(&mut mring, &storage, &mut pubsub).await;
+            for vnode in 4..8 {
+                let event = completed(vnode, "n2");
+                mring.handle(&storage, &mut pubsub, event).await.unwrap();
+            }
+            let event = Event::RemoveNode { node: "n2".into() };
+            let (code, _) = mring.handle(&storage, &mut pubsub, event).await.unwrap();
+            assert_eq!(code, 200);
+            let pending = mring.relocations(&storage).await;
+            assert!(!pending.is_empty());
+            assert!(pending.iter().all(|r| r.src == "n2"));
+
+            // n2 left but still has to hand its vnodes off
+            let (code, _) = mring
+                .handle(&storage, &mut pubsub, add("n3"))
+                .await
+                .unwrap();
+            assert_eq!(code, 409);
+            assert_eq!(mring.relocations(&storage).await, pending);
+
+            for r in pending {
+                let event = completed(r.vnode, &r.dst);
+                mring.handle(&storage, &mut pubsub, event).await.unwrap();
+            }
+            let (code, _) = mring
+                .handle(&storage, &mut pubsub, add("n3"))
+                .await
+                .unwrap();
+            assert_eq!(code, 200);
+        });
+    }
+
+    #[test]
+    fn test_abandoning_ends_a_resize() {
+        let storage = block_on(MemStorage::new(NodeId(1), &Config::default())).unwrap();
+        let (mut pubsub, _rx) = futures::channel::mpsc::channel(crate::CHANNEL_SIZE);
+        let mut mring = Service::new();
+        block_on(async {
+            two_nodes(&mut mring, &storage, &mut pubsub).await;
+            mring
+                .handle(&storage, &mut pubsub, Event::AbandonRelocations)
+                .await
+                .unwrap();
+            let event = Event::Resize { size: 16 };
+            let (code, _) = mring.handle(&storage, &mut pubsub, event).await.unwrap();
+            assert_eq!(code, 200);
+            assert!(mring.resizing(&storage).await.is_some());
+
+            let event = Event::AbandonRelocations;
+            let (code, _) = mring.handle(&storage, &mut pubsub, event).await.unwrap();
+            assert_eq!(code, 200);
+            assert!(mring.resizing(&storage).await.is_none());
+            assert_eq!(mring.size(&storage).await, Some(16));
+        });
+    }
+}
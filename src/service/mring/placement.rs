@@ -13,11 +13,583 @@
 // limitations under the License.
 
 pub mod continuous;
-use uring_common::{MRingNodes, Relocations};
+pub mod rendezvous;
+pub mod weighted;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use uring_common::{MRingNode, MRingNodes, NodeMeta, Relocations};
 
 pub trait Placement {
-    fn add_node(count: u64, current: MRingNodes, new: String) -> (MRingNodes, Relocations);
+    fn add_node(
+        count: u64,
+        current: MRingNodes,
+        new: String,
+        meta: NodeMeta,
+    ) -> (MRingNodes, Relocations);
     fn remove_node(count: u64, current: MRingNodes, old: String) -> (MRingNodes, Relocations);
-    fn new(count: u64, new: String) -> MRingNodes;
+    fn new(count: u64, new: String, meta: NodeMeta) -> MRingNodes;
     fn name() -> String;
 }
+
+fn relocate(rs: &mut Relocations, src: &str, dst: &str, vnode: u64) {
+    rs.entry(src.to_string())
+        .or_default()
+        .destinations
+        .entry(dst.to_string())
+        .or_default()
+        .push(vnode);
+}
+
+fn sort_relocations(rs: &mut Relocations) {
+    for r in rs.values_mut() {
+        for moves in r.destinations.values_mut() {
+            moves.sort();
+        }
+        for copies in r.replicas.values_mut() {
+            copies.sort();
+        }
+        r.dropped.sort();
+    }
+}
+
+/// The most vnodes a ring can have. Every ring change publishes the vnodes
+/// of all nodes and the relocations between them, and placing vnodes takes
+/// work per vnode, so sizes are capped before any of that is computed.
+pub const MAX_RING_SIZE: u64 = 1 << 16;
+
+/// The failure domain of a node, a node without a zone is a zone of its own.
+fn zone(node: &MRingNode) -> &str {
+    node.meta.zone.as_deref().unwrap_or(&node.id)
+}
+
+/// The owner of `vnode` followed by the nodes after it in ring order,
+/// skipping zones that already hold the vnode. `owners` maps vnodes to the
+/// position of their owner in `nodes`.
+fn successors<'n>(
+    nodes: &'n [MRingNode],
+    owners: &HashMap<u64, usize>,
+    vnode: u64,
+    replicas: usize,
+) -> Vec<&'n MRingNode> {
+    let owner = if let Some(owner) = owners.get(&vnode) {
+        *owner
+    } else {
+        return Vec::new();
+    };
+    let mut zones = HashSet::new();
+    nodes[owner..]
+        .iter()
+        .chain(&nodes[..owner])
+        .filter(|n| zones.insert(zone(n)))
+        .take(replicas)
+        .collect()
+}
+
+/// The placement strategies a ring can be created with, the choice is
+/// stored with the ring so every node places vnodes the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    Continuous,
+    Rendezvous,
+    Weighted,
+}
+
+impl Default for Strategy {
+    fn default() -> Self {
+        Self::Continuous
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "continuous" => Ok(Self::Continuous),
+            "rendezvous" => Ok(Self::Rendezvous),
+            "weighted" => Ok(Self::Weighted),
+            other => Err(format!("unknown placement strategy `{}`", other)),
+        }
+    }
+}
+
+impl Strategy {
+    pub fn name(self) -> String {
+        match self {
+            Self::Continuous => continuous::Strategy::name(),
+            Self::Rendezvous => rendezvous::Strategy::name(),
+            Self::Weighted => weighted::Strategy::name(),
+        }
+    }
+    pub fn initial(self, count: u64, new: String, meta: NodeMeta) -> MRingNodes {
+        match self {
+            Self::Continuous => continuous::Strategy::new(count, new, meta),
+            Self::Rendezvous => rendezvous::Strategy::new(count, new, meta),
+            Self::Weighted => weighted::Strategy::new(count, new, meta),
+        }
+    }
+    pub fn add_node(
+        self,
+        count: u64,
+        replicas: usize,
+        current: MRingNodes,
+        new: String,
+        meta: NodeMeta,
+    ) -> (MRingNodes, Relocations) {
+        let before = current.clone();
+        let (mut next, mut rs) = self.place(count, current, new, meta);
+        self.replicate(count, replicas, &before, &mut next, &mut rs);
+        (next, rs)
+    }
+    pub fn remove_node(
+        self,
+        count: u64,
+        replicas: usize,
+        current: MRingNodes,
+        old: String,
+    ) -> (MRingNodes, Relocations) {
+        let before = current.clone();
+        let (mut next, mut rs) = match self {
+            Self::Continuous => continuous::Strategy::remove_node(count, current, old),
+            Self::Rendezvous => rendezvous::Strategy::remove_node(count, current, old),
+            Self::Weighted => weighted::Strategy::remove_node(count, current, old),
+        };
+        self.replicate(count, replicas, &before, &mut next, &mut rs);
+        (next, rs)
+    }
+
+    /// Splits every vnode into `size / count` vnodes, a key of vnode `v`
+    /// ends up in one of `v + i * count`. The new vnodes start out on the
+    /// nodes holding `v` and are handed to where the strategy places them
+    /// in a ring of the new size built from the same nodes. `size` is
+    /// expected to be checked against `MAX_RING_SIZE` by the caller.
+    pub fn resize(
+        self,
+        count: u64,
+        size: u64,
+        replicas: usize,
+        current: &[MRingNode],
+    ) -> (MRingNodes, Relocations) {
+        let split = |vnodes: &[u64]| -> Vec<u64> {
+            let mut split: Vec<u64> = (0..size / count)
+                .flat_map(|i| vnodes.iter().map(move |v| v + i * count))
+                .collect();
+            split.sort();
+            split
+        };
+        let before: MRingNodes = current
+            .iter()
+            .map(|n| MRingNode {
+                vnodes: split(&n.vnodes),
+                replicas: split(&n.replicas),
+                // ranks only matter for the ring after the resize
+                ranks: Vec::new(),
+                ..n.clone()
+            })
+            .collect();
+
+        let mut members = current.iter();
+        let mut next = if let Some(first) = members.next() {
+            self.initial(size, first.id.clone(), first.meta.clone())
+        } else {
+            return (before, Relocations::new());
+        };
+        for n in members {
+            next = self.place(size, next, n.id.clone(), n.meta.clone()).0;
+        }
+
+        let mut rs = Relocations::new();
+        let owners: HashMap<u64, &str> = before
+            .iter()
+            .flat_map(|n| n.vnodes.iter().map(move |v| (*v, n.id.as_str())))
+            .collect();
+        for n in &next {
+            for vnode in &n.vnodes {
+                match owners.get(vnode) {
+                    Some(src) if *src != n.id => relocate(&mut rs, src, &n.id, *vnode),
+                    _ => (),
+                }
+            }
+        }
+        self.replicate(size, replicas, &before, &mut next, &mut rs);
+        (next, rs)
+    }
+
+    /// Places a new node without touching replicas.
+    fn place(
+        self,
+        count: u64,
+        current: MRingNodes,
+        new: String,
+        meta: NodeMeta,
+    ) -> (MRingNodes, Relocations) {
+        match self {
+            Self::Continuous => continuous::Strategy::add_node(count, current, new, meta),
+            Self::Rendezvous => rendezvous::Strategy::add_node(count, current, new, meta),
+            Self::Weighted => weighted::Strategy::add_node(count, current, new, meta),
+        }
+    }
+
+    /// For every vnode of a ring of `count` vnodes up to `replicas` nodes
+    /// that hold it, the primary first and no two in the same zone.
+    pub fn preferences(
+        self,
+        nodes: &[MRingNode],
+        count: u64,
+        replicas: usize,
+    ) -> Vec<Vec<&MRingNode>> {
+        match self {
+            Self::Continuous | Self::Rendezvous => {
+                let owners: HashMap<u64, usize> = nodes
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(i, n)| n.vnodes.iter().map(move |v| (*v, i)))
+                    .collect();
+                (0..count)
+                    .map(|vnode| successors(nodes, &owners, vnode, replicas))
+                    .collect()
+            }
+            Self::Weighted => (0..count)
+                .map(|vnode| weighted::preference(nodes, vnode, replicas))
+                .collect(),
+        }
+    }
+
+    /// Assigns replicas along the preference lists of `next` and records
+    /// the replicas to copy, from the primary before the change, and the
+    /// replicas to drop.
+    fn replicate(
+        self,
+        count: u64,
+        replicas: usize,
+        before: &[MRingNode],
+        next: &mut MRingNodes,
+        rs: &mut Relocations,
+    ) {
+        let mut held: HashMap<String, Vec<(u64, usize)>> = HashMap::new();
+        let preferences = self.preferences(next, count, replicas);
+        for (vnode, preference) in (0..count).zip(preferences) {
+            for (rank, n) in preference.into_iter().enumerate().skip(1) {
+                held.entry(n.id.clone()).or_default().push((vnode, rank));
+            }
+        }
+        for n in next.iter_mut() {
+            let (replicas, ranks) = held.remove(&n.id).unwrap_or_default().into_iter().unzip();
+            n.replicas = replicas;
+            n.ranks = ranks;
+        }
+
+        let holders = |nodes: &[MRingNode]| -> HashSet<(String, u64)> {
+            nodes
+                .iter()
+                .flat_map(|n| {
+                    n.vnodes
+                        .iter()
+                        .chain(&n.replicas)
+                        .map(move |v| (n.id.clone(), *v))
+                })
+                .collect()
+        };
+        let (held_before, held_next) = (holders(before), holders(next));
+        let primaries: HashMap<u64, &str> = before
+            .iter()
+            .flat_map(|n| n.vnodes.iter().map(move |v| (*v, n.id.as_str())))
+            .collect();
+        for n in next.iter() {
+            for vnode in &n.replicas {
+                if held_before.contains(&(n.id.clone(), *vnode)) {
+                    continue;
+                }
+                if let Some(src) = primaries.get(vnode) {
+                    rs.entry((*src).to_string())
+                        .or_default()
+                        .replicas
+                        .entry(n.id.clone())
+                        .or_default()
+                        .push(*vnode);
+                }
+            }
+        }
+        // nodes leaving the ring give up everything anyway
+        let members: HashSet<&str> = next.iter().map(|n| n.id.as_str()).collect();
+        for n in before.iter().filter(|n| members.contains(n.id.as_str())) {
+            for vnode in &n.replicas {
+                if !held_next.contains(&(n.id.clone(), *vnode)) {
+                    rs.entry(n.id.clone()).or_default().dropped.push(*vnode);
+                }
+            }
+        }
+        sort_relocations(rs);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use uring_common::{vnode_nodes, MRingNode};
+
+    pub(crate) fn ring(nodes: &[(&str, &[u64])]) -> MRingNodes {
+        nodes
+            .iter()
+            .map(|(id, vnodes)| MRingNode {
+                id: (*id).to_string(),
+                vnodes: vnodes.to_vec(),
+                ..MRingNode::default()
+            })
+            .collect()
+    }
+
+    /// Flattens relocations into sorted `(src, dst, vnodes)` triples.
+    pub(crate) fn moves(rs: &Relocations) -> Vec<(&str, &str, Vec<u64>)> {
+        let mut moves: Vec<_> = rs
+            .iter()
+            .flat_map(|(src, r)| {
+                r.destinations
+                    .iter()
+                    .filter(|(_, vnodes)| !vnodes.is_empty())
+                    .map(move |(dst, vnodes)| (src.as_str(), dst.as_str(), vnodes.clone()))
+            })
+            .collect();
+        moves.sort();
+        moves
+    }
+
+    /// xorshift, so the properties are checked against the same rings on
+    /// every run.
+    pub(crate) struct Rng(pub(crate) u64);
+
+    impl Rng {
+        pub(crate) fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+
+        /// Weights from 1 to 4 spread over four zones.
+        pub(crate) fn meta(&mut self) -> NodeMeta {
+            NodeMeta {
+                weight: 1 + self.below(4) as u32,
+                zone: Some(format!("z{}", self.below(4))),
+                rack: None,
+            }
+        }
+    }
+
+    fn owners(nodes: &[MRingNode]) -> HashMap<u64, &str> {
+        nodes
+            .iter()
+            .flat_map(|n| n.vnodes.iter().map(move |v| (*v, n.id.as_str())))
+            .collect()
+    }
+
+    /// A node joining or leaving the ring.
+    pub(crate) struct Step<'a> {
+        pub(crate) node: &'a str,
+        pub(crate) joined: bool,
+        pub(crate) next: &'a [MRingNode],
+        pub(crate) moves: Vec<(&'a str, &'a str, Vec<u64>)>,
+        /// The least number of vnodes a balanced placement has to move
+        pub(crate) minimum: usize,
+    }
+
+    impl<'a> Step<'a> {
+        pub(crate) fn moved(&self) -> usize {
+            self.moves.iter().map(|(_, _, vnodes)| vnodes.len()).sum()
+        }
+
+        pub(crate) fn assert_balanced(&self) {
+            let sizes = self.next.iter().map(|n| n.vnodes.len());
+            assert!(sizes.clone().max().unwrap() - sizes.min().unwrap() <= 1);
+        }
+    }
+
+    /// Grows and shrinks rings of random sizes by random joins and leaves
+    /// and checks after every step that each vnode has exactly one owner
+    /// and that the relocations turn the old placement into the new one.
+    /// Strategy specific properties are checked by `check` on every step.
+    pub(crate) fn check_properties<P: Placement>(check: impl Fn(&Step)) {
+        for seed in 1..=32 {
+            let mut rng = Rng(seed);
+            let count = 1 + rng.below(256);
+            let mut nodes = P::new(count, "n0".into(), rng.meta());
+            let mut next_id = 1;
+            for _ in 0..64 {
+                let joined = nodes.len() == 1 || (nodes.len() < 16 && rng.below(3) != 0);
+                let (node, next, rs, minimum) = if joined {
+                    let node = format!("n{}", next_id);
+                    next_id += 1;
+                    let (next, rs) = P::add_node(count, nodes.clone(), node.clone(), rng.meta());
+                    let minimum = count as usize / next.len();
+                    (node, next, rs, minimum)
+                } else {
+                    let leaving = &nodes[rng.below(nodes.len() as u64) as usize];
+                    let node = leaving.id.clone();
+                    let minimum = leaving.vnodes.len();
+                    let (next, rs) = P::remove_node(count, nodes.clone(), node.clone());
+                    (node, next, rs, minimum)
+                };
+
+                let after = owners(&next);
+                assert_eq!(after.len() as u64, count);
+                assert_eq!(
+                    next.iter().map(|n| n.vnodes.len() as u64).sum::<u64>(),
+                    count
+                );
+
+                let mut before = owners(&nodes);
+                let moves = moves(&rs);
+                for (src, dst, vnodes) in &moves {
+                    for v in vnodes {
+                        assert_eq!(before.insert(*v, *dst), Some(*src));
+                    }
+                }
+                assert_eq!(before, after);
+                check(&Step {
+                    node: &node,
+                    joined,
+                    next: &next,
+                    moves,
+                    minimum,
+                });
+                nodes = next;
+            }
+        }
+    }
+
+    fn holdings(nodes: &[MRingNode]) -> HashSet<(String, u64)> {
+        nodes
+            .iter()
+            .flat_map(|n| {
+                n.vnodes
+                    .iter()
+                    .chain(&n.replicas)
+                    .map(move |v| (n.id.clone(), *v))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replicas_follow_relocations() {
+        let count = 64;
+        for strategy in &[
+            Strategy::Continuous,
+            Strategy::Rendezvous,
+            Strategy::Weighted,
+        ] {
+            let mut rng = Rng(7);
+            let mut nodes = strategy.initial(count, "n0".into(), rng.meta());
+            let mut next_id = 1;
+            for _ in 0..48 {
+                let (next, rs) = if nodes.len() == 1 || (nodes.len() < 12 && rng.below(3) != 0) {
+                    let node = format!("n{}", next_id);
+                    next_id += 1;
+                    strategy.add_node(count, 3, nodes.clone(), node, rng.meta())
+                } else {
+                    let node = nodes[rng.below(nodes.len() as u64) as usize].id.clone();
+                    strategy.remove_node(count, 3, nodes.clone(), node)
+                };
+
+                // every vnode is held three times, or once per zone if
+                // there are fewer zones
+                let zones: HashSet<&str> = next.iter().map(zone).collect();
+                let preferences = strategy.preferences(&next, count, 3);
+                for vnode in 0..count {
+                    // routes list replicas in the order of the preference
+                    // list
+                    let preference: Vec<String> = preferences[vnode as usize]
+                        .iter()
+                        .map(|n| n.id.clone())
+                        .collect();
+                    assert_eq!(vnode_nodes(&next, vnode), preference);
+                    let holders: HashSet<&str> = next
+                        .iter()
+                        .filter(|n| n.vnodes.contains(&vnode) || n.replicas.contains(&vnode))
+                        .map(zone)
+                        .collect();
+                    assert_eq!(holders.len(), zones.len().min(3));
+                }
+
+                // copies come from nodes holding the vnode, sources of a
+                // primary move keep what they still replicate
+                let after = holdings(&next);
+                let mut held = holdings(&nodes);
+                for (src, r) in &rs {
+                    for (dst, vnodes) in r.destinations.iter().chain(&r.replicas) {
+                        for v in vnodes {
+                            assert!(held.contains(&(src.clone(), *v)));
+                            held.insert((dst.clone(), *v));
+                        }
+                    }
+                }
+                for (src, r) in &rs {
+                    for vnodes in r.destinations.values() {
+                        for v in vnodes {
+                            if !after.contains(&(src.clone(), *v)) {
+                                held.remove(&(src.clone(), *v));
+                            }
+                        }
+                    }
+                    for v in &r.dropped {
+                        assert!(held.remove(&(src.clone(), *v)));
+                    }
+                }
+                held.retain(|(id, _)| next.iter().any(|n| &n.id == id));
+                assert_eq!(held, after);
+                nodes = next;
+            }
+        }
+    }
+
+    #[test]
+    fn resize_splits_vnodes() {
+        let (count, size) = (8, 32);
+        for strategy in &[
+            Strategy::Continuous,
+            Strategy::Rendezvous,
+            Strategy::Weighted,
+        ] {
+            let mut rng = Rng(11);
+            let mut nodes = strategy.initial(count, "n0".into(), rng.meta());
+            for i in 1..5 {
+                let node = format!("n{}", i);
+                nodes = strategy.add_node(count, 2, nodes, node, rng.meta()).0;
+            }
+            let (next, rs) = strategy.resize(count, size, 2, &nodes);
+
+            // the new vnodes move from the nodes holding the vnode they
+            // were split from
+            let held = holdings(&nodes);
+            let mut before: HashMap<u64, &str> = owners(&nodes)
+                .into_iter()
+                .flat_map(|(v, id)| (0..size / count).map(move |i| (v + i * count, id)))
+                .collect();
+            for (src, r) in &rs {
+                for (dst, vnodes) in &r.destinations {
+                    for v in vnodes {
+                        assert!(held.contains(&(src.clone(), v % count)));
+                        assert_eq!(before.insert(*v, dst), Some(src.as_str()));
+                    }
+                }
+                for vnodes in r.replicas.values() {
+                    for v in vnodes {
+                        assert!(held.contains(&(src.clone(), v % count)));
+                    }
+                }
+            }
+            assert_eq!(before, owners(&next));
+            assert_eq!(before.len() as u64, size);
+
+            // the result is the ring the strategy builds at the new size
+            let mut members = nodes.iter();
+            let first = members.next().unwrap();
+            let mut rebuilt = strategy.initial(size, first.id.clone(), first.meta.clone());
+            for n in members {
+                rebuilt = strategy
+                    .add_node(size, 2, rebuilt, n.id.clone(), n.meta.clone())
+                    .0;
+            }
+            assert_eq!(next, rebuilt);
+        }
+    }
+}
@@ -13,7 +13,7 @@
 // limitations under the License.
 
 use super::Placement;
-use uring_common::{MRingNode, MRingNodes, Relocation, Relocations};
+use uring_common::{MRingNode, MRingNodes, NodeMeta, Relocation, Relocations};
 
 pub struct Strategy {}
 
@@ -21,13 +21,21 @@ impl Placement for Strategy {
     fn name() -> String {
         "continuous".into()
     }
-    fn new(count: u64, new: String) -> MRingNodes {
+    fn new(count: u64, new: String, meta: NodeMeta) -> MRingNodes {
         vec![MRingNode {
             id: new,
             vnodes: (0..count).collect(),
+            replicas: Vec::new(),
+            ranks: Vec::new(),
+            meta,
         }]
     }
-    fn add_node(count: u64, mut current: MRingNodes, new: String) -> (MRingNodes, Relocations) {
+    fn add_node(
+        count: u64,
+        mut current: MRingNodes,
+        new: String,
+        meta: NodeMeta,
+    ) -> (MRingNodes, Relocations) {
         let mut rs = Relocations::new();
         // skip nodes we know
         for n in &current {
@@ -38,6 +46,9 @@ impl Placement for Strategy {
         let new_node = MRingNode {
             id: new,
             vnodes: Vec::new(),
+            replicas: Vec::new(),
+            ranks: Vec::new(),
+            meta,
         };
         current.push(new_node);
         let vnodes_per_node = count as usize / current.len();
@@ -142,6 +153,7 @@ impl Placement for Strategy {
 
 #[cfg(test)]
 mod test {
+    use super::super::test::check_properties;
     use super::*;
     macro_rules! assert_move {
         ($r:expr, $src:expr, $($dst:expr, $exp:expr),*) => {
@@ -161,46 +173,52 @@ mod test {
     }
     #[test]
     fn continuous_add() {
-        let p = Strategy::new(8, "n1".into());
+        let p = Strategy::new(8, "n1".into(), NodeMeta::default());
         assert_eq!(
             p,
             vec![MRingNode {
                 id: "n1".into(),
-                vnodes: vec![0, 1, 2, 3, 4, 5, 6, 7]
+                vnodes: vec![0, 1, 2, 3, 4, 5, 6, 7],
+                ..MRingNode::default()
             }]
         );
-        let (p, mut r) = Strategy::add_node(8, p, "n2".into());
+        let (p, mut r) = Strategy::add_node(8, p, "n2".into(), NodeMeta::default());
         assert_eq!(
             p,
             vec![
                 MRingNode {
                     id: "n1".into(),
-                    vnodes: vec![0, 1, 2, 3]
+                    vnodes: vec![0, 1, 2, 3],
+                    ..MRingNode::default()
                 },
                 MRingNode {
                     id: "n2".into(),
-                    vnodes: vec![4, 5, 6, 7]
+                    vnodes: vec![4, 5, 6, 7],
+                    ..MRingNode::default()
                 }
             ]
         );
         assert_move!(r, "n1", "n2", &[4, 5, 6, 7]);
         assert!(r.is_empty());
 
-        let (p, mut r) = Strategy::add_node(8, p, "n3".into());
+        let (p, mut r) = Strategy::add_node(8, p, "n3".into(), NodeMeta::default());
         assert_eq!(
             p,
             vec![
                 MRingNode {
                     id: "n1".into(),
-                    vnodes: vec![0, 1, 2]
+                    vnodes: vec![0, 1, 2],
+                    ..MRingNode::default()
                 },
                 MRingNode {
                     id: "n2".into(),
-                    vnodes: vec![3, 4, 5]
+                    vnodes: vec![3, 4, 5],
+                    ..MRingNode::default()
                 },
                 MRingNode {
                     id: "n3".into(),
-                    vnodes: vec![6, 7]
+                    vnodes: vec![6, 7],
+                    ..MRingNode::default()
                 }
             ]
         );
@@ -208,25 +226,29 @@ mod test {
         assert_move!(r, "n2", "n3", &[6, 7]);
         assert!(r.is_empty());
 
-        let (p, mut r) = Strategy::add_node(8, p, "n4".into());
+        let (p, mut r) = Strategy::add_node(8, p, "n4".into(), NodeMeta::default());
         assert_eq!(
             p,
             vec![
                 MRingNode {
                     id: "n1".into(),
-                    vnodes: vec![0, 1]
+                    vnodes: vec![0, 1],
+                    ..MRingNode::default()
                 },
                 MRingNode {
                     id: "n2".into(),
-                    vnodes: vec![2, 3]
+                    vnodes: vec![2, 3],
+                    ..MRingNode::default()
                 },
                 MRingNode {
                     id: "n3".into(),
-                    vnodes: vec![4, 5]
+                    vnodes: vec![4, 5],
+                    ..MRingNode::default()
                 },
                 MRingNode {
                     id: "n4".into(),
-                    vnodes: vec![6, 7]
+                    vnodes: vec![6, 7],
+                    ..MRingNode::default()
                 }
             ]
         );
@@ -242,18 +264,22 @@ mod test {
             MRingNode {
                 id: "n1".into(),
                 vnodes: vec![0, 1],
+                ..MRingNode::default()
             },
             MRingNode {
                 id: "n2".into(),
                 vnodes: vec![2, 3],
+                ..MRingNode::default()
             },
             MRingNode {
                 id: "n3".into(),
                 vnodes: vec![4, 5],
+                ..MRingNode::default()
             },
             MRingNode {
                 id: "n4".into(),
                 vnodes: vec![6, 7],
+                ..MRingNode::default()
             },
         ];
 
@@ -264,14 +290,17 @@ mod test {
                 MRingNode {
                     id: "n1".into(),
                     vnodes: vec![0, 1, 2],
+                    ..MRingNode::default()
                 },
                 MRingNode {
                     id: "n2".into(),
                     vnodes: vec![3, 4, 5],
+                    ..MRingNode::default()
                 },
                 MRingNode {
                     id: "n3".into(),
                     vnodes: vec![6, 7],
+                    ..MRingNode::default()
                 },
             ]
         );
@@ -287,11 +316,13 @@ mod test {
             vec![
                 MRingNode {
                     id: "n1".into(),
-                    vnodes: vec![0, 1, 2, 3]
+                    vnodes: vec![0, 1, 2, 3],
+                    ..MRingNode::default()
                 },
                 MRingNode {
                     id: "n2".into(),
-                    vnodes: vec![4, 5, 6, 7]
+                    vnodes: vec![4, 5, 6, 7],
+                    ..MRingNode::default()
                 }
             ]
         );
@@ -304,7 +335,8 @@ mod test {
             p,
             vec![MRingNode {
                 id: "n1".into(),
-                vnodes: vec![0, 1, 2, 3, 4, 5, 6, 7]
+                vnodes: vec![0, 1, 2, 3, 4, 5, 6, 7],
+                ..MRingNode::default()
             },]
         );
         assert_move!(r, "n2", "n1", &[4, 5, 6, 7]);
@@ -317,18 +349,22 @@ mod test {
             MRingNode {
                 id: "n1".into(),
                 vnodes: vec![0, 1],
+                ..MRingNode::default()
             },
             MRingNode {
                 id: "n2".into(),
                 vnodes: vec![2, 3],
+                ..MRingNode::default()
             },
             MRingNode {
                 id: "n3".into(),
                 vnodes: vec![4, 5],
+                ..MRingNode::default()
             },
             MRingNode {
                 id: "n4".into(),
                 vnodes: vec![6, 7],
+                ..MRingNode::default()
             },
         ];
 
@@ -339,14 +375,17 @@ mod test {
                 MRingNode {
                     id: "n2".into(),
                     vnodes: vec![0, 1, 2],
+                    ..MRingNode::default()
                 },
                 MRingNode {
                     id: "n3".into(),
                     vnodes: vec![3, 4, 5],
+                    ..MRingNode::default()
                 },
                 MRingNode {
                     id: "n4".into(),
                     vnodes: vec![6, 7],
+                    ..MRingNode::default()
                 },
             ]
         );
@@ -361,11 +400,13 @@ mod test {
             vec![
                 MRingNode {
                     id: "n3".into(),
-                    vnodes: vec![0, 1, 2, 3]
+                    vnodes: vec![0, 1, 2, 3],
+                    ..MRingNode::default()
                 },
                 MRingNode {
                     id: "n4".into(),
-                    vnodes: vec![4, 5, 6, 7]
+                    vnodes: vec![4, 5, 6, 7],
+                    ..MRingNode::default()
                 }
             ]
         );
@@ -378,7 +419,8 @@ mod test {
             p,
             vec![MRingNode {
                 id: "n4".into(),
-                vnodes: vec![0, 1, 2, 3, 4, 5, 6, 7]
+                vnodes: vec![0, 1, 2, 3, 4, 5, 6, 7],
+                ..MRingNode::default()
             },]
         );
         assert_move!(r, "n3", "n4", &[0, 1, 2, 3]);
@@ -391,18 +433,22 @@ mod test {
             MRingNode {
                 id: "n1".into(),
                 vnodes: vec![0, 1],
+                ..MRingNode::default()
             },
             MRingNode {
                 id: "n2".into(),
                 vnodes: vec![2, 3],
+                ..MRingNode::default()
             },
             MRingNode {
                 id: "n3".into(),
                 vnodes: vec![4, 5],
+                ..MRingNode::default()
             },
             MRingNode {
                 id: "n4".into(),
                 vnodes: vec![6, 7],
+                ..MRingNode::default()
             },
         ];
 
@@ -413,14 +459,17 @@ mod test {
                 MRingNode {
                     id: "n1".into(),
                     vnodes: vec![0, 1, 2],
+                    ..MRingNode::default()
                 },
                 MRingNode {
                     id: "n3".into(),
                     vnodes: vec![3, 4, 5],
+                    ..MRingNode::default()
                 },
                 MRingNode {
                     id: "n4".into(),
                     vnodes: vec![6, 7],
+                    ..MRingNode::default()
                 },
             ]
         );
@@ -433,15 +482,27 @@ mod test {
             vec![
                 MRingNode {
                     id: "n1".into(),
-                    vnodes: vec![0, 1, 2, 3]
+                    vnodes: vec![0, 1, 2, 3],
+                    ..MRingNode::default()
                 },
                 MRingNode {
                     id: "n4".into(),
-                    vnodes: vec![4, 5, 6, 7]
+                    vnodes: vec![4, 5, 6, 7],
+                    ..MRingNode::default()
                 }
             ]
         );
         assert_move!(r, "n3", "n1", [3], "n4", [4, 5]);
         assert!(r.is_empty());
     }
+
+    #[test]
+    fn continuous_properties() {
+        // shifting vnodes along the ring keeps it balanced but moves more
+        // than a joining or leaving node strictly requires
+        check_properties::<Strategy>(|step| {
+            step.assert_balanced();
+            assert!(step.moved() >= step.minimum);
+        });
+    }
 }
@@ -0,0 +1,170 @@
+// Copyright 2018-2020, Wayfair GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{relocate, sort_relocations, Placement};
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use uring_common::{MRingNode, MRingNodes, NodeMeta, Relocations};
+
+/// Rendezvous (highest random weight) placement with bounded load.
+///
+/// Every node scores every vnode with a stable hash of the pair. A joining
+/// node takes the vnodes it scores highest from the most loaded nodes until
+/// it holds its fair share, the vnodes of a leaving node go to the least
+/// loaded of the remaining nodes, preferring the one scoring them highest.
+/// Only the vnodes that have to move do and node sizes never differ by more
+/// than one vnode.
+pub struct Strategy {}
+
+fn score(node: &str, vnode: u64) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(node.as_bytes());
+    hasher.update(vnode.to_be_bytes());
+    let digest = hasher.finalize();
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+impl Placement for Strategy {
+    fn name() -> String {
+        "rendezvous".into()
+    }
+    fn new(count: u64, new: String, meta: NodeMeta) -> MRingNodes {
+        vec![MRingNode {
+            id: new,
+            vnodes: (0..count).collect(),
+            replicas: Vec::new(),
+            ranks: Vec::new(),
+            meta,
+        }]
+    }
+    fn add_node(
+        count: u64,
+        mut current: MRingNodes,
+        new: String,
+        meta: NodeMeta,
+    ) -> (MRingNodes, Relocations) {
+        let mut rs = Relocations::new();
+        // skip nodes we know
+        if current.iter().any(|n| n.id == new) {
+            return (current, rs);
+        }
+        if current.is_empty() {
+            return (Self::new(count, new, meta), rs);
+        }
+        let share = count as usize / (current.len() + 1);
+        let mut vnodes = Vec::with_capacity(share);
+        while vnodes.len() < share {
+            // the first of the most loaded nodes gives up the vnode the
+            // new node scores highest
+            let donor = current
+                .iter_mut()
+                .enumerate()
+                .max_by_key(|(i, n)| (n.vnodes.len(), Reverse(*i)))
+                .map(|(_, n)| n)
+                .unwrap();
+            let (pos, vnode) = donor
+                .vnodes
+                .iter()
+                .copied()
+                .enumerate()
+                .max_by_key(|(_, v)| (score(&new, *v), *v))
+                .unwrap();
+            donor.vnodes.remove(pos);
+            relocate(&mut rs, &donor.id, &new, vnode);
+            vnodes.push(vnode);
+        }
+        vnodes.sort();
+        current.push(MRingNode {
+            id: new,
+            vnodes,
+            replicas: Vec::new(),
+            ranks: Vec::new(),
+            meta,
+        });
+        sort_relocations(&mut rs);
+        (current, rs)
+    }
+
+    fn remove_node(_count: u64, mut current: MRingNodes, old: String) -> (MRingNodes, Relocations) {
+        let mut rs = Relocations::new();
+        // The last node has nowhere to hand its vnodes to
+        if current.len() < 2 {
+            return (current, rs);
+        }
+        let old = if let Some(pos) = current.iter().position(|n| n.id == old) {
+            current.remove(pos)
+        } else {
+            return (current, rs);
+        };
+        for vnode in old.vnodes {
+            let dst = current
+                .iter_mut()
+                .min_by_key(|n| (n.vnodes.len(), Reverse(score(&n.id, vnode))))
+                .unwrap();
+            dst.vnodes.push(vnode);
+            relocate(&mut rs, &old.id, &dst.id, vnode);
+        }
+        for n in &mut current {
+            n.vnodes.sort();
+        }
+        sort_relocations(&mut rs);
+        (current, rs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test::*;
+    use super::*;
+
+    #[test]
+    fn rendezvous_add_remove() {
+        let p = Strategy::new(8, "n1".into(), NodeMeta::default());
+        let (p, r) = Strategy::add_node(8, p, "n2".into(), NodeMeta::default());
+        assert_eq!(p, ring(&[("n1", &[2, 4, 5, 6]), ("n2", &[0, 1, 3, 7])]));
+        assert_eq!(moves(&r), vec![("n1", "n2", vec![0, 1, 3, 7])]);
+
+        let (p, r) = Strategy::add_node(8, p, "n3".into(), NodeMeta::default());
+        assert_eq!(
+            p,
+            ring(&[("n1", &[2, 4, 5]), ("n2", &[0, 3, 7]), ("n3", &[1, 6])])
+        );
+        assert_eq!(
+            moves(&r),
+            vec![("n1", "n3", vec![6]), ("n2", "n3", vec![1])]
+        );
+
+        let (p, r) = Strategy::add_node(8, p, "n3".into(), NodeMeta::default());
+        assert!(r.is_empty());
+
+        let (p, r) = Strategy::remove_node(8, p, "n1".into());
+        assert_eq!(p, ring(&[("n2", &[0, 3, 5, 7]), ("n3", &[1, 2, 4, 6])]));
+        assert_eq!(
+            moves(&r),
+            vec![("n1", "n2", vec![5]), ("n1", "n3", vec![2, 4])]
+        );
+    }
+
+    #[test]
+    fn rendezvous_properties() {
+        // nothing moves but the share a joining node has to take and the
+        // vnodes a leaving node has to hand off
+        check_properties::<Strategy>(|step| {
+            step.assert_balanced();
+            assert_eq!(step.moved(), step.minimum);
+        });
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright 2018-2020, Wayfair GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{relocate, sort_relocations, zone, Placement};
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use uring_common::{MRingNode, MRingNodes, NodeMeta, Relocations};
+
+/// Weighted, zone aware rendezvous placement.
+///
+/// A node scores `-weight / ln(h)` for a vnode, `h` being a hash of the two
+/// in (0, 1), so its chance of owning a vnode is proportional to its weight
+/// at the cost of a single hash whatever the weight.
+/// Placement only depends on membership: a joining node only takes vnodes
+/// and a leaving node only gives up its own. Preference lists rank nodes by
+/// the same score and skip zones that already hold the vnode, a node
+/// without a zone is a zone of its own.
+pub struct Strategy {}
+
+/// The score of a node for a vnode as the bits of a positive float, those
+/// order the same way the floats do.
+fn score(node: &MRingNode, vnode: u64) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update(node.id.as_bytes());
+    hasher.update(vnode.to_be_bytes());
+    let digest = hasher.finalize();
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    // the top 53 bits fit a float exactly, the half keeps h off 0 and 1
+    let h = ((u64::from_be_bytes(bytes) >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+    (-f64::from(node.meta.weight) / h.ln()).to_bits()
+}
+
+/// Orders nodes for a vnode, higher is preferred.
+fn rank(node: &MRingNode, vnode: u64) -> (u64, Reverse<&str>) {
+    (score(node, vnode), Reverse(node.id.as_str()))
+}
+
+/// Up to `replicas` nodes for `vnode`, best first and no two in the same
+/// zone. The first one owns the vnode.
+pub fn preference(nodes: &[MRingNode], vnode: u64, replicas: usize) -> Vec<&MRingNode> {
+    let mut ranked: Vec<_> = nodes.iter().map(|n| (rank(n, vnode), n)).collect();
+    ranked.sort_by(|(a, _), (b, _)| b.cmp(a));
+    let mut zones = HashSet::new();
+    ranked
+        .into_iter()
+        .map(|(_, n)| n)
+        .filter(|n| zones.insert(zone(n)))
+        .take(replicas)
+        .collect()
+}
+
+impl Placement for Strategy {
+    fn name() -> String {
+        "weighted".into()
+    }
+    fn new(count: u64, new: String, meta: NodeMeta) -> MRingNodes {
+        vec![MRingNode {
+            id: new,
+            vnodes: (0..count).collect(),
+            replicas: Vec::new(),
+            ranks: Vec::new(),
+            meta,
+        }]
+    }
+    fn add_node(
+        _count: u64,
+        mut current: MRingNodes,
+        new: String,
+        meta: NodeMeta,
+    ) -> (MRingNodes, Relocations) {
+        let mut rs = Relocations::new();
+        // skip nodes we know
+        if current.iter().any(|n| n.id == new) {
+            return (current, rs);
+        }
+        let mut new = MRingNode {
+            id: new,
+            vnodes: Vec::new(),
+            replicas: Vec::new(),
+            ranks: Vec::new(),
+            meta,
+        };
+        // owners are already the best of the other nodes, so a vnode only
+        // moves if the new node outranks its owner
+        for n in &mut current {
+            let (moved, kept) = n
+                .vnodes
+                .iter()
+                .partition(|v| rank(&new, **v) > rank(n, **v));
+            n.vnodes = kept;
+            for vnode in moved {
+                relocate(&mut rs, &n.id, &new.id, vnode);
+                new.vnodes.push(vnode);
+            }
+        }
+        new.vnodes.sort();
+        current.push(new);
+        sort_relocations(&mut rs);
+        (current, rs)
+    }
+
+    fn remove_node(_count: u64, mut current: MRingNodes, old: String) -> (MRingNodes, Relocations) {
+        let mut rs = Relocations::new();
+        // The last node has nowhere to hand its vnodes to
+        if current.len() < 2 {
+            return (current, rs);
+        }
+        let old = if let Some(pos) = current.iter().position(|n| n.id == old) {
+            current.remove(pos)
+        } else {
+            return (current, rs);
+        };
+        let mut owned: HashMap<String, Vec<u64>> = HashMap::new();
+        for vnode in old.vnodes {
+            if let Some(dst) = preference(&current, vnode, 1).first() {
+                relocate(&mut rs, &old.id, &dst.id, vnode);
+                owned.entry(dst.id.clone()).or_default().push(vnode);
+            }
+        }
+        for n in &mut current {
+            if let Some(mut vnodes) = owned.remove(&n.id) {
+                n.vnodes.append(&mut vnodes);
+                n.vnodes.sort();
+            }
+        }
+        sort_relocations(&mut rs);
+        (current, rs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test::*;
+    use super::*;
+
+    fn node(id: &str, weight: u32, zone: &str) -> MRingNode {
+        MRingNode {
+            id: id.into(),
+            vnodes: Vec::new(),
+            replicas: Vec::new(),
+            ranks: Vec::new(),
+            meta: NodeMeta {
+                weight,
+                zone: Some(zone.into()),
+                rack: None,
+            },
+        }
+    }
+
+    #[test]
+    fn weighted_properties() {
+        // vnodes only ever move to a joining node or away from a leaving one
+        check_properties::<Strategy>(|step| {
+            for (src, dst, _) in &step.moves {
+                if step.joined {
+                    assert_eq!(*dst, step.node);
+                } else {
+                    assert_eq!(*src, step.node);
+                }
+            }
+            if !step.joined {
+                assert_eq!(step.moved(), step.minimum);
+            }
+        });
+    }
+
+    #[test]
+    fn weighted_shares_follow_weights() {
+        let count = 4096;
+        for seed in 1..=4 {
+            let mut rng = Rng(seed);
+            let mut nodes = Strategy::new(count, "n0".into(), rng.meta());
+            for i in 1..2 + rng.below(7) {
+                nodes = Strategy::add_node(count, nodes, format!("n{}", i), rng.meta()).0;
+            }
+            let total: u32 = nodes.iter().map(|n| n.meta.weight).sum();
+            for n in &nodes {
+                let share = (count * u64::from(n.meta.weight) / u64::from(total)) as f64;
+                let deviation = (n.vnodes.len() as f64 - share).abs() / share;
+                assert!(
+                    deviation < 0.2,
+                    "{} holds {} of {}",
+                    n.id,
+                    n.vnodes.len(),
+                    share
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn weighted_replicas_span_zones() {
+        let nodes = vec![
+            node("n1", 1, "z1"),
+            node("n2", 2, "z1"),
+            node("n3", 1, "z2"),
+            node("n4", 3, "z2"),
+            node("n5", 1, "z3"),
+        ];
+        for vnode in 0..256 {
+            let replicas = preference(&nodes, vnode, 3);
+            let zones: HashSet<_> = replicas.iter().map(|n| &n.meta.zone).collect();
+            assert_eq!(zones.len(), 3);
+            // asking for more replicas than zones never doubles up a zone
+            assert_eq!(preference(&nodes, vnode, 5).len(), 3);
+        }
+    }
+}
@@ -98,8 +98,15 @@ mod test {
 
         {
             block_on(async {
-                let mut node: RaftNode<storage::NullStorage, _> =
-                    RaftNode::create_raft_leader(&logger, id, topic, network).await;
+                let mut node: RaftNode<storage::NullStorage, _> = RaftNode::create_raft_leader(
+                    &logger,
+                    id,
+                    storage::Config::default(),
+                    topic,
+                    network,
+                )
+                .await
+                .unwrap();
                 let raw_node = node.raft_group.unwrap();
                 let status_check: RaftNodeStatus = raft_node::status(&raw_node).await.unwrap();
                 let status_response: RaftNodeStatus = serde_json::from_slice(
@@ -99,8 +99,15 @@ mod test {
         let topic = Sender::<pubsub::Msg>::from(tx);
 
         block_on(async {
-            let mut node: RaftNode<storage::NullStorage, _> =
-                RaftNode::create_raft_leader(&logger, id, topic, network).await;
+            let mut node: RaftNode<storage::NullStorage, _> = RaftNode::create_raft_leader(
+                &logger,
+                id,
+                storage::Config::default(),
+                topic,
+                network,
+            )
+            .await
+            .unwrap();
             let version = s
                 .execute(node.raft_group.as_ref().unwrap(), &mut node.pubsub, get)
                 .await
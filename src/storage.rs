@@ -14,21 +14,164 @@
 
 // inspired by https://github.com/LucioFranco/kv/blob/master/src/storage.rs
 
+mod mem;
+
 use crate::NodeId;
 use async_trait::async_trait;
+use byteorder::{BigEndian, ByteOrder};
 use bytes::buf::BufMut;
+pub use mem::MemStorage;
 use protobuf::Message;
 use raft::prelude::*;
 pub use raft::storage::Storage as ReadStorage;
 use raft::{Error as RaftError, Result as RaftResult, StorageError};
 use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::borrow::Borrow;
+use std::cmp::{max, min};
+use std::collections::HashMap;
 use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::{fmt, fs};
+
+#[derive(Debug)]
+pub enum Error {
+    Config(String),
+    Backend(String),
+}
+impl std::error::Error for Error {}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Config(e) => write!(f, "invalid storage configuration: {}", e),
+            Self::Backend(e) => write!(f, "storage error: {}", e),
+        }
+    }
+}
 
 #[async_trait]
-pub trait Storage: WriteStorage + ReadStorage {
-    async fn new_with_conf_state(id: NodeId, state: ConfState) -> Self;
-    async fn new(id: NodeId) -> Self;
+pub trait Storage: WriteStorage + ReadStorage + Sized {
+    async fn new_with_conf_state(
+        id: NodeId,
+        config: &Config,
+        state: ConfState,
+    ) -> Result<Self, Error>;
+    async fn new(id: NodeId, config: &Config) -> Result<Self, Error>;
+}
+
+/// When writes are synced to disk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// Every write is synced.
+    Always,
+    /// Only raft log appends and the hard state are synced, other writes
+    /// are synced along with them.
+    Batched,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "always" => Ok(Self::Always),
+            "batched" => Ok(Self::Batched),
+            other => Err(Error::Config(format!("unknown sync policy `{}`", other))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "none" => Ok(Self::None),
+            "snappy" => Ok(Self::Snappy),
+            "lz4" => Ok(Self::Lz4),
+            "zstd" => Ok(Self::Zstd),
+            other => Err(Error::Config(format!("unknown compression `{}`", other))),
+        }
+    }
+}
+
+impl From<Compression> for DBCompressionType {
+    fn from(c: Compression) -> Self {
+        match c {
+            Compression::None => Self::None,
+            Compression::Snappy => Self::Snappy,
+            Compression::Lz4 => Self::Lz4,
+            Compression::Zstd => Self::Zstd,
+        }
+    }
+}
+
+/// Storage configuration, the defaults match what a node used before it
+/// became configurable.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Directory the `raft-rocks-<id>` database is created in.
+    pub data_dir: PathBuf,
+    /// Directory for the write ahead log, defaults to the database directory.
+    pub wal_dir: Option<PathBuf>,
+    pub sync: SyncPolicy,
+    pub compression: Compression,
+    /// Size of the block cache in bytes.
+    pub cache_size: usize,
+    pub compaction: CompactionPolicy,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            data_dir: PathBuf::from("."),
+            wal_dir: None,
+            sync: SyncPolicy::Always,
+            compression: Compression::Snappy,
+            cache_size: 8 * 1024 * 1024,
+            compaction: CompactionPolicy::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), Error> {
+        check_dir("data dir", &self.data_dir)?;
+        if let Some(wal_dir) = &self.wal_dir {
+            check_dir("wal dir", wal_dir)?;
+        }
+        if self.cache_size == 0 {
+            return Err(Error::Config("cache size must be greater than 0".into()));
+        }
+        if self.compaction.snapshot_interval == 0 {
+            return Err(Error::Config(
+                "snapshot interval must be greater than 0".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn db_path(&self, id: NodeId) -> PathBuf {
+        self.data_dir.join(format!("raft-rocks-{}", id.0))
+    }
+}
+
+fn check_dir(name: &str, dir: &PathBuf) -> Result<(), Error> {
+    if dir.exists() && !dir.is_dir() {
+        return Err(Error::Config(format!(
+            "{} {} is not a directory",
+            name,
+            dir.display()
+        )));
+    }
+    fs::create_dir_all(dir).map_err(|e| Error::Config(format!("{} {}: {}", name, dir.display(), e)))
 }
 
 /// The missing storage trait from raft-rs ...
@@ -37,8 +180,30 @@ pub trait WriteStorage {
     async fn append(&self, entries: &[Entry]) -> RaftResult<()>;
     async fn apply_snapshot(&mut self, snapshot: Snapshot) -> RaftResult<()>;
     async fn set_conf_state(&mut self, cs: ConfState) -> RaftResult<()>;
-    async fn set_hard_state(&mut self, commit: u64, term: u64) -> RaftResult<()>;
+    async fn set_hard_state(&mut self, hs: HardState) -> RaftResult<()>;
+    async fn set_applied(&mut self, applied: u64) -> RaftResult<()>;
+    async fn applied(&self) -> u64;
+    /// Creates snapshots and truncates the log behind the applied index
+    /// according to the storage's compaction policy.
+    async fn compact(&mut self, applied: u64) -> RaftResult<()>;
+    /// Stores a chunk of an incoming snapshot and returns the offset of the
+    /// next chunk expected.
+    async fn write_snapshot_chunk(&mut self, chunk: &SnapshotChunk) -> RaftResult<u64>;
+    /// Whether all chunks of the given snapshot were received and verified.
+    async fn snapshot_received(&self, index: u64, term: u64) -> bool;
     async fn get(&self, scope: u16, key: &[u8]) -> Option<Vec<u8>>;
+    /// Returns up to `limit` entries of the scope with keys from `start`
+    /// (inclusive) to `end` (exclusive), in key order or, if `reverse` is
+    /// set, the last ones in descending order. Without an `end` the scan runs
+    /// to the end of the scope.
+    async fn scan(
+        &self,
+        scope: u16,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+        reverse: bool,
+    ) -> Vec<(Vec<u8>, Vec<u8>)>;
     async fn put(&self, keyscope: u16, key: &[u8], value: &[u8]);
     async fn cas(
         &self,
@@ -48,29 +213,188 @@ pub trait WriteStorage {
         store_value: &[u8],
     ) -> Option<Option<Vec<u8>>>;
     async fn delete(&self, scope: u16, key: &[u8]) -> Option<Vec<u8>>;
+    /// Applies all writes of the batch atomically if every compare in it
+    /// holds, nothing is written otherwise.
+    async fn write_batch(&self, ops: &[BatchOp]) -> BatchResult;
+}
+
+/// A single operation of a write batch.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum BatchOp {
+    Put {
+        scope: u16,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        scope: u16,
+        key: Vec<u8>,
+    },
+    /// Guards the batch on the current value of a key, `None` requires the
+    /// key to be absent.
+    Compare {
+        scope: u16,
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+    },
 }
 
-use rocksdb::{Direction, IteratorMode, WriteBatch, DB};
+impl BatchOp {
+    fn data_key(&self) -> Vec<u8> {
+        match self {
+            BatchOp::Put { scope, key, .. }
+            | BatchOp::Delete { scope, key }
+            | BatchOp::Compare { scope, key, .. } => make_data_key(*scope, key),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum BatchResult {
+    /// The batch was written, holds the value each op's key had before the
+    /// op was applied.
+    Applied(Vec<Option<Vec<u8>>>),
+    /// The batch was rejected, holds the position of every failed compare
+    /// along with the value found.
+    Conflict(Vec<(usize, Option<Vec<u8>>)>),
+}
+
+/// Evaluates the ops of a batch in order against `lookup`, later ops observe
+/// the writes of earlier ones.
+fn eval_batch<F>(ops: &[BatchOp], lookup: F) -> BatchResult
+where
+    F: Fn(&[u8]) -> Option<Vec<u8>>,
+{
+    let mut written: HashMap<Vec<u8>, Option<Vec<u8>>> = HashMap::new();
+    let mut previous = Vec::with_capacity(ops.len());
+    let mut conflicts = Vec::new();
+    for (i, op) in ops.iter().enumerate() {
+        let key = op.data_key();
+        let current = match written.get(&key) {
+            Some(v) => v.clone(),
+            None => lookup(&key),
+        };
+        match op {
+            BatchOp::Put { value, .. } => {
+                written.insert(key, Some(value.clone()));
+            }
+            BatchOp::Delete { .. } => {
+                written.insert(key, None);
+            }
+            BatchOp::Compare { value, .. } => {
+                if current != *value {
+                    conflicts.push((i, current.clone()));
+                }
+            }
+        }
+        previous.push(current);
+    }
+    if conflicts.is_empty() {
+        BatchResult::Applied(previous)
+    } else {
+        BatchResult::Conflict(conflicts)
+    }
+}
+
+use rocksdb::{
+    BlockBasedOptions, DBCompressionType, Direction, IteratorMode, Options, WriteBatch,
+    WriteOptions, DB,
+};
 
 const CONF_STATE: &'static [u8; 16] = b"\0\0\0\0\0\0\0ConfState";
 const HARD_STATE: &'static [u8; 16] = b"\0\0\0\0\0\0\0HardState";
+const APPLIED: &'static [u8; 16] = b"\0\0\0\0\0\0\0\0\0Applied";
+const TRUNCATED: &'static [u8; 16] = b"\0\0\0\0\0\0\0Truncated";
+const SNAPSHOT_META: &'static [u8; 16] = b"\0\0\0\0SnapshotMeta";
+const SNAPSHOT_DATA: &'static [u8; 16] = b"\0\0\0\0SnapshotData";
+const INCOMING: &'static [u8; 16] = b"\0\0\0\0\0\0\0\0Incoming";
+
+/// A slice of snapshot data streamed to a follower ahead of the raft
+/// snapshot message itself.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotChunk {
+    pub index: u64,
+    pub term: u64,
+    pub offset: u64,
+    /// base64 encoded data
+    pub data: String,
+    /// Checksum of the whole snapshot, set on the last chunk only.
+    pub checksum: Option<String>,
+}
+
+impl SnapshotChunk {
+    pub fn new(
+        meta: &SnapshotMetadata,
+        offset: u64,
+        data: &[u8],
+        checksum: Option<String>,
+    ) -> Self {
+        Self {
+            index: meta.index,
+            term: meta.term,
+            offset,
+            data: base64::encode(data),
+            checksum,
+        }
+    }
+}
+
+pub fn snapshot_checksum(data: &[u8]) -> String {
+    base64::encode(Sha256::digest(data))
+}
+
+/// Progress of a snapshot that is being received in chunks.
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct IncomingSnapshot {
+    index: u64,
+    term: u64,
+    offset: u64,
+    complete: bool,
+}
+
+/// Decides how much of the raft log is kept around once entries are applied.
+#[derive(Debug, Clone)]
+pub struct CompactionPolicy {
+    /// Number of applied entries kept for followers that lag behind.
+    pub retain_entries: u64,
+    /// Upper bound for the size of the retained log in bytes.
+    pub retain_bytes: Option<u64>,
+    /// A new snapshot is taken every `snapshot_interval` applied entries.
+    pub snapshot_interval: u64,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        Self {
+            retain_entries: 1024,
+            retain_bytes: None,
+            snapshot_interval: 4096,
+        }
+    }
+}
 
 //#[derive(Default)]
 pub struct URRocksStorage {
     backend: DB,
     conf_state: Option<ConfState>,
+    policy: CompactionPolicy,
+    sync: SyncPolicy,
 }
 
 pub struct NullStorage {}
 
 #[async_trait]
 impl Storage for NullStorage {
-    async fn new_with_conf_state(_id: NodeId, _state: ConfState) -> Self {
-        NullStorage {}
+    async fn new_with_conf_state(
+        _id: NodeId,
+        _config: &Config,
+        _state: ConfState,
+    ) -> Result<Self, Error> {
+        Ok(NullStorage {})
     }
 
-    async fn new(_id: NodeId) -> Self {
-        NullStorage {}
+    async fn new(_id: NodeId, _config: &Config) -> Result<Self, Error> {
+        Ok(NullStorage {})
     }
 }
 
@@ -85,12 +409,37 @@ impl WriteStorage for NullStorage {
     async fn set_conf_state(&mut self, _cs: ConfState) -> RaftResult<()> {
         unimplemented!()
     }
-    async fn set_hard_state(&mut self, _commit: u64, _term: u64) -> RaftResult<()> {
+    async fn set_hard_state(&mut self, _hs: HardState) -> RaftResult<()> {
+        unimplemented!()
+    }
+    async fn set_applied(&mut self, _applied: u64) -> RaftResult<()> {
+        unimplemented!()
+    }
+    async fn applied(&self) -> u64 {
+        0
+    }
+    async fn compact(&mut self, _applied: u64) -> RaftResult<()> {
+        unimplemented!()
+    }
+    async fn write_snapshot_chunk(&mut self, _chunk: &SnapshotChunk) -> RaftResult<u64> {
+        unimplemented!()
+    }
+    async fn snapshot_received(&self, _index: u64, _term: u64) -> bool {
         unimplemented!()
     }
     async fn get(&self, _scope: u16, _key: &[u8]) -> Option<Vec<u8>> {
         unimplemented!()
     }
+    async fn scan(
+        &self,
+        _scope: u16,
+        _start: &[u8],
+        _end: Option<&[u8]>,
+        _limit: usize,
+        _reverse: bool,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        unimplemented!()
+    }
     async fn put(&self, _keyscope: u16, _key: &[u8], _value: &[u8]) {
         unimplemented!()
     }
@@ -106,6 +455,9 @@ impl WriteStorage for NullStorage {
     async fn delete(&self, _scope: u16, _key: &[u8]) -> Option<Vec<u8>> {
         unimplemented!()
     }
+    async fn write_batch(&self, _ops: &[BatchOp]) -> BatchResult {
+        unimplemented!()
+    }
 }
 
 pub type NullStorageError = raft::Error;
@@ -140,19 +492,49 @@ unsafe impl Send for URRocksStorage {}
 
 #[async_trait]
 impl Storage for URRocksStorage {
-    async fn new_with_conf_state(id: NodeId, state: ConfState) -> Self {
-        let mut db = Self::new(id).await;
+    async fn new_with_conf_state(
+        id: NodeId,
+        config: &Config,
+        state: ConfState,
+    ) -> Result<Self, Error> {
+        let mut db = Self::new(id, config).await?;
 
-        db.set_conf_state(state).await.unwrap();
-        db.set_hard_state(1, 1).await.unwrap();
-        db
+        // Only bootstrap a fresh store, a restarted node keeps its state.
+        if db.get_hard_state() == HardState::default() {
+            let mut hs = HardState::new();
+            hs.commit = 1;
+            hs.term = 1;
+            let backend = |e: RaftError| Error::Backend(format!("{}", e));
+            db.set_conf_state(state).await.map_err(backend)?;
+            db.set_hard_state(hs).await.map_err(backend)?;
+            db.set_truncated_state(1, 1);
+            db.set_applied(1).await.map_err(backend)?;
+        }
+        Ok(db)
     }
-    async fn new(id: NodeId) -> Self {
-        let backend = DB::open_default(&format!("raft-rocks-{}", id.0)).unwrap();
-        URRocksStorage {
+    async fn new(id: NodeId, config: &Config) -> Result<Self, Error> {
+        config.validate()?;
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.set_compression_type(config.compression.into());
+        if let Some(wal_dir) = &config.wal_dir {
+            opts.set_wal_dir(wal_dir.join(format!("raft-rocks-{}", id.0)));
+        }
+        let mut table = BlockBasedOptions::default();
+        table.set_lru_cache(config.cache_size);
+        opts.set_block_based_table_factory(&table);
+
+        let path = config.db_path(id);
+        let backend = DB::open(&opts, &path)
+            .map_err(|e| Error::Backend(format!("{}: {}", path.display(), e)))?;
+        check_key_layout(&backend)
+            .map_err(|e| Error::Backend(format!("{}: {}", path.display(), e)))?;
+        Ok(URRocksStorage {
             backend,
             conf_state: None,
-        }
+            policy: config.compaction.clone(),
+            sync: config.sync,
+        })
     }
 }
 
@@ -162,6 +544,18 @@ struct HandoffKV {
     value: String,
 }
 impl URRocksStorage {
+    fn write_opts(&self) -> WriteOptions {
+        let mut opts = WriteOptions::default();
+        opts.set_sync(self.sync == SyncPolicy::Always);
+        opts
+    }
+
+    fn sync_opts(&self) -> WriteOptions {
+        let mut opts = WriteOptions::default();
+        opts.set_sync(true);
+        opts
+    }
+
     fn get_hard_state(&self) -> HardState {
         let mut hs = HardState::new();
         if let Ok(Some(data)) = self.backend.get(&HARD_STATE) {
@@ -176,6 +570,134 @@ impl URRocksStorage {
         };
         cs
     }
+    fn get_applied(&self) -> u64 {
+        self.backend
+            .get(&APPLIED)
+            .unwrap()
+            .map(|v| BigEndian::read_u64(&v))
+            .unwrap_or_default()
+    }
+
+    /// Index and term of the last entry removed from the log.
+    fn get_truncated_state(&self) -> (u64, u64) {
+        self.backend
+            .get(&TRUNCATED)
+            .unwrap()
+            .map(|v| (BigEndian::read_u64(&v[..8]), BigEndian::read_u64(&v[8..])))
+            .unwrap_or_default()
+    }
+
+    fn set_truncated_state(&self, index: u64, term: u64) {
+        let mut data = Vec::with_capacity(16);
+        data.put_u64(index);
+        data.put_u64(term);
+        self.backend
+            .put_opt(&TRUNCATED, &data, &self.write_opts())
+            .unwrap();
+    }
+
+    fn get_snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::new();
+        if let Ok(Some(data)) = self.backend.get(&SNAPSHOT_META) {
+            snapshot.mut_metadata().merge_from_bytes(&data).unwrap();
+            if let Ok(Some(data)) = self.backend.get(&SNAPSHOT_DATA) {
+                snapshot.set_data(data.to_vec());
+            }
+        };
+        snapshot
+    }
+
+    fn set_snapshot(&self, snapshot: &Snapshot) -> RaftResult<()> {
+        let meta = snapshot.get_metadata().write_to_bytes()?;
+        let mut batch = WriteBatch::default();
+        batch.put(&SNAPSHOT_DATA, snapshot.get_data());
+        batch.put(&SNAPSHOT_META, &meta);
+        self.backend.write_opt(batch, &self.write_opts()).unwrap();
+        Ok(())
+    }
+
+    /// Takes a snapshot of the data at the applied index and keeps it
+    /// around to serve followers that fall behind the compacted log.
+    fn create_snapshot(&self) -> RaftResult<Snapshot> {
+        let applied = self.get_applied();
+        let mut snapshot = Snapshot::new();
+        snapshot.set_data(self.data_snapshot());
+        let meta = snapshot.mut_metadata();
+        meta.index = applied;
+        meta.term = self.term(applied)?;
+        meta.set_conf_state(self.get_conf_state());
+        self.set_snapshot(&snapshot)?;
+        Ok(snapshot)
+    }
+
+    /// Index up to which the log can be dropped so that the entries behind
+    /// `applied` stay within `max_bytes`.
+    fn retained_bytes_boundary(&self, applied: u64, max_bytes: u64) -> u64 {
+        let from = make_log_key(applied);
+        let mut size = 0;
+        self.backend
+            .iterator(IteratorMode::From(&from, Direction::Reverse))
+            .take_while(|(k, _)| {
+                let k: &[u8] = k.borrow();
+                k >= &LOW_INDEX[..]
+            })
+            .find_map(|(k, v)| {
+                size += v.len() as u64;
+                if size > max_bytes {
+                    Some(log_key_index(&k))
+                } else {
+                    None
+                }
+            })
+            .unwrap_or_default()
+    }
+
+    fn get_incoming(&self) -> IncomingSnapshot {
+        self.backend
+            .get(&INCOMING)
+            .unwrap()
+            .and_then(|v| serde_json::from_slice(&v).ok())
+            .unwrap_or_default()
+    }
+
+    fn incoming_chunks(&self) -> impl Iterator<Item = (Box<[u8]>, Box<[u8]>)> + '_ {
+        self.backend
+            .iterator(IteratorMode::From(&LOW_INCOMING, Direction::Forward))
+            .take_while(|(k, _)| {
+                let k: &[u8] = k.borrow();
+                k <= &HIGH_INCOMING[..]
+            })
+    }
+
+    fn clear_incoming(&self) {
+        self.incoming_chunks()
+            .for_each(|(k, _)| self.backend.delete_opt(&k, &self.write_opts()).unwrap());
+        self.backend
+            .delete_opt(&INCOMING, &self.write_opts())
+            .unwrap();
+    }
+
+    /// Replaces the data with the chunks of a completely received snapshot,
+    /// the old data is removed in the same batch the new one is written in.
+    fn apply_incoming_snapshot(&self) {
+        let mut batch = WriteBatch::default();
+        self.clear_data(&mut batch);
+
+        let mut rest: Vec<u8> = Vec::new();
+        for (_, chunk) in self.incoming_chunks() {
+            rest.extend_from_slice(&chunk);
+            let mut lines: Vec<&[u8]> = rest.split(|c| *c == b'\n').collect();
+            let partial = lines.pop().unwrap_or_default().to_vec();
+            for kv in lines {
+                put_handoff_kv(&mut batch, kv);
+            }
+            rest = partial;
+        }
+        put_handoff_kv(&mut batch, &rest);
+        self.backend.write_opt(batch, &self.write_opts()).unwrap();
+        self.clear_incoming();
+    }
+
     fn clear_log(&self) {
         self.clear_log_to(u64::max_value());
     }
@@ -192,17 +714,17 @@ impl URRocksStorage {
                 let k: &[u8] = k.borrow();
                 k <= &before[..]
             })
-            .for_each(|(k, _)| self.backend.delete(&k).unwrap());
+            .for_each(|(k, _)| self.backend.delete_opt(&k, &self.write_opts()).unwrap());
     }
 
-    fn clear_data(&self) {
+    fn clear_data(&self, batch: &mut WriteBatch) {
         self.backend
             .iterator(IteratorMode::From(&LOW_DATA, Direction::Forward))
             .take_while(|(k, _)| {
                 let k: &[u8] = k.borrow();
                 k <= &HIGH_DATA[..]
             })
-            .for_each(|(k, _)| self.backend.delete(&k).unwrap());
+            .for_each(|(k, _)| batch.delete(&k));
     }
 
     pub fn data_snapshot(&self) -> Vec<u8> {
@@ -225,15 +747,21 @@ impl URRocksStorage {
     }
 
     pub fn apply_data_snapshot(&self, data: Vec<u8>) {
-        self.clear_data();
+        let mut batch = WriteBatch::default();
+        self.clear_data(&mut batch);
 
         for kv in data.split(|c| *c == b'\n') {
-            if let Ok(kv) = serde_json::from_slice::<HandoffKV>(&kv) {
-                let k = base64::decode(&kv.key).unwrap();
-                let v = base64::decode(&kv.value).unwrap();
-                self.backend.put(&k, &v).unwrap();
-            }
+            put_handoff_kv(&mut batch, kv);
         }
+        self.backend.write_opt(batch, &self.write_opts()).unwrap();
+    }
+}
+
+fn put_handoff_kv(batch: &mut WriteBatch, kv: &[u8]) {
+    if let Ok(kv) = serde_json::from_slice::<HandoffKV>(kv) {
+        let k = base64::decode(&kv.key).unwrap();
+        let v = base64::decode(&kv.value).unwrap();
+        batch.put(&k, &v);
     }
 }
 
@@ -243,9 +771,48 @@ impl WriteStorage for URRocksStorage {
         let key = make_data_key(scope, key);
         self.backend.get(key).unwrap().map(|v| v.to_vec())
     }
+    async fn scan(
+        &self,
+        scope: u16,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+        reverse: bool,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let (low, high) = make_data_range(scope, start, end);
+        let entries = |(k, v): (Box<[u8]>, Box<[u8]>)| (k[8..].to_vec(), v.to_vec());
+        if reverse {
+            // A reverse iterator starts at the last key not past `high`.
+            self.backend
+                .iterator(IteratorMode::From(&high, Direction::Reverse))
+                .skip_while(|(k, _)| {
+                    let k: &[u8] = k.borrow();
+                    k >= &high[..]
+                })
+                .take_while(|(k, _)| {
+                    let k: &[u8] = k.borrow();
+                    k >= &low[..]
+                })
+                .take(limit)
+                .map(entries)
+                .collect()
+        } else {
+            self.backend
+                .iterator(IteratorMode::From(&low, Direction::Forward))
+                .take_while(|(k, _)| {
+                    let k: &[u8] = k.borrow();
+                    k < &high[..]
+                })
+                .take(limit)
+                .map(entries)
+                .collect()
+        }
+    }
     async fn put(&self, scope: u16, key: &[u8], value: &[u8]) {
         let key = make_data_key(scope, key);
-        self.backend.put(key, value).unwrap();
+        self.backend
+            .put_opt(key, value, &self.write_opts())
+            .unwrap();
     }
     async fn cas(
         &self,
@@ -254,28 +821,56 @@ impl WriteStorage for URRocksStorage {
         check_value: Option<&[u8]>,
         store_value: &[u8],
     ) -> Option<Option<Vec<u8>>> {
-        let value = self.get(scope, key).await;
-        if value.as_ref().map(|v| v.as_slice()) == check_value {
-            self.put(scope, key, store_value).await;
-            None
-        } else {
-            Some(value)
+        let ops = [
+            BatchOp::Compare {
+                scope,
+                key: key.to_vec(),
+                value: check_value.map(<[u8]>::to_vec),
+            },
+            BatchOp::Put {
+                scope,
+                key: key.to_vec(),
+                value: store_value.to_vec(),
+            },
+        ];
+        match self.write_batch(&ops).await {
+            BatchResult::Applied(_) => None,
+            BatchResult::Conflict(mut conflicts) => conflicts.pop().map(|(_, v)| v),
         }
     }
     async fn delete(&self, scope: u16, key: &[u8]) -> Option<Vec<u8>> {
-        match self.get(scope, key).await {
-            None => None,
-            Some(v) => {
-                let v = v.clone();
-                let key = make_data_key(scope, key);
-                self.backend.delete(key).unwrap();
-                Some(v)
+        let ops = [BatchOp::Delete {
+            scope,
+            key: key.to_vec(),
+        }];
+        match self.write_batch(&ops).await {
+            BatchResult::Applied(mut previous) => previous.pop().flatten(),
+            BatchResult::Conflict(_) => None,
+        }
+    }
+    async fn write_batch(&self, ops: &[BatchOp]) -> BatchResult {
+        let result = eval_batch(ops, |key| {
+            self.backend.get(key).unwrap().map(|v| v.to_vec())
+        });
+        if let BatchResult::Applied(_) = result {
+            let mut batch = WriteBatch::default();
+            for op in ops {
+                match op {
+                    BatchOp::Put { value, .. } => {
+                        batch.put(op.data_key(), value);
+                    }
+                    BatchOp::Delete { .. } => {
+                        batch.delete(op.data_key());
+                    }
+                    BatchOp::Compare { .. } => (),
+                }
             }
+            self.backend.write_opt(batch, &self.write_opts()).unwrap();
         }
+        result
     }
-    async fn apply_snapshot(&mut self, mut snapshot: Snapshot) -> RaftResult<()> {
-        let mut meta = snapshot.take_metadata();
-        self.apply_data_snapshot(snapshot.take_data());
+    async fn apply_snapshot(&mut self, snapshot: Snapshot) -> RaftResult<()> {
+        let meta = snapshot.get_metadata();
         let term = meta.term;
         let index = meta.index;
 
@@ -285,10 +880,32 @@ impl WriteStorage for URRocksStorage {
             return Err(RaftError::Store(StorageError::SnapshotOutOfDate));
         }
 
-        self.set_hard_state(index, term).await?;
-        self.set_conf_state(meta.take_conf_state()).await?;
-        // From Mem node do we only want to clear up to index?
+        // Snapshots sent over the network arrive in chunks ahead of the
+        // raft message, which then only carries the metadata.
+        let chunked = snapshot.get_data().is_empty();
+        if chunked {
+            let incoming = self.get_incoming();
+            if !incoming.complete || incoming.index != index || incoming.term != term {
+                return Err(RaftError::Store(
+                    StorageError::SnapshotTemporarilyUnavailable,
+                ));
+            }
+            self.apply_incoming_snapshot();
+        } else {
+            self.apply_data_snapshot(snapshot.get_data().to_vec());
+        }
+        let mut hs = self.get_hard_state();
+        hs.term = max(hs.term, term);
+        hs.commit = index;
+        self.set_hard_state(hs).await?;
+        self.set_conf_state(meta.get_conf_state().clone()).await?;
+        self.set_truncated_state(index, term);
         self.clear_log();
+        self.set_applied(index).await?;
+        if !chunked {
+            // Keep the snapshot so it can be handed on to other followers.
+            self.set_snapshot(&snapshot)?;
+        }
         Ok(())
     }
 
@@ -302,8 +919,7 @@ impl WriteStorage for URRocksStorage {
             let data = entry.write_to_bytes()?;
             batch.put(&key, &data);
         }
-        self.backend.write(batch).unwrap();
-        self.backend.flush().unwrap();
+        self.backend.write_opt(batch, &self.sync_opts()).unwrap();
 
         Ok(())
     }
@@ -312,19 +928,110 @@ impl WriteStorage for URRocksStorage {
         self.conf_state = Some(cs.clone());
 
         let data = cs.write_to_bytes()?;
-        self.backend.put(&CONF_STATE, &data).unwrap();
-        self.backend.flush().unwrap();
+        self.backend
+            .put_opt(&CONF_STATE, &data, &self.write_opts())
+            .unwrap();
         Ok(())
     }
 
-    async fn set_hard_state(&mut self, commit: u64, term: u64) -> RaftResult<()> {
-        let mut hs = HardState::new();
-        hs.commit = commit;
-        hs.term = term;
+    async fn set_hard_state(&mut self, hs: HardState) -> RaftResult<()> {
         let data = hs.write_to_bytes()?;
-        self.backend.put(&HARD_STATE, &data).unwrap();
-        self.clear_log_to(commit);
-        self.backend.flush().unwrap();
+        self.backend
+            .put_opt(&HARD_STATE, &data, &self.sync_opts())
+            .unwrap();
+        Ok(())
+    }
+
+    async fn set_applied(&mut self, applied: u64) -> RaftResult<()> {
+        let mut data = Vec::with_capacity(8);
+        data.put_u64(applied);
+        self.backend
+            .put_opt(&APPLIED, &data, &self.write_opts())
+            .unwrap();
+        Ok(())
+    }
+
+    async fn applied(&self) -> u64 {
+        self.get_applied()
+    }
+
+    async fn write_snapshot_chunk(&mut self, chunk: &SnapshotChunk) -> RaftResult<u64> {
+        let mut incoming = self.get_incoming();
+        if incoming.index != chunk.index || incoming.term != chunk.term {
+            if chunk.offset != 0 {
+                // Unknown transfer, have it start over.
+                return Ok(0);
+            }
+            self.clear_incoming();
+            incoming = IncomingSnapshot {
+                index: chunk.index,
+                term: chunk.term,
+                ..IncomingSnapshot::default()
+            };
+        }
+        // Duplicated or out of order chunk, point the sender to where we are.
+        if incoming.complete || chunk.offset != incoming.offset {
+            return Ok(incoming.offset);
+        }
+
+        let data = match base64::decode(&chunk.data) {
+            Ok(data) => data,
+            Err(_) => return Ok(incoming.offset),
+        };
+        self.backend
+            .put_opt(&make_incoming_key(chunk.offset), &data, &self.write_opts())
+            .unwrap();
+        incoming.offset += data.len() as u64;
+
+        if let Some(checksum) = &chunk.checksum {
+            let mut hasher = Sha256::new();
+            for (_, chunk) in self.incoming_chunks() {
+                hasher.update(&chunk);
+            }
+            if &base64::encode(hasher.finalize()) != checksum {
+                self.clear_incoming();
+                return Ok(0);
+            }
+            incoming.complete = true;
+        }
+
+        self.backend
+            .put_opt(
+                &INCOMING,
+                &serde_json::to_vec(&incoming).unwrap(),
+                &self.write_opts(),
+            )
+            .unwrap();
+        Ok(incoming.offset)
+    }
+
+    async fn snapshot_received(&self, index: u64, term: u64) -> bool {
+        let incoming = self.get_incoming();
+        incoming.complete && incoming.index == index && incoming.term == term
+    }
+
+    async fn compact(&mut self, applied: u64) -> RaftResult<()> {
+        let mut snapshot_index = self.get_snapshot().get_metadata().index;
+        if applied >= snapshot_index + self.policy.snapshot_interval {
+            snapshot_index = self.create_snapshot()?.get_metadata().index;
+        }
+
+        let mut compact_to = applied.saturating_sub(self.policy.retain_entries);
+        if let Some(max_bytes) = self.policy.retain_bytes {
+            compact_to = max(compact_to, self.retained_bytes_boundary(applied, max_bytes));
+        }
+        // Never drop entries past the latest snapshot, followers that are
+        // sent the snapshot need them to catch up.
+        let compact_to = min(compact_to, snapshot_index);
+
+        let (truncated, _) = self.get_truncated_state();
+        if compact_to > truncated {
+            let term = self.term(compact_to)?;
+            // The truncated state goes first so a crash in between only
+            // leaves unreachable entries behind.
+            self.set_truncated_state(compact_to, term);
+            self.clear_log_to(compact_to);
+        }
         Ok(())
     }
 }
@@ -345,7 +1052,6 @@ impl ReadStorage for URRocksStorage {
         high: u64,
         max_size: impl Into<Option<u64>>,
     ) -> RaftResult<Vec<Entry>> {
-        use std::cmp::max;
         let first_index = self.first_index().unwrap();
         if low < first_index {
             return Err(RaftError::Store(StorageError::Compacted));
@@ -379,15 +1085,13 @@ impl ReadStorage for URRocksStorage {
     }
 
     fn term(&self, idx: u64) -> RaftResult<u64> {
-        let first_index = self.first_index().unwrap();
+        let (truncated, term) = self.get_truncated_state();
 
-        let hs = self.get_hard_state();
-
-        if idx == hs.commit {
-            return Ok(hs.term);
+        if idx == truncated {
+            return Ok(term);
         }
 
-        if idx < first_index {
+        if idx < truncated {
             return Err(RaftError::Store(StorageError::Compacted));
         }
 
@@ -404,21 +1108,8 @@ impl ReadStorage for URRocksStorage {
     }
 
     fn first_index(&self) -> RaftResult<u64> {
-        let first = self
-            .backend
-            .iterator(IteratorMode::From(&LOW_INDEX, Direction::Forward))
-            .take_while(|(k, _)| {
-                let k: &[u8] = k.borrow();
-                k <= &HIGH_INDEX[..]
-            })
-            .next()
-            .map(|(_, v)| {
-                let mut e = Entry::new();
-                e.merge_from_bytes(&v).unwrap();
-                e.index
-            })
-            .unwrap_or_else(|| self.get_hard_state().commit + 1);
-        Ok(first)
+        let (truncated, _) = self.get_truncated_state();
+        Ok(truncated + 1)
     }
 
     fn last_index(&self) -> RaftResult<u64> {
@@ -430,37 +1121,32 @@ impl ReadStorage for URRocksStorage {
                 k >= &LOW_INDEX[..]
             })
             .next()
-            .map(|(_k, v)| {
-                let mut e = Entry::new();
-                e.merge_from_bytes(&v).unwrap();
-                e.index
-            })
-            .unwrap_or_else(|| self.get_hard_state().commit);
-        Ok(last)
+            .map(|(k, _v)| log_key_index(&k))
+            .unwrap_or_default();
+        let (truncated, _) = self.get_truncated_state();
+        Ok(max(last, truncated))
     }
 
     fn snapshot(&self, request_index: u64) -> RaftResult<Snapshot> {
-        let mut snapshot = Snapshot::default();
-        let hs = self.get_hard_state();
-        // Use the latest applied_idx to construct the snapshot.
-        let applied_idx = hs.commit;
-        let term = hs.term;
-        snapshot.set_data(self.data_snapshot());
-        let meta = snapshot.mut_metadata();
-        meta.index = applied_idx;
-        meta.term = term;
-
-        meta.set_conf_state(self.get_conf_state().clone());
-        // https://github.com/tikv/raft-rs/blob/3f5171a9f833679cb40437ca47031eb0e9f4aa3e/src/storage.rs#L494
-        if meta.index < request_index {
-            meta.index = request_index;
+        let snapshot = self.get_snapshot();
+        let index = snapshot.get_metadata().index;
+        let (truncated, _) = self.get_truncated_state();
+        if index > 0 && index >= request_index && index >= truncated {
+            return Ok(snapshot);
         }
-        Ok(snapshot)
+        // The periodic snapshot is missing or too old, take a fresh one.
+        if self.get_applied() < request_index {
+            return Err(RaftError::Store(
+                StorageError::SnapshotTemporarilyUnavailable,
+            ));
+        }
+        self.create_snapshot()
     }
 }
 
 // const CONF_PREFIX: u8 = 0;
 const RAFT_PREFIX: u8 = 1;
+const INCOMING_PREFIX: u8 = 2;
 const DATA_PREFIX: u8 = 255;
 // https://github.com/LucioFranco/kv/blob/417dbb7f969bd311e1e9ed91ab9980a1cae25f56/src/storage.rs#L152
 const HIGH_INDEX: [u8; 16] = [
@@ -483,28 +1169,287 @@ const HIGH_INDEX: [u8; 16] = [
 ];
 const LOW_INDEX: [u8; 16] = [RAFT_PREFIX, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
 
+const LOW_INCOMING: [u8; 16] = [INCOMING_PREFIX, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+const HIGH_INCOMING: [u8; 16] = [
+    INCOMING_PREFIX,
+    0,
+    0,
+    0,
+    0,
+    0,
+    0,
+    0,
+    255,
+    255,
+    255,
+    255,
+    255,
+    255,
+    255,
+    255,
+];
+
 const LOW_DATA: [u8; 8] = [DATA_PREFIX, 0, 0, 0, 0, 0, 0, 0];
 const HIGH_DATA: [u8; 8] = [DATA_PREFIX, 0, 0, 0, 255, 255, 255, 255];
+// Indexes are stored big endian so the keys sort in log order.
 fn make_log_key(idx: u64) -> Vec<u8> {
-    let mut key: Vec<u8> = vec![0; 16];
+    let mut key: Vec<u8> = Vec::with_capacity(16);
 
     {
-        // let mut key = Cursor::new(&mut key[..]);
         key.put_u64_le(RAFT_PREFIX as u64);
-        key.put_u64_le(idx);
+        key.put_u64(idx);
     }
 
     key
 }
 
+fn make_incoming_key(offset: u64) -> Vec<u8> {
+    let mut key: Vec<u8> = Vec::with_capacity(16);
+    key.put_u64_le(INCOMING_PREFIX as u64);
+    key.put_u64(offset);
+    key
+}
+
+fn log_key_index(key: &[u8]) -> u64 {
+    BigEndian::read_u64(&key[8..16])
+}
+
+/// The data keys from `start` up to `end`, or the end of the scope.
+fn make_data_range(scope: u16, start: &[u8], end: Option<&[u8]>) -> (Vec<u8>, Vec<u8>) {
+    let high = if let Some(end) = end {
+        make_data_key(scope, end)
+    } else {
+        let mut key = Vec::with_capacity(8);
+        key.put_u32_le(DATA_PREFIX as u32);
+        key.put_u32(scope as u32 + 1);
+        key
+    };
+    (make_data_key(scope, start), high)
+}
+
 fn make_data_key(prefix: u16, key_s: &[u8]) -> Vec<u8> {
-    let mut key = vec![0; 8 + key_s.len()];
+    let mut key = Vec::with_capacity(8 + key_s.len());
 
     {
         key.put_u32_le(DATA_PREFIX as u32);
-        key.put_u32_le(prefix as u32);
+        key.put_u32(prefix as u32);
         key.write_all(key_s).unwrap();
     }
 
     key
 }
+
+// Log and data keys used to be written after 16 and 8 zero bytes with a
+// little endian index and scope, so none of them fell into the ranges above.
+const OLD_LOG_PREFIX: [u8; 17] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, RAFT_PREFIX];
+const OLD_DATA_PREFIX: [u8; 9] = [0, 0, 0, 0, 0, 0, 0, 0, DATA_PREFIX];
+
+/// Refuses stores written with the old key layout, reading them would show
+/// an empty log and no data.
+fn check_key_layout(db: &DB) -> Result<(), String> {
+    for prefix in &[&OLD_LOG_PREFIX[..], &OLD_DATA_PREFIX[..]] {
+        let mut iter = db.iterator(IteratorMode::From(prefix, Direction::Forward));
+        if iter.next().map_or(false, |(k, _)| k.starts_with(prefix)) {
+            return Err(
+                "the store uses the old key layout, remove it and rejoin the node to resync"
+                    .to_string(),
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn entry(index: u64, term: u64) -> Entry {
+        let mut e = Entry::new();
+        e.index = index;
+        e.term = term;
+        e.data = vec![0; 16];
+        e
+    }
+
+    /// A config for a fresh database in a directory of its own
+    fn config(name: &str, compaction: CompactionPolicy) -> Config {
+        let data_dir =
+            std::env::temp_dir().join(format!("uring-storage-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        Config {
+            data_dir,
+            compaction,
+            ..Config::default()
+        }
+    }
+
+    fn open(config: &Config) -> URRocksStorage {
+        block_on(URRocksStorage::new_with_conf_state(
+            NodeId(1),
+            config,
+            ConfState::from((vec![1], vec![])),
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_compaction_keeps_retained_entries() {
+        let config = config(
+            "retain-entries",
+            CompactionPolicy {
+                retain_entries: 2,
+                retain_bytes: None,
+                snapshot_interval: 5,
+            },
+        );
+        let mut s = open(&config);
+        block_on(async {
+            let entries: Vec<Entry> = (2..=10).map(|i| entry(i, 1)).collect();
+            s.append(&entries).await.unwrap();
+            s.put(0, b"snot", b"badger").await;
+            s.set_applied(8).await.unwrap();
+            s.compact(8).await.unwrap();
+        });
+        // The snapshot is taken at 8, the log is kept for two more entries.
+        assert_eq!(s.snapshot(0).unwrap().get_metadata().index, 8);
+        assert_eq!(s.first_index().unwrap(), 7);
+        assert_eq!(s.last_index().unwrap(), 10);
+        assert_eq!(s.term(6).unwrap(), 1);
+        assert_eq!(
+            s.entries(5, 7, None),
+            Err(RaftError::Store(StorageError::Compacted))
+        );
+        drop(s);
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_compaction_keeps_retained_bytes() {
+        let size = entry(1, 1).write_to_bytes().unwrap().len() as u64;
+        let config = config(
+            "retain-bytes",
+            CompactionPolicy {
+                retain_entries: 100,
+                retain_bytes: Some(2 * size),
+                snapshot_interval: 5,
+            },
+        );
+        let mut s = open(&config);
+        block_on(async {
+            let entries: Vec<Entry> = (2..=10).map(|i| entry(i, 1)).collect();
+            s.append(&entries).await.unwrap();
+            s.set_applied(8).await.unwrap();
+            s.compact(8).await.unwrap();
+        });
+        // Entries 7 and 8 fit into the bytes retained behind the applied index.
+        assert_eq!(s.first_index().unwrap(), 7);
+        assert_eq!(s.last_index().unwrap(), 10);
+        drop(s);
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_chunked_snapshots_replace_the_data() {
+        let src_config = config("snapshot-src", CompactionPolicy::default());
+        let dst_config = config("snapshot-dst", CompactionPolicy::default());
+        let mut src = open(&src_config);
+        let mut dst = open(&dst_config);
+        let snapshot = block_on(async {
+            let entries: Vec<Entry> = (2..=5).map(|i| entry(i, 1)).collect();
+            src.append(&entries).await.unwrap();
+            for i in 0..10u8 {
+                src.put(0, &[b'k', i], b"badger").await;
+            }
+            src.set_applied(5).await.unwrap();
+            dst.put(0, b"stale", b"snot").await;
+            src.snapshot(0).unwrap()
+        });
+        let meta = snapshot.get_metadata();
+        let data = snapshot.get_data();
+        let chunks: Vec<(u64, &[u8])> = data
+            .chunks(16)
+            .enumerate()
+            .map(|(i, chunk)| (i as u64 * 16, chunk))
+            .collect();
+        let last = chunks.len() - 1;
+        let send = |dst: &mut URRocksStorage, checksum: &str| {
+            for (i, (offset, chunk)) in chunks.iter().enumerate() {
+                let checksum = if i == last {
+                    Some(checksum.to_string())
+                } else {
+                    None
+                };
+                let chunk = SnapshotChunk::new(meta, *offset, chunk, checksum);
+                let next = block_on(dst.write_snapshot_chunk(&chunk)).unwrap();
+                if i < last {
+                    assert_eq!(next, offset + 16);
+                } else {
+                    return next;
+                }
+            }
+            unreachable!()
+        };
+
+        // A transfer has to start at the beginning.
+        let chunk = SnapshotChunk::new(meta, 16, chunks[1].1, None);
+        assert_eq!(block_on(dst.write_snapshot_chunk(&chunk)).unwrap(), 0);
+        // A bad checksum has the sender start over.
+        assert_eq!(send(&mut dst, "bad"), 0);
+        assert!(!block_on(dst.snapshot_received(meta.index, meta.term)));
+        let checksum = snapshot_checksum(data);
+        assert_eq!(send(&mut dst, &checksum), data.len() as u64);
+        assert!(block_on(dst.snapshot_received(meta.index, meta.term)));
+
+        let mut announced = Snapshot::new();
+        announced.set_metadata(meta.clone());
+        block_on(async {
+            dst.apply_snapshot(announced).await.unwrap();
+            assert_eq!(dst.get(0, b"stale").await, None);
+            for i in 0..10u8 {
+                assert_eq!(dst.get(0, &[b'k', i]).await, Some(b"badger".to_vec()));
+            }
+            assert_eq!(dst.applied().await, 5);
+            assert!(!dst.snapshot_received(meta.index, meta.term).await);
+        });
+        assert_eq!(dst.first_index().unwrap(), 6);
+        drop(src);
+        drop(dst);
+        fs::remove_dir_all(&src_config.data_dir).unwrap();
+        fs::remove_dir_all(&dst_config.data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_applied_and_truncated_survive_a_restart() {
+        let config = config(
+            "restart",
+            CompactionPolicy {
+                retain_entries: 2,
+                retain_bytes: None,
+                snapshot_interval: 5,
+            },
+        );
+        let mut s = open(&config);
+        block_on(async {
+            let entries: Vec<Entry> = (2..=10).map(|i| entry(i, 2)).collect();
+            s.append(&entries).await.unwrap();
+            let mut hs = s.get_hard_state();
+            hs.term = 2;
+            hs.commit = 10;
+            s.set_hard_state(hs).await.unwrap();
+            s.set_applied(8).await.unwrap();
+            s.compact(8).await.unwrap();
+        });
+        drop(s);
+
+        // Reopening must not bootstrap the store again.
+        let s = open(&config);
+        assert_eq!(block_on(s.applied()), 8);
+        assert_eq!(s.first_index().unwrap(), 7);
+        assert_eq!(s.last_index().unwrap(), 10);
+        assert_eq!(s.term(6).unwrap(), 2);
+        assert_eq!(s.initial_state().unwrap().hard_state.commit, 10);
+        drop(s);
+        fs::remove_dir_all(&config.data_dir).unwrap();
+    }
+}
@@ -0,0 +1,631 @@
+// Copyright 2018-2020, Wayfair GmbH
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::*;
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+/// Keeps the raft log and the data in memory, nothing survives a restart.
+pub struct MemStorage {
+    state: RwLock<State>,
+    policy: CompactionPolicy,
+}
+
+#[derive(Default)]
+struct State {
+    hard_state: HardState,
+    conf_state: ConfState,
+    // Index and term of the last entry removed from the log.
+    truncated: (u64, u64),
+    entries: Vec<Entry>,
+    applied: u64,
+    snapshot: Snapshot,
+    incoming: Option<(IncomingSnapshot, Vec<u8>)>,
+    data: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl State {
+    fn first_index(&self) -> u64 {
+        self.truncated.0 + 1
+    }
+
+    fn last_index(&self) -> u64 {
+        self.truncated.0 + self.entries.len() as u64
+    }
+
+    fn term(&self, idx: u64) -> RaftResult<u64> {
+        let (truncated, term) = self.truncated;
+        if idx == truncated {
+            return Ok(term);
+        }
+        if idx < truncated {
+            return Err(RaftError::Store(StorageError::Compacted));
+        }
+        self.entries
+            .get((idx - truncated - 1) as usize)
+            .map(|e| e.term)
+            .ok_or(RaftError::Store(StorageError::Unavailable))
+    }
+
+    fn truncate_to(&mut self, index: u64) -> RaftResult<()> {
+        let term = self.term(index)?;
+        let drop = (index - self.truncated.0) as usize;
+        self.entries.drain(..drop);
+        self.truncated = (index, term);
+        Ok(())
+    }
+
+    fn data_snapshot(&self) -> Vec<u8> {
+        self.data
+            .iter()
+            .map(|(k, v)| {
+                serde_json::to_string(&HandoffKV {
+                    key: base64::encode(k),
+                    value: base64::encode(v),
+                })
+                .unwrap()
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+            .into_bytes()
+    }
+
+    fn apply_data_snapshot(&mut self, data: &[u8]) {
+        self.data.clear();
+        for kv in data.split(|c| *c == b'\n') {
+            if let Ok(kv) = serde_json::from_slice::<HandoffKV>(kv) {
+                let k = base64::decode(&kv.key).unwrap();
+                let v = base64::decode(&kv.value).unwrap();
+                self.data.insert(k, v);
+            }
+        }
+    }
+
+    fn create_snapshot(&mut self) -> RaftResult<Snapshot> {
+        let mut snapshot = Snapshot::new();
+        snapshot.set_data(self.data_snapshot());
+        let meta = snapshot.mut_metadata();
+        meta.index = self.applied;
+        meta.term = self.term(self.applied)?;
+        meta.set_conf_state(self.conf_state.clone());
+        self.snapshot = snapshot.clone();
+        Ok(snapshot)
+    }
+}
+
+#[async_trait]
+impl Storage for MemStorage {
+    async fn new_with_conf_state(
+        id: NodeId,
+        config: &Config,
+        state: ConfState,
+    ) -> Result<Self, Error> {
+        let db = Self::new(id, config).await?;
+        {
+            let mut s = db.state.write().unwrap();
+            s.conf_state = state;
+            s.hard_state.commit = 1;
+            s.hard_state.term = 1;
+            s.truncated = (1, 1);
+            s.applied = 1;
+        }
+        Ok(db)
+    }
+    async fn new(_id: NodeId, config: &Config) -> Result<Self, Error> {
+        if config.compaction.snapshot_interval == 0 {
+            return Err(Error::Config(
+                "snapshot interval must be greater than 0".into(),
+            ));
+        }
+        Ok(Self {
+            state: RwLock::new(State::default()),
+            policy: config.compaction.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl WriteStorage for MemStorage {
+    async fn get(&self, scope: u16, key: &[u8]) -> Option<Vec<u8>> {
+        let key = make_data_key(scope, key);
+        self.state.read().unwrap().data.get(&key).cloned()
+    }
+    async fn scan(
+        &self,
+        scope: u16,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+        reverse: bool,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let (low, high) = make_data_range(scope, start, end);
+        let state = self.state.read().unwrap();
+        if low >= high {
+            return Vec::new();
+        }
+        let range = state.data.range(low..high);
+        let entries = |(k, v): (&Vec<u8>, &Vec<u8>)| (k[8..].to_vec(), v.clone());
+        if reverse {
+            range.rev().take(limit).map(entries).collect()
+        } else {
+            range.take(limit).map(entries).collect()
+        }
+    }
+    async fn put(&self, scope: u16, key: &[u8], value: &[u8]) {
+        let key = make_data_key(scope, key);
+        self.state.write().unwrap().data.insert(key, value.to_vec());
+    }
+    async fn cas(
+        &self,
+        scope: u16,
+        key: &[u8],
+        check_value: Option<&[u8]>,
+        store_value: &[u8],
+    ) -> Option<Option<Vec<u8>>> {
+        let ops = [
+            BatchOp::Compare {
+                scope,
+                key: key.to_vec(),
+                value: check_value.map(<[u8]>::to_vec),
+            },
+            BatchOp::Put {
+                scope,
+                key: key.to_vec(),
+                value: store_value.to_vec(),
+            },
+        ];
+        match self.write_batch(&ops).await {
+            BatchResult::Applied(_) => None,
+            BatchResult::Conflict(mut conflicts) => conflicts.pop().map(|(_, v)| v),
+        }
+    }
+    async fn delete(&self, scope: u16, key: &[u8]) -> Option<Vec<u8>> {
+        let ops = [BatchOp::Delete {
+            scope,
+            key: key.to_vec(),
+        }];
+        match self.write_batch(&ops).await {
+            BatchResult::Applied(mut previous) => previous.pop().flatten(),
+            BatchResult::Conflict(_) => None,
+        }
+    }
+    async fn write_batch(&self, ops: &[BatchOp]) -> BatchResult {
+        let mut state = self.state.write().unwrap();
+        let result = eval_batch(ops, |key| state.data.get(key).cloned());
+        if let BatchResult::Applied(_) = result {
+            for op in ops {
+                match op {
+                    BatchOp::Put { value, .. } => {
+                        state.data.insert(op.data_key(), value.clone());
+                    }
+                    BatchOp::Delete { .. } => {
+                        state.data.remove(&op.data_key());
+                    }
+                    BatchOp::Compare { .. } => (),
+                }
+            }
+        }
+        result
+    }
+    async fn apply_snapshot(&mut self, snapshot: Snapshot) -> RaftResult<()> {
+        let mut state = self.state.write().unwrap();
+        let mut snapshot = snapshot;
+        let meta = snapshot.get_metadata().clone();
+
+        // Make sure the snapshot is not prior to our first log
+        if state.first_index() > meta.index {
+            return Err(RaftError::Store(StorageError::SnapshotOutOfDate));
+        }
+
+        // Snapshots sent over the network arrive in chunks ahead of the
+        // raft message, which then only carries the metadata.
+        if snapshot.get_data().is_empty() {
+            match state.incoming.take() {
+                Some((incoming, data))
+                    if incoming.complete
+                        && incoming.index == meta.index
+                        && incoming.term == meta.term =>
+                {
+                    snapshot.set_data(data)
+                }
+                incoming => {
+                    state.incoming = incoming;
+                    return Err(RaftError::Store(
+                        StorageError::SnapshotTemporarilyUnavailable,
+                    ));
+                }
+            }
+        }
+
+        state.apply_data_snapshot(snapshot.get_data());
+        state.hard_state.term = max(state.hard_state.term, meta.term);
+        state.hard_state.commit = meta.index;
+        state.conf_state = meta.get_conf_state().clone();
+        state.truncated = (meta.index, meta.term);
+        state.entries.clear();
+        state.applied = meta.index;
+        state.snapshot = snapshot;
+        Ok(())
+    }
+
+    async fn append(&self, entries: &[Entry]) -> RaftResult<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let mut state = self.state.write().unwrap();
+        let first_index = state.first_index();
+        // Conflicting entries are replaced by the new ones.
+        let keep = entries[0].index.saturating_sub(first_index) as usize;
+        state.entries.truncate(keep);
+        state
+            .entries
+            .extend(entries.iter().filter(|e| e.index >= first_index).cloned());
+        Ok(())
+    }
+
+    async fn set_conf_state(&mut self, cs: ConfState) -> RaftResult<()> {
+        self.state.write().unwrap().conf_state = cs;
+        Ok(())
+    }
+
+    async fn set_hard_state(&mut self, hs: HardState) -> RaftResult<()> {
+        self.state.write().unwrap().hard_state = hs;
+        Ok(())
+    }
+
+    async fn set_applied(&mut self, applied: u64) -> RaftResult<()> {
+        self.state.write().unwrap().applied = applied;
+        Ok(())
+    }
+
+    async fn applied(&self) -> u64 {
+        self.state.read().unwrap().applied
+    }
+
+    async fn compact(&mut self, applied: u64) -> RaftResult<()> {
+        let mut state = self.state.write().unwrap();
+        let mut snapshot_index = state.snapshot.get_metadata().index;
+        if applied >= snapshot_index + self.policy.snapshot_interval {
+            snapshot_index = state.create_snapshot()?.get_metadata().index;
+        }
+
+        let mut compact_to = applied.saturating_sub(self.policy.retain_entries);
+        if let Some(max_bytes) = self.policy.retain_bytes {
+            let mut size = 0;
+            let boundary = state
+                .entries
+                .iter()
+                .rev()
+                .skip_while(|e| e.index > applied)
+                .find(|e| {
+                    size += u64::from(e.compute_size());
+                    size > max_bytes
+                })
+                .map(|e| e.index)
+                .unwrap_or_default();
+            compact_to = max(compact_to, boundary);
+        }
+        // Never drop entries past the latest snapshot, followers that are
+        // sent the snapshot need them to catch up.
+        let compact_to = min(compact_to, snapshot_index);
+
+        if compact_to > state.truncated.0 {
+            state.truncate_to(compact_to)?;
+        }
+        Ok(())
+    }
+
+    async fn write_snapshot_chunk(&mut self, chunk: &SnapshotChunk) -> RaftResult<u64> {
+        let mut state = self.state.write().unwrap();
+        let current = state
+            .incoming
+            .as_ref()
+            .map(|(i, _)| i.index == chunk.index && i.term == chunk.term)
+            .unwrap_or_default();
+        if !current {
+            if chunk.offset != 0 {
+                // Unknown transfer, have it start over.
+                return Ok(0);
+            }
+            let incoming = IncomingSnapshot {
+                index: chunk.index,
+                term: chunk.term,
+                ..IncomingSnapshot::default()
+            };
+            state.incoming = Some((incoming, Vec::new()));
+        }
+        let (incoming, buffer) = state.incoming.as_mut().unwrap();
+        // Duplicated or out of order chunk, point the sender to where we are.
+        if incoming.complete || chunk.offset != incoming.offset {
+            return Ok(incoming.offset);
+        }
+
+        let data = match base64::decode(&chunk.data) {
+            Ok(data) => data,
+            Err(_) => return Ok(incoming.offset),
+        };
+        buffer.extend_from_slice(&data);
+        incoming.offset += data.len() as u64;
+
+        if let Some(checksum) = &chunk.checksum {
+            if &snapshot_checksum(buffer) != checksum {
+                state.incoming = None;
+                return Ok(0);
+            }
+            incoming.complete = true;
+        }
+        Ok(incoming.offset)
+    }
+
+    async fn snapshot_received(&self, index: u64, term: u64) -> bool {
+        self.state
+            .read()
+            .unwrap()
+            .incoming
+            .as_ref()
+            .map(|(i, _)| i.complete && i.index == index && i.term == term)
+            .unwrap_or_default()
+    }
+}
+
+impl ReadStorage for MemStorage {
+    fn initial_state(&self) -> RaftResult<RaftState> {
+        let state = self.state.read().unwrap();
+        Ok(RaftState::new(
+            state.hard_state.clone(),
+            state.conf_state.clone(),
+        ))
+    }
+
+    fn entries(
+        &self,
+        low: u64,
+        high: u64,
+        max_size: impl Into<Option<u64>>,
+    ) -> RaftResult<Vec<Entry>> {
+        let state = self.state.read().unwrap();
+        let first_index = state.first_index();
+        if low < first_index {
+            return Err(RaftError::Store(StorageError::Compacted));
+        }
+        let last_index = state.last_index() + 1;
+        if high > last_index {
+            panic!("index out of bound (last: {}, high: {})", last_index, high);
+        }
+
+        let lo = (low - first_index) as usize;
+        let hi = (high - first_index) as usize;
+        let mut entries = state.entries[lo..hi].to_vec();
+        raft::util::limit_size(&mut entries, max_size.into());
+        Ok(entries)
+    }
+
+    fn term(&self, idx: u64) -> RaftResult<u64> {
+        self.state.read().unwrap().term(idx)
+    }
+
+    fn first_index(&self) -> RaftResult<u64> {
+        Ok(self.state.read().unwrap().first_index())
+    }
+
+    fn last_index(&self) -> RaftResult<u64> {
+        Ok(self.state.read().unwrap().last_index())
+    }
+
+    fn snapshot(&self, request_index: u64) -> RaftResult<Snapshot> {
+        let mut state = self.state.write().unwrap();
+        let index = state.snapshot.get_metadata().index;
+        if index > 0 && index >= request_index && index >= state.truncated.0 {
+            return Ok(state.snapshot.clone());
+        }
+        // The periodic snapshot is missing or too old, take a fresh one.
+        if state.applied < request_index {
+            return Err(RaftError::Store(
+                StorageError::SnapshotTemporarilyUnavailable,
+            ));
+        }
+        state.create_snapshot()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn entry(index: u64, term: u64) -> Entry {
+        let mut e = Entry::new();
+        e.index = index;
+        e.term = term;
+        e.data = vec![0; 16];
+        e
+    }
+
+    fn storage(compaction: CompactionPolicy) -> MemStorage {
+        let config = Config {
+            compaction,
+            ..Config::default()
+        };
+        block_on(MemStorage::new_with_conf_state(
+            NodeId(1),
+            &config,
+            ConfState::from((vec![1], vec![])),
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_append_replaces_conflicts() {
+        let s = storage(CompactionPolicy::default());
+        block_on(async {
+            s.append(&[entry(2, 1), entry(3, 1), entry(4, 1)])
+                .await
+                .unwrap();
+            s.append(&[entry(3, 2)]).await.unwrap();
+        });
+        assert_eq!(s.first_index().unwrap(), 2);
+        assert_eq!(s.last_index().unwrap(), 3);
+        assert_eq!(s.term(1).unwrap(), 1);
+        assert_eq!(s.term(3).unwrap(), 2);
+        assert_eq!(
+            s.entries(2, 4, None)
+                .unwrap()
+                .iter()
+                .map(|e| e.index)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+    }
+
+    #[test]
+    fn test_compaction_keeps_retention_and_snapshot() {
+        let mut s = storage(CompactionPolicy {
+            retain_entries: 2,
+            retain_bytes: None,
+            snapshot_interval: 5,
+        });
+        block_on(async {
+            let entries: Vec<Entry> = (2..=10).map(|i| entry(i, 1)).collect();
+            s.append(&entries).await.unwrap();
+            s.put(0, b"snot", b"badger").await;
+            s.set_applied(8).await.unwrap();
+            s.compact(8).await.unwrap();
+        });
+        // The snapshot is taken at 8, the log is kept for two more entries.
+        assert_eq!(s.snapshot(0).unwrap().get_metadata().index, 8);
+        assert_eq!(s.first_index().unwrap(), 7);
+        assert_eq!(s.last_index().unwrap(), 10);
+        assert_eq!(
+            s.entries(5, 7, None),
+            Err(RaftError::Store(StorageError::Compacted))
+        );
+    }
+
+    #[test]
+    fn test_write_batch_is_atomic() {
+        let s = storage(CompactionPolicy::default());
+        block_on(async {
+            s.put(0, b"snot", b"badger").await;
+            let conflicting = [
+                BatchOp::Put {
+                    scope: 0,
+                    key: b"snot".to_vec(),
+                    value: b"badger2".to_vec(),
+                },
+                BatchOp::Compare {
+                    scope: 0,
+                    key: b"fox".to_vec(),
+                    value: Some(b"den".to_vec()),
+                },
+            ];
+            assert_eq!(
+                s.write_batch(&conflicting).await,
+                BatchResult::Conflict(vec![(1, None)])
+            );
+            assert_eq!(s.get(0, b"snot").await, Some(b"badger".to_vec()));
+
+            let ops = [
+                BatchOp::Compare {
+                    scope: 0,
+                    key: b"snot".to_vec(),
+                    value: Some(b"badger".to_vec()),
+                },
+                BatchOp::Put {
+                    scope: 0,
+                    key: b"fox".to_vec(),
+                    value: b"den".to_vec(),
+                },
+                BatchOp::Delete {
+                    scope: 0,
+                    key: b"snot".to_vec(),
+                },
+            ];
+            assert_eq!(
+                s.write_batch(&ops).await,
+                BatchResult::Applied(vec![
+                    Some(b"badger".to_vec()),
+                    None,
+                    Some(b"badger".to_vec())
+                ])
+            );
+            assert_eq!(s.get(0, b"snot").await, None);
+            assert_eq!(s.get(0, b"fox").await, Some(b"den".to_vec()));
+            assert_eq!(
+                s.cas(0, b"fox", None, b"hole").await,
+                Some(Some(b"den".to_vec()))
+            );
+            assert_eq!(s.cas(0, b"fox", Some(b"den"), b"hole").await, None);
+        });
+    }
+
+    #[test]
+    fn test_scan_stays_in_range_and_scope() {
+        let s = storage(CompactionPolicy::default());
+        let keys = |entries: Vec<(Vec<u8>, Vec<u8>)>| {
+            entries
+                .into_iter()
+                .map(|(k, _)| String::from_utf8(k).unwrap())
+                .collect::<Vec<_>>()
+        };
+        block_on(async {
+            for key in &["a", "b", "c", "d"] {
+                s.put(0, key.as_bytes(), b"v").await;
+            }
+            s.put(1, b"a", b"other scope").await;
+            assert_eq!(
+                keys(s.scan(0, b"", None, 10, false).await),
+                ["a", "b", "c", "d"]
+            );
+            assert_eq!(
+                keys(s.scan(0, b"b", Some(b"d"), 10, false).await),
+                ["b", "c"]
+            );
+            assert_eq!(keys(s.scan(0, b"", None, 3, true).await), ["d", "c", "b"]);
+            assert_eq!(
+                keys(s.scan(0, b"a", Some(b"c"), 10, true).await),
+                ["b", "a"]
+            );
+            assert!(s.scan(0, b"d", Some(b"a"), 10, false).await.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let mut leader = storage(CompactionPolicy::default());
+        let mut follower = block_on(MemStorage::new(NodeId(2), &Config::default())).unwrap();
+        let snapshot = block_on(async {
+            leader.append(&[entry(2, 1), entry(3, 1)]).await.unwrap();
+            leader.put(0, b"snot", b"badger").await;
+            leader.set_applied(3).await.unwrap();
+            leader.snapshot(0).unwrap()
+        });
+        block_on(async {
+            let meta = snapshot.get_metadata();
+            let data = snapshot.get_data();
+            let chunk = SnapshotChunk::new(meta, 0, data, Some(snapshot_checksum(data)));
+            assert_eq!(
+                follower.write_snapshot_chunk(&chunk).await.unwrap(),
+                data.len() as u64
+            );
+            assert!(follower.snapshot_received(meta.index, meta.term).await);
+
+            let mut announced = snapshot.clone();
+            announced.clear_data();
+            follower.apply_snapshot(announced).await.unwrap();
+            assert_eq!(follower.get(0, b"snot").await, Some(b"badger".to_vec()));
+        });
+        assert_eq!(follower.first_index().unwrap(), 4);
+        assert_eq!(follower.term(3).unwrap(), 1);
+    }
+}
@@ -14,7 +14,7 @@
 
 use serde_derive::{Deserialize, Serialize};
 use slog::{Key, Record, Value};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 
 #[derive(Serialize, Deserialize, Debug, Hash, PartialEq, Eq, Ord, PartialOrd, Clone, Copy)]
@@ -118,22 +118,385 @@ impl fmt::Display for ProposalId {
 
 #[derive(Default, Serialize, Deserialize, Debug, Clone)]
 pub struct Relocation {
+    /// vnodes whose primary moves to the destination node
     pub destinations: HashMap<String, Vec<u64>>,
+    /// vnodes to copy to the destination node, which becomes a replica
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub replicas: HashMap<String, Vec<u64>>,
+    /// replicas this node no longer holds
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dropped: Vec<u64>,
 }
 pub type Relocations = HashMap<String, Relocation>;
 
+/// Capacity and failure domain of an mring node
+#[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
+pub struct NodeMeta {
+    /// Relative capacity, a node of weight 2 is meant to hold twice the
+    /// vnodes of a node of weight 1
+    #[serde(default = "NodeMeta::default_weight")]
+    pub weight: u32,
+    /// Failure domain, replicas of a vnode are kept in different zones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rack: Option<String>,
+}
+
+impl NodeMeta {
+    fn default_weight() -> u32 {
+        1
+    }
+}
+
+impl Default for NodeMeta {
+    fn default() -> Self {
+        Self {
+            weight: Self::default_weight(),
+            zone: None,
+            rack: None,
+        }
+    }
+}
+
 #[derive(PartialEq, Default, Serialize, Deserialize, Debug, Clone)]
 pub struct MRingNode {
     pub id: String,
+    /// vnodes this node is the primary of
     pub vnodes: Vec<u64>,
+    /// vnodes this node holds a replica of
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replicas: Vec<u64>,
+    /// the place of this node in the preference list of each of its
+    /// replicas, in the order of `replicas`, 1 is the first after the primary
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ranks: Vec<usize>,
+    #[serde(flatten)]
+    pub meta: NodeMeta,
 }
 
 pub type MRingNodes = Vec<MRingNode>;
 
+/// The vnode a key belongs to: the 64 bit FNV-1a hash of the key bytes
+/// modulo the ring size. The hash is part of the protocol and won't change,
+/// `None` if the ring has no vnodes.
+pub fn key_vnode(key: &[u8], size: u64) -> Option<u64> {
+    let hash = key.iter().fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    hash.checked_rem(size)
+}
+
+/// The primary of a vnode followed by the nodes holding a replica of it,
+/// in the order of its preference list. Replicas without a rank come last
+/// in ring order.
+pub fn vnode_nodes(nodes: &[MRingNode], vnode: u64) -> Vec<String> {
+    let primary = nodes.iter().filter(|n| n.vnodes.contains(&vnode));
+    let mut replicas: Vec<(usize, &MRingNode)> = nodes
+        .iter()
+        .filter_map(|n| {
+            let pos = n.replicas.iter().position(|v| *v == vnode)?;
+            Some((n.ranks.get(pos).copied().unwrap_or(usize::MAX), n))
+        })
+        .collect();
+    replicas.sort_by_key(|(rank, _)| *rank);
+    primary
+        .chain(replicas.into_iter().map(|(_, n)| n))
+        .map(|n| n.id.clone())
+        .collect()
+}
+
+/// A vnode handed from `src` to `dst` by a ring change, kept until `dst`
+/// reports that it received it
+#[derive(PartialEq, Default, Serialize, Deserialize, Debug, Clone)]
+pub struct PendingRelocation {
+    pub vnode: u64,
+    pub src: String,
+    pub dst: String,
+    /// `dst` becomes a replica of the vnode rather than its primary
+    #[serde(default)]
+    pub replica: bool,
+    /// Failed attempts so far, handoffs are retried until they succeed or
+    /// failed too often
+    #[serde(default)]
+    pub failures: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl PendingRelocation {
+    /// The handoffs of a ring change, ordered by vnode and destination.
+    pub fn from_relocations(relocations: &Relocations) -> Vec<Self> {
+        let mut pending = Vec::new();
+        for (src, r) in relocations {
+            let primaries = r.destinations.iter().map(|d| (d, false));
+            let replicas = r.replicas.iter().map(|d| (d, true));
+            for ((dst, vnodes), replica) in primaries.chain(replicas) {
+                for vnode in vnodes {
+                    pending.push(Self {
+                        vnode: *vnode,
+                        src: src.clone(),
+                        dst: dst.clone(),
+                        replica,
+                        ..Self::default()
+                    });
+                }
+            }
+        }
+        pending.sort_by(|a, b| (a.vnode, &a.dst).cmp(&(b.vnode, &b.dst)));
+        pending
+    }
+}
+
+/// The state of the mring, `version` counts the changes made to it
+#[derive(PartialEq, Default, Serialize, Deserialize, Debug, Clone)]
+pub struct Ring {
+    pub version: u64,
+    pub size: u64,
+    pub nodes: MRingNodes,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resizing: Option<Resizing>,
+}
+
+/// A resize in progress. The ring before the resize stays around until
+/// every vnode in `pending` reached the nodes it is handed to.
+#[derive(PartialEq, Default, Serialize, Deserialize, Debug, Clone)]
+pub struct Resizing {
+    pub size: u64,
+    pub nodes: MRingNodes,
+    /// vnodes of the new size and the nodes still waiting for them
+    pub pending: BTreeMap<u64, Vec<String>>,
+}
+
+impl Resizing {
+    pub fn new(size: u64, nodes: MRingNodes, relocations: &Relocations) -> Self {
+        let mut pending: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+        for r in relocations.values() {
+            for (dst, vnodes) in r.destinations.iter().chain(&r.replicas) {
+                for vnode in vnodes {
+                    pending.entry(*vnode).or_default().push(dst.clone());
+                }
+            }
+        }
+        for nodes in pending.values_mut() {
+            nodes.sort();
+        }
+        Self {
+            size,
+            nodes,
+            pending,
+        }
+    }
+
+    /// Records that `node` received `vnode`, returns false if it wasn't
+    /// waiting for it.
+    pub fn complete(&mut self, vnode: u64, node: &str) -> bool {
+        let nodes = if let Some(nodes) = self.pending.get_mut(&vnode) {
+            nodes
+        } else {
+            return false;
+        };
+        let len = nodes.len();
+        nodes.retain(|n| n != node);
+        let completed = nodes.len() < len;
+        if nodes.is_empty() {
+            self.pending.remove(&vnode);
+        }
+        completed
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+/// Where a key lives, `nodes` starts with the primary of its vnode
+#[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
+pub struct Route {
+    pub key: String,
+    pub vnode: u64,
+    pub nodes: Vec<String>,
+    /// While its vnode is still being handed off after a resize the key is
+    /// served by these nodes, primary first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub previous: Vec<String>,
+}
+
+impl Ring {
+    /// `None` until the ring size is set
+    pub fn route(&self, key: String) -> Option<Route> {
+        let vnode = key_vnode(key.as_bytes(), self.size)?;
+        let nodes = vnode_nodes(&self.nodes, vnode);
+        let previous = match &self.resizing {
+            Some(resizing) if resizing.pending.contains_key(&vnode) => {
+                key_vnode(key.as_bytes(), resizing.size)
+                    .map(|old| vnode_nodes(&resizing.nodes, old))
+                    .unwrap_or_default()
+            }
+            _ => Vec::new(),
+        };
+        Some(Route {
+            key,
+            vnode,
+            nodes,
+            previous,
+        })
+    }
+}
+
+/// Reply to an mring lookup
+#[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
+pub struct Lookup {
+    pub version: u64,
+    #[serde(flatten)]
+    pub route: Route,
+}
+
+/// Reply to a batch mring lookup, routes are in the order of the keys
+#[derive(PartialEq, Serialize, Deserialize, Debug, Clone)]
+pub struct Lookups {
+    pub version: u64,
+    pub routes: Vec<Route>,
+}
+
+/// Consistency a read is answered with
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Consistency {
+    /// Served from the local state machine, might be stale
+    Stale,
+    /// Served locally by the leader, other nodes confirm via ReadIndex
+    Lease,
+    /// Confirmed through a raft ReadIndex before it is answered
+    Linearizable,
+}
+
+impl Default for Consistency {
+    fn default() -> Self {
+        Self::Stale
+    }
+}
+
+/// How kv values are represented in requests, replies and events
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// A UTF-8 string
+    Raw,
+    /// A base64 encoded string, for binary values
+    Base64,
+    /// Any JSON value, stored in its serialized form
+    Json,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Self::Raw
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn key_vnode_is_fnv1a() {
+        // reference values of 64 bit FNV-1a
+        assert_eq!(key_vnode(b"", u64::MAX), Some(0xcbf2_9ce4_8422_2325));
+        assert_eq!(key_vnode(b"a", u64::MAX), Some(0xaf63_dc4c_8601_ec8c));
+        assert_eq!(key_vnode(b"foobar", u64::MAX), Some(0x8594_4171_f739_67e8));
+        assert_eq!(key_vnode(b"foobar", 64), Some(0x28));
+        assert_eq!(key_vnode(b"foobar", 0), None);
+    }
+
+    #[test]
+    fn ring_route() {
+        let node = |id: &str, vnodes: Vec<u64>, replicas: Vec<(u64, usize)>| {
+            let (replicas, ranks) = replicas.into_iter().unzip();
+            MRingNode {
+                id: id.into(),
+                vnodes,
+                replicas,
+                ranks,
+                meta: NodeMeta::default(),
+            }
+        };
+        let mut ring = Ring {
+            version: 3,
+            size: 4,
+            nodes: vec![
+                node("n1", vec![0, 1], vec![(2, 1), (3, 2)]),
+                node("n2", vec![2, 3], vec![(0, 2), (1, 1)]),
+                node("n3", vec![], vec![(0, 1), (3, 1)]),
+            ],
+            resizing: None,
+        };
+        // replicas come in the order of the preference list, not the ring
+        let route = ring.route("foobar".into()).unwrap();
+        assert_eq!(route.vnode, 0);
+        assert_eq!(route.nodes, vec!["n1", "n3", "n2"]);
+        ring.size = 0;
+        assert!(ring.route("foobar".into()).is_none());
+    }
+
+    #[test]
+    fn pending_relocations() {
+        let mut relocations = Relocations::new();
+        let r = relocations.entry("n1".into()).or_default();
+        r.destinations.insert("n2".into(), vec![3, 1]);
+        r.replicas.insert("n3".into(), vec![1]);
+        relocations.entry("n4".into()).or_default().dropped.push(2);
+        let pending: Vec<_> = PendingRelocation::from_relocations(&relocations)
+            .into_iter()
+            .map(|p| (p.vnode, p.src, p.dst, p.replica))
+            .collect();
+        assert_eq!(
+            pending,
+            vec![
+                (1, "n1".into(), "n2".into(), false),
+                (1, "n1".into(), "n3".into(), true),
+                (3, "n1".into(), "n2".into(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn resizing_routes_to_previous_nodes() {
+        let node = |id: &str, vnodes: Vec<u64>| MRingNode {
+            id: id.into(),
+            vnodes,
+            ..MRingNode::default()
+        };
+        let previous = vec![node("n1", vec![0]), node("n2", vec![1])];
+        let mut relocations = Relocations::new();
+        relocations
+            .entry("n1".into())
+            .or_default()
+            .destinations
+            .insert("n2".into(), vec![2]);
+        let mut ring = Ring {
+            version: 4,
+            size: 4,
+            nodes: vec![node("n1", vec![0, 3]), node("n2", vec![1, 2])],
+            resizing: Some(Resizing::new(2, previous, &relocations)),
+        };
+        // "c" hashes to vnode 2 of 4, a child of vnode 0 of 2
+        let route = ring.route("c".into()).unwrap();
+        assert_eq!(route.vnode, 2);
+        assert_eq!(route.nodes, vec!["n2"]);
+        assert_eq!(route.previous, vec!["n1"]);
+        // "foo" hashes to vnode 3 which stays where it was
+        assert!(ring.route("foo".into()).unwrap().previous.is_empty());
+
+        let resizing = ring.resizing.as_mut().unwrap();
+        assert!(!resizing.complete(2, "n1"));
+        assert!(resizing.complete(2, "n2"));
+        assert!(resizing.is_done());
+        assert!(ring.route("c".into()).unwrap().previous.is_empty());
+    }
 }
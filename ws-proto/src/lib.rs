@@ -23,6 +23,7 @@ pub enum Protocol {
     MRing,
     Version,
     Status,
+    Lock,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -47,6 +48,20 @@ pub enum ProtocolSelect {
     },
     Subscribe {
         channel: String,
+        /// Replay versioned messages starting at this revision first
+        #[serde(default)]
+        from: Option<u64>,
+    },
+    TransferLeader {
+        rid: RequestId,
+        node: NodeId,
+    },
+    ChangeMembership {
+        rid: RequestId,
+        #[serde(default)]
+        add: Vec<NodeId>,
+        #[serde(default)]
+        remove: Vec<NodeId>,
     },
 }
 
@@ -54,32 +69,284 @@ pub enum ProtocolSelect {
 pub enum KVRequest {
     Get {
         rid: RequestId,
+        #[serde(default)]
+        ns: Option<String>,
         key: String,
+        #[serde(default)]
+        consistency: Consistency,
+        #[serde(default)]
+        encoding: Encoding,
     },
     Put {
         rid: RequestId,
+        #[serde(default)]
+        ns: Option<String>,
         key: String,
-        store: String,
+        store: serde_json::Value,
+        #[serde(default)]
+        lease: Option<u64>,
+        #[serde(default)]
+        encoding: Encoding,
     },
     Delete {
         rid: RequestId,
+        #[serde(default)]
+        ns: Option<String>,
         key: String,
+        #[serde(default)]
+        encoding: Encoding,
     },
     Cas {
         rid: RequestId,
+        #[serde(default)]
+        ns: Option<String>,
+        key: String,
+        check: Option<serde_json::Value>,
+        store: serde_json::Value,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    Txn {
+        rid: RequestId,
+        #[serde(default)]
+        ns: Option<String>,
+        ops: Vec<TxnOp>,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    Scan {
+        rid: RequestId,
+        #[serde(flatten)]
+        scan: Scan,
+    },
+    Grant {
+        rid: RequestId,
+        #[serde(default)]
+        ns: Option<String>,
+        ttl: u64,
+    },
+    KeepAlive {
+        rid: RequestId,
+        #[serde(default)]
+        ns: Option<String>,
+        lease: u64,
+    },
+    Revoke {
+        rid: RequestId,
+        #[serde(default)]
+        ns: Option<String>,
+        lease: u64,
+    },
+    History {
+        rid: RequestId,
+        #[serde(default)]
+        ns: Option<String>,
+        key: String,
+        #[serde(default)]
+        consistency: Consistency,
+        #[serde(default)]
+        encoding: Encoding,
+    },
+    /// Counters are integers stored as decimal strings, absent keys count
+    /// as 0. Updates that would leave `min`..`max` are rejected.
+    Incr {
+        rid: RequestId,
+        #[serde(default)]
+        ns: Option<String>,
+        key: String,
+        #[serde(default)]
+        min: Option<i64>,
+        #[serde(default)]
+        max: Option<i64>,
+    },
+    Decr {
+        rid: RequestId,
+        #[serde(default)]
+        ns: Option<String>,
+        key: String,
+        #[serde(default)]
+        min: Option<i64>,
+        #[serde(default)]
+        max: Option<i64>,
+    },
+    Add {
+        rid: RequestId,
+        #[serde(default)]
+        ns: Option<String>,
+        key: String,
+        delta: i64,
+        #[serde(default)]
+        min: Option<i64>,
+        #[serde(default)]
+        max: Option<i64>,
+    },
+    CreateNamespace {
+        rid: RequestId,
+        ns: String,
+        #[serde(default)]
+        quota: Quota,
+    },
+    Namespaces {
+        rid: RequestId,
+    },
+}
+
+/// Selects the keys of a scan, either all keys starting with `prefix` or
+/// the ones from `start` (inclusive) to `end` (exclusive).
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Scan {
+    /// The namespace, the default one if absent
+    #[serde(default)]
+    pub ns: Option<String>,
+    pub prefix: Option<String>,
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub reverse: bool,
+    /// The `next` token of the previous page
+    pub token: Option<String>,
+    #[serde(default)]
+    pub consistency: Consistency,
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+/// Limits of a kv namespace, sizes count keys and values.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Quota {
+    pub max_keys: Option<u64>,
+    pub max_bytes: Option<u64>,
+}
+
+/// An operation of a kv transaction, the transaction is only committed when
+/// all of its compares hold. Values use the encoding of the transaction.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum TxnOp {
+    Put {
+        key: String,
+        store: serde_json::Value,
+    },
+    Delete {
+        key: String,
+    },
+    Compare {
         key: String,
-        check: Option<String>,
-        store: String,
+        check: Option<serde_json::Value>,
     },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum MRRequest {
-    SetSize { rid: RequestId, size: u64 },
-    GetSize { rid: RequestId },
-    GetNodes { rid: RequestId },
-    AddNode { rid: RequestId, node: String },
-    RemoveNode { rid: RequestId, node: String },
+    SetSize {
+        rid: RequestId,
+        size: u64,
+        #[serde(default)]
+        strategy: Option<String>,
+        #[serde(default)]
+        replicas: Option<u64>,
+    },
+    GetSize {
+        rid: RequestId,
+    },
+    GetNodes {
+        rid: RequestId,
+    },
+    /// A node with its vnodes
+    GetNode {
+        rid: RequestId,
+        node: String,
+    },
+    /// `weight`, `zone` and `rack` are optional and flattened into the request
+    AddNode {
+        rid: RequestId,
+        node: String,
+        #[serde(flatten)]
+        meta: NodeMeta,
+    },
+    RemoveNode {
+        rid: RequestId,
+        node: String,
+    },
+    /// The size, nodes and version of the ring
+    GetRing {
+        rid: RequestId,
+    },
+    /// The nodes a key is placed on, see `uring_common::key_vnode`
+    Lookup {
+        rid: RequestId,
+        key: String,
+    },
+    LookupMany {
+        rid: RequestId,
+        keys: Vec<String>,
+    },
+    /// Grows the ring to `size`, a multiple of the current size
+    Resize {
+        rid: RequestId,
+        size: u64,
+    },
+    /// Sent by `node` once it received `vnode`
+    HandoffCompleted {
+        rid: RequestId,
+        vnode: u64,
+        node: String,
+    },
+    /// Sent by the source when handing `vnode` to `node` failed
+    HandoffFailed {
+        rid: RequestId,
+        vnode: u64,
+        node: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    /// The handoffs of the last ring change that are still in flight
+    GetRelocations {
+        rid: RequestId,
+    },
+    /// Gives up on all handoffs in flight so the ring can change again
+    AbandonRelocations {
+        rid: RequestId,
+    },
+}
+
+/// Locks are held by a lease that has to be kept alive within `ttl` seconds.
+/// Elections are locks whose holder is the leader, candidates campaign with
+/// their value and resign by releasing their lease.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum LockRequest {
+    Acquire {
+        rid: RequestId,
+        name: String,
+        owner: String,
+        ttl: u64,
+    },
+    Release {
+        rid: RequestId,
+        lease: u64,
+    },
+    KeepAlive {
+        rid: RequestId,
+        lease: u64,
+    },
+    Get {
+        rid: RequestId,
+        name: String,
+    },
+    Campaign {
+        rid: RequestId,
+        election: String,
+        value: String,
+        ttl: u64,
+    },
+    Resign {
+        rid: RequestId,
+        lease: u64,
+    },
+    Observe {
+        rid: RequestId,
+        election: String,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -99,23 +366,46 @@ pub struct Reply {
     pub data: serde_json::Value,
 }
 
+/// Ring changes, `version` is the ring version after the change
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum PSMRing {
     SetSize {
         size: u64,
         strategy: String,
+        replicas: u64,
+        #[serde(default)]
+        version: u64,
     },
     NodeAdded {
         node: String,
         strategy: String,
         next: MRingNodes,
         relocations: Relocations,
+        #[serde(default)]
+        version: u64,
     },
     NodeRemoved {
         node: String,
         strategy: String,
         next: MRingNodes,
         relocations: Relocations,
+        #[serde(default)]
+        version: u64,
+    },
+    /// Every vnode of a ring of size `from` was split into `size / from`
+    /// vnodes, `vnode + i * from` starts out on the nodes holding `vnode`
+    Resized {
+        from: u64,
+        size: u64,
+        strategy: String,
+        next: MRingNodes,
+        relocations: Relocations,
+        version: u64,
+    },
+    /// All handoffs of the resize to `size` completed
+    ResizeCompleted {
+        size: u64,
+        version: u64,
     },
 }
 
@@ -137,6 +427,28 @@ pub enum PSURing {
         node: NodeId,
         new_node: NodeId,
     },
+    AddLearner {
+        node: NodeId,
+        new_node: NodeId,
+    },
+    PromoteNode {
+        node: NodeId,
+        learner: NodeId,
+    },
+    RemoveNode {
+        node: NodeId,
+        old_node: NodeId,
+    },
+    LeaderTransfer {
+        node: NodeId,
+        transferee: NodeId,
+        success: bool,
+    },
+    MembershipChange {
+        node: NodeId,
+        add: Vec<NodeId>,
+        remove: Vec<NodeId>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -145,4 +457,7 @@ pub enum SubscriberMsg {
         channel: String,
         msg: serde_json::Value,
     },
+    /// Sent instead of a replay when the requested revision is no longer
+    /// kept, `revision` is the oldest one still available.
+    Compacted { channel: String, revision: u64 },
 }